@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::metadata::FileKind;
+
+// The baseline is what /a and /b looked like the last time they agreed on a
+// path. Two-way syncing can't work without it, if /a has a file and /b
+// doesn't there is no telling if /a made it or /b deleted it. With a baseline
+// it's easy, if the baseline has it /b deleted it, if not /a made it.
+//
+// One file per sync uuid, json lines like the journal. Its rewritten whole
+// (tmp + rename) at the end of every reconcile so a crash halfway through
+// leaves the old one which is still right for anything that didn't get
+// synced, the things that did just look like both sides made the same change
+// next time around and converge.
+
+/// Per side bits of a baseline entry, inodes and mtimes differ between sides
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SideStamp {
+    pub mtime: u64,
+    pub inode: u64,
+}
+
+/// What a path looked like on both sides the last time they agreed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BaselineEntry {
+    pub kind: FileKind,
+    #[serde(default)]
+    pub size: u64,
+    /// blake3 of the contents, files only and only if we had reason to hash
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// Symlink target
+    #[serde(default)]
+    pub target: Option<PathBuf>,
+    pub lhs: SideStamp,
+    pub rhs: SideStamp,
+}
+
+/// Re-key from and everything under it to be under to instead
+pub(super) fn rekey<V>(map: &mut BTreeMap<PathBuf, V>, from: &Path, to: &Path) {
+    // Kids sort right after their parent, Path ordering is per component
+    let moved: Vec<PathBuf> = map
+        .range(from.to_path_buf()..)
+        .map(|(path, _)| path)
+        .take_while(|path| path.starts_with(from))
+        .cloned()
+        .collect();
+
+    for old in moved {
+        let Some(value) = map.remove(&old) else {
+            continue;
+        };
+        let rest = old.strip_prefix(from).unwrap_or(&old);
+        let new = if rest.as_os_str().is_empty() {
+            to.to_path_buf()
+        } else {
+            to.join(rest)
+        };
+        map.insert(new, value);
+    }
+}
+
+// One line in the file
+#[derive(Serialize, Deserialize)]
+struct Line {
+    path: PathBuf,
+    #[serde(flatten)]
+    entry: BaselineEntry,
+}
+
+/// Last agreed state of every path in a two-way sync, keyed by the path
+/// relative to either root.
+#[derive(Debug)]
+pub struct Baseline {
+    path: PathBuf,
+    entries: BTreeMap<PathBuf, BaselineEntry>,
+}
+
+impl Baseline {
+    /// Load the baseline at path, a missing file is an empty baseline aka the
+    /// first sync. Torn/garbage lines are skipped, whatever they were for will
+    /// look like it was created on both sides.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = BTreeMap::new();
+
+        match std::fs::File::open(&path) {
+            Ok(f) => {
+                for (n, line) in std::io::BufReader::new(f).lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<Line>(&line) {
+                        Ok(line) => {
+                            entries.insert(line.path, line.entry);
+                        }
+                        Err(e) => tracing::warn!(
+                            "{}:{} skipping bad baseline entry: {}",
+                            path.display(),
+                            n + 1,
+                            e
+                        ),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        Ok(Self { path, entries })
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&BaselineEntry> {
+        self.entries.get(path)
+    }
+
+    pub fn insert(&mut self, path: PathBuf, entry: BaselineEntry) {
+        self.entries.insert(path, entry);
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<BaselineEntry> {
+        self.entries.remove(path)
+    }
+
+    /// Move from and everything under it over to to, for after a rename got
+    /// applied on the other side.
+    pub fn rename_tree(&mut self, from: &Path, to: &Path) {
+        rekey(&mut self.entries, from, to);
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.entries.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Write the whole thing out, atomically replacing the old one.
+    pub fn save(&self) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut out = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+            for (path, entry) in &self.entries {
+                serde_json::to_writer(
+                    &mut out,
+                    &Line {
+                        path: path.clone(),
+                        entry: entry.clone(),
+                    },
+                )?;
+                out.write_all(b"\n")?;
+            }
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+        std::fs::rename(&tmp, &self.path)
+    }
+}
@@ -0,0 +1,127 @@
+use std::path::PathBuf;
+
+use super::limits::parse_size;
+
+// Memory budget for a sync's in flight work.
+//
+// Before this everything went through an unbounded channel into an unbounded
+// queue, so scanning 50M files meant 50M WorkItems sitting in memory waiting
+// for writers to catch up. Now the budget is split up roughly like so:
+//
+// - A quarter of it for the reader -> queue channel, once that is full the
+//   scanners block until the queue takes more.
+// - Once ready work in the queue goes over half the budget (the high water
+//   mark) we stop taking from the channel until writers get it back down to a
+//   quarter (the low water mark).
+// - Blocked work (waiting on a parent dir) can't drain until the reader gets
+//   further along, so it can't be used for backpressure or we'd deadlock.
+//   Instead once it goes over a quarter of the budget it gets spilled to disk.
+//
+// - Fan-out syncs give each destination a quarter of the budget to fall
+//   behind the fastest one by, past that its backlog goes to disk too.
+//
+// None of this is exact, WorkItem::mem_size() is an estimate, but it keeps
+// memory use flat on huge trees which is the point.
+
+/// 256MiB per sync by default, enough for a few hundred thousand items
+pub const DEFAULT_MEMORY_BUDGET: u64 = 256 * 1024 * 1024;
+
+/// Smallest budget we'll take, below this the channel is uselessly small
+pub const MIN_MEMORY_BUDGET: u64 = 4 * 1024 * 1024;
+
+/// Rough average size of a WorkItem for sizing the channel, paths included
+const EST_ITEM_BYTES: u64 = 512;
+
+const MIN_CHANNEL_CAPACITY: usize = 1024;
+const MAX_CHANNEL_CAPACITY: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBudget {
+    bytes: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(bytes: u64) -> Self {
+        Self {
+            bytes: bytes.max(MIN_MEMORY_BUDGET),
+        }
+    }
+
+    /// Parse a budget like 512M or 2G, same suffixes as limits
+    pub fn parse(s: &str) -> Result<Self, String> {
+        parse_size(s).map(Self::new)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Number of WorkItems the reader -> queue channel can hold
+    pub fn channel_capacity(&self) -> usize {
+        ((self.bytes / 4 / EST_ITEM_BYTES) as usize)
+            .clamp(MIN_CHANNEL_CAPACITY, MAX_CHANNEL_CAPACITY)
+    }
+
+    /// Stop taking work from the reader once ready work is over this
+    pub fn high_water(&self) -> usize {
+        (self.bytes / 2) as usize
+    }
+
+    /// Start taking work from the reader again once ready work is under this
+    pub fn low_water(&self) -> usize {
+        (self.bytes / 4) as usize
+    }
+
+    /// Spill blocked work to disk once it goes over this
+    pub fn spill_threshold(&self) -> usize {
+        (self.bytes / 4) as usize
+    }
+
+    /// Where a sync spills blocked work to, under the per user cache dir so
+    /// nobody else gets to plant things in it.
+    pub fn spill_dir(&self, uuid: u128) -> PathBuf {
+        spill_root().join(uuid::Uuid::from_u128(uuid).to_string())
+    }
+
+    /// How far a fan-out destination can fall behind before its backlog
+    /// goes to disk
+    pub fn fan_out_threshold(&self) -> usize {
+        (self.bytes / 4) as usize
+    }
+
+    /// Where a fan-out destination's backlog overflows to
+    pub fn fan_out_file(&self, uuid: u128) -> PathBuf {
+        self.spill_dir(uuid).with_extension("fanout")
+    }
+}
+
+// Made 0700 up front so whatever makes the per sync dirs and files in it
+// doesn't have to care. If it can't be made they'll say so when they fail.
+fn spill_root() -> PathBuf {
+    let root = directories::ProjectDirs::from("net", "mitchty", "yeet")
+        .expect("couldn't determine project directory location")
+        .cache_dir()
+        .join("spill");
+
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    let _ = builder.create(&root);
+    root
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMORY_BUDGET)
+    }
+}
+
+impl std::fmt::Display for MemoryBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}MiB", self.bytes / (1024 * 1024))
+    }
+}
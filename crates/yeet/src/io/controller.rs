@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+// Feedback controller for writer concurrency and batch sizes.
+//
+// Not a real PID controller despite all the TODOs saying I'd write one, its a
+// dumb hill climber which is good enough to start. Writers report how much they
+// did and how long it took, every TUNE_INTERVAL we look at throughput and
+// latency and nudge the number of active writers up/down one at a time:
+//
+// - Throughput went up? Keep going the same direction.
+// - Throughput went down? Reverse direction.
+// - Latency per op blew up compared to the best we've seen? Back off.
+// - Nothing much changed? Hold, but probe every so often in case the device
+//   has more in it.
+//
+// Batch sizes follow from the measured op rate, aim for each writer batch to be
+// about TARGET_BATCH_SECS worth of work so the queue lock isn't hammered but
+// work still gets spread around evenly.
+//
+// Controllers are per destination device (st_dev), so syncs hitting the same
+// disk share what was learned. Network filesystems are pinned to at most
+// NETWORK_MAX_WRITERS writers, more than that just makes smb/nfs servers sad.
+
+/// How often the controller re-evaluates
+const TUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Changes smaller than this fraction are noise
+const NOISE: f64 = 0.05;
+
+/// Back off if latency per op is this many times worse than the best seen
+const LATENCY_BACKOFF: f64 = 4.0;
+
+/// Ticks to hold steady before probing another step
+const PROBE_AFTER_TICKS: u32 = 5;
+
+/// Rough cost of a file op in bytes so a tree of tiny files still counts as
+/// throughput.
+pub(crate) const OP_COST_BYTES: f64 = 64.0 * 1024.0;
+
+/// Target wall clock time for a single writer batch
+const TARGET_BATCH_SECS: f64 = 0.1;
+
+pub const MIN_WRITER_BATCH: usize = 10;
+pub const MAX_WRITER_BATCH: usize = 1000;
+pub const MIN_QUEUE_BATCH: usize = 100;
+pub const MAX_QUEUE_BATCH: usize = 10000;
+
+/// Network filesystems never get more than this many writers
+pub const NETWORK_MAX_WRITERS: usize = 2;
+
+/// One round of measurements from the writers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sample {
+    pub bytes: u64,
+    pub ops: u64,
+    /// Time spent by writers doing those ops, summed across writers
+    pub busy: Duration,
+    /// Wall clock time the sample covers
+    pub elapsed: Duration,
+}
+
+/// Snapshot of what the controller is doing for stats/monitor output
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ControllerState {
+    pub device: u64,
+    pub network: bool,
+    pub active_writers: usize,
+    pub max_writers: usize,
+    pub writer_batch: usize,
+    pub queue_batch: usize,
+    pub throughput_bps: f64,
+    pub ops_per_sec: f64,
+    pub avg_latency_us: f64,
+}
+
+#[derive(Debug)]
+struct Tuning {
+    last_tick: Instant,
+    last_score: f64,
+    best_latency_us: f64,
+    direction: isize,
+    steady_ticks: u32,
+    throughput_bps: f64,
+    ops_per_sec: f64,
+    avg_latency_us: f64,
+}
+
+#[derive(Debug)]
+pub struct Controller {
+    device: u64,
+    network: bool,
+    max_writers: AtomicUsize,
+    active_writers: AtomicUsize,
+    writer_batch: AtomicUsize,
+    queue_batch: AtomicUsize,
+
+    // Accumulated since the last tick
+    bytes: AtomicU64,
+    ops: AtomicU64,
+    busy_us: AtomicU64,
+
+    tuning: Mutex<Tuning>,
+}
+
+impl Controller {
+    pub fn new(device: u64, network: bool, max_writers: usize) -> Self {
+        let max_writers = Self::clamp_max(network, max_writers);
+
+        Self {
+            device,
+            network,
+            max_writers: AtomicUsize::new(max_writers),
+            active_writers: AtomicUsize::new(max_writers),
+            writer_batch: AtomicUsize::new(100),
+            queue_batch: AtomicUsize::new(1000),
+            bytes: AtomicU64::new(0),
+            ops: AtomicU64::new(0),
+            busy_us: AtomicU64::new(0),
+            tuning: Mutex::new(Tuning {
+                last_tick: Instant::now(),
+                last_score: 0.0,
+                best_latency_us: f64::MAX,
+                direction: -1,
+                steady_ticks: 0,
+                throughput_bps: 0.0,
+                ops_per_sec: 0.0,
+                avg_latency_us: 0.0,
+            }),
+        }
+    }
+
+    fn clamp_max(network: bool, max_writers: usize) -> usize {
+        let max_writers = max_writers.max(1);
+        if network {
+            max_writers.min(NETWORK_MAX_WRITERS)
+        } else {
+            max_writers
+        }
+    }
+
+    pub fn is_network(&self) -> bool {
+        self.network
+    }
+
+    /// Raise/lower the ceiling, returns what the ceiling actually ended up
+    /// being after network pinning. Writer pools spawn this many threads.
+    pub fn set_max_writers(&self, max_writers: usize) -> usize {
+        let max_writers = Self::clamp_max(self.network, max_writers);
+        self.max_writers.store(max_writers, Ordering::Relaxed);
+        if self.active_writers.load(Ordering::Relaxed) > max_writers {
+            self.active_writers.store(max_writers, Ordering::Relaxed);
+        }
+        max_writers
+    }
+
+    pub fn max_writers(&self) -> usize {
+        self.max_writers.load(Ordering::Relaxed)
+    }
+
+    pub fn active_writers(&self) -> usize {
+        self.active_writers.load(Ordering::Relaxed)
+    }
+
+    /// Workers with an id >= the active count sit things out.
+    pub fn may_run(&self, worker_id: usize) -> bool {
+        worker_id < self.active_writers()
+    }
+
+    pub fn writer_batch(&self) -> usize {
+        self.writer_batch.load(Ordering::Relaxed)
+    }
+
+    pub fn queue_batch(&self) -> usize {
+        self.queue_batch.load(Ordering::Relaxed)
+    }
+
+    /// Writers call this after each batch, whoever notices the interval is up
+    /// does the tuning.
+    pub fn record(&self, bytes: u64, ops: u64, busy: Duration) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.ops.fetch_add(ops, Ordering::Relaxed);
+        self.busy_us
+            .fetch_add(busy.as_micros() as u64, Ordering::Relaxed);
+
+        // Only one writer needs to do this, everyone else keeps on writing.
+        let Some(mut tuning) = self.tuning.try_lock() else {
+            return;
+        };
+
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(tuning.last_tick);
+        if elapsed < TUNE_INTERVAL {
+            return;
+        }
+        tuning.last_tick = now;
+
+        let sample = Sample {
+            bytes: self.bytes.swap(0, Ordering::Relaxed),
+            ops: self.ops.swap(0, Ordering::Relaxed),
+            busy: Duration::from_micros(self.busy_us.swap(0, Ordering::Relaxed)),
+            elapsed,
+        };
+
+        self.tune(&mut tuning, sample);
+    }
+
+    fn tune(&self, tuning: &mut Tuning, sample: Sample) {
+        // Idle, nothing to learn from
+        if sample.ops == 0 || sample.elapsed.is_zero() {
+            return;
+        }
+
+        let secs = sample.elapsed.as_secs_f64();
+        let bps = sample.bytes as f64 / secs;
+        let ops_ps = sample.ops as f64 / secs;
+        let latency_us = sample.busy.as_micros() as f64 / sample.ops as f64;
+        let score = bps + ops_ps * OP_COST_BYTES;
+
+        tuning.throughput_bps = bps;
+        tuning.ops_per_sec = ops_ps;
+        tuning.avg_latency_us = latency_us;
+        tuning.best_latency_us = tuning.best_latency_us.min(latency_us);
+
+        let active = self.active_writers();
+        let max = self.max_writers();
+
+        let step = if tuning.last_score == 0.0 {
+            // First sample is the baseline
+            0
+        } else if latency_us > tuning.best_latency_us * LATENCY_BACKOFF && active > 1 {
+            tuning.direction = -1;
+            -1
+        } else if score < tuning.last_score * (1.0 - NOISE) {
+            tuning.direction = -tuning.direction;
+            tuning.direction
+        } else if score > tuning.last_score * (1.0 + NOISE) {
+            tuning.direction
+        } else {
+            tuning.steady_ticks += 1;
+            if tuning.steady_ticks >= PROBE_AFTER_TICKS {
+                // Bounce off the walls when probing
+                if active >= max {
+                    tuning.direction = -1;
+                } else if active <= 1 {
+                    tuning.direction = 1;
+                }
+                tuning.direction
+            } else {
+                0
+            }
+        };
+
+        if step != 0 {
+            tuning.steady_ticks = 0;
+        }
+
+        let new_active = active.saturating_add_signed(step).clamp(1, max);
+        if new_active != active {
+            tracing::debug!(
+                "device {} writers {} -> {} ({:.0} B/s {:.0} ops/s {:.0}us/op)",
+                self.device,
+                active,
+                new_active,
+                bps,
+                ops_ps,
+                latency_us
+            );
+            self.active_writers.store(new_active, Ordering::Relaxed);
+        }
+
+        // Aim for each batch to be ~TARGET_BATCH_SECS of work per writer, and
+        // the queue to get enough at a time to feed everyone once.
+        let per_writer = ops_ps / new_active as f64;
+        let writer_batch =
+            ((per_writer * TARGET_BATCH_SECS) as usize).clamp(MIN_WRITER_BATCH, MAX_WRITER_BATCH);
+        let queue_batch = (writer_batch * new_active).clamp(MIN_QUEUE_BATCH, MAX_QUEUE_BATCH);
+
+        self.writer_batch.store(writer_batch, Ordering::Relaxed);
+        self.queue_batch.store(queue_batch, Ordering::Relaxed);
+
+        tuning.last_score = score;
+    }
+
+    pub fn state(&self) -> ControllerState {
+        let tuning = self.tuning.lock();
+        ControllerState {
+            device: self.device,
+            network: self.network,
+            active_writers: self.active_writers(),
+            max_writers: self.max_writers(),
+            writer_batch: self.writer_batch(),
+            queue_batch: self.queue_batch(),
+            throughput_bps: tuning.throughput_bps,
+            ops_per_sec: tuning.ops_per_sec,
+            avg_latency_us: tuning.avg_latency_us,
+        }
+    }
+}
+
+/// Daemon wide registry of controllers keyed by destination device.
+#[derive(Debug, Clone, Default)]
+pub struct Controllers {
+    devices: Arc<Mutex<HashMap<u64, Arc<Controller>>>>,
+}
+
+impl Controllers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the controller for whatever device dest lives on, creating one if
+    /// this is the first time we've written there.
+    pub fn for_dest(&self, dest: &Path, network: bool, max_writers: usize) -> Arc<Controller> {
+        let device = device_id(dest);
+        self.devices
+            .lock()
+            .entry(device)
+            .or_insert_with(|| Arc::new(Controller::new(device, network, max_writers)))
+            .clone()
+    }
+
+    pub fn states(&self) -> Vec<ControllerState> {
+        self.devices.lock().values().map(|c| c.state()).collect()
+    }
+}
+
+#[cfg(unix)]
+fn device_id(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).map(|m| m.dev()).unwrap_or(0)
+}
+
+// Everything is one big device on platforms I don't care about
+#[cfg(not(unix))]
+fn device_id(_path: &Path) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(bytes: u64, ops: u64, busy_ms: u64) -> Sample {
+        Sample {
+            bytes,
+            ops,
+            busy: Duration::from_millis(busy_ms),
+            elapsed: Duration::from_secs(1),
+        }
+    }
+
+    fn tune(c: &Controller, s: Sample) {
+        let mut tuning = c.tuning.lock();
+        c.tune(&mut tuning, s);
+    }
+
+    #[test]
+    fn test_network_is_pinned() {
+        let c = Controller::new(1, true, 16);
+        assert_eq!(c.max_writers(), NETWORK_MAX_WRITERS);
+        assert_eq!(c.set_max_writers(32), NETWORK_MAX_WRITERS);
+        assert!(!c.may_run(NETWORK_MAX_WRITERS));
+    }
+
+    #[test]
+    fn test_backs_off_when_throughput_drops() {
+        let c = Controller::new(1, false, 8);
+        assert_eq!(c.active_writers(), 8);
+
+        // Baseline, no change
+        tune(&c, sample(100 << 20, 1000, 1000));
+        assert_eq!(c.active_writers(), 8);
+
+        // Worse, reverse direction (starts heading down so now up, clamped)
+        tune(&c, sample(50 << 20, 500, 500));
+        assert_eq!(c.active_writers(), 8);
+
+        // Worse again, reverse back down
+        tune(&c, sample(25 << 20, 250, 250));
+        assert_eq!(c.active_writers(), 7);
+
+        // Better, keep heading down
+        tune(&c, sample(50 << 20, 500, 500));
+        assert_eq!(c.active_writers(), 6);
+    }
+
+    #[test]
+    fn test_latency_spike_backs_off() {
+        let c = Controller::new(1, false, 4);
+        tune(&c, sample(10 << 20, 1000, 100));
+
+        // Same throughput but 10x the latency per op
+        tune(&c, sample(10 << 20, 1000, 1000));
+        assert_eq!(c.active_writers(), 3);
+    }
+
+    #[test]
+    fn test_probes_after_holding_steady() {
+        let c = Controller::new(1, false, 4);
+        for _ in 0..PROBE_AFTER_TICKS {
+            tune(&c, sample(10 << 20, 1000, 1000));
+            assert_eq!(c.active_writers(), 4);
+        }
+        tune(&c, sample(10 << 20, 1000, 1000));
+        assert_eq!(c.active_writers(), 3);
+    }
+
+    #[test]
+    fn test_batch_sizes_follow_op_rate() {
+        let c = Controller::new(1, false, 4);
+
+        tune(&c, sample(0, 40_000, 1000));
+        // 10k ops/s per writer at 100ms a batch
+        assert_eq!(c.writer_batch(), MAX_WRITER_BATCH);
+        assert_eq!(c.queue_batch(), 4000);
+
+        tune(&c, sample(0, 40, 1000));
+        assert_eq!(c.writer_batch(), MIN_WRITER_BATCH);
+        assert!(c.queue_batch() >= MIN_QUEUE_BATCH);
+    }
+
+    #[test]
+    fn test_idle_does_nothing() {
+        let c = Controller::new(1, false, 4);
+        tune(&c, Sample::default());
+        assert_eq!(c.state().ops_per_sec, 0.0);
+        assert_eq!(c.active_writers(), 4);
+    }
+
+    #[test]
+    fn test_controllers_share_per_device() {
+        let controllers = Controllers::new();
+        let dir = std::env::temp_dir();
+        let a = controllers.for_dest(&dir, false, 4);
+        let b = controllers.for_dest(&dir, false, 8);
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(controllers.states().len(), 1);
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+// Editors and build tools don't write a file once, they write it a bunch. vim
+// writes a temp file and renames it over the original, compilers truncate and
+// then append in little bits, loggers append forever. Syncing on every watch
+// event copies the same file over and over, or worse copies half of one.
+//
+// So changed paths sit in here until they've been quiet for a bit and then go
+// out as one batch. Before a path goes out it gets stat()ed, if it was
+// modified within the quiet window or its size moved since the last look
+// something is still writing to it and it waits another window. Nothing waits
+// longer than max_delay though, a log that never stops growing still gets
+// synced every so often and quick-check sorts out the rest.
+//
+// There is no timer in here, whoever owns this asks for the deadline() and
+// sleeps until then or the next event. Nothing pending means no deadline which
+// means sleeping until the watcher has something, aka hurry up and wait.
+
+/// How long a path has to go without events before it gets synced
+pub const DEFAULT_QUIET: Duration = Duration::from_millis(500);
+
+/// Longest a path can keep getting pushed back for still changing
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebounceConfig {
+    pub quiet: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for DebounceConfig {
+    fn default() -> Self {
+        Self {
+            quiet: DEFAULT_QUIET,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+}
+
+// mtime and size, same thing quick-check compares
+type Stamp = (Option<SystemTime>, u64);
+
+#[derive(Debug)]
+struct Pending {
+    first: Instant,
+    last: Instant,
+    // What the path looked like the last time we checked if it was done, None
+    // until the first check.
+    stamp: Option<Stamp>,
+}
+
+/// Collects changed paths and hands them back once they've settled down
+#[derive(Debug)]
+pub struct Debouncer {
+    config: DebounceConfig,
+    pending: HashMap<PathBuf, Pending>,
+}
+
+impl Debouncer {
+    pub fn new(config: DebounceConfig) -> Self {
+        Self {
+            config,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Note that path changed, repeat events just push its quiet window out
+    pub fn touch(&mut self, path: PathBuf, now: Instant) {
+        self.pending
+            .entry(path)
+            .and_modify(|p| p.last = now)
+            .or_insert(Pending {
+                first: now,
+                last: now,
+                stamp: None,
+            });
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn due(&self, p: &Pending) -> Instant {
+        (p.last + self.config.quiet).min(p.first + self.config.max_delay)
+    }
+
+    /// When the next path could be ready, None if nothing is pending
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|p| self.due(p)).min()
+    }
+
+    /// Paths that are done changing as of now, most recently touched first.
+    /// Anything with an ancestor in the batch is dropped as the ancestor gets
+    /// rescanned whole.
+    pub fn ready(&mut self, now: Instant) -> Vec<PathBuf> {
+        self.ready_with(now, SystemTime::now(), stat)
+    }
+
+    fn ready_with(
+        &mut self,
+        now: Instant,
+        wall: SystemTime,
+        stat: impl Fn(&Path) -> Option<Stamp>,
+    ) -> Vec<PathBuf> {
+        let due: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| self.due(p) <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        let mut out: Vec<(Instant, PathBuf)> = Vec::with_capacity(due.len());
+        for path in due {
+            // Gone already, rename-over temp files mostly. Removals aren't a
+            // thing yet so nothing to do.
+            let Some(stamp) = stat(&path) else {
+                self.pending.remove(&path);
+                continue;
+            };
+
+            let Some(p) = self.pending.get_mut(&path) else {
+                continue;
+            };
+
+            let overdue = now >= p.first + self.config.max_delay;
+            let recent = stamp
+                .0
+                .and_then(|mtime| wall.duration_since(mtime).ok())
+                .is_some_and(|age| age < self.config.quiet);
+            let moved = p.stamp.is_some_and(|s| s != stamp);
+
+            if !overdue && (recent || moved) {
+                tracing::trace!("{} still being written, waiting", path.display());
+                p.stamp = Some(stamp);
+                p.last = now;
+                continue;
+            }
+
+            if overdue && (recent || moved) {
+                tracing::debug!(
+                    "{} still changing after {:?}, syncing it anyway",
+                    path.display(),
+                    self.config.max_delay
+                );
+            }
+
+            let last = p.last;
+            self.pending.remove(&path);
+            out.push((last, path));
+        }
+
+        out.sort_by_key(|(last, _)| std::cmp::Reverse(*last));
+        let mut paths: Vec<PathBuf> = out.into_iter().map(|(_, path)| path).collect();
+
+        let keep: std::collections::HashSet<PathBuf> =
+            super::watch::collapse(paths.clone()).into_iter().collect();
+        paths.retain(|p| keep.contains(p));
+        paths
+    }
+}
+
+fn stat(path: &Path) -> Option<Stamp> {
+    let meta = std::fs::symlink_metadata(path).ok()?;
+    Some((meta.modified().ok(), meta.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DebounceConfig {
+        DebounceConfig {
+            quiet: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+
+    // Everything was last written well before "now" and never changes
+    fn settled(_: &Path) -> Option<Stamp> {
+        Some((Some(SystemTime::UNIX_EPOCH), 1))
+    }
+
+    #[test]
+    fn test_coalesces_bursts() {
+        let mut d = Debouncer::new(config());
+        let start = Instant::now();
+        let wall = SystemTime::now();
+        let ms = |n| start + Duration::from_millis(n);
+
+        assert_eq!(d.deadline(), None, "idle means no wakeups");
+
+        for n in 0..10 {
+            d.touch(PathBuf::from("/src/a"), ms(n * 10));
+        }
+        d.touch(PathBuf::from("/src/b"), ms(50));
+        assert_eq!(d.len(), 2);
+
+        // Still inside both quiet windows
+        assert!(d.ready_with(ms(120), wall, settled).is_empty());
+        assert_eq!(d.deadline(), Some(ms(150)));
+
+        // Most recently touched goes first
+        assert_eq!(
+            d.ready_with(ms(200), wall, settled),
+            vec![PathBuf::from("/src/a"), PathBuf::from("/src/b")]
+        );
+        assert!(d.is_empty());
+        assert_eq!(d.deadline(), None);
+    }
+
+    #[test]
+    fn test_waits_for_writes_to_finish() {
+        let mut d = Debouncer::new(config());
+        let start = Instant::now();
+        let wall = SystemTime::now();
+        let ms = |n| start + Duration::from_millis(n);
+
+        // mtime says someone wrote it just now
+        d.touch(PathBuf::from("/src/log"), ms(0));
+        let fresh = |_: &Path| Some((Some(wall), 10));
+        assert!(d.ready_with(ms(100), wall, fresh).is_empty());
+
+        // Old mtime but the size keeps moving, think tar preserving mtimes
+        let grew = |_: &Path| Some((Some(SystemTime::UNIX_EPOCH), 20));
+        assert!(d.ready_with(ms(200), wall, grew).is_empty());
+
+        // Settled down, out it goes
+        assert_eq!(
+            d.ready_with(ms(300), wall, grew),
+            vec![PathBuf::from("/src/log")]
+        );
+    }
+
+    #[test]
+    fn test_max_delay_and_gone() {
+        let mut d = Debouncer::new(config());
+        let start = Instant::now();
+        let wall = SystemTime::now();
+        let ms = |n| start + Duration::from_millis(n);
+
+        // Touched constantly, never quiet
+        let mut n = 0;
+        while n < 1000 {
+            d.touch(PathBuf::from("/src/busy"), ms(n));
+            n += 50;
+        }
+        d.touch(PathBuf::from("/src/tmp"), ms(900));
+        assert_eq!(d.deadline(), Some(ms(1000)));
+
+        let gone = |p: &Path| (p != Path::new("/src/tmp")).then_some((Some(wall), 1));
+        assert_eq!(
+            d.ready_with(ms(1000), wall, gone),
+            vec![PathBuf::from("/src/busy")]
+        );
+        assert!(d.is_empty(), "vanished paths are dropped");
+    }
+
+    #[test]
+    fn test_drops_kids_of_ready_dirs() {
+        let mut d = Debouncer::new(config());
+        let start = Instant::now();
+        let wall = SystemTime::now();
+
+        d.touch(PathBuf::from("/src/dir/file"), start);
+        d.touch(PathBuf::from("/src/dir"), start);
+        d.touch(PathBuf::from("/src/other"), start);
+
+        let mut ready = d.ready_with(start + Duration::from_secs(1), wall, settled);
+        ready.sort();
+        assert_eq!(
+            ready,
+            vec![PathBuf::from("/src/dir"), PathBuf::from("/src/other")]
+        );
+    }
+}
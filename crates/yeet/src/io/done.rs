@@ -0,0 +1,82 @@
+// Reader and writers each flip one of these once they're finished. Used to
+// be a Mutex<bool> that everything that cared polled every so often, this
+// way they can just wait on it, async or from a plain thread.
+
+/// Set once and stays set
+#[derive(Debug, Default)]
+pub struct Done {
+    done: parking_lot::Mutex<bool>,
+    cond: parking_lot::Condvar,
+    notify: tokio::sync::Notify,
+}
+
+impl Done {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_set(&self) -> bool {
+        *self.done.lock()
+    }
+
+    /// Mark it done and wake up anyone waiting, true if it wasn't already
+    pub fn set(&self) -> bool {
+        let was = std::mem::replace(&mut *self.done.lock(), true);
+        self.cond.notify_all();
+        self.notify.notify_waiters();
+        !was
+    }
+
+    pub async fn wait(&self) {
+        // Has to exist before the check or a set() in between gets missed
+        let notified = self.notify.notified();
+        if self.is_set() {
+            return;
+        }
+        notified.await;
+    }
+
+    /// wait() for threads that aren't async
+    pub fn wait_blocking(&self) {
+        let mut done = self.done.lock();
+        while !*done {
+            self.cond.wait(&mut done);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wakes_waiters() {
+        let done = Arc::new(Done::new());
+
+        let waiting = {
+            let done = done.clone();
+            tokio::spawn(async move { done.wait().await })
+        };
+        let blocking = {
+            let done = done.clone();
+            std::thread::spawn(move || done.wait_blocking())
+        };
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+        assert!(done.set());
+        assert!(!done.set());
+
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("async waiter never woke")
+            .unwrap();
+        blocking.join().unwrap();
+
+        // Already set means no waiting at all
+        done.wait().await;
+        done.wait_blocking();
+    }
+}
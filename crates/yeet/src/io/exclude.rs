@@ -2,6 +2,7 @@ use std::collections::HashSet;
 use std::path::Path;
 
 /// OS-specific default excludes for paths we never want to sync
+#[derive(Clone)]
 pub struct ExcludeRules {
     /// File names to exclude
     file_excludes: HashSet<&'static str>,
@@ -14,6 +15,11 @@ impl ExcludeRules {
         let file_excludes = HashSet::new();
         let mut dir_excludes = HashSet::new();
 
+        // Old versions of dest files, see trash.rs
+        dir_excludes.insert(super::trash::TRASH_DIR);
+        // Half sent files from remote syncs, see remote.rs
+        dir_excludes.insert(super::remote::STAGING_DIR);
+
         // Unix-wide directory excludes
         #[cfg(unix)]
         {
@@ -94,6 +100,13 @@ mod tests {
         assert!(!rules.should_exclude_dir(".git"));
     }
 
+    #[test]
+    fn test_trash_excluded() {
+        let rules = ExcludeRules::new();
+        assert!(rules.should_exclude_dir(super::super::trash::TRASH_DIR));
+        assert!(rules.should_exclude_dir(super::super::remote::STAGING_DIR));
+    }
+
     #[test]
     #[cfg(unix)]
     fn test_dir_path_excludes() {
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use super::controller::OP_COST_BYTES;
+
+// Weighted fair queuing across syncs sharing the daemon wide writer pool.
+//
+// Each sync (uuid) is a flow with a weight and a virtual time, which is how
+// much work it has been given divided by its weight. Writers always serve the
+// flow with the lowest virtual time that has ready work, so over time each
+// flow gets a share of the writers proportional to its weight. A huge backup
+// at weight 100 and a small interactive sync at weight 100 get half each, the
+// backup can't hog every writer just because it queued a million files first.
+//
+// Flows that are idle (nothing ready, reader still scanning, etc...) don't get
+// to bank credit, once they have work again they're pulled up to the virtual
+// clock so they can't come back and starve everyone for a while.
+//
+// Cost is bytes plus a per op cost so a tree of tiny files is charged for the
+// metadata churn it causes and not just its (tiny) bytes.
+
+/// Weight syncs get unless told otherwise
+pub const DEFAULT_WEIGHT: u32 = 100;
+
+#[derive(Debug, Clone, Copy)]
+struct Flow {
+    weight: u32,
+    vtime: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct FairQueue {
+    flows: HashMap<u128, Flow>,
+
+    /// Virtual start time of the last flow served, only ever goes up
+    clock: f64,
+}
+
+impl FairQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a flow, it starts at the current virtual clock so it neither waits
+    /// behind everyone nor gets to jump everyone.
+    pub fn add(&mut self, uuid: u128, weight: u32) {
+        let clock = self.clock;
+        self.flows
+            .entry(uuid)
+            .and_modify(|f| f.weight = weight.max(1))
+            .or_insert(Flow {
+                weight: weight.max(1),
+                vtime: clock,
+            });
+    }
+
+    pub fn remove(&mut self, uuid: u128) {
+        self.flows.remove(&uuid);
+    }
+
+    pub fn contains(&self, uuid: u128) -> bool {
+        self.flows.contains_key(&uuid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    pub fn weight(&self, uuid: u128) -> Option<u32> {
+        self.flows.get(&uuid).map(|f| f.weight)
+    }
+
+    /// Change a flows weight, false if the uuid isn't known. Only affects
+    /// future charges, what was already served stays served.
+    pub fn set_weight(&mut self, uuid: u128, weight: u32) -> bool {
+        match self.flows.get_mut(&uuid) {
+            Some(flow) => {
+                flow.weight = weight.max(1);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Flows in the order they should be offered to a writer, lowest virtual
+    /// time first.
+    pub fn order(&self) -> Vec<u128> {
+        let mut flows: Vec<(u128, f64)> = self.flows.iter().map(|(u, f)| (*u, f.vtime)).collect();
+        flows.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        flows.into_iter().map(|(u, _)| u).collect()
+    }
+
+    /// Flow had nothing ready when offered, don't let it bank credit.
+    pub fn idle(&mut self, uuid: u128) {
+        let clock = self.clock;
+        if let Some(flow) = self.flows.get_mut(&uuid) {
+            flow.vtime = flow.vtime.max(clock);
+        }
+    }
+
+    /// Charge a flow for work it was just handed.
+    pub fn charge(&mut self, uuid: u128, bytes: u64, ops: u64) {
+        if let Some(flow) = self.flows.get_mut(&uuid) {
+            self.clock = self.clock.max(flow.vtime);
+            let cost = bytes as f64 + ops as f64 * OP_COST_BYTES;
+            flow.vtime += cost / flow.weight as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Always serve whoever is first in line and count what they got.
+    fn simulate(queue: &mut FairQueue, rounds: usize, bytes: u64) -> HashMap<u128, usize> {
+        let mut served = HashMap::new();
+        for _ in 0..rounds {
+            let uuid = queue.order()[0];
+            queue.charge(uuid, bytes, 1);
+            *served.entry(uuid).or_default() += 1;
+        }
+        served
+    }
+
+    #[test]
+    fn test_equal_weights_share_equally() {
+        let mut q = FairQueue::new();
+        q.add(1, DEFAULT_WEIGHT);
+        q.add(2, DEFAULT_WEIGHT);
+
+        let served = simulate(&mut q, 1000, 4096);
+        assert_eq!(served[&1], 500);
+        assert_eq!(served[&2], 500);
+    }
+
+    #[test]
+    fn test_weights_are_proportional() {
+        let mut q = FairQueue::new();
+        q.add(1, 300);
+        q.add(2, 100);
+
+        let served = simulate(&mut q, 1000, 4096);
+        assert!((740..=760).contains(&served[&1]), "got {:?}", served);
+        assert!((240..=260).contains(&served[&2]), "got {:?}", served);
+
+        assert!(q.set_weight(2, 300));
+        assert!(!q.set_weight(3, 300));
+        assert_eq!(q.weight(2), Some(300));
+    }
+
+    #[test]
+    fn test_new_flow_does_not_wait_behind_backlog() {
+        let mut q = FairQueue::new();
+        q.add(1, DEFAULT_WEIGHT);
+        simulate(&mut q, 10_000, 1024 * 1024);
+
+        // Shows up late, should get half from here on out and not all of it
+        // (banked credit) or none of it (waiting for 1 to finish).
+        q.add(2, DEFAULT_WEIGHT);
+        let served = simulate(&mut q, 100, 1024 * 1024);
+        assert!((49..=51).contains(&served[&2]), "got {:?}", served);
+    }
+
+    #[test]
+    fn test_idle_flow_does_not_bank_credit() {
+        let mut q = FairQueue::new();
+        q.add(1, DEFAULT_WEIGHT);
+        q.add(2, DEFAULT_WEIGHT);
+
+        // 2 has nothing to do for a while
+        for _ in 0..100 {
+            q.charge(1, 4096, 1);
+            q.idle(2);
+        }
+
+        let served = simulate(&mut q, 100, 4096);
+        assert!((49..=51).contains(&served[&2]), "got {:?}", served);
+
+        q.remove(1);
+        q.remove(2);
+        assert!(q.is_empty());
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::error::TrySendError;
+
+use super::error::IoError;
+use super::progress::AtomicOperationProgress;
+use super::work::WorkItem;
+
+// Star topology, one source scan feeding N destinations.
+//
+// Each destination is its own sync with its own uuid, queue, writers,
+// progress and errors, the scan is the only thing they share. Everything the
+// reader sends comes through here and gets copied to each destination's
+// channel. If a destination is full its copy goes on that destination's
+// backlog instead of waiting, so a slow disk doesn't hold up the fast ones.
+// The reader only waits once every destination is behind, aka it goes as fast
+// as the fastest destination.
+//
+// Backlogs stay in memory up to MemoryBudget::fan_out_threshold(), past that
+// they go to a file and get read back in order as the destination catches
+// up. A slow enough destination on a big enough tree can still eat a bunch of
+// disk, future mitch can worry about that.
+
+/// One destination being fed from the scan
+pub struct Leg {
+    uuid: u128,
+    tx: tokio::sync::mpsc::Sender<WorkItem>,
+    errors: Arc<Mutex<Vec<IoError>>>,
+    mirror: Option<Mirror>,
+    backlog: VecDeque<WorkItem>,
+    backlog_bytes: usize,
+    threshold: usize,
+    overflow: Overflow,
+    /// Only if the overflow file couldn't be written, goes after it
+    tail: VecDeque<WorkItem>,
+    /// Receiver went away, nothing more to send
+    closed: bool,
+}
+
+// Destinations that aren't the one doing the scan get its found counts and
+// their reader done flag set once they have everything.
+struct Mirror {
+    progress: Arc<AtomicOperationProgress>,
+    reader_done: Arc<super::done::Done>,
+}
+
+impl Leg {
+    /// Feed tx, anything more than threshold bytes behind goes to overflow
+    pub fn new(
+        uuid: u128,
+        tx: tokio::sync::mpsc::Sender<WorkItem>,
+        errors: Arc<Mutex<Vec<IoError>>>,
+        threshold: usize,
+        overflow: PathBuf,
+    ) -> Self {
+        Self {
+            uuid,
+            tx,
+            errors,
+            mirror: None,
+            backlog: VecDeque::new(),
+            backlog_bytes: 0,
+            threshold,
+            overflow: Overflow::new(overflow),
+            tail: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    /// For destinations other than the one the reader reports to
+    pub fn with_mirror(
+        mut self,
+        progress: Arc<AtomicOperationProgress>,
+        reader_done: Arc<super::done::Done>,
+    ) -> Self {
+        self.mirror = Some(Mirror {
+            progress,
+            reader_done,
+        });
+        self
+    }
+
+    /// Anything waiting to go out
+    fn is_behind(&self) -> bool {
+        !self.closed
+            && (!self.backlog.is_empty() || !self.overflow.is_empty() || !self.tail.is_empty())
+    }
+
+    async fn push(&mut self, mut item: WorkItem, scan: &AtomicOperationProgress) {
+        if self.closed {
+            return;
+        }
+        item.set_uuid(self.uuid);
+
+        if self.is_behind() {
+            self.queue(item);
+        } else if let Some(item) = self.send(item, scan).await {
+            self.queue(item);
+        }
+    }
+
+    // Backlog in order of memory, disk, then the tail
+    fn queue(&mut self, item: WorkItem) {
+        if self.overflow.is_empty() && self.tail.is_empty() && self.backlog_bytes < self.threshold {
+            self.backlog_bytes += item.mem_size();
+            self.backlog.push_back(item);
+            return;
+        }
+
+        if self.tail.is_empty() {
+            match self.overflow.push(&item) {
+                Ok(()) => return,
+                Err(e) => tracing::warn!(
+                    "{} can't write fan-out backlog to {}, keeping it in memory: {}",
+                    uuid::Uuid::from_u128(self.uuid),
+                    self.overflow.path.display(),
+                    e
+                ),
+            }
+        }
+        self.tail.push_back(item);
+    }
+
+    /// Send as much of the backlog as the destination will take right now
+    async fn flush(&mut self, scan: &AtomicOperationProgress) {
+        while self.is_behind() {
+            if self.backlog.is_empty() {
+                self.refill().await;
+            }
+            let Some(item) = self.backlog.pop_front() else {
+                break;
+            };
+            let size = item.mem_size();
+            self.backlog_bytes = self.backlog_bytes.saturating_sub(size);
+
+            if let Some(item) = self.send(item, scan).await {
+                self.backlog_bytes += size;
+                self.backlog.push_front(item);
+                break;
+            }
+        }
+    }
+
+    // Pull the next chunk back in from disk, or the tail once that's empty
+    async fn refill(&mut self) {
+        while self.backlog.is_empty() || self.backlog_bytes < self.threshold / 2 {
+            match self.overflow.pop() {
+                Ok(Some(item)) => {
+                    self.backlog_bytes += item.mem_size();
+                    self.backlog.push_back(item);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    let msg = format!("lost fan-out backlog: {}", e);
+                    tracing::error!("{}: {}", msg, self.overflow.path.display());
+                    self.errors
+                        .lock()
+                        .await
+                        .push(IoError::destination(msg, self.overflow.path.clone()));
+                    self.overflow.clear();
+                    break;
+                }
+            }
+        }
+
+        if self.backlog.is_empty() && self.overflow.is_empty() {
+            for item in self.tail.drain(..) {
+                self.backlog_bytes += item.mem_size();
+                self.backlog.push_back(item);
+            }
+        }
+    }
+
+    // Hand item to the destination if it has room, otherwise hand it back
+    async fn send(&mut self, item: WorkItem, scan: &AtomicOperationProgress) -> Option<WorkItem> {
+        let complete = matches!(item, WorkItem::ScanComplete { .. });
+        self.mirror_counts(scan);
+
+        match self.tx.try_send(item) {
+            Ok(()) => {
+                if complete && let Some(mirror) = &self.mirror {
+                    mirror.reader_done.set();
+                }
+                None
+            }
+            Err(TrySendError::Full(item)) => Some(item),
+            Err(TrySendError::Closed(_)) => {
+                tracing::debug!(
+                    "{} stopped taking fan-out work",
+                    uuid::Uuid::from_u128(self.uuid)
+                );
+                self.closed = true;
+                self.backlog.clear();
+                self.tail.clear();
+                self.overflow.clear();
+                None
+            }
+        }
+    }
+
+    fn mirror_counts(&self, scan: &AtomicOperationProgress) {
+        let Some(mirror) = &self.mirror else {
+            return;
+        };
+        for (from, to) in [
+            (&scan.dirs_found, &mirror.progress.dirs_found),
+            (&scan.files_found, &mirror.progress.files_found),
+            (&scan.total_size, &mirror.progress.total_size),
+            (&scan.skipped_count, &mirror.progress.skipped_count),
+        ] {
+            to.store(from.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Copies everything from one reader out to every leg
+pub struct FanOut {
+    uuid: u128,
+    /// The scan's progress, what the reader updates
+    scan: Arc<AtomicOperationProgress>,
+    legs: Vec<Leg>,
+}
+
+impl FanOut {
+    pub fn new(uuid: u128, scan: Arc<AtomicOperationProgress>, legs: Vec<Leg>) -> Self {
+        Self { uuid, scan, legs }
+    }
+
+    /// Run until the reader is done and every leg has everything
+    pub fn spawn(self, rx: tokio::sync::mpsc::Receiver<WorkItem>) {
+        tokio::spawn(self.run(rx));
+    }
+
+    async fn run(mut self, mut rx: tokio::sync::mpsc::Receiver<WorkItem>) {
+        let mut scanning = true;
+
+        loop {
+            for leg in &mut self.legs {
+                leg.flush(&self.scan).await;
+            }
+            if self.legs.iter().all(|leg| leg.closed) {
+                break;
+            }
+
+            let behind = self.legs.iter().any(|leg| leg.is_behind());
+            let caught_up = self.legs.iter().any(|leg| !leg.closed && !leg.is_behind());
+
+            if !scanning && !behind {
+                break;
+            }
+
+            // Nobody can take anything right now, the reader waits until the
+            // fastest destination has room. Otherwise wait on the reader,
+            // waking up when anyone behind has room.
+            let item = if !scanning || !caught_up {
+                room(&self.legs).await;
+                continue;
+            } else if behind {
+                tokio::select! {
+                    item = rx.recv() => item,
+                    _ = room(&self.legs) => continue,
+                }
+            } else {
+                rx.recv().await
+            };
+
+            let Some(item) = item else {
+                scanning = false;
+                continue;
+            };
+
+            for leg in &mut self.legs {
+                leg.push(item.clone(), &self.scan).await;
+            }
+        }
+
+        tracing::debug!(
+            "{} fan-out to {} destinations done",
+            uuid::Uuid::from_u128(self.uuid),
+            self.legs.len()
+        );
+    }
+}
+
+// Done once any leg that's behind has room for another item, or went away
+// which counts too since flush() sorts that out. Only called with at least one
+// leg behind, otherwise it'd never wake up. The permit goes right back, only
+// we send on these so the room is still there when flush() gets to it.
+async fn room(legs: &[Leg]) {
+    let mut reserves: Vec<_> = legs
+        .iter()
+        .filter(|leg| leg.is_behind())
+        .map(|leg| Box::pin(leg.tx.reserve()))
+        .collect();
+
+    std::future::poll_fn(|cx| {
+        if reserves
+            .iter_mut()
+            .any(|reserve| reserve.as_mut().poll(cx).is_ready())
+        {
+            std::task::Poll::Ready(())
+        } else {
+            std::task::Poll::Pending
+        }
+    })
+    .await
+}
+
+// Backlog that didn't fit in memory, json lines appended at the end and read
+// back from the front. Starts over whenever it empties out so it doesn't grow
+// forever.
+struct Overflow {
+    path: PathBuf,
+    files: Option<(BufWriter<File>, BufReader<File>)>,
+    len: usize,
+}
+
+impl Overflow {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            files: None,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, item: &WorkItem) -> std::io::Result<()> {
+        if self.files.is_none() {
+            if let Some(parent) = self.path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let writer = File::create(&self.path)?;
+            let reader = File::open(&self.path)?;
+            self.files = Some((BufWriter::new(writer), BufReader::new(reader)));
+        }
+
+        let (writer, _) = self.files.as_mut().expect("overflow file was just opened");
+        serde_json::to_writer(&mut *writer, item)?;
+        writer.write_all(b"\n")?;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> std::io::Result<Option<WorkItem>> {
+        let Some((writer, reader)) = self.files.as_mut() else {
+            return Ok(None);
+        };
+        if self.len == 0 {
+            return Ok(None);
+        }
+
+        writer.flush()?;
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let item = serde_json::from_str(&line)?;
+
+        self.len -= 1;
+        if self.len == 0 {
+            self.clear();
+        }
+        Ok(Some(item))
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+        if self.files.take().is_some() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Drop for Overflow {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    fn dir(uuid: u128) -> WorkItem {
+        WorkItem::DirectoryScanned {
+            uuid,
+            dest_path: PathBuf::from(format!("d{}", uuid)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_leg_doesnt_block() {
+        let path = TestDir::new("fanout");
+        let scan = Arc::new(AtomicOperationProgress::new());
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let (fast_tx, mut fast_rx) = tokio::sync::mpsc::channel(1024);
+        let (slow_tx, mut slow_rx) = tokio::sync::mpsc::channel(1);
+        let done = Arc::new(crate::io::done::Done::new());
+
+        let legs = vec![
+            Leg::new(1, fast_tx, errors.clone(), 1024, path.join("fast")),
+            // Tiny threshold so most of it goes to disk
+            Leg::new(2, slow_tx, errors.clone(), 200, path.join("slow"))
+                .with_mirror(Arc::new(AtomicOperationProgress::new()), done.clone()),
+        ];
+
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        FanOut::new(1, scan, legs).spawn(rx);
+        for n in 0..100 {
+            tx.send(dir(n)).await.unwrap();
+        }
+        tx.send(WorkItem::ScanComplete { uuid: 0 }).await.unwrap();
+        drop(tx);
+
+        // Fast gets everything while slow hasn't taken a thing
+        for n in 0..100 {
+            let item = fast_rx.recv().await.unwrap();
+            assert_eq!(item.uuid(), 1);
+            assert_eq!(item.dest_path(), dir(n).dest_path());
+        }
+        assert!(matches!(
+            fast_rx.recv().await,
+            Some(WorkItem::ScanComplete { uuid: 1 })
+        ));
+        assert!(!done.is_set());
+
+        // Slow still gets it all in order once it gets around to it
+        for n in 0..100 {
+            let item = slow_rx.recv().await.unwrap();
+            assert_eq!(item.uuid(), 2);
+            assert_eq!(item.dest_path(), dir(n).dest_path());
+        }
+        assert!(matches!(
+            slow_rx.recv().await,
+            Some(WorkItem::ScanComplete { uuid: 2 })
+        ));
+        assert!(slow_rx.recv().await.is_none());
+        assert!(done.is_set());
+        assert!(errors.lock().await.is_empty());
+        assert!(!path.join("slow").exists(), "overflow cleaned up");
+    }
+}
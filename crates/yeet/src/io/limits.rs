@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+// Token bucket rate limiting for writers (and eventually the network path).
+//
+// Each limiter has two buckets, one for bytes and one for file operations
+// (mkdir/copy/symlink). There is one daemon wide limiter and optionally one per
+// sync uuid, callers have to get through both of them.
+//
+// Buckets are allowed to go into debt, so asking for 64MiB against a 1MiB/s
+// bucket succeeds right away but the caller is told to wait ~64 seconds. This
+// keeps the average rate correct without needing to split everything up into
+// tiny requests. Large copies still get chunked so the disk isn't hit with a
+// massive burst after a long sleep though.
+
+/// Chunk size for throttled copies, small enough that a low bandwidth limit
+/// still results in a steady trickle instead of bursts.
+pub const THROTTLE_CHUNK_SIZE: usize = 256 * 1024;
+
+/// A rate limit, 0 means unlimited for either field.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limit {
+    pub bytes_per_sec: u64,
+    pub ops_per_sec: u64,
+}
+
+impl Limit {
+    pub fn is_unlimited(&self) -> bool {
+        self.bytes_per_sec == 0 && self.ops_per_sec == 0
+    }
+}
+
+/// Simple token bucket, burst size is a seconds worth of tokens.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Tokens per second, 0 is unlimited
+    rate: u64,
+    /// Can go negative, that is the debt callers have to sleep off
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Change the rate, any banked tokens are clamped to the new burst size
+    /// but existing debt is kept so lowering a limit takes effect right away.
+    /// Going from unlimited to limited starts out with a full burst.
+    pub fn set_rate(&mut self, rate: u64) {
+        self.refill(Instant::now());
+        if self.rate == 0 {
+            self.tokens = rate as f64;
+        }
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        if self.rate > 0 {
+            self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        }
+    }
+
+    /// Take n tokens, returns how long the caller needs to wait before its
+    /// allowed to proceed.
+    pub fn take(&mut self, n: u64, now: Instant) -> Duration {
+        if self.rate == 0 || n == 0 {
+            return Duration::ZERO;
+        }
+
+        self.refill(now);
+        self.tokens -= n as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+/// Bytes and ops buckets for one scope (global or a single uuid).
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes: TokenBucket,
+    ops: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Limit) -> Self {
+        Self {
+            bytes: TokenBucket::new(limit.bytes_per_sec),
+            ops: TokenBucket::new(limit.ops_per_sec),
+        }
+    }
+
+    pub fn limit(&self) -> Limit {
+        Limit {
+            bytes_per_sec: self.bytes.rate(),
+            ops_per_sec: self.ops.rate(),
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: Limit) {
+        self.bytes.set_rate(limit.bytes_per_sec);
+        self.ops.set_rate(limit.ops_per_sec);
+    }
+
+    pub fn take(&mut self, bytes: u64, ops: u64, now: Instant) -> Duration {
+        self.bytes.take(bytes, now).max(self.ops.take(ops, now))
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(Limit::default())
+    }
+}
+
+/// Daemon wide registry of limits, cheap to clone and shared by every writer
+/// pool. Limits can be changed at runtime and writers pick them up on their
+/// next request.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    global: Arc<Mutex<RateLimiter>>,
+    per_op: Arc<Mutex<HashMap<u128, Arc<Mutex<RateLimiter>>>>>,
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn global(&self) -> Limit {
+        self.global.lock().limit()
+    }
+
+    pub fn set_global(&self, limit: Limit) {
+        self.global.lock().set_limit(limit);
+    }
+
+    /// Limit for a specific uuid, not counting the global limit.
+    pub fn get(&self, uuid: u128) -> Limit {
+        self.per_op
+            .lock()
+            .get(&uuid)
+            .map(|l| l.lock().limit())
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, uuid: u128, limit: Limit) {
+        let mut per_op = self.per_op.lock();
+        if let Some(limiter) = per_op.get(&uuid) {
+            limiter.lock().set_limit(limit);
+        } else if !limit.is_unlimited() {
+            per_op.insert(uuid, Arc::new(Mutex::new(RateLimiter::new(limit))));
+        }
+    }
+
+    /// Forget about a uuid, called once an operation completes.
+    pub fn remove(&self, uuid: u128) {
+        self.per_op.lock().remove(&uuid);
+    }
+
+    /// Is there any limit that applies to this uuid?
+    pub fn is_limited(&self, uuid: u128) -> bool {
+        !self.global().is_unlimited() || !self.get(uuid).is_unlimited()
+    }
+
+    /// Reserve bytes/ops against both the global and uuid limits, returns how
+    /// long to wait before doing the work.
+    pub fn reserve(&self, uuid: u128, bytes: u64, ops: u64) -> Duration {
+        let now = Instant::now();
+        let global_wait = self.global.lock().take(bytes, ops, now);
+
+        let limiter = self.per_op.lock().get(&uuid).cloned();
+        let op_wait = limiter
+            .map(|l| l.lock().take(bytes, ops, now))
+            .unwrap_or_default();
+
+        global_wait.max(op_wait)
+    }
+
+    /// Blocking version for use on writer threads/spawn_blocking.
+    pub fn throttle(&self, uuid: u128, bytes: u64, ops: u64) {
+        let wait = self.reserve(uuid, bytes, ops);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Async version for anything running on a tokio runtime.
+    pub async fn throttle_async(&self, uuid: u128, bytes: u64, ops: u64) {
+        let wait = self.reserve(uuid, bytes, ops);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Same idea as std::io::copy but every chunk goes through the limiter first.
+/// Only used when a limit applies, otherwise we want std::io::copy so the
+/// kernel can do copy_file_range and friends.
+pub fn copy_throttled<R: Read, W: Write>(
+    limits: &Limits,
+    uuid: u128,
+    reader: &mut R,
+    writer: &mut W,
+) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; THROTTLE_CHUNK_SIZE];
+    let mut total = 0u64;
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        limits.throttle(uuid, n as u64, 0);
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+
+    Ok(total)
+}
+
+/// Parse a byte count with an optional binary suffix, 10M == 10MiB etc...
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let upper = s.to_ascii_uppercase();
+    let trimmed = upper
+        .trim_end_matches("IB")
+        .trim_end_matches('B')
+        .trim_end();
+
+    let (digits, multiplier) = match trimmed.chars().last() {
+        Some('K') => (&trimmed[..trimmed.len() - 1], 1024u64),
+        Some('M') => (&trimmed[..trimmed.len() - 1], 1024 * 1024),
+        Some('G') => (&trimmed[..trimmed.len() - 1], 1024 * 1024 * 1024),
+        Some('T') => (&trimmed[..trimmed.len() - 1], 1024 * 1024 * 1024 * 1024),
+        _ => (trimmed, 1),
+    };
+
+    digits
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid size '{}': {}", s, e))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited_bucket_never_waits() {
+        let mut bucket = TokenBucket::new(0);
+        assert_eq!(bucket.take(u64::MAX, Instant::now()), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_bucket_goes_into_debt() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100);
+
+        // Full burst available up front
+        assert_eq!(bucket.take(100, now), Duration::ZERO);
+
+        // Another 50 tokens is half a second of debt
+        let wait = bucket.take(50, now);
+        assert!(
+            (wait.as_secs_f64() - 0.5).abs() < 0.01,
+            "expected ~0.5s wait got {:?}",
+            wait
+        );
+
+        // A second later we've paid off the debt and have 50 to spare
+        let later = now + Duration::from_secs(1);
+        assert_eq!(bucket.take(50, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_global_and_per_uuid_limits_combine() {
+        let limits = Limits::new();
+        limits.set_global(Limit {
+            bytes_per_sec: 1000,
+            ops_per_sec: 0,
+        });
+        limits.set(
+            1,
+            Limit {
+                bytes_per_sec: 0,
+                ops_per_sec: 10,
+            },
+        );
+
+        assert!(limits.is_limited(1));
+        assert!(limits.is_limited(2));
+
+        // Burn the burst of both buckets, the slower one wins
+        assert_eq!(limits.reserve(1, 1000, 10), Duration::ZERO);
+        let wait = limits.reserve(1, 100, 5);
+        assert!(wait >= Duration::from_millis(450), "wait was {:?}", wait);
+
+        limits.remove(1);
+        assert_eq!(limits.get(1), Limit::default());
+
+        limits.set_global(Limit::default());
+        assert!(!limits.is_limited(2));
+    }
+
+    #[test]
+    fn test_copy_throttled_copies_everything() {
+        let limits = Limits::new();
+        let data = vec![7u8; THROTTLE_CHUNK_SIZE * 2 + 13];
+        let mut out = Vec::new();
+
+        let copied = copy_throttled(&limits, 1, &mut data.as_slice(), &mut out).unwrap();
+        assert_eq!(copied, data.len() as u64);
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("10K"), Ok(10 * 1024));
+        assert_eq!(parse_size("10M"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("10MiB"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("2gb"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+}
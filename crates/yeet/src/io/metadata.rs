@@ -1,5 +1,7 @@
 use std::path::PathBuf;
 
+use serde::{Deserialize, Serialize};
+
 // Note: Any non unix blocks here are more to act as fillers of "future me or
 // preferably someone that knows how the hell windows works and you might sync
 // to/from it" work.
@@ -11,7 +13,7 @@ use std::path::PathBuf;
 //
 // This is a "future mitch" task for sure.
 /// File kind enum
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub enum FileKind {
     /// Regular file
     File,
@@ -31,7 +33,7 @@ pub enum FileKind {
 }
 
 /// File metadata captured during directory traversal
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMetadata {
     /// Path to the file
     pub path: PathBuf,
@@ -135,9 +137,32 @@ impl FileMetadata {
             let _ = chown(dest_path, Some(self.uid), Some(self.gid));
         }
 
+        // Keep mtime in sync so quick-check can tell this file is done next
+        // time around. Also best effort, only the owner can set it anyway.
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(self.mtime);
+        if let Err(e) = std::fs::File::open(dest_path).and_then(|f| f.set_modified(mtime)) {
+            tracing::debug!("couldn't set mtime on {}: {}", dest_path.display(), e);
+        }
+
         Ok(())
     }
 
+    /// Quick-check, rsync style: dest is a regular file with the same size
+    /// and mtime (to the second) as us so we presume its already copied.
+    pub fn unchanged(&self, dest_path: &std::path::Path) -> bool {
+        let Ok(dest) = std::fs::symlink_metadata(dest_path) else {
+            return false;
+        };
+
+        let mtime = dest
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+
+        dest.is_file() && dest.len() == self.size && mtime == Some(self.mtime)
+    }
+
     #[cfg(not(unix))]
     pub async fn apply_to(&self, _dest_path: &std::path::Path) -> std::io::Result<()> {
         // On non-Unix systems, no idea how to deal with this
@@ -150,7 +175,7 @@ impl FileMetadata {
 // Also how do I want to handle symlinks that are broken? Obviously copy it
 // simply and maybe let the user know.
 /// Symlink metadata
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SymlinkMetadata {
     /// Path to the symlink
     pub path: PathBuf,
@@ -199,7 +224,7 @@ impl SymlinkMetadata {
 }
 
 /// Directory metadata
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DirMetadata {
     /// Path to the directory
     pub path: PathBuf,
@@ -1,8 +1,28 @@
+pub mod baseline;
+pub mod budget;
+pub mod controller;
+pub mod debounce;
+pub mod done;
 pub mod error;
 pub mod exclude;
+pub mod fair;
+pub mod fanout;
+pub mod limits;
 pub mod metadata;
 pub mod progress;
 pub mod reader;
+pub mod reconcile;
+pub mod remote;
+pub mod resolve;
+pub mod scan;
+pub mod schedule;
+pub mod snapshot;
+pub mod spill;
+pub mod trash;
+pub mod twoway;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
+pub mod watch;
 pub mod work;
 pub mod work_simple;
 pub mod work_tree;
@@ -18,7 +38,7 @@ pub const LARGE_FILE_THRESHOLD: u64 = 64 * 1024 * 1024;
 use error::IoError;
 use progress::{AtomicOperationProgress, Progress};
 use work::WorkItem;
-use work_simple::SimpleWorkQueue;
+use work_tree::TreeWorkQueue;
 
 /// The main I/O subsystem that bridges Bevy ECS and async I/O operations.
 #[derive(Clone)]
@@ -32,16 +52,76 @@ pub struct IoSubsystem {
     /// Shared error log that need more use/abuse
     pub errors: Arc<Mutex<Vec<IoError>>>,
 
-    /// Simple FIFO work queue for local→local copies, inter node copies NYI
-    work_queue: Arc<Mutex<SimpleWorkQueue>>,
+    /// Daemon wide bandwidth/iops limits, shared with every other subsystem
+    limits: limits::Limits,
+
+    /// Daemon wide writer concurrency controllers, one per dest device
+    controllers: controller::Controllers,
+
+    /// Controller for this operation's dest, set once start() figures out
+    /// where we're writing.
+    controller: Arc<parking_lot::Mutex<Option<Arc<controller::Controller>>>>,
+
+    /// Tree aware work queue, files only go out once their parent dir is
+    /// scanned and created, ordered by the sync's scheduling policy.
+    work_queue: Arc<Mutex<TreeWorkQueue>>,
 
     /// Channel sender for reader to submit work, no blocking locks
-    work_tx: Option<tokio::sync::mpsc::UnboundedSender<WorkItem>>,
+    work_tx: Option<tokio::sync::mpsc::Sender<WorkItem>>,
+
+    /// How much memory queued work for this operation is allowed to use
+    budget: budget::MemoryBudget,
+
+    /// Writer pool, normally the daemon wide one shared with every other sync
+    writers: Arc<writer::WriterPool>,
+
+    /// Share of the writer pool relative to other syncs
+    weight: u32,
+
+    /// How the source tree gets scanned, order and number of scanner threads
+    scan_order: scan::ScanOrder,
+    scanners: Option<usize>,
+
+    /// Skip files the dest already has, see FileMetadata::unchanged()
+    quick_check: bool,
+
+    /// Keep watching the source after the initial scan, None is a one shot copy
+    watch: Option<watch::WatchConfig>,
+    watcher: Arc<parking_lot::Mutex<Option<watch::Watcher>>>,
+
+    /// Two-way sync against the baseline at this path instead of a copy
+    two_way: Option<std::path::PathBuf>,
+    two_way_report: Arc<parking_lot::Mutex<Option<twoway::TwoWayReport>>>,
+
+    /// Other destinations fed from our scan, each its own sync with its own
+    /// uuid. Only the one doing the scan has these.
+    fan_out: Vec<(u128, std::path::PathBuf, IoSubsystem)>,
+
+    /// Fan-out leg whose dest is on another machine, where its work goes
+    /// instead of to writers here, see send_to_remote(). Shared so whichever
+    /// clone starts the fan-out takes it and the channel closes with the scan.
+    sending: Arc<parking_lot::Mutex<Option<tokio::sync::mpsc::Sender<WorkItem>>>>,
+
+    /// Scan a read only snapshot of the source instead of the live tree
+    snapshot: Option<snapshot::SnapshotProvider>,
+    /// The snapshot while we (or a fan-out of ours) still read from it
+    source_snapshot: Arc<parking_lot::Mutex<Option<Arc<snapshot::Snapshot>>>>,
+
+    /// Move old versions of anything overwritten or removed aside first
+    trash: Option<trash::TrashConfig>,
+
+    /// Never finish writing, more work can always show up
+    persistent: bool,
+
+    /// Sources are staged copies from a remote daemon, see remote.rs
+    staged: bool,
+
+    /// Uuid we were started with, shared so clones can clean up after us
+    uuid: Arc<parking_lot::Mutex<Option<u128>>>,
 
     reader_handle: Option<Arc<reader::ReaderPool>>,
-    writer_handle: Option<Arc<writer::WriterPool>>,
-    reader_done: Arc<Mutex<bool>>,
-    writer_done: Arc<Mutex<bool>>,
+    reader_done: Arc<done::Done>,
+    writer_done: Arc<done::Done>,
 }
 
 impl IoSubsystem {
@@ -49,15 +129,151 @@ impl IoSubsystem {
         Self {
             progress: Progress::default(),
             errors: Arc::new(Mutex::new(Vec::new())),
-            work_queue: Arc::new(Mutex::new(SimpleWorkQueue::new())),
+            limits: limits::Limits::default(),
+            controllers: controller::Controllers::default(),
+            controller: Arc::new(parking_lot::Mutex::new(None)),
+            work_queue: Arc::new(Mutex::new(TreeWorkQueue::new())),
             work_tx: None,
+            budget: budget::MemoryBudget::default(),
+            writers: Arc::new(writer::WriterPool::new()),
+            weight: fair::DEFAULT_WEIGHT,
+            scan_order: scan::ScanOrder::default(),
+            scanners: None,
+            quick_check: false,
+            watch: None,
+            watcher: Arc::new(parking_lot::Mutex::new(None)),
+            two_way: None,
+            two_way_report: Arc::new(parking_lot::Mutex::new(None)),
+            fan_out: Vec::new(),
+            sending: Arc::new(parking_lot::Mutex::new(None)),
+            snapshot: None,
+            source_snapshot: Arc::new(parking_lot::Mutex::new(None)),
+            trash: None,
+            persistent: false,
+            staged: false,
+            uuid: Arc::new(parking_lot::Mutex::new(None)),
             reader_handle: None,
-            writer_handle: None,
-            reader_done: Arc::new(Mutex::new(false)),
-            writer_done: Arc::new(Mutex::new(false)),
+            reader_done: Arc::new(done::Done::new()),
+            writer_done: Arc::new(done::Done::new()),
         }
     }
 
+    /// Use the daemon's limits registry instead of a private unlimited one.
+    pub fn with_limits(mut self, limits: limits::Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &limits::Limits {
+        &self.limits
+    }
+
+    /// Use the daemon's controllers so syncs to the same device share one.
+    pub fn with_controllers(mut self, controllers: controller::Controllers) -> Self {
+        self.controllers = controllers;
+        self
+    }
+
+    /// Order ready files using this policy instead of plain FIFO. Needs to be
+    /// called before start(), the queue is replaced wholesale.
+    pub fn with_schedule(mut self, policy: schedule::SchedulePolicy) -> Self {
+        self.work_queue = Arc::new(Mutex::new(TreeWorkQueue::with_policy(policy)));
+        self
+    }
+
+    /// Use the daemon's writer pool instead of a private one.
+    pub fn with_writers(mut self, writers: Arc<writer::WriterPool>) -> Self {
+        self.writers = writers;
+        self
+    }
+
+    /// Weight for fair queuing against other syncs in the same writer pool.
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Cap how much memory queued work can use before the reader is slowed
+    /// down and blocked work is spilled to disk.
+    pub fn with_memory_budget(mut self, budget: budget::MemoryBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Skip copying files the dest already has with the same size and mtime,
+    /// what makes restarting a sync from scratch cheap.
+    pub fn with_quick_check(mut self, quick_check: bool) -> Self {
+        self.quick_check = quick_check;
+        self
+    }
+
+    /// Keep syncing changes after the initial copy instead of finishing.
+    /// Quick-check is always on for these, rescans would redo everything
+    /// otherwise.
+    pub fn with_watch(mut self, watch: Option<watch::WatchConfig>) -> Self {
+        self.watch = watch;
+        self
+    }
+
+    /// What the watcher is actually using, None if not watching
+    pub fn watch_backend(&self) -> Option<watch::WatchBackend> {
+        self.watcher.lock().as_ref().map(|w| w.backend())
+    }
+
+    /// Sync both ways using the baseline stored at this path, changes on
+    /// either side go to the other, see twoway.rs. None is a normal copy.
+    pub fn with_two_way(mut self, baseline: Option<std::path::PathBuf>) -> Self {
+        self.two_way = baseline;
+        self
+    }
+
+    /// What the two-way run did once its done, None until then or if this
+    /// isn't a two-way sync.
+    pub fn two_way_report(&self) -> Option<twoway::TwoWayReport> {
+        self.two_way_report.lock().clone()
+    }
+
+    /// Also copy everything to these destinations, each with its own uuid
+    /// and subsystem so progress, errors and completion are theirs alone.
+    /// Their subsystems only write, the scan is ours.
+    pub fn with_fan_out(mut self, legs: Vec<(u128, std::path::PathBuf, IoSubsystem)>) -> Self {
+        self.fan_out = legs;
+        self
+    }
+
+    /// Keep whatever we overwrite or remove on the dest, see trash.rs
+    pub fn with_trash(mut self, trash: Option<trash::TrashConfig>) -> Self {
+        self.trash = trash;
+        self
+    }
+
+    /// Keep writing whatever shows up instead of finishing once the scan is
+    /// done, watched syncs always are. For dests whose scan is elsewhere.
+    pub fn with_persistent(mut self, persistent: bool) -> Self {
+        self.persistent = persistent;
+        self
+    }
+
+    /// Snapshot the source first and copy from that, released once the sync
+    /// is done with it.
+    pub fn with_snapshot(mut self, provider: Option<snapshot::SnapshotProvider>) -> Self {
+        self.snapshot = provider;
+        self
+    }
+
+    /// Scan the source tree in this order with this many scanners, None
+    /// means one per core up to scan::MAX_DEFAULT_SCANNERS.
+    pub fn with_scan(mut self, order: scan::ScanOrder, scanners: Option<usize>) -> Self {
+        self.scan_order = order;
+        self.scanners = scanners;
+        self
+    }
+
+    /// What the writer concurrency controller is up to for this operation
+    pub fn controller_state(&self) -> Option<controller::ControllerState> {
+        self.controller.lock().as_ref().map(|c| c.state())
+    }
+
     pub async fn start(
         &mut self,
         uuid: u128,
@@ -65,6 +281,120 @@ impl IoSubsystem {
         dest: std::path::PathBuf,
         num_writers: Option<usize>,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(baseline) = self.two_way.clone() {
+            self.start_two_way(uuid, source, dest, baseline);
+            return Ok(());
+        }
+
+        let source = match self.snapshot.clone() {
+            Some(provider) => self.start_snapshot(uuid, provider, source).await?,
+            None => source,
+        };
+
+        let work_tx = self.start_writing(uuid, dest, num_writers).await?;
+
+        // Other destinations get a copy of everything the scan finds
+        let work_tx = if self.fan_out.is_empty() {
+            work_tx
+        } else {
+            self.start_fan_out(uuid, work_tx, num_writers).await
+        };
+
+        self.start_reading(uuid, source, work_tx).await
+    }
+
+    // The scan side, everything it finds goes to work_tx
+    async fn start_reading(
+        &mut self,
+        uuid: u128,
+        source: std::path::PathBuf,
+        work_tx: tokio::sync::mpsc::Sender<WorkItem>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // start the reader pool for this uuid operation
+        self.work_tx = Some(work_tx.clone());
+        let source_root = source.clone();
+        let reader_pool = reader::ReaderPool::new(
+            uuid,
+            source,
+            work_tx,
+            self.progress.clone(),
+            self.errors.clone(),
+            self.reader_done.clone(),
+        )
+        .with_scan_order(self.scan_order);
+        let reader_pool = match self.scanners {
+            Some(scanners) => reader_pool.with_scanners(scanners),
+            None => reader_pool,
+        };
+
+        let reader_handle = Arc::new(reader_pool);
+
+        // Watcher goes first so nothing changing mid scan gets missed
+        if let Some(config) = self.watch {
+            self.start_watching(uuid, source_root, config, reader_handle.clone())?;
+        }
+
+        reader_handle.clone().start().await;
+        self.reader_handle = Some(reader_handle);
+
+        Ok(())
+    }
+
+    // Take the snapshot and hand back where to read from instead. If that
+    // fails nothing gets copied, we and any fan-out destinations finish with
+    // the error rather than copy a live tree someone wanted frozen.
+    async fn start_snapshot(
+        &mut self,
+        uuid: u128,
+        provider: snapshot::SnapshotProvider,
+        source: std::path::PathBuf,
+    ) -> Result<std::path::PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+        let taken = {
+            let source = source.clone();
+            tokio::task::spawn_blocking(move || snapshot::Snapshot::take(&provider, &source, uuid))
+                .await
+                .map_err(std::io::Error::other)
+                .and_then(|r| r)
+        };
+
+        match taken {
+            Ok(snapshot) => {
+                let snapshot = Arc::new(snapshot);
+                let path = snapshot.path().to_path_buf();
+                *self.source_snapshot.lock() = Some(snapshot.clone());
+                for (_, _, leg) in &self.fan_out {
+                    *leg.source_snapshot.lock() = Some(snapshot.clone());
+                }
+                Ok(path)
+            }
+            Err(e) => {
+                let error_msg = format!("failed to snapshot {}: {}", source.display(), e);
+                tracing::error!("{}", error_msg);
+                for (leg_uuid, _, leg) in &self.fan_out {
+                    leg.errors
+                        .lock()
+                        .await
+                        .push(error::IoError::source(error_msg.clone(), source.clone()));
+                    leg.give_up(*leg_uuid).await;
+                }
+                self.errors
+                    .lock()
+                    .await
+                    .push(error::IoError::source(error_msg.clone(), source));
+                self.give_up(uuid).await;
+                Err(error_msg.into())
+            }
+        }
+    }
+
+    // Everything on the dest side, the queue and our share of the writers.
+    // Returns where to send work for it.
+    async fn start_writing(
+        &mut self,
+        uuid: u128,
+        dest: std::path::PathBuf,
+        num_writers: Option<usize>,
+    ) -> Result<tokio::sync::mpsc::Sender<WorkItem>, Box<dyn std::error::Error + Send + Sync>> {
         // Ensure destination root directory exists
         if let Err(e) = tokio::fs::create_dir_all(&dest).await {
             let error_msg = format!(
@@ -78,22 +408,53 @@ impl IoSubsystem {
             return Err(error_msg.into());
         }
 
-        // Create channel for (mostly lock free) reader -> queue communication
-        let (work_tx, mut work_rx) = tokio::sync::mpsc::unbounded_channel::<WorkItem>();
-        self.work_tx = Some(work_tx.clone());
+        // Try to figure out if our dest is a problematic fs or not that might not
+        // support chmod, or is over the network and shouldn't get hammered.
+        let fs_features = writer::detect_fs_features(&dest);
+        // The controller is for the whole device, num_writers is just us and
+        // the writer pool holds us to it.
+        let controller =
+            self.controllers
+                .for_dest(&dest, fs_features.is_network(), writer::get_num_workers());
+        *self.controller.lock() = Some(controller.clone());
+        *self.uuid.lock() = Some(uuid);
+
+        // Blocked work that doesn't fit in the budget goes to disk
+        let spill_dir = self.budget.spill_dir(uuid);
+        if let Err(e) = self
+            .work_queue
+            .lock()
+            .await
+            .enable_spill(spill_dir.clone(), self.budget.spill_threshold())
+        {
+            tracing::warn!(
+                "can't spill to {}, blocked work will stay in memory: {}",
+                spill_dir.display(),
+                e
+            );
+        }
+
+        // Create bounded channel for (mostly lock free) reader -> queue
+        // communication, once its full scanners block until we catch up.
+        let (work_tx, mut work_rx) =
+            tokio::sync::mpsc::channel::<WorkItem>(self.budget.channel_capacity());
 
         // Spawn a tokio to yeet items in batches into the tree queue. Batched
         // to minimize async locking contention.
         let queue = self.work_queue.clone();
+        let queue_writers = self.writers.clone();
+        let queue_controller = controller.clone();
+        let (high_water, low_water) = (self.budget.high_water(), self.budget.low_water());
+        let drained = self.work_queue.lock().await.watch_low_water(low_water);
         tokio::spawn(async move {
-            const BATCH_SIZE: usize = 1000;
-            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            let mut batch = Vec::with_capacity(queue_controller.queue_batch());
 
             while let Some(item) = work_rx.recv().await {
                 batch.push(item);
 
-                // This could use a PID controller to let BATCH_SIZE scale/up down dynamically.
-                while batch.len() < BATCH_SIZE {
+                // Controller scales this with how fast writers are chewing through stuff
+                let batch_size = queue_controller.queue_batch();
+                while batch.len() < batch_size {
                     match work_rx.try_recv() {
                         Ok(item) => batch.push(item),
                         Err(_) => break, // No more items ready
@@ -101,46 +462,268 @@ impl IoSubsystem {
                 }
 
                 // We only lock here with actual stuff to push onto the batch queue.
-                {
+                let ready_bytes = {
                     let mut q = queue.lock().await;
                     for item in batch.drain(..) {
                         q.push(item);
                     }
+                    q.ready_bytes()
+                };
+                queue_writers.wake();
+
+                // Over the high water mark, stop taking from the channel until
+                // writers get us under the low water mark. The channel fills up
+                // and the reader waits on us in the meantime.
+                if ready_bytes > high_water {
+                    tracing::trace!(
+                        "work queue over high water mark ({} bytes), pausing reader",
+                        ready_bytes
+                    );
+                    while queue.lock().await.ready_bytes() > low_water {
+                        drained.notified().await;
+                    }
                 }
             }
             tracing::trace!("work queue finished");
         });
 
-        // start the reader pool for this uuid operation
-        let reader_pool = reader::ReaderPool::new(
-            uuid,
-            source,
-            work_tx,
-            self.progress.clone(),
-            self.errors.clone(),
-            self.reader_done.clone(),
-        );
-
-        let reader_handle = Arc::new(reader_pool);
-        reader_handle.clone().start().await;
-        self.reader_handle = Some(reader_handle);
-
-        // Start writer pool if not already running, writer is shared.
-        if self.writer_handle.is_none() {
-            let writer_pool = writer::WriterPool::new(
+        // Writers are shared, hand our queue to the pool and let it decide
+        // when we get a turn.
+        if !self.writers.contains(uuid) {
+            let trash = self.trash.as_ref().map(|config| {
+                let trash = trash::Trash::new(dest.clone(), config);
+                let pruning = trash.clone();
+                tokio::task::spawn_blocking(move || {
+                    match pruning.prune(std::time::SystemTime::now()) {
+                        Ok(0) => (),
+                        Ok(n) => tracing::info!(
+                            "pruned {} old versions from {}",
+                            n,
+                            pruning.dir().display()
+                        ),
+                        Err(e) => {
+                            tracing::warn!("couldn't prune {}: {}", pruning.dir().display(), e)
+                        }
+                    }
+                });
+                trash
+            });
+            let job = writer::WriteJob::new(
+                uuid,
                 dest,
                 self.work_queue.clone(),
                 self.progress.clone(),
                 self.errors.clone(),
-                self.reader_done.clone(),
+                self.limits.clone(),
+                controller,
+                fs_features,
                 self.writer_done.clone(),
-            );
+            )
+            .with_quick_check(self.quick_check || self.watch.is_some())
+            .with_persistent(self.persistent || self.watch.is_some())
+            .with_trash(trash)
+            .with_staged(self.staged);
+            self.writers.add(job, self.weight, num_writers);
+        }
 
-            let writer_handle = Arc::new(writer_pool);
-            writer_handle.clone().start(num_writers).await;
-            self.writer_handle = Some(writer_handle);
+        Ok(work_tx)
+    }
+
+    // The reader sends to the fan-out instead of our queue, it sends to ours
+    // and every other destination's. A destination that can't even start
+    // gives up on its own, the rest carry on.
+    async fn start_fan_out(
+        &mut self,
+        uuid: u128,
+        work_tx: tokio::sync::mpsc::Sender<WorkItem>,
+        num_writers: Option<usize>,
+    ) -> tokio::sync::mpsc::Sender<WorkItem> {
+        let mut legs = vec![fanout::Leg::new(
+            uuid,
+            work_tx,
+            self.errors.clone(),
+            self.budget.fan_out_threshold(),
+            self.budget.fan_out_file(uuid),
+        )];
+
+        for (leg_uuid, dest, leg) in &mut self.fan_out {
+            let sending = leg.sending.lock().take();
+            let started = match sending {
+                Some(tx) => Ok(tx),
+                None => {
+                    leg.start_writing(*leg_uuid, dest.clone(), num_writers)
+                        .await
+                }
+            };
+            match started {
+                Ok(tx) => legs.push(
+                    fanout::Leg::new(
+                        *leg_uuid,
+                        tx,
+                        leg.errors.clone(),
+                        leg.budget.fan_out_threshold(),
+                        leg.budget.fan_out_file(*leg_uuid),
+                    )
+                    .with_mirror(
+                        leg.progress.get_or_create(*leg_uuid),
+                        leg.reader_done.clone(),
+                    ),
+                ),
+                Err(e) => {
+                    tracing::error!(
+                        "{} fan-out to {} failed to start: {}",
+                        uuid::Uuid::from_u128(*leg_uuid),
+                        dest.display(),
+                        e
+                    );
+                    leg.give_up(*leg_uuid).await;
+                }
+            }
         }
 
+        let (tx, rx) = tokio::sync::mpsc::channel::<WorkItem>(self.budget.channel_capacity());
+        fanout::FanOut::new(uuid, self.progress.get_or_create(uuid), legs).spawn(rx);
+        tx
+    }
+
+    // Nothing is coming, mark it done so it finishes with whatever errors it
+    // has. The root counts so it looks like there was something to do.
+    async fn give_up(&self, uuid: u128) {
+        self.progress
+            .get_or_create(uuid)
+            .dirs_found
+            .fetch_max(1, std::sync::atomic::Ordering::Relaxed);
+        self.reader_done.set();
+        self.writer_done.set();
+    }
+
+    // Two-way scans and reconciles on a blocking thread and hands the writes
+    // to the writer pool a side at a time, see twoway.rs. Both done flags
+    // flip at the end so completion and the journal work the same as a copy.
+    fn start_two_way(
+        &self,
+        uuid: u128,
+        lhs: std::path::PathBuf,
+        rhs: std::path::PathBuf,
+        baseline: std::path::PathBuf,
+    ) {
+        *self.uuid.lock() = Some(uuid);
+        let progress = self.progress.get_or_create(uuid);
+        let two_way = twoway::TwoWay::new(
+            uuid,
+            lhs.clone(),
+            rhs,
+            baseline,
+            progress.clone(),
+            self.errors.clone(),
+        )
+        .with_trash(self.trash.as_ref())
+        .with_writers(
+            self.writers.clone(),
+            self.limits.clone(),
+            self.controllers.clone(),
+            self.weight,
+        );
+        let errors = self.errors.clone();
+        let reader_done = self.reader_done.clone();
+        let writer_done = self.writer_done.clone();
+        let report = self.two_way_report.clone();
+
+        tokio::task::spawn_blocking(move || {
+            match two_way.run_blocking() {
+                Ok(done) => *report.lock() = Some(done),
+                Err(e) => {
+                    let msg = format!("two-way sync failed: {}", e);
+                    tracing::error!("{}", msg);
+                    errors.blocking_lock().push(IoError::source(msg, lhs));
+                }
+            }
+            // The root counts, otherwise an empty or failed sync never
+            // looks done.
+            progress
+                .dirs_found
+                .fetch_max(1, std::sync::atomic::Ordering::Relaxed);
+            reader_done.set();
+            writer_done.set();
+        });
+    }
+
+    // Changes get fed to the reader from their own thread. Anything that comes
+    // in during the initial scan just piles up until its done, the scan will
+    // have gotten most of it and quick-check sorts out the rest.
+    //
+    // Events go through a Debouncer first so a burst of writes to the same
+    // file turns into one rescan once things calm down. With nothing pending
+    // this thread just sits in recv() until the watcher has something.
+    fn start_watching(
+        &self,
+        uuid: u128,
+        source: std::path::PathBuf,
+        config: watch::WatchConfig,
+        reader: Arc<reader::ReaderPool>,
+    ) -> std::io::Result<()> {
+        use std::sync::mpsc::RecvTimeoutError;
+        use std::time::Instant;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let watcher = watch::Watcher::start(source, config, tx)?;
+        *self.watcher.lock() = Some(watcher);
+
+        let reader_done = self.reader_done.clone();
+        std::thread::Builder::new()
+            .name("yeet-rescan".to_string())
+            .spawn(move || {
+                reader_done.wait_blocking();
+
+                let mut debouncer = debounce::Debouncer::new(config.debounce);
+                loop {
+                    let event = match debouncer.deadline() {
+                        Some(deadline) => {
+                            rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                        }
+                        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+
+                    match event {
+                        Ok(event) => {
+                            let now = Instant::now();
+                            for event in std::iter::once(event).chain(rx.try_iter()) {
+                                match event {
+                                    watch::WatchEvent::Changed(path) => debouncer.touch(path, now),
+                                    // Renames go right away, they have to beat
+                                    // the debounced rescan of the new name or
+                                    // it gets copied instead.
+                                    watch::WatchEvent::Renamed { from, to } => {
+                                        reader.rename_blocking(from, to)
+                                    }
+                                }
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => (),
+                        // Watcher is stopped, whatever is pending goes with it
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+
+                    let now = Instant::now();
+                    if debouncer.deadline().is_none_or(|d| d > now) {
+                        continue;
+                    }
+
+                    let paths = debouncer.ready(now);
+                    if paths.is_empty() {
+                        continue;
+                    }
+                    tracing::debug!(
+                        "{} rescanning {} changed paths, {} still settling",
+                        uuid::Uuid::from_u128(uuid),
+                        paths.len(),
+                        debouncer.len()
+                    );
+                    reader.rescan_blocking(paths);
+                }
+                tracing::debug!("{} rescanner exiting", uuid::Uuid::from_u128(uuid));
+            })?;
+
         Ok(())
     }
 
@@ -169,8 +752,8 @@ impl IoSubsystem {
     /// An operation is complete when both reader(s) and writer say they've
     /// completed. Need to have a better option here.
     pub async fn is_complete(&self, uuid: u128) -> bool {
-        let reader_complete = *self.reader_done.lock().await;
-        let writer_complete = *self.writer_done.lock().await;
+        let reader_complete = self.reader_done.is_set();
+        let writer_complete = self.writer_done.is_set();
 
         // Check if we actually found files, aka empty dir isn't worth creating read/write nonsense
         let has_work = self
@@ -182,11 +765,20 @@ impl IoSubsystem {
     }
 
     pub async fn shutdown(&mut self) {
+        if let Some(mut watcher) = self.watcher.lock().take() {
+            watcher.stop();
+        }
         if let Some(reader) = &self.reader_handle {
             reader.shutdown().await;
         }
-        if let Some(writer) = &self.writer_handle {
-            writer.shutdown().await;
+        // Fan-out destinations hold on to it until they're done too
+        let snapshot = self.source_snapshot.lock().take();
+        if let Some(snapshot) = snapshot {
+            snapshot::Snapshot::release(snapshot).await;
+        }
+        // Pool outlives us, just make sure we're not in it anymore
+        if let Some(uuid) = *self.uuid.lock() {
+            self.writers.remove(uuid);
         }
     }
 }
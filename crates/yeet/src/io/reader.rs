@@ -1,5 +1,6 @@
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::Mutex;
 
 use super::LARGE_FILE_THRESHOLD;
@@ -7,31 +8,68 @@ use super::error::IoError;
 use super::exclude::ExcludeRules;
 use super::metadata::{DirMetadata, FileMetadata};
 use super::progress::Progress;
+use super::scan::{Frontier, ScanOrder};
 use super::work::WorkItem;
 
 // Reduce the amount of atomic updates
 const PROGRESS_UPDATE_INTERVAL: u64 = 1000;
 
+/// Running totals shared by every scanner thread
+#[derive(Debug, Default)]
+struct ScanCounters {
+    dirs: AtomicU64,
+    files: AtomicU64,
+    size: AtomicU64,
+    skipped: AtomicU64,
+    since_update: AtomicU64,
+}
+
+impl ScanCounters {
+    /// Count items seen, true when its time to push progress out
+    fn tick(&self, n: u64) -> bool {
+        let seen = self.since_update.fetch_add(n, Ordering::Relaxed) + n;
+        if seen >= PROGRESS_UPDATE_INTERVAL {
+            self.since_update.store(0, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn totals(&self) -> (u64, u64, u64, u64) {
+        (
+            self.dirs.load(Ordering::Relaxed),
+            self.files.load(Ordering::Relaxed),
+            self.size.load(Ordering::Relaxed),
+            self.skipped.load(Ordering::Relaxed),
+        )
+    }
+}
+
 /// Reader pool that traverses the source directory and queues work
 pub struct ReaderPool {
     uuid: u128,
     source: PathBuf,
-    work_tx: tokio::sync::mpsc::UnboundedSender<WorkItem>,
+    work_tx: tokio::sync::mpsc::Sender<WorkItem>,
     progress: Progress,
     errors: Arc<Mutex<Vec<IoError>>>,
     shutdown: Arc<Mutex<bool>>,
-    done: Arc<Mutex<bool>>,
+    done: Arc<super::done::Done>,
     exclude_rules: ExcludeRules,
+    scan_order: ScanOrder,
+    scanners: usize,
+    /// Totals across the initial scan and any watch rescans after it
+    counters: ScanCounters,
 }
 
 impl ReaderPool {
     pub fn new(
         uuid: u128,
         source: PathBuf,
-        work_tx: tokio::sync::mpsc::UnboundedSender<WorkItem>,
+        work_tx: tokio::sync::mpsc::Sender<WorkItem>,
         progress: Progress,
         errors: Arc<Mutex<Vec<IoError>>>,
-        done: Arc<Mutex<bool>>,
+        done: Arc<super::done::Done>,
     ) -> Self {
         Self {
             uuid,
@@ -42,9 +80,24 @@ impl ReaderPool {
             shutdown: Arc::new(Mutex::new(false)),
             done,
             exclude_rules: ExcludeRules::new(),
+            scan_order: ScanOrder::default(),
+            scanners: super::scan::default_scanners(),
+            counters: ScanCounters::default(),
         }
     }
 
+    /// Order to scan directories in
+    pub fn with_scan_order(mut self, order: ScanOrder) -> Self {
+        self.scan_order = order;
+        self
+    }
+
+    /// Number of scanner threads listing directories at once
+    pub fn with_scanners(mut self, scanners: usize) -> Self {
+        self.scanners = scanners.max(1);
+        self
+    }
+
     pub async fn start(self: Arc<Self>) {
         let pool = self.clone();
         let uuid = pool.uuid;
@@ -74,132 +127,212 @@ impl ReaderPool {
     /// Main reader loop
     async fn run(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let root = self.source.clone();
-        tracing::debug!("reader traversing root: {}", root.display());
-
-        let mut local_dirs_found = 0u64;
-        let mut local_files_found = 0u64;
-        let mut local_total_size = 0u64;
-        let mut local_skipped = 0u64;
-        let mut items_since_update = 0u64;
+        tracing::debug!(
+            "reader traversing root: {} ({} with {} scanners)",
+            root.display(),
+            self.scan_order,
+            self.scanners
+        );
 
-        // Run traversal in blocking task (uses std::fs, not tokio::fs that was slow af)
+        // Run traversal in blocking threads (uses std::fs, not tokio::fs that was slow af)
         // Work items are sent via channel immediately as they're discovered to
         // keep writers busy.
         let pool = self.clone();
-        let result = tokio::task::spawn_blocking(
-            move || -> Result<(u64, u64, u64, u64), Box<dyn std::error::Error + Send + Sync>> {
-                pool.traverse_directory_blocking(
-                    root.clone(),
-                    PathBuf::new(),
-                    &mut local_dirs_found,
-                    &mut local_files_found,
-                    &mut local_total_size,
-                    &mut local_skipped,
-                    &mut items_since_update,
-                )?;
-                Ok((
-                    local_dirs_found,
-                    local_files_found,
-                    local_total_size,
-                    local_skipped,
-                ))
-            },
-        )
+        let result = tokio::task::spawn_blocking(move || {
+            pool.scan_parallel_blocking(root, PathBuf::new());
+        })
         .await;
 
-        match result {
-            Ok(Ok((dirs, files, size, skipped))) => {
-                let atomic_progress = self.progress.get_or_create(self.uuid);
-                atomic_progress
-                    .dirs_found
-                    .store(dirs, std::sync::atomic::Ordering::Relaxed);
-                atomic_progress
-                    .files_found
-                    .store(files, std::sync::atomic::Ordering::Relaxed);
-                atomic_progress
-                    .total_size
-                    .store(size, std::sync::atomic::Ordering::Relaxed);
-                atomic_progress
-                    .skipped_count
-                    .store(skipped, std::sync::atomic::Ordering::Relaxed);
-
-                local_dirs_found = dirs;
-                local_files_found = files;
-                local_total_size = size;
-                local_skipped = skipped;
-            }
-            Ok(Err(e)) => {
-                tracing::error!("reader traversal error: {}", e);
-                let mut errors = self.errors.lock().await;
-                errors.push(IoError::source(
-                    format!("reader traversal error: {}", e),
-                    self.source.clone(),
-                ));
-            }
-            Err(e) => {
-                tracing::error!("reader task panicked: {}", e);
-                let mut errors = self.errors.lock().await;
-                // TODO: I need a better error approach in general
-                errors.push(IoError::source(
-                    format!("reader task panic: {}", e),
-                    self.source.clone(),
-                ));
-            }
+        if let Err(e) = result {
+            tracing::error!("reader task panicked: {}", e);
+            let mut errors = self.errors.lock().await;
+            // TODO: I need a better error approach in general
+            errors.push(IoError::source(
+                format!("reader task panic: {}", e),
+                self.source.clone(),
+            ));
         }
 
+        let (dirs, files, size, skipped) = self.counters.totals();
+        self.update_progress_blocking(dirs, files, size, skipped);
+
         // TODO: Add duration logging?
         tracing::info!(
             "reader traversal complete: {} dirs, {} files, {} skipped, {} bytes total",
-            local_dirs_found,
-            local_files_found,
-            local_skipped,
-            local_total_size
+            dirs,
+            files,
+            skipped,
+            size
         );
 
-        // Send the sentinel enum
-        self.send_scan_complete();
+        // Send the sentinel enum, only once every scanner is done so the tree
+        // queue can trust it.
+        self.send_scan_complete().await;
 
-        let mut done = self.done.lock().await;
-        *done = true;
+        self.done.set();
         tracing::debug!("{} reader complete", uuid::Uuid::from_u128(self.uuid));
 
         Ok(())
     }
 
-    /// Traverse a directory using blocking I/O (std::fs)
+    /// Queue up whatever changed under the source since the initial scan,
+    /// the watcher hands us absolute paths. Dirs get scanned whole, files and
+    /// symlinks on their own, anything that vanished is ignored since
+    /// removals aren't a thing yet.
+    pub fn rescan_blocking(&self, paths: Vec<PathBuf>) {
+        use super::metadata::FileKind;
+
+        let counters = &self.counters;
+
+        for path in paths {
+            let Ok(relative) = path.strip_prefix(&self.source).map(|p| p.to_path_buf()) else {
+                tracing::debug!("ignoring change outside of source: {}", path.display());
+                continue;
+            };
+
+            if self.excluded_parent(&relative) {
+                continue;
+            }
+
+            let metadata = match self.get_file_metadata_blocking(&path) {
+                Ok(m) => m,
+                Err(e) => {
+                    tracing::trace!("changed path went away {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            match metadata.kind {
+                FileKind::Directory => {
+                    if !self.exclude_rules.should_exclude_dir_path(&relative) {
+                        self.scan_parallel_blocking(path, relative);
+                    }
+                }
+                FileKind::File => {
+                    if !self.exclude_rules.should_exclude_file_path(&relative) {
+                        self.enqueue_file_blocking(path, relative, metadata, counters);
+                    }
+                }
+                FileKind::Symlink => self.enqueue_symlink_blocking(path, relative, counters),
+                FileKind::Special => self.skip_special_file_blocking(path, &metadata, counters),
+                FileKind::Unknown => {
+                    counters.skipped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let (dirs, files, size, skipped) = counters.totals();
+        self.update_progress_blocking(dirs, files, size, skipped);
+    }
+
+    /// The watcher saw from move to to inside the source, have the writer do
+    /// the same on the destination so a moved tree isn't copied all over
+    /// again. The change for to that comes after gets rescanned as usual and
+    /// copies anything the rename didn't cover. If either end is excluded the
+    /// dest never had it or shouldn't get it, so that's left to the rescan.
+    pub fn rename_blocking(&self, from: PathBuf, to: PathBuf) {
+        let (Ok(from), Ok(to)) = (
+            from.strip_prefix(&self.source),
+            to.strip_prefix(&self.source),
+        ) else {
+            return;
+        };
+
+        let is_dir = std::fs::symlink_metadata(self.source.join(to)).is_ok_and(|m| m.is_dir());
+        let excluded = |relative: &std::path::Path| {
+            self.excluded_parent(relative)
+                || if is_dir {
+                    self.exclude_rules.should_exclude_dir_path(relative)
+                } else {
+                    self.exclude_rules.should_exclude_file_path(relative)
+                }
+        };
+        if excluded(from) || excluded(to) {
+            return;
+        }
+
+        let item = WorkItem::Rename {
+            uuid: self.uuid,
+            from: from.to_path_buf(),
+            dest_path: to.to_path_buf(),
+        };
+        if self.work_tx.blocking_send(item).is_err() {
+            tracing::debug!("work channel closed, dropping rename");
+        }
+    }
+
+    // Anything under an excluded dir stays excluded
+    fn excluded_parent(&self, relative: &std::path::Path) -> bool {
+        relative.parent().is_some_and(|parent| {
+            parent.components().any(|c| {
+                c.as_os_str()
+                    .to_str()
+                    .is_some_and(|name| self.exclude_rules.should_exclude_dir(name))
+            })
+        })
+    }
+
+    /// Fan directories out across scanner threads until the whole tree under
+    /// root is listed. Each directory is listed by exactly one scanner, so the
+    /// CreateDir -> entries -> DirectoryScanned ordering per directory is the
+    /// same as the old single threaded recursion.
+    fn scan_parallel_blocking(&self, root: PathBuf, relative: PathBuf) {
+        let counters = &self.counters;
+        let frontier = Frontier::new(self.scan_order, (root, relative));
+
+        std::thread::scope(|s| {
+            for scanner_id in 0..self.scanners.max(1) {
+                let frontier = &frontier;
+                s.spawn(move || {
+                    tracing::trace!("scanner {} starting", scanner_id);
+                    while let Some((source_path, relative_path)) = frontier.next() {
+                        if self.shutdown.try_lock().is_ok_and(|s| *s) {
+                            tracing::info!("scanner {} received shutdown signal", scanner_id);
+                            frontier.close();
+                        } else {
+                            let subdirs =
+                                self.scan_directory_blocking(source_path, relative_path, counters);
+                            frontier.push_all(subdirs);
+                        }
+                        frontier.done();
+                    }
+                    tracing::trace!("scanner {} exiting", scanner_id);
+                });
+            }
+        });
+    }
+
+    /// List a single directory using blocking I/O (std::fs), returns the
+    /// subdirectories to scan next.
     /// Much faster than async I/O for scanning that spent too much time in mutex bs
-    // TODO: I'll fix it in post/add a struct later...
-    #[allow(clippy::too_many_arguments)]
-    fn traverse_directory_blocking(
+    fn scan_directory_blocking(
         &self,
         source_path: PathBuf,
         relative_path: PathBuf,
-        local_dirs_found: &mut u64,
-        local_files_found: &mut u64,
-        local_total_size: &mut u64,
-        local_skipped: &mut u64,
-        items_since_update: &mut u64,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        counters: &ScanCounters,
+    ) -> Vec<(PathBuf, PathBuf)> {
         tracing::trace!(
             "traversing directory: {} rel: {}",
             source_path.display(),
             relative_path.display()
         );
 
-        *local_dirs_found += 1;
-        *items_since_update += 1;
+        counters.dirs.fetch_add(1, Ordering::Relaxed);
+        counters.tick(1);
+
+        let mut subdirs = Vec::new();
 
         let dir_metadata = match self.get_dir_metadata_blocking(&source_path) {
             Ok(m) => m,
             Err(e) => {
                 let error_msg = format!("failed to read directory metadata: {}", e);
                 tracing::error!("{}: {}", error_msg, source_path.display());
-                return Ok(());
+                return subdirs;
             }
         };
 
         // Send directory creation data to enable any blocked workers to do work
-        if let Err(e) = self.work_tx.send(WorkItem::CreateDir {
+        if let Err(e) = self.work_tx.blocking_send(WorkItem::CreateDir {
             uuid: self.uuid,
             source_path: source_path.clone(),
             dest_path: relative_path.clone(),
@@ -213,16 +346,30 @@ impl ReaderPool {
             Err(e) => {
                 let error_msg = format!("failed to read directory: {}", e);
                 tracing::error!("{}: {}", error_msg, source_path.display());
-                return Ok(());
+                self.errors
+                    .blocking_lock()
+                    .push(IoError::source(error_msg, source_path.clone()));
+                self.send_directory_scanned(relative_path);
+                return subdirs;
             }
         };
 
-        // First pass: process files and symlinks in this directory only
-        // Collect subdirectories for second pass usage
-        let mut subdirs = Vec::new();
-
+        // Process files and symlinks in this directory only, subdirectories
+        // go back to the frontier for whichever scanner gets to them.
         for entry in entries {
-            let entry = entry?;
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    // Whatever we got so far is still good, don't throw
+                    // away the whole scan over one bad readdir.
+                    let error_msg = format!("failed to read directory entry: {}", e);
+                    tracing::error!("{}: {}", error_msg, source_path.display());
+                    self.errors
+                        .blocking_lock()
+                        .push(IoError::source(error_msg, source_path.clone()));
+                    break;
+                }
+            };
             let entry_path = entry.path();
             let file_name = match entry.file_name().into_string() {
                 Ok(name) => name,
@@ -246,77 +393,47 @@ impl ReaderPool {
                 FileKind::Special => {
                     // Skip special files (FIFO, socket, device, etc.)
                     tracing::warn!("skipping special file type: {}", entry_path.display());
-                    self.skip_special_file_blocking(entry_path, &metadata, local_skipped);
-                    *items_since_update += 1;
+                    self.skip_special_file_blocking(entry_path, &metadata, counters);
                 }
                 FileKind::Symlink => {
                     // Handle symlink - don't follow it!
-                    self.enqueue_symlink_blocking(entry_path, entry_relative, local_files_found);
-                    *items_since_update += 1;
+                    self.enqueue_symlink_blocking(entry_path, entry_relative, counters);
                 }
                 FileKind::Directory => {
                     if self.exclude_rules.should_exclude_dir(&file_name) {
                         tracing::info!("excluding directory: {}", entry_path.display());
-                        *local_skipped += 1;
-                        *items_since_update += 1;
+                        counters.skipped.fetch_add(1, Ordering::Relaxed);
                     } else {
                         subdirs.push((entry_path, entry_relative));
+                        continue;
                     }
                 }
                 FileKind::File => {
                     if self.exclude_rules.should_exclude_file(&file_name) {
                         tracing::info!("excluding file: {}", entry_path.display());
-                        *local_skipped += 1;
-                        *items_since_update += 1;
+                        counters.skipped.fetch_add(1, Ordering::Relaxed);
                     } else {
-                        self.enqueue_file_blocking(
-                            entry_path,
-                            entry_relative,
-                            metadata,
-                            local_files_found,
-                            local_total_size,
-                        );
-                        *items_since_update += 1;
+                        self.enqueue_file_blocking(entry_path, entry_relative, metadata, counters);
                     }
                 }
                 FileKind::Unknown => {
                     // Skip unknown file types
                     tracing::warn!("skipping unknown file type: {}", entry_path.display());
-                    *local_skipped += 1;
-                    *items_since_update += 1;
+                    counters.skipped.fetch_add(1, Ordering::Relaxed);
                 }
             }
+
+            if counters.tick(1) {
+                let (dirs, files, size, skipped) = counters.totals();
+                self.update_progress_blocking(dirs, files, size, skipped);
+            }
         }
 
         // Send sentinel: this directory's immediate contents are now queued for doing wrok
         // This allows the tree queue to mark children as ready and a worker to start doing crap for this dir.
-        self.send_directory_scanned(relative_path.clone());
-
-        if *items_since_update >= PROGRESS_UPDATE_INTERVAL {
-            self.update_progress_blocking(
-                *local_dirs_found,
-                *local_files_found,
-                *local_total_size,
-                *local_skipped,
-            );
-            *items_since_update = 0;
-        }
+        self.send_directory_scanned(relative_path);
 
-        // Second pass: recursively process subdirectories.
-        // TODO: Basically BFS, Make this DFS too so users can choose leaf vfs branch syncing? Seems silly to bother
-        for (subdir_path, subdir_relative) in subdirs {
-            self.traverse_directory_blocking(
-                subdir_path,
-                subdir_relative,
-                local_dirs_found,
-                local_files_found,
-                local_total_size,
-                local_skipped,
-                items_since_update,
-            )?;
-        }
-
-        Ok(())
+        subdirs
     }
 
     fn get_file_metadata_blocking(&self, path: &std::path::Path) -> std::io::Result<FileMetadata> {
@@ -394,11 +511,10 @@ impl ReaderPool {
         source_path: PathBuf,
         relative_path: PathBuf,
         metadata: FileMetadata,
-        local_files_found: &mut u64,
-        local_total_size: &mut u64,
+        counters: &ScanCounters,
     ) {
-        *local_files_found += 1;
-        *local_total_size += metadata.size;
+        counters.files.fetch_add(1, Ordering::Relaxed);
+        counters.size.fetch_add(metadata.size, Ordering::Relaxed);
 
         let work_item = if metadata.size >= LARGE_FILE_THRESHOLD {
             WorkItem::CopyLargeFile {
@@ -416,7 +532,7 @@ impl ReaderPool {
             }
         };
 
-        if let Err(e) = self.work_tx.send(work_item) {
+        if let Err(e) = self.work_tx.blocking_send(work_item) {
             tracing::error!("failed to send file work item: {}", e);
         }
     }
@@ -425,7 +541,7 @@ impl ReaderPool {
         &self,
         source_path: PathBuf,
         relative_path: PathBuf,
-        local_files_found: &mut u64,
+        counters: &ScanCounters,
     ) {
         use super::metadata::SymlinkMetadata;
 
@@ -464,9 +580,9 @@ impl ReaderPool {
             }
         };
 
-        *local_files_found += 1;
+        counters.files.fetch_add(1, Ordering::Relaxed);
 
-        if let Err(e) = self.work_tx.send(WorkItem::CreateSymlink {
+        if let Err(e) = self.work_tx.blocking_send(WorkItem::CreateSymlink {
             uuid: self.uuid,
             source_path,
             dest_path: relative_path,
@@ -480,7 +596,7 @@ impl ReaderPool {
         &self,
         source_path: PathBuf,
         metadata: &FileMetadata,
-        local_skipped: &mut u64,
+        counters: &ScanCounters,
     ) {
         #[cfg(unix)]
         tracing::warn!(
@@ -490,7 +606,7 @@ impl ReaderPool {
         );
         #[cfg(not(unix))]
         tracing::warn!("skipping special file: {}", source_path.display());
-        *local_skipped += 1;
+        counters.skipped.fetch_add(1, Ordering::Relaxed);
     }
 
     fn send_directory_scanned(&self, dest_path: PathBuf) {
@@ -498,14 +614,14 @@ impl ReaderPool {
             uuid: self.uuid,
             dest_path,
         };
-        if let Err(e) = self.work_tx.send(sentinel) {
+        if let Err(e) = self.work_tx.blocking_send(sentinel) {
             tracing::error!("failed to send directory fully scanned sentinel: {}", e);
         }
     }
 
-    fn send_scan_complete(&self) {
+    async fn send_scan_complete(&self) {
         let sentinel = WorkItem::ScanComplete { uuid: self.uuid };
-        if let Err(e) = self.work_tx.send(sentinel) {
+        if let Err(e) = self.work_tx.send(sentinel).await {
             tracing::error!("failed to send scan complete sentinel: {}", e);
         }
     }
@@ -517,7 +633,6 @@ impl ReaderPool {
         total_size: u64,
         skipped: u64,
     ) {
-        use std::sync::atomic::Ordering;
         let atomic_progress = self.progress.get_or_create(self.uuid);
         atomic_progress
             .dirs_found
@@ -0,0 +1,855 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::baseline::{Baseline, BaselineEntry, SideStamp};
+use super::metadata::{FileKind, FileMetadata, SymlinkMetadata};
+use super::work::WorkItem;
+
+// Three way reconcile for /a <-> /b. Each path gets looked at three times, what
+// the baseline says both sides agreed on last time and what each side has now.
+// Each side's state vs the baseline says if that side changed it, the pair of
+// changes says what to do:
+//
+//   lhs       rhs       verdict
+//   same      same      nothing
+//   changed   same      copy lhs -> rhs (and the reverse)
+//   deleted   same      delete on rhs (and the reverse)
+//   deleted   deleted   forget about it
+//   changed   changed   converged if they ended up the same, else conflict
+//   deleted   changed   conflict, never lose the change
+//
+// Moves get found before any of that. A path gone from one side plus a new
+// path on that same side with the inode the baseline had for the old one is a
+// rename, as long as the content still matches. Replaying it on the other side
+// and re-keying the baseline turns the rest into a normal reconcile where the
+// moved stuff is unchanged, instead of a delete of the old name and a full
+// copy of the new one.
+//
+// Nothing in here touches the filesystem besides the hash callback, it just
+// decides. The two-way runner applies the work and checks each target still
+// looks like what we decided on before it overwrites anything.
+
+/// One of the two roots of a two-way sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Lhs,
+    Rhs,
+}
+
+impl Side {
+    pub fn other(self) -> Self {
+        match self {
+            Side::Lhs => Side::Rhs,
+            Side::Rhs => Side::Lhs,
+        }
+    }
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Side::Lhs => write!(f, "lhs"),
+            Side::Rhs => write!(f, "rhs"),
+        }
+    }
+}
+
+/// What one side has at a path right now
+#[derive(Debug, Clone)]
+pub struct Observed {
+    pub metadata: FileMetadata,
+    pub inode: u64,
+    /// Symlink target, None for anything else
+    pub target: Option<PathBuf>,
+}
+
+impl Observed {
+    pub fn kind(&self) -> FileKind {
+        self.metadata.kind
+    }
+
+    pub fn stamp(&self) -> SideStamp {
+        SideStamp {
+            mtime: self.metadata.mtime,
+            inode: self.inode,
+        }
+    }
+
+    /// Is this still the same thing, what the runner checks right before it
+    /// overwrites or removes something. Dirs only need to still be dirs.
+    pub fn same_as(&self, other: &Observed) -> bool {
+        if self.kind() != other.kind() {
+            return false;
+        }
+        match self.kind() {
+            FileKind::Directory => true,
+            FileKind::Symlink => self.target == other.target,
+            _ => {
+                self.metadata.size == other.metadata.size
+                    && self.metadata.mtime == other.metadata.mtime
+                    && self.inode == other.inode
+            }
+        }
+    }
+}
+
+/// Everything on one side, paths relative to its root
+pub type Tree = BTreeMap<PathBuf, Observed>;
+
+/// What one side did to a path since the baseline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Not in the baseline and not here
+    Absent,
+    Same,
+    Created,
+    Modified,
+    Deleted,
+}
+
+impl Change {
+    fn changed(self) -> bool {
+        matches!(self, Change::Created | Change::Modified)
+    }
+}
+
+/// Why a path can't be reconciled on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    /// Both sides changed it and didn't end up the same
+    BothChanged,
+    /// One side deleted what the other changed
+    DeleteChange { deleted: Side },
+}
+
+impl ConflictKind {
+    /// Short no spaces version for output people grep/cut
+    pub fn tag(&self) -> &'static str {
+        match self {
+            ConflictKind::BothChanged => "both-changed",
+            ConflictKind::DeleteChange { deleted: Side::Lhs } => "deleted-lhs",
+            ConflictKind::DeleteChange { deleted: Side::Rhs } => "deleted-rhs",
+        }
+    }
+}
+
+impl std::fmt::Display for ConflictKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConflictKind::BothChanged => write!(f, "changed on both sides"),
+            ConflictKind::DeleteChange { deleted } => {
+                write!(
+                    f,
+                    "deleted on {} but changed on {}",
+                    deleted,
+                    deleted.other()
+                )
+            }
+        }
+    }
+}
+
+/// What to do with a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Unchanged,
+    /// Only this side changed it, copy it over the other
+    Changed(Side),
+    /// This side deleted it and the other didn't touch it
+    Deleted(Side),
+    /// Gone on both, drop it from the baseline
+    BothDeleted,
+    /// Both changed it to the same thing, nothing to copy
+    Converged,
+    Conflict(ConflictKind),
+}
+
+/// One side moved from to to since the baseline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rename {
+    /// Who did the moving, the other side gets the same rename
+    pub side: Side,
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl Rename {
+    /// The rename for the other side, other being that side's tree
+    pub fn plan(&self, uuid: u128, other: &Tree) -> Planned {
+        Planned {
+            target: self.side.other(),
+            item: WorkItem::Rename {
+                uuid,
+                from: self.from.clone(),
+                dest_path: self.to.clone(),
+            },
+            expect: other.get(&self.from).cloned(),
+            source: None,
+        }
+    }
+}
+
+/// The verdict for one path and what each side had when it was made
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub path: PathBuf,
+    pub verdict: Verdict,
+    pub lhs: Option<Observed>,
+    pub rhs: Option<Observed>,
+    /// Content hash if we had to work it out, saves doing it again next time
+    pub hash: Option<String>,
+}
+
+/// A work item for one side, expect is what the target side had when we
+/// decided. If it doesn't look like that anymore someone changed it since and
+/// the item must not be applied.
+#[derive(Debug, Clone)]
+pub struct Planned {
+    pub target: Side,
+    pub item: WorkItem,
+    pub expect: Option<Observed>,
+    pub source: Option<Observed>,
+}
+
+impl Decision {
+    pub fn side(&self, side: Side) -> Option<&Observed> {
+        match side {
+            Side::Lhs => self.lhs.as_ref(),
+            Side::Rhs => self.rhs.as_ref(),
+        }
+    }
+
+    /// The work to get both sides agreeing, None if there is nothing to do or
+    /// its a conflict for someone else to sort out.
+    pub fn plan(&self, uuid: u128, source_root: impl Fn(Side) -> PathBuf) -> Option<Planned> {
+        let (from, target) = match self.verdict {
+            Verdict::Changed(side) => (side, side.other()),
+            Verdict::Deleted(side) => {
+                let target = side.other();
+                let expect = self.side(target)?.clone();
+                return Some(Planned {
+                    target,
+                    item: WorkItem::Remove {
+                        uuid,
+                        dest_path: self.path.clone(),
+                        kind: expect.kind(),
+                    },
+                    expect: Some(expect),
+                    source: None,
+                });
+            }
+            _ => return None,
+        };
+
+        let source = self.side(from)?.clone();
+        let source_path = source_root(from).join(&self.path);
+        let dest_path = self.path.clone();
+
+        let item = match source.kind() {
+            FileKind::Directory => WorkItem::CreateDir {
+                uuid,
+                source_path,
+                dest_path,
+                metadata: super::metadata::DirMetadata {
+                    path: source.metadata.path.clone(),
+                    #[cfg(unix)]
+                    mode: source.metadata.mode,
+                    #[cfg(unix)]
+                    uid: source.metadata.uid,
+                    #[cfg(unix)]
+                    gid: source.metadata.gid,
+                },
+            },
+            FileKind::Symlink => WorkItem::CreateSymlink {
+                uuid,
+                source_path,
+                dest_path,
+                metadata: SymlinkMetadata {
+                    path: source.metadata.path.clone(),
+                    target: source.target.clone().unwrap_or_default(),
+                    #[cfg(unix)]
+                    mode: source.metadata.mode,
+                    #[cfg(unix)]
+                    uid: source.metadata.uid,
+                    #[cfg(unix)]
+                    gid: source.metadata.gid,
+                },
+            },
+            FileKind::File if source.metadata.size >= super::LARGE_FILE_THRESHOLD => {
+                WorkItem::CopyLargeFile {
+                    uuid,
+                    source_path,
+                    dest_path,
+                    metadata: source.metadata.clone(),
+                }
+            }
+            FileKind::File => WorkItem::CopySmallFile {
+                uuid,
+                source_path,
+                dest_path,
+                metadata: source.metadata.clone(),
+            },
+            FileKind::Special | FileKind::Unknown => return None,
+        };
+
+        Some(Planned {
+            target,
+            item,
+            expect: self.side(target).cloned(),
+            source: Some(source),
+        })
+    }
+
+    /// Baseline entry for when both sides agree on this path, None if it
+    /// should be dropped. Sides are what they look like after any work was
+    /// applied.
+    pub fn agreed(&self, lhs: Option<&Observed>, rhs: Option<&Observed>) -> Option<BaselineEntry> {
+        let (lhs, rhs) = (lhs?, rhs?);
+        Some(BaselineEntry {
+            kind: lhs.kind(),
+            size: lhs.metadata.size,
+            hash: self.hash.clone(),
+            target: lhs.target.clone(),
+            lhs: lhs.stamp(),
+            rhs: rhs.stamp(),
+        })
+    }
+}
+
+/// Work out what each side did to a path since the baseline. Only hashes if
+/// the size matches but the mtime or inode moved, touch and editors that
+/// rename an identical file over the old one do that.
+fn change(
+    base: Option<&BaselineEntry>,
+    now: Option<&Observed>,
+    side: Side,
+    hash: &mut impl FnMut(Side) -> Option<String>,
+) -> Change {
+    let (base, now) = match (base, now) {
+        (None, None) => return Change::Absent,
+        (None, Some(_)) => return Change::Created,
+        (Some(_), None) => return Change::Deleted,
+        (Some(base), Some(now)) => (base, now),
+    };
+
+    if base.kind != now.kind() {
+        return Change::Modified;
+    }
+
+    match now.kind() {
+        FileKind::Directory => Change::Same,
+        FileKind::Symlink if base.target == now.target => Change::Same,
+        FileKind::Symlink => Change::Modified,
+        _ => {
+            let stamp = match side {
+                Side::Lhs => base.lhs,
+                Side::Rhs => base.rhs,
+            };
+            if base.size != now.metadata.size {
+                Change::Modified
+            } else if stamp == now.stamp() {
+                Change::Same
+            } else {
+                match (&base.hash, hash(side)) {
+                    (Some(then), Some(now)) if *then == now => Change::Same,
+                    _ => Change::Modified,
+                }
+            }
+        }
+    }
+}
+
+/// Reconcile both sides against the baseline, one decision per path either
+/// side or the baseline knows about, in path order.
+pub fn reconcile(
+    baseline: &Baseline,
+    lhs: &Tree,
+    rhs: &Tree,
+    mut hash: impl FnMut(Side, &Path) -> Option<String>,
+) -> Vec<Decision> {
+    let mut paths: Vec<&PathBuf> = lhs
+        .keys()
+        .chain(rhs.keys())
+        .chain(baseline.paths())
+        .collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut decisions = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        let base = baseline.get(path);
+        let (l, r) = (lhs.get(path), rhs.get(path));
+
+        // Hashes get cached per path so deciding and recording share them
+        let mut hashes: [Option<Option<String>>; 2] = [None, None];
+        let mut hash_of = |side: Side| -> Option<String> {
+            let slot = &mut hashes[side as usize];
+            slot.get_or_insert_with(|| hash(side, path)).clone()
+        };
+
+        let lc = change(base, l, Side::Lhs, &mut hash_of);
+        let rc = change(base, r, Side::Rhs, &mut hash_of);
+
+        let verdict = match (lc, rc) {
+            (Change::Same, Change::Same) => Verdict::Unchanged,
+            (Change::Absent, Change::Absent) => continue,
+            (c, Change::Same | Change::Absent) if c.changed() => Verdict::Changed(Side::Lhs),
+            (Change::Same | Change::Absent, c) if c.changed() => Verdict::Changed(Side::Rhs),
+            (Change::Deleted, Change::Same) => Verdict::Deleted(Side::Lhs),
+            (Change::Same, Change::Deleted) => Verdict::Deleted(Side::Rhs),
+            (Change::Deleted, Change::Deleted | Change::Absent)
+            | (Change::Absent, Change::Deleted) => Verdict::BothDeleted,
+            (Change::Deleted, _) => {
+                Verdict::Conflict(ConflictKind::DeleteChange { deleted: Side::Lhs })
+            }
+            (_, Change::Deleted) => {
+                Verdict::Conflict(ConflictKind::DeleteChange { deleted: Side::Rhs })
+            }
+            _ => {
+                // Both changed, fine if they changed to the same thing
+                let (l, r) = (
+                    l.expect("changed lhs exists"),
+                    r.expect("changed rhs exists"),
+                );
+                let same = l.kind() == r.kind()
+                    && match l.kind() {
+                        FileKind::Directory => true,
+                        FileKind::Symlink => l.target == r.target,
+                        _ => {
+                            l.metadata.size == r.metadata.size
+                                && hash_of(Side::Lhs).is_some()
+                                && hash_of(Side::Lhs) == hash_of(Side::Rhs)
+                        }
+                    };
+                if same {
+                    Verdict::Converged
+                } else {
+                    Verdict::Conflict(ConflictKind::BothChanged)
+                }
+            }
+        };
+
+        // Hash of whatever both sides will have once this is applied, if we
+        // happen to know it
+        let known = |side: Side| hashes[side as usize].clone().flatten();
+        let hash = match verdict {
+            Verdict::Changed(side) => known(side),
+            Verdict::Converged => known(Side::Lhs),
+            Verdict::Unchanged => known(Side::Lhs)
+                .or_else(|| known(Side::Rhs))
+                .or_else(|| base.and_then(|b| b.hash.clone())),
+            _ => None,
+        };
+
+        decisions.push(Decision {
+            path: path.clone(),
+            verdict,
+            lhs: l.cloned(),
+            rhs: r.cloned(),
+            hash,
+        });
+    }
+
+    keep_dirs_with_survivors(&mut decisions);
+    decisions
+}
+
+/// Find what either side moved since the baseline. The old name has to be
+/// gone on the moving side and still be the same thing on the other, the new
+/// name has to be new to both with the inode the moving side had for the old
+/// one. Files also need the same size and either the same mtime or contents,
+/// inodes get reused. A moved dir takes everything under it along so none of
+/// that gets looked at on its own.
+pub fn detect_renames(
+    baseline: &Baseline,
+    lhs: &Tree,
+    rhs: &Tree,
+    mut hash: impl FnMut(Side, &Path) -> Option<String>,
+) -> Vec<Rename> {
+    let mut renames: Vec<Rename> = Vec::new();
+
+    for side in [Side::Lhs, Side::Rhs] {
+        let (moved, other) = match side {
+            Side::Lhs => (lhs, rhs),
+            Side::Rhs => (rhs, lhs),
+        };
+        let stamp = |b: &BaselineEntry, side: Side| match side {
+            Side::Lhs => b.lhs,
+            Side::Rhs => b.rhs,
+        };
+
+        let created: HashMap<u64, &PathBuf> = moved
+            .iter()
+            .filter(|(path, o)| {
+                o.inode != 0 && baseline.get(path).is_none() && !other.contains_key(*path)
+            })
+            .map(|(path, o)| (o.inode, path))
+            .collect();
+        if created.is_empty() {
+            continue;
+        }
+
+        // Sorted, so a moved dir always comes before its kids
+        let mut taken: Option<PathBuf> = None;
+        for from in baseline.paths() {
+            if taken.as_ref().is_some_and(|t| from.starts_with(t)) || moved.contains_key(from) {
+                continue;
+            }
+            let Some(base) = baseline.get(from) else {
+                continue;
+            };
+            let inode = stamp(base, side).inode;
+            if inode == 0 {
+                continue;
+            }
+            let Some(&to) = created.get(&inode) else {
+                continue;
+            };
+            if to.starts_with(from) {
+                continue;
+            }
+
+            // Whatever the other side did to it otherwise rides along with
+            // the rename and the reconcile after sorts it out.
+            let Some(theirs) = other.get(from) else {
+                continue;
+            };
+            let their_inode = stamp(base, side.other()).inode;
+            if theirs.kind() != base.kind || (their_inode != 0 && theirs.inode != their_inode) {
+                continue;
+            }
+
+            let now = &moved[to];
+            if now.kind() != base.kind {
+                continue;
+            }
+            let same = match base.kind {
+                FileKind::Directory => true,
+                FileKind::Symlink => now.target == base.target,
+                _ => {
+                    now.metadata.size == base.size
+                        && (now.metadata.mtime == stamp(base, side).mtime || {
+                            let then = base.hash.clone().or_else(|| hash(side.other(), from));
+                            then.is_some() && then == hash(side, to)
+                        })
+                }
+            };
+            if !same {
+                continue;
+            }
+
+            renames.push(Rename {
+                side,
+                from: from.clone(),
+                to: to.clone(),
+            });
+            taken = Some(from.clone());
+        }
+    }
+
+    renames
+}
+
+// A dir deleted on one side can only go away on the other if everything under
+// it is going away too. If something new or changed is in there the dir gets
+// put back instead so nothing under it is lost. Deepest first so it carries up.
+fn keep_dirs_with_survivors(decisions: &mut [Decision]) {
+    for i in (0..decisions.len()).rev() {
+        let Verdict::Deleted(side) = decisions[i].verdict else {
+            continue;
+        };
+        if decisions[i].side(side.other()).map(|o| o.kind()) != Some(FileKind::Directory) {
+            continue;
+        }
+
+        let dir = decisions[i].path.clone();
+        let survivor = decisions[i + 1..]
+            .iter()
+            .take_while(|d| d.path.starts_with(&dir))
+            .any(|d| {
+                !matches!(d.verdict, Verdict::BothDeleted) && d.verdict != Verdict::Deleted(side)
+            });
+
+        if survivor {
+            tracing::debug!(
+                "{} deleted on {} but has new stuff under it, keeping it",
+                dir.display(),
+                side
+            );
+            decisions[i].verdict = Verdict::Changed(side.other());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, size: u64, mtime: u64, inode: u64) -> Observed {
+        Observed {
+            metadata: FileMetadata {
+                path: PathBuf::from(path),
+                size,
+                #[cfg(unix)]
+                mode: 0o644,
+                #[cfg(unix)]
+                uid: 0,
+                #[cfg(unix)]
+                gid: 0,
+                kind: FileKind::File,
+                mtime,
+            },
+            inode,
+            target: None,
+        }
+    }
+
+    fn dir(path: &str) -> Observed {
+        let mut d = file(path, 0, 0, 0);
+        d.metadata.kind = FileKind::Directory;
+        d
+    }
+
+    fn base(o: &Observed, hash: Option<&str>) -> BaselineEntry {
+        BaselineEntry {
+            kind: o.kind(),
+            size: o.metadata.size,
+            hash: hash.map(|h| h.to_string()),
+            target: None,
+            lhs: o.stamp(),
+            rhs: o.stamp(),
+        }
+    }
+
+    fn baseline(name: &str, entries: &[(&str, BaselineEntry)]) -> Baseline {
+        let path = std::env::temp_dir().join(format!(
+            "yeet-reconcile-test-{}-{}.jsonl",
+            std::process::id(),
+            name
+        ));
+        let mut b = Baseline::open(path).unwrap();
+        for (p, e) in entries {
+            b.insert(PathBuf::from(p), e.clone());
+        }
+        b
+    }
+
+    fn tree(items: &[(&str, Observed)]) -> Tree {
+        items
+            .iter()
+            .map(|(p, o)| (PathBuf::from(p), o.clone()))
+            .collect()
+    }
+
+    fn verdicts(decisions: &[Decision]) -> Vec<(String, Verdict)> {
+        decisions
+            .iter()
+            .map(|d| (d.path.display().to_string(), d.verdict))
+            .collect()
+    }
+
+    fn no_hash(_: Side, _: &Path) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn test_detect_renames() {
+        let mut d = dir("d");
+        d.inode = 7;
+        let f = file("d/f", 5, 10, 8);
+        let g = file("g", 3, 10, 9);
+        let reused = file("h", 3, 10, 10);
+        let bl = baseline(
+            "renames",
+            &[
+                ("d", base(&d, None)),
+                ("d/f", base(&f, None)),
+                ("g", base(&g, None)),
+                ("h", base(&reused, Some("old"))),
+            ],
+        );
+
+        // lhs moved d to e and g to sub/g, rhs replaced h with something
+        // else that got h's inode
+        let lhs = tree(&[
+            ("e", d.clone()),
+            ("e/f", f.clone()),
+            ("h", reused.clone()),
+            ("sub", dir("sub")),
+            ("sub/g", g.clone()),
+        ]);
+        let rhs = tree(&[
+            ("d", d.clone()),
+            ("d/f", f.clone()),
+            ("g", g.clone()),
+            ("i", file("i", 3, 20, 10)),
+        ]);
+
+        let renames = detect_renames(&bl, &lhs, &rhs, |_, _| Some("new".to_string()));
+        let found: Vec<(Side, &str, &str)> = renames
+            .iter()
+            .map(|r| (r.side, r.from.to_str().unwrap(), r.to.to_str().unwrap()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![(Side::Lhs, "d", "e"), (Side::Lhs, "g", "sub/g")],
+            "h is still on rhs so i can't be a rename of it"
+        );
+    }
+
+    #[test]
+    fn test_one_sided_changes() {
+        let a = file("a", 1, 10, 1);
+        let b = base(&a, None);
+        let bl = baseline(
+            "one-sided",
+            &[("same", b.clone()), ("lmod", b.clone()), ("rdel", b)],
+        );
+
+        let lhs = tree(&[
+            ("same", a.clone()),
+            ("lmod", file("lmod", 2, 20, 1)),
+            ("rdel", a.clone()),
+            ("lnew", file("lnew", 1, 1, 9)),
+        ]);
+        let rhs = tree(&[
+            ("same", a.clone()),
+            ("lmod", a.clone()),
+            ("rnew", a.clone()),
+        ]);
+
+        assert_eq!(
+            verdicts(&reconcile(&bl, &lhs, &rhs, no_hash)),
+            vec![
+                ("lmod".to_string(), Verdict::Changed(Side::Lhs)),
+                ("lnew".to_string(), Verdict::Changed(Side::Lhs)),
+                ("rdel".to_string(), Verdict::Deleted(Side::Rhs)),
+                ("rnew".to_string(), Verdict::Changed(Side::Rhs)),
+                ("same".to_string(), Verdict::Unchanged),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_conflicts_and_convergence() {
+        let a = file("a", 1, 10, 1);
+        let b = base(&a, Some("old"));
+        let bl = baseline(
+            "conflicts",
+            &[
+                ("both", b.clone()),
+                ("conv", b.clone()),
+                ("delmod", b.clone()),
+                ("gone", b),
+            ],
+        );
+
+        let lhs = tree(&[
+            ("both", file("both", 2, 20, 1)),
+            ("conv", file("conv", 3, 30, 1)),
+            ("new", file("new", 5, 5, 5)),
+        ]);
+        let rhs = tree(&[
+            ("both", file("both", 4, 40, 2)),
+            ("conv", file("conv", 3, 31, 2)),
+            ("delmod", file("delmod", 9, 90, 1)),
+            ("new", file("new", 5, 6, 6)),
+        ]);
+
+        // Same size files hash the same unless its "both"
+        let hash = |_: Side, p: &Path| Some(format!("hash-{}", p.display()));
+        assert_eq!(
+            verdicts(&reconcile(&bl, &lhs, &rhs, hash)),
+            vec![
+                (
+                    "both".to_string(),
+                    Verdict::Conflict(ConflictKind::BothChanged)
+                ),
+                ("conv".to_string(), Verdict::Converged),
+                (
+                    "delmod".to_string(),
+                    Verdict::Conflict(ConflictKind::DeleteChange { deleted: Side::Lhs })
+                ),
+                ("gone".to_string(), Verdict::BothDeleted),
+                ("new".to_string(), Verdict::Converged),
+            ]
+        );
+
+        // Can't prove they're the same without hashes, so its a conflict
+        let decisions = reconcile(&bl, &lhs, &rhs, no_hash);
+        assert_eq!(
+            decisions[1].verdict,
+            Verdict::Conflict(ConflictKind::BothChanged)
+        );
+    }
+
+    #[test]
+    fn test_touch_is_not_a_change() {
+        let a = file("a", 1, 10, 1);
+        let bl = baseline("touch", &[("a", base(&a, Some("abc")))]);
+
+        // mtime and inode moved, contents didn't
+        let lhs = tree(&[("a", file("a", 1, 99, 7))]);
+        let rhs = tree(&[("a", a.clone())]);
+        let decisions = reconcile(&bl, &lhs, &rhs, |_, _| Some("abc".to_string()));
+        assert_eq!(decisions[0].verdict, Verdict::Unchanged);
+        assert_eq!(decisions[0].hash.as_deref(), Some("abc"));
+
+        let decisions = reconcile(&bl, &lhs, &rhs, |_, _| Some("def".to_string()));
+        assert_eq!(decisions[0].verdict, Verdict::Changed(Side::Lhs));
+    }
+
+    #[test]
+    fn test_deleted_dir_keeps_new_kids() {
+        let d = dir("d");
+        let f = file("d/f", 1, 1, 1);
+        let bl = baseline(
+            "dirs",
+            &[
+                ("d", base(&d, None)),
+                ("d/f", base(&f, None)),
+                ("e", base(&d, None)),
+                ("e/f", base(&f, None)),
+            ],
+        );
+
+        // lhs deleted both dirs, rhs added something to d
+        let lhs = tree(&[]);
+        let rhs = tree(&[
+            ("d", d.clone()),
+            ("d/f", f.clone()),
+            ("d/new", file("d/new", 1, 1, 2)),
+            ("e", d.clone()),
+            ("e/f", f.clone()),
+        ]);
+
+        let decisions = reconcile(&bl, &lhs, &rhs, no_hash);
+        assert_eq!(
+            verdicts(&decisions),
+            vec![
+                ("d".to_string(), Verdict::Changed(Side::Rhs)),
+                ("d/f".to_string(), Verdict::Deleted(Side::Lhs)),
+                ("d/new".to_string(), Verdict::Changed(Side::Rhs)),
+                ("e".to_string(), Verdict::Deleted(Side::Lhs)),
+                ("e/f".to_string(), Verdict::Deleted(Side::Lhs)),
+            ]
+        );
+
+        // Puts d back on lhs and removes e's file from rhs, guarded by what
+        // rhs had
+        let root = |s: Side| PathBuf::from(format!("/{}", s));
+        let planned = decisions[0].plan(1, root).unwrap();
+        assert_eq!(planned.target, Side::Lhs);
+        assert!(matches!(planned.item, WorkItem::CreateDir { .. }));
+
+        let planned = decisions[4].plan(1, root).unwrap();
+        assert_eq!(planned.target, Side::Rhs);
+        assert!(matches!(planned.item, WorkItem::Remove { .. }));
+        assert!(planned.expect.unwrap().same_as(&f));
+    }
+}
@@ -0,0 +1,972 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::Mutex;
+
+use super::IoSubsystem;
+use super::error::IoError;
+use super::progress::AtomicOperationProgress;
+use super::work::WorkItem;
+
+// Remote destinations, aka yeet cp /here host:/there.
+//
+// The daemon with the source scans like always, but instead of its writers
+// everything the scan finds goes over grpc (rpc/transfer.rs) to the daemon on
+// the dest host, over the ssh forward to it. That daemon runs the work items
+// through its own writers like any other sync. Writers want a source path to
+// copy from so file data gets staged on the dest first, under STAGING_DIR at
+// the dest root so its on the same filesystem, and the writers remove the
+// staged copy once its copied into place.
+//
+// TODO: staged files could be renamed into place instead of copied a second
+// time, future mitch problem once this works at all.
+//
+// The sending side only knows how things are going by asking, Report mirrors
+// what the remote daemon says into the local progress and errors so the rest
+// of the daemon can't tell a remote dest from a local one.
+//
+// Remote sources, aka yeet cp host:/there /here, are the same thing turned
+// around. The daemon on the source host scans (Scan) and streams what it finds
+// here, we pull file data for each file item and it goes through a Receiver
+// into our writers like it would over there.
+
+/// Most file data per grpc message, anything bigger goes in chunks this size.
+/// Well under tonic's default 4MiB message limit.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Where a remote dest stages incoming file data, relative to the dest root
+pub const STAGING_DIR: &str = ".yeet-incoming";
+
+/// How a remote dest is getting on
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Status {
+    pub dirs_written: u64,
+    pub files_written: u64,
+    pub bytes_written: u64,
+    /// Errors since whatever the asker has already seen, path and error
+    pub errors: Vec<(PathBuf, String)>,
+    /// Everything is written, nothing more is coming
+    pub done: bool,
+}
+
+/// What a scan has found so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Found {
+    pub dirs: u64,
+    pub files: u64,
+    pub size: u64,
+}
+
+/// The dest end of a remote sync, on the daemon that owns the dest.
+pub struct Receiver {
+    uuid: u128,
+    dest: PathBuf,
+    staging: PathBuf,
+    /// Symlinks we've been sent, see check_beneath()
+    links: parking_lot::Mutex<HashSet<PathBuf>>,
+    subsystem: IoSubsystem,
+    work_tx: tokio::sync::mpsc::Sender<WorkItem>,
+    next: AtomicU64,
+}
+
+impl Receiver {
+    /// Start writing to dest with subsystem's writers, subsystem should be
+    /// fresh and set up however the daemon sets up local syncs.
+    pub async fn start(
+        mut subsystem: IoSubsystem,
+        uuid: u128,
+        dest: PathBuf,
+        num_writers: Option<usize>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let staging = dest
+            .join(STAGING_DIR)
+            .join(uuid::Uuid::from_u128(uuid).to_string());
+        tokio::fs::create_dir_all(&staging)
+            .await
+            .map_err(|e| format!("failed to create staging dir {}: {}", staging.display(), e))?;
+
+        let work_tx = subsystem
+            .start_receiving(uuid, dest.clone(), num_writers)
+            .await?;
+
+        Ok(Self {
+            uuid,
+            dest,
+            staging,
+            links: parking_lot::Mutex::new(HashSet::new()),
+            subsystem,
+            work_tx,
+            next: AtomicU64::new(0),
+        })
+    }
+
+    /// Queue item, data is the whole file for file copies and ignored for
+    /// anything else.
+    pub async fn put(&self, item: WorkItem, data: Vec<u8>) -> std::io::Result<()> {
+        if !is_file(&item) {
+            return self.send(item).await;
+        }
+
+        let mut staged = self.stage(item).await?;
+        staged.write(data).await?;
+        let item = staged.finish().await?;
+        self.send(item).await
+    }
+
+    /// Start staging a file copy whose data comes in pieces, the item goes in
+    /// the queue once its finished and handed to send().
+    pub async fn stage(&self, item: WorkItem) -> std::io::Result<Staged> {
+        if !is_file(&item) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "only file copies have data",
+            ));
+        }
+        self.check(&item)?;
+
+        let path = self
+            .staging
+            .join(self.next.fetch_add(1, Ordering::Relaxed).to_string());
+        let file = tokio::fs::File::create(&path).await?.into_std().await;
+        Ok(Staged {
+            item,
+            path,
+            file: Arc::new(file),
+            written: 0,
+        })
+    }
+
+    /// Straight on to the writers
+    pub async fn send(&self, mut item: WorkItem) -> std::io::Result<()> {
+        self.check(&item)?;
+        item.set_uuid(self.uuid);
+
+        let complete = matches!(item, WorkItem::ScanComplete { .. });
+        self.work_tx
+            .send(item)
+            .await
+            .map_err(|_| std::io::Error::other("writers went away"))?;
+
+        // Same as a fan-out leg, the scan is someone else's
+        if complete {
+            self.subsystem.reader_done.set();
+        }
+        Ok(())
+    }
+
+    // Nothing outside the dest and nothing through a symlink in it either,
+    // otherwise a peer sends a -> /etc and then writes a/passwd. Ones we were
+    // sent might not be written yet so those are remembered, renames included.
+    fn check(&self, item: &WorkItem) -> std::io::Result<()> {
+        check_contained(item)?;
+
+        // Replacing or removing a symlink doesn't go through it, everything
+        // else would, a file that used to be a symlink on the dest gets an
+        // error instead.
+        let itself = !matches!(
+            item,
+            WorkItem::CreateSymlink { .. } | WorkItem::Remove { .. } | WorkItem::Rename { .. }
+        );
+
+        let mut links = self.links.lock();
+        for path in paths(item) {
+            check_beneath(&self.dest, path, itself, &links)?;
+        }
+
+        match item {
+            WorkItem::CreateSymlink { dest_path, .. } => {
+                links.insert(dest_path.clone());
+            }
+            WorkItem::Rename {
+                from, dest_path, ..
+            } => {
+                let moved: Vec<PathBuf> = links
+                    .iter()
+                    .filter_map(|link| link.strip_prefix(from).ok())
+                    .map(|rest| dest_path.join(rest))
+                    .collect();
+                links.extend(moved);
+            }
+            WorkItem::Remove { dest_path, .. } => {
+                links.retain(|link| !link.starts_with(dest_path));
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Where things are at, errors from errors_since on
+    pub async fn status(&self, errors_since: usize) -> Status {
+        let progress = self.subsystem.get_atomic_progress(self.uuid);
+        Status {
+            dirs_written: progress.dirs_written.load(Ordering::Relaxed),
+            files_written: progress.files_written.load(Ordering::Relaxed),
+            bytes_written: progress.bytes_written.load(Ordering::Relaxed),
+            errors: self
+                .subsystem
+                .errors
+                .lock()
+                .await
+                .iter()
+                .skip(errors_since)
+                .map(|e| (e.path.clone(), e.error.clone()))
+                .collect(),
+            done: self.subsystem.writer_done.is_set(),
+        }
+    }
+
+    /// Mirror a remote scan's found counts, ours only go up
+    pub fn found(&self, found: Found) {
+        let progress = self.subsystem.get_atomic_progress(self.uuid);
+        progress.dirs_found.fetch_max(found.dirs, Ordering::Relaxed);
+        progress
+            .files_found
+            .fetch_max(found.files, Ordering::Relaxed);
+        progress.total_size.fetch_max(found.size, Ordering::Relaxed);
+    }
+
+    /// Something went wrong on the source end with path
+    pub async fn error(&self, error: String, path: PathBuf) {
+        tracing::error!("{}: {}", error, path.display());
+        self.subsystem
+            .errors
+            .lock()
+            .await
+            .push(IoError::source(error, path));
+    }
+
+    /// Lost the source end, nothing more is coming so finish with the error
+    pub async fn fail(&self, error: String, path: PathBuf) {
+        self.error(error, path).await;
+        self.subsystem.give_up(self.uuid).await;
+    }
+
+    /// Wait for the writers to be done with everything
+    pub async fn finished(&self) {
+        self.subsystem.writer_done.wait().await
+    }
+
+    /// Stop writing and clean up whatever is still staged
+    pub async fn close(&self) {
+        // Clones share everything, its fine to shut one down
+        self.subsystem.clone().shutdown().await;
+        if let Err(e) = tokio::fs::remove_dir_all(&self.staging).await {
+            tracing::warn!(
+                "couldn't clean up staging dir {}: {}",
+                self.staging.display(),
+                e
+            );
+        }
+        // Don't care if there are other syncs still using it
+        if let Some(parent) = self.staging.parent() {
+            let _ = tokio::fs::remove_dir(parent).await;
+        }
+    }
+}
+
+/// The source end of a remote sync with a remote dest, on the daemon that
+/// owns the source. Only scans, the other end pulls file data from root.
+pub struct Scan {
+    uuid: u128,
+    root: PathBuf,
+    subsystem: IoSubsystem,
+}
+
+impl Scan {
+    /// Start scanning source, everything found comes out of the returned
+    /// channel. Subsystem should be fresh with whatever scan options.
+    pub async fn start(
+        mut subsystem: IoSubsystem,
+        uuid: u128,
+        source: PathBuf,
+    ) -> Result<
+        (Self, tokio::sync::mpsc::Receiver<WorkItem>),
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        // Here a missing source scans as nothing to do, from another machine
+        // its more likely a typo.
+        tokio::fs::symlink_metadata(&source)
+            .await
+            .map_err(|e| format!("can't scan {}: {}", source.display(), e))?;
+
+        // Nothing here writes, the Report is for a push
+        let (rx, _report) = subsystem.start_remote(uuid, source.clone(), None).await?;
+
+        // Files come from wherever the scan did
+        let root = match &*subsystem.source_snapshot.lock() {
+            Some(snapshot) => snapshot.path().to_path_buf(),
+            None => source,
+        };
+
+        Ok((
+            Self {
+                uuid,
+                root,
+                subsystem,
+            },
+            rx,
+        ))
+    }
+
+    /// Where a file from the scan is, path is its dest path
+    pub fn path(&self, path: &Path) -> std::io::Result<PathBuf> {
+        check_relative(path)?;
+        check_beneath(&self.root, path, true, &HashSet::new())?;
+        Ok(self.root.join(path))
+    }
+
+    pub fn found(&self) -> Found {
+        let progress = self.subsystem.get_atomic_progress(self.uuid);
+        Found {
+            dirs: progress.dirs_found.load(Ordering::Relaxed),
+            files: progress.files_found.load(Ordering::Relaxed),
+            size: progress.total_size.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Scan errors from since on, path and error
+    pub async fn errors(&self, since: usize) -> Vec<(PathBuf, String)> {
+        self.subsystem
+            .errors
+            .lock()
+            .await
+            .iter()
+            .skip(since)
+            .map(|e| (e.path.clone(), e.error.clone()))
+            .collect()
+    }
+
+    /// Stop scanning, lets go of the snapshot if there is one
+    pub async fn close(&self) {
+        self.subsystem.clone().shutdown().await;
+    }
+}
+
+/// A file copy's data on its way in
+pub struct Staged {
+    item: WorkItem,
+    path: PathBuf,
+    file: Arc<std::fs::File>,
+    written: u64,
+}
+
+impl Staged {
+    pub async fn write(&mut self, data: Vec<u8>) -> std::io::Result<()> {
+        use std::io::Write;
+
+        self.written += data.len() as u64;
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || (&*file).write_all(&data))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    /// All the data is in, the item now copies from the staged file. The
+    /// staged file gets the scanned mtime so the writer doesn't think the
+    /// source changed during the copy. Anything but the scanned size means
+    /// the sender gave up partway, a short file never gets written.
+    pub async fn finish(mut self) -> std::io::Result<WorkItem> {
+        let (WorkItem::CopySmallFile {
+            source_path,
+            metadata,
+            ..
+        }
+        | WorkItem::CopyLargeFile {
+            source_path,
+            metadata,
+            ..
+        }) = &mut self.item
+        else {
+            unreachable!("only file copies get staged");
+        };
+
+        if self.written != metadata.size {
+            let _ = tokio::fs::remove_file(&self.path).await;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("got {} of {} bytes", self.written, metadata.size),
+            ));
+        }
+
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(metadata.mtime);
+        let file = self.file;
+        tokio::task::spawn_blocking(move || file.set_modified(mtime))
+            .await
+            .map_err(std::io::Error::other)??;
+
+        *source_path = self.path;
+        Ok(self.item)
+    }
+}
+
+/// The sending end of a remote sync, mirrors the remote daemon's status into
+/// the local progress and errors.
+pub struct Report {
+    progress: Arc<AtomicOperationProgress>,
+    errors: Arc<Mutex<Vec<IoError>>>,
+    reader_done: Arc<super::done::Done>,
+    writer_done: Arc<super::done::Done>,
+    /// Remote errors we already have
+    seen: usize,
+}
+
+impl Report {
+    /// How many remote errors we already have, ask for ones after these
+    pub fn errors_seen(&self) -> usize {
+        self.seen
+    }
+
+    /// Take in a status from the remote end, returns if its done
+    pub async fn update(&mut self, status: Status) -> bool {
+        self.progress
+            .dirs_written
+            .fetch_max(status.dirs_written, Ordering::Relaxed);
+        self.progress
+            .files_written
+            .fetch_max(status.files_written, Ordering::Relaxed);
+        let bytes = self.progress.bytes_written.load(Ordering::Relaxed);
+        if status.bytes_written > bytes {
+            self.progress.record_write(status.bytes_written - bytes);
+        }
+
+        if !status.errors.is_empty() {
+            self.seen += status.errors.len();
+            let mut errors = self.errors.lock().await;
+            for (path, error) in status.errors {
+                errors.push(IoError::destination(error, path));
+            }
+        }
+
+        if status.done {
+            self.writer_done.set();
+        }
+        status.done
+    }
+
+    /// Something local went wrong sending path
+    pub async fn error(&self, error: String, path: PathBuf) {
+        tracing::error!("{}: {}", error, path.display());
+        self.errors.lock().await.push(IoError::source(error, path));
+    }
+
+    /// Couldn't talk to the other end at all, finish with the error instead
+    /// of waiting on it forever.
+    pub async fn fail(&self, error: String, dest: PathBuf) {
+        tracing::error!("{}: {}", error, dest.display());
+        self.errors
+            .lock()
+            .await
+            .push(IoError::destination(error, dest));
+        self.progress.dirs_found.fetch_max(1, Ordering::Relaxed);
+        self.reader_done.set();
+        self.writer_done.set();
+    }
+}
+
+impl IoSubsystem {
+    /// Scan source for a dest on another machine. Nothing gets written here,
+    /// everything the scan finds comes out of the returned channel for the
+    /// rpc side to ship (see rpc/transfer.rs), which tells us how its going
+    /// through the Report. num_writers only matters to local fan-out dests.
+    pub async fn start_remote(
+        &mut self,
+        uuid: u128,
+        source: PathBuf,
+        num_writers: Option<usize>,
+    ) -> Result<
+        (tokio::sync::mpsc::Receiver<WorkItem>, Report),
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        *self.uuid.lock() = Some(uuid);
+
+        let source = match self.snapshot.clone() {
+            Some(provider) => self.start_snapshot(uuid, provider, source).await?,
+            None => source,
+        };
+
+        let (work_tx, work_rx) = tokio::sync::mpsc::channel(self.budget.channel_capacity());
+        let report = self.report(uuid);
+
+        // Fan-out dests get their copy of the scan same as off a local lead
+        let work_tx = if self.fan_out.is_empty() {
+            work_tx
+        } else {
+            self.start_fan_out(uuid, work_tx, num_writers).await
+        };
+
+        self.start_reading(uuid, source, work_tx).await?;
+        Ok((work_rx, report))
+    }
+
+    /// Fan-out leg for a dest on another machine. Whatever the lead's scan
+    /// hands this leg comes out the returned channel for the rpc side to ship
+    /// just like start_remote(), the lead does the scanning.
+    pub fn send_to_remote(&self, uuid: u128) -> (tokio::sync::mpsc::Receiver<WorkItem>, Report) {
+        *self.uuid.lock() = Some(uuid);
+
+        let (work_tx, work_rx) = tokio::sync::mpsc::channel(self.budget.channel_capacity());
+        *self.sending.lock() = Some(work_tx);
+        (work_rx, self.report(uuid))
+    }
+
+    fn report(&self, uuid: u128) -> Report {
+        Report {
+            progress: self.progress.get_or_create(uuid),
+            errors: self.errors.clone(),
+            reader_done: self.reader_done.clone(),
+            writer_done: self.writer_done.clone(),
+            seen: 0,
+        }
+    }
+
+    // The other end of start_remote(), work comes from a daemon scanning
+    // somewhere else. Files in it are staged copies, ours to remove.
+    async fn start_receiving(
+        &mut self,
+        uuid: u128,
+        dest: PathBuf,
+        num_writers: Option<usize>,
+    ) -> Result<tokio::sync::mpsc::Sender<WorkItem>, Box<dyn std::error::Error + Send + Sync>> {
+        self.staged = true;
+        self.start_writing(uuid, dest, num_writers).await
+    }
+}
+
+fn is_file(item: &WorkItem) -> bool {
+    matches!(
+        item,
+        WorkItem::CopySmallFile { .. } | WorkItem::CopyLargeFile { .. }
+    )
+}
+
+// Paths come from another machine, nothing gets to write outside the dest
+fn check_contained(item: &WorkItem) -> std::io::Result<()> {
+    for path in paths(item) {
+        check_relative(path)?;
+    }
+    Ok(())
+}
+
+fn paths(item: &WorkItem) -> impl Iterator<Item = &Path> {
+    let from = match item {
+        WorkItem::Rename { from, .. } => Some(from.as_path()),
+        _ => None,
+    };
+    item.dest_path().into_iter().chain(from)
+}
+
+// No dir on the way to path under root can be a symlink, on disk already or
+// in links, nor path itself if its going to be followed.
+fn check_beneath(
+    root: &Path,
+    path: &Path,
+    itself: bool,
+    links: &HashSet<PathBuf>,
+) -> std::io::Result<()> {
+    for parent in path.ancestors().skip(if itself { 0 } else { 1 }) {
+        if parent.as_os_str().is_empty() {
+            break;
+        }
+        let linked = links.contains(parent)
+            || std::fs::symlink_metadata(root.join(parent))
+                .is_ok_and(|meta| meta.file_type().is_symlink());
+        if linked {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "{} goes through symlink {}",
+                    path.display(),
+                    parent.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_relative(path: &Path) -> std::io::Result<()> {
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} isn't relative to the sync root", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Where other daemons are allowed to sync to or from through us.
+///
+/// The daemon's Transfer service has no auth of its own, anyone who can get
+/// to the port is someone we'd read or write files for. So the daemon only
+/// does that under whatever its owner exported, and nothing by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exports {
+    /// Whoever is on the other end already is us, aka an agent over ssh
+    All,
+    /// Only under these, none is nothing at all
+    Roots(Vec<PathBuf>),
+}
+
+impl Default for Exports {
+    fn default() -> Self {
+        Self::Roots(vec![])
+    }
+}
+
+impl Exports {
+    /// Whoever is asking can do anything we could, aka run commands
+    pub fn trusted(&self) -> bool {
+        matches!(self, Self::All)
+    }
+}
+
+/// Whether a path another daemon sent us is somewhere we'd sync to or from,
+/// for the rpc side to check before anything gets started there.
+pub fn check_root(root: &Path, exports: &Exports) -> std::io::Result<()> {
+    if !root.is_absolute() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("remote path {} has to be absolute", root.display()),
+        ));
+    }
+
+    // No /export/../etc games, starts_with() is only by component
+    if root.components().any(|c| matches!(c, Component::ParentDir)) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("remote path {} can't have .. in it", root.display()),
+        ));
+    }
+
+    // TODO: a symlink under an export pointing out of it still gets followed,
+    // whoever exported the dir put it there so I'm calling that on purpose.
+    match exports {
+        Exports::All => Ok(()),
+        Exports::Roots(roots) if roots.iter().any(|r| root.starts_with(r)) => Ok(()),
+        Exports::Roots(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} isn't under anything exported", root.display()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::metadata::{DirMetadata, FileKind, FileMetadata};
+    use crate::testdir::TestDir;
+
+    fn file(dest_path: &str, size: u64) -> WorkItem {
+        WorkItem::CopySmallFile {
+            uuid: 0,
+            source_path: PathBuf::from("/elsewhere").join(dest_path),
+            dest_path: PathBuf::from(dest_path),
+            metadata: FileMetadata {
+                path: PathBuf::from("/elsewhere").join(dest_path),
+                size,
+                #[cfg(unix)]
+                mode: 0o640,
+                #[cfg(unix)]
+                uid: 0,
+                #[cfg(unix)]
+                gid: 0,
+                kind: FileKind::File,
+                mtime: 1_700_000_000,
+            },
+        }
+    }
+
+    #[test]
+    fn test_contained() {
+        assert!(check_contained(&file("a/b", 1)).is_ok());
+        assert!(check_contained(&file("../b", 1)).is_err());
+        assert!(check_contained(&file("/etc/passwd", 1)).is_err());
+        assert!(
+            check_contained(&WorkItem::Rename {
+                uuid: 0,
+                from: PathBuf::from("a/../../x"),
+                dest_path: PathBuf::from("b"),
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_check_root() {
+        assert!(check_root(Path::new("relative"), &Exports::All).is_err());
+        assert!(check_root(Path::new("/etc"), &Exports::All).is_ok());
+        assert!(check_root(Path::new("/etc"), &Exports::default()).is_err());
+
+        let exports = Exports::Roots(vec![PathBuf::from("/srv/data")]);
+        assert!(check_root(Path::new("/srv/data"), &exports).is_ok());
+        assert!(check_root(Path::new("/srv/data/a/b"), &exports).is_ok());
+        assert!(check_root(Path::new("/srv/database"), &exports).is_err());
+        assert!(check_root(Path::new("/srv/data/../../etc"), &exports).is_err());
+        assert!(check_root(Path::new("/srv"), &exports).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_receive() {
+        let dir = TestDir::new("remote-receive");
+        let dest = dir.join("dest");
+
+        let uuid = 7;
+        let receiver = Receiver::start(IoSubsystem::new(), uuid, dest.clone(), Some(2))
+            .await
+            .unwrap();
+
+        receiver
+            .put(
+                WorkItem::CreateDir {
+                    uuid,
+                    source_path: PathBuf::from("/elsewhere/sub"),
+                    dest_path: PathBuf::from("sub"),
+                    metadata: DirMetadata {
+                        path: PathBuf::from("/elsewhere/sub"),
+                        #[cfg(unix)]
+                        mode: 0o755,
+                        #[cfg(unix)]
+                        uid: 0,
+                        #[cfg(unix)]
+                        gid: 0,
+                    },
+                },
+                vec![],
+            )
+            .await
+            .unwrap();
+        receiver
+            .put(file("small", 5), b"small".to_vec())
+            .await
+            .unwrap();
+
+        // Chunked, scan came in before the file did
+        receiver
+            .send(WorkItem::DirectoryScanned {
+                uuid,
+                dest_path: PathBuf::from(""),
+            })
+            .await
+            .unwrap();
+        let mut staged = receiver.stage(file("sub/big", 6)).await.unwrap();
+        staged.write(b"big".to_vec()).await.unwrap();
+        staged.write(b"big".to_vec()).await.unwrap();
+        receiver.send(staged.finish().await.unwrap()).await.unwrap();
+        receiver
+            .send(WorkItem::DirectoryScanned {
+                uuid,
+                dest_path: PathBuf::from("sub"),
+            })
+            .await
+            .unwrap();
+
+        // Nothing escapes the dest
+        assert!(
+            receiver
+                .put(file("../out", 1), b"x".to_vec())
+                .await
+                .is_err()
+        );
+
+        // Sender gave up partway, nothing half there gets written
+        let mut staged = receiver.stage(file("short", 10)).await.unwrap();
+        staged.write(b"sho".to_vec()).await.unwrap();
+        assert!(staged.finish().await.is_err());
+
+        receiver
+            .send(WorkItem::ScanComplete { uuid })
+            .await
+            .unwrap();
+
+        let mut status = receiver.status(0).await;
+        for _ in 0..500 {
+            if status.done {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            status = receiver.status(0).await;
+        }
+        assert!(status.done, "{:?}", status);
+        assert_eq!(status.files_written, 2);
+        assert!(status.errors.is_empty(), "{:?}", status.errors);
+
+        assert_eq!(std::fs::read(dest.join("small")).unwrap(), b"small");
+        assert_eq!(std::fs::read(dest.join("sub/big")).unwrap(), b"bigbig");
+        assert!(!dest.join("short").exists());
+
+        // Staged copies are gone as soon as they're in place
+        let staging = dest.join(STAGING_DIR);
+        assert_eq!(
+            std::fs::read_dir(staging.join(uuid::Uuid::from_u128(uuid).to_string()))
+                .unwrap()
+                .count(),
+            0
+        );
+        receiver.close().await;
+        assert!(!staging.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_receive_refuses_symlinks() {
+        use crate::io::metadata::SymlinkMetadata;
+
+        let dir = TestDir::new("remote-links");
+        let dest = dir.join("dest");
+        let outside = dir.join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+
+        let uuid = 9;
+        let receiver = Receiver::start(IoSubsystem::new(), uuid, dest.clone(), Some(1))
+            .await
+            .unwrap();
+
+        // One the peer sends, might not even be written yet
+        let link = |dest_path: &str| WorkItem::CreateSymlink {
+            uuid,
+            source_path: PathBuf::from("/elsewhere").join(dest_path),
+            dest_path: PathBuf::from(dest_path),
+            metadata: SymlinkMetadata {
+                path: PathBuf::from("/elsewhere").join(dest_path),
+                target: PathBuf::from("/etc"),
+                mode: 0o777,
+                uid: 0,
+                gid: 0,
+            },
+        };
+        receiver.send(link("a")).await.unwrap();
+        assert!(
+            receiver
+                .put(file("a/passwd", 1), b"x".to_vec())
+                .await
+                .is_err()
+        );
+        assert!(receiver.put(file("a", 1), b"x".to_vec()).await.is_err());
+        assert!(receiver.send(link("a/b")).await.is_err());
+
+        // Moving it doesn't lose track of it
+        receiver
+            .send(WorkItem::Rename {
+                uuid,
+                from: PathBuf::from("a"),
+                dest_path: PathBuf::from("c"),
+            })
+            .await
+            .unwrap();
+        assert!(
+            receiver
+                .put(file("c/passwd", 1), b"x".to_vec())
+                .await
+                .is_err()
+        );
+
+        // One already on the dest from before
+        std::os::unix::fs::symlink(&outside, dest.join("old")).unwrap();
+        assert!(receiver.put(file("old/x", 1), b"x".to_vec()).await.is_err());
+        assert!(
+            receiver
+                .send(WorkItem::Remove {
+                    uuid,
+                    dest_path: PathBuf::from("old/x"),
+                    kind: FileKind::File,
+                })
+                .await
+                .is_err()
+        );
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+
+        // Everything else still goes
+        receiver.put(file("fine", 1), b"x".to_vec()).await.unwrap();
+
+        receiver.close().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scan() {
+        let dir = TestDir::new("remote-scan");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/file"), "file").unwrap();
+
+        let uuid = 8;
+        let (scan, mut rx) = Scan::start(IoSubsystem::new(), uuid, dir.to_path_buf())
+            .await
+            .unwrap();
+
+        let mut files = Vec::new();
+        while let Some(item) = rx.recv().await {
+            match item {
+                WorkItem::ScanComplete { .. } => break,
+                WorkItem::CopySmallFile { dest_path, .. } => files.push(dest_path),
+                _ => (),
+            }
+        }
+        assert_eq!(files, vec![PathBuf::from("sub/file")]);
+        assert_eq!(scan.found().files, 1);
+        assert_eq!(scan.found().size, 4);
+        assert!(scan.errors(0).await.is_empty());
+
+        // Only what's under the source
+        assert_eq!(scan.path(&files[0]).unwrap(), dir.join("sub/file"));
+        assert!(scan.path(Path::new("../elsewhere")).is_err());
+        assert!(scan.path(Path::new("/etc/passwd")).is_err());
+
+        scan.close().await;
+
+        assert!(
+            Scan::start(IoSubsystem::new(), uuid, dir.join("missing"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fan_out_to_remote() {
+        let dir = TestDir::new("remote-fanout");
+        let source = dir.join("source");
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        std::fs::write(source.join("sub/file"), "file").unwrap();
+
+        let leg = IoSubsystem::new();
+        let (mut rx, _report) = leg.send_to_remote(2);
+        let mut lead =
+            IoSubsystem::new().with_fan_out(vec![(2, PathBuf::from("/elsewhere"), leg.clone())]);
+
+        lead.start(1, source, dir.join("dest"), Some(1))
+            .await
+            .unwrap();
+
+        let mut files = Vec::new();
+        let recv = async {
+            while let Some(item) = rx.recv().await {
+                assert_eq!(item.uuid(), 2);
+                match item {
+                    WorkItem::ScanComplete { .. } => break,
+                    WorkItem::CopySmallFile { dest_path, .. } => files.push(dest_path),
+                    _ => (),
+                }
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(10), recv)
+            .await
+            .unwrap();
+        assert_eq!(files, vec![PathBuf::from("sub/file")]);
+        assert_eq!(
+            leg.progress
+                .get_or_create(2)
+                .files_found
+                .load(Ordering::Relaxed),
+            1
+        );
+
+        // Lead still writes its own dest
+        for _ in 0..500 {
+            if lead.is_complete(1).await {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(std::fs::read(dir.join("dest/sub/file")).unwrap(), b"file");
+    }
+}
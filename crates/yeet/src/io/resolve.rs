@@ -0,0 +1,320 @@
+use std::path::{Path, PathBuf};
+
+use super::metadata::FileKind;
+use super::reconcile::{Observed, Side};
+use super::trash::{Trash, TrashConfig};
+use super::twoway::{observe, tmp_path};
+
+// Sorting out a two-way conflict, aka the user told us which side wins.
+//
+// No .conflict turd files get left around, resolving just makes both sides
+// the same at that path right now. The next two-way run sees both sides agree
+// (converged or gone on both) and records that in the baseline, so there's no
+// need to touch the baseline from here and fight with a run in progress.
+//
+// Whatever loses goes to that side's trash first, dir and all. Picking a side
+// is throwing the other away on purpose, but on purpose isn't the same as
+// sure, so this happens whether or not the sync has a trash set up.
+
+/// Which side of a conflict to keep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keep {
+    Lhs,
+    Rhs,
+    /// Keep both, each side's version gets renamed to name.lhs/name.rhs
+    /// (before any extension) and both end up on both sides.
+    BothRenamed,
+    /// Whichever side was modified last, deletes lose as we can't tell when
+    /// they happened.
+    Newest,
+}
+
+impl std::str::FromStr for Keep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lhs" => Ok(Keep::Lhs),
+            "rhs" => Ok(Keep::Rhs),
+            "both-renamed" => Ok(Keep::BothRenamed),
+            "newest" => Ok(Keep::Newest),
+            _ => Err(format!(
+                "unknown keep '{}', expected lhs, rhs, both-renamed or newest",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Keep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Keep::Lhs => write!(f, "lhs"),
+            Keep::Rhs => write!(f, "rhs"),
+            Keep::BothRenamed => write!(f, "both-renamed"),
+            Keep::Newest => write!(f, "newest"),
+        }
+    }
+}
+
+/// Make both roots agree on path, relative to both, per keep. Returns what
+/// was done for telling the user.
+pub fn resolve(
+    lhs: &Path,
+    rhs: &Path,
+    path: &Path,
+    keep: Keep,
+    trash: &TrashConfig,
+) -> std::io::Result<String> {
+    let root = |side| match side {
+        Side::Lhs => lhs,
+        Side::Rhs => rhs,
+    };
+
+    // Losing side's version of relative goes to its trash unless the winner
+    // is a dir too, dirs only get their mode changed.
+    let lose = |side: Side, relative: &Path, winner: Option<FileKind>| -> std::io::Result<()> {
+        let loser = observe(&root(side).join(relative))?;
+        if loser
+            .is_none_or(|l| l.kind() == FileKind::Directory && winner == Some(FileKind::Directory))
+        {
+            return Ok(());
+        }
+        Trash::new(root(side).to_path_buf(), &trash.side(side)).keep_tree(relative)?;
+        Ok(())
+    };
+
+    let winner = match keep {
+        Keep::Lhs => Side::Lhs,
+        Keep::Rhs => Side::Rhs,
+        Keep::Newest => newest(&lhs.join(path), &rhs.join(path))?,
+        Keep::BothRenamed => {
+            let mut kept = Vec::new();
+            for side in [Side::Lhs, Side::Rhs] {
+                let from = root(side).join(path);
+                if observe(&from)?.is_none() {
+                    continue;
+                }
+                let renamed = renamed(path, side);
+                if observe(&root(side).join(&renamed))?.is_some() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        format!("{} already exists", root(side).join(&renamed).display()),
+                    ));
+                }
+                std::fs::rename(&from, root(side).join(&renamed))?;
+                let kind = observe(&root(side).join(&renamed))?.map(|o| o.kind());
+                lose(side.other(), &renamed, kind)?;
+                mirror(
+                    &root(side).join(&renamed),
+                    &root(side.other()).join(&renamed),
+                )?;
+                kept.push(renamed.display().to_string());
+            }
+            return Ok(format!("kept both as {}", kept.join(" and ")));
+        }
+    };
+
+    let kind = observe(&root(winner).join(path))?.map(|o| o.kind());
+    lose(winner.other(), path, kind)?;
+    mirror(&root(winner).join(path), &root(winner.other()).join(path))?;
+    Ok(format!("kept {}", winner))
+}
+
+// lhs wins ties, have to pick something
+fn newest(lhs: &Path, rhs: &Path) -> std::io::Result<Side> {
+    Ok(match (observe(lhs)?, observe(rhs)?) {
+        (Some(l), Some(r)) if r.metadata.mtime > l.metadata.mtime => Side::Rhs,
+        (None, Some(_)) => Side::Rhs,
+        _ => Side::Lhs,
+    })
+}
+
+// foo.txt -> foo.lhs.txt, foo -> foo.lhs
+fn renamed(path: &Path, side: Side) -> PathBuf {
+    let name = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => format!(
+            "{}.{}.{}",
+            stem.to_string_lossy(),
+            side,
+            ext.to_string_lossy()
+        ),
+        _ => format!(
+            "{}.{}",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            side
+        ),
+    };
+    path.with_file_name(name)
+}
+
+// Make to look like from, including making it go away if from isn't there.
+// Dirs only get created, whatever is in them was held back by the conflict
+// and the next run sorts it out.
+fn mirror(from: &Path, to: &Path) -> std::io::Result<()> {
+    let Some(source) = observe(from)? else {
+        return remove(to, observe(to)?.as_ref());
+    };
+    let existing = observe(to)?;
+
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    match source.kind() {
+        FileKind::Directory => {
+            if existing
+                .as_ref()
+                .is_some_and(|e| e.kind() != FileKind::Directory)
+            {
+                std::fs::remove_file(to)?;
+            }
+            std::fs::create_dir_all(to)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(
+                    to,
+                    std::fs::Permissions::from_mode(source.metadata.mode),
+                )?;
+            }
+            Ok(())
+        }
+        FileKind::Symlink => {
+            let tmp = tmp_path(to);
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(source.target.clone().unwrap_or_default(), &tmp)?;
+            #[cfg(not(unix))]
+            return Err(std::io::Error::other("symlinks not supported here"));
+            #[allow(unreachable_code)]
+            replace(&tmp, to, existing.as_ref())
+        }
+        FileKind::File => {
+            let tmp = tmp_path(to);
+            let result =
+                copy(from, &tmp, &source).and_then(|()| replace(&tmp, to, existing.as_ref()));
+            if result.is_err() {
+                let _ = std::fs::remove_file(&tmp);
+            }
+            result
+        }
+        FileKind::Special | FileKind::Unknown => Err(std::io::Error::other(format!(
+            "can't sync special file {}",
+            from.display()
+        ))),
+    }
+}
+
+fn copy(from: &Path, tmp: &Path, source: &Observed) -> std::io::Result<()> {
+    std::fs::copy(from, tmp)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(tmp, std::fs::Permissions::from_mode(source.metadata.mode))?;
+    }
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(source.metadata.mtime);
+    std::fs::File::options()
+        .write(true)
+        .open(tmp)?
+        .set_modified(mtime)
+}
+
+fn replace(tmp: &Path, to: &Path, existing: Option<&Observed>) -> std::io::Result<()> {
+    if existing.is_some_and(|e| e.kind() == FileKind::Directory) {
+        std::fs::remove_dir_all(to)?;
+    }
+    std::fs::rename(tmp, to)
+}
+
+// The user said this side loses, so unlike two-way removals a dir goes with
+// everything in it.
+fn remove(path: &Path, existing: Option<&Observed>) -> std::io::Result<()> {
+    match existing.map(|e| e.kind()) {
+        None => Ok(()),
+        Some(FileKind::Directory) => std::fs::remove_dir_all(path),
+        Some(_) => std::fs::remove_file(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    fn dir(name: &str) -> (TestDir, PathBuf, PathBuf) {
+        let dir = TestDir::new(&format!("resolve-{}", name));
+        let (lhs, rhs) = (dir.join("lhs"), dir.join("rhs"));
+        std::fs::create_dir_all(&lhs).unwrap();
+        std::fs::create_dir_all(&rhs).unwrap();
+        (dir, lhs, rhs)
+    }
+
+    fn read(path: PathBuf) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn test_keep_a_side() {
+        let (_dir, l, r) = dir("side");
+        std::fs::write(l.join("f"), "lhs").unwrap();
+        std::fs::write(r.join("f"), "rhs").unwrap();
+
+        resolve(&l, &r, Path::new("f"), Keep::Rhs, &TrashConfig::default()).unwrap();
+        assert_eq!(read(l.join("f")).as_deref(), Some("rhs"));
+        assert_eq!(read(r.join("f")).as_deref(), Some("rhs"));
+
+        // Losers are kept
+        let trash = |root: &Path| root.join(super::super::trash::TRASH_DIR);
+        let lost = super::super::trash::versions(&trash(&l), Path::new("f")).unwrap();
+        assert_eq!(read(lost[0].path.clone()).as_deref(), Some("lhs"));
+
+        // Keeping a delete deletes
+        std::fs::remove_file(l.join("f")).unwrap();
+        resolve(&l, &r, Path::new("f"), Keep::Lhs, &TrashConfig::default()).unwrap();
+        assert!(!r.join("f").exists());
+        assert_eq!(
+            super::super::trash::versions(&trash(&r), Path::new("f"))
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Newest keeps whatever survived a delete
+        std::fs::write(r.join("g"), "rhs").unwrap();
+        assert_eq!(
+            resolve(
+                &l,
+                &r,
+                Path::new("g"),
+                Keep::Newest,
+                &TrashConfig::default()
+            )
+            .unwrap(),
+            "kept rhs"
+        );
+        assert_eq!(read(l.join("g")).as_deref(), Some("rhs"));
+    }
+
+    #[test]
+    fn test_both_renamed() {
+        let (_dir, l, r) = dir("both");
+        std::fs::create_dir(l.join("sub")).unwrap();
+        std::fs::create_dir(r.join("sub")).unwrap();
+        std::fs::write(l.join("sub/f.txt"), "lhs").unwrap();
+        std::fs::write(r.join("sub/f.txt"), "rhs").unwrap();
+
+        resolve(
+            &l,
+            &r,
+            Path::new("sub/f.txt"),
+            Keep::BothRenamed,
+            &TrashConfig::default(),
+        )
+        .unwrap();
+        for root in [&l, &r] {
+            assert!(!root.join("sub/f.txt").exists());
+            assert_eq!(read(root.join("sub/f.lhs.txt")).as_deref(), Some("lhs"));
+            assert_eq!(read(root.join("sub/f.rhs.txt")).as_deref(), Some("rhs"));
+        }
+    }
+}
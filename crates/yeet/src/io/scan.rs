@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+
+use parking_lot::{Condvar, Mutex};
+
+// Directory frontier for the parallel scanner.
+//
+// Scanner threads pull a directory off the frontier, list it, push any
+// subdirectories they found back on and then say they're done with it. The
+// scan is over once nothing is pending and nobody is in the middle of a
+// directory, as an in flight directory might still push more work.
+//
+// BFS pulls from the front so we sweep the tree a level at a time, DFS pulls
+// from the back so whatever was found last gets scanned next and we head
+// straight for the leaves. With more than one scanner the order is only
+// approximate, but its still the general shape of things.
+
+/// Never spin up more scanners than this by default, past this point getdents
+/// isn't the bottleneck anymore something else is.
+pub const MAX_DEFAULT_SCANNERS: usize = 8;
+
+/// Default number of scanner threads, one per core up to the max.
+pub fn default_scanners() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(MAX_DEFAULT_SCANNERS)
+}
+
+/// Order directories get scanned in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScanOrder {
+    /// Breadth first, a level at a time
+    #[default]
+    Bfs,
+    /// Depth first, leaf branches get done first
+    Dfs,
+}
+
+impl std::str::FromStr for ScanOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bfs" | "breadth" | "breadth-first" => Ok(ScanOrder::Bfs),
+            "dfs" | "depth" | "depth-first" => Ok(ScanOrder::Dfs),
+            _ => Err(format!("unknown scan order '{}', expected bfs or dfs", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for ScanOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanOrder::Bfs => write!(f, "bfs"),
+            ScanOrder::Dfs => write!(f, "dfs"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State<T> {
+    pending: VecDeque<T>,
+    in_flight: usize,
+    closed: bool,
+}
+
+/// Work that scanner threads share, see the top of the file.
+#[derive(Debug)]
+pub struct Frontier<T> {
+    order: ScanOrder,
+    state: Mutex<State<T>>,
+    cond: Condvar,
+}
+
+impl<T> Frontier<T> {
+    pub fn new(order: ScanOrder, root: T) -> Self {
+        Self {
+            order,
+            state: Mutex::new(State {
+                pending: VecDeque::from([root]),
+                in_flight: 0,
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Add subdirectories found while scanning, in the order they were found.
+    pub fn push_all(&self, items: Vec<T>) {
+        if items.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.lock();
+        match self.order {
+            ScanOrder::Bfs => state.pending.extend(items),
+            // Reversed so the first one found is the first one popped
+            ScanOrder::Dfs => state.pending.extend(items.into_iter().rev()),
+        }
+        drop(state);
+        self.cond.notify_all();
+    }
+
+    /// Next directory to scan, blocks until there is one or the scan is over.
+    /// Every Some has to be followed by a call to done().
+    pub fn next(&self) -> Option<T> {
+        let mut state = self.state.lock();
+        loop {
+            if state.closed {
+                return None;
+            }
+
+            let item = match self.order {
+                ScanOrder::Bfs => state.pending.pop_front(),
+                ScanOrder::Dfs => state.pending.pop_back(),
+            };
+
+            if let Some(item) = item {
+                state.in_flight += 1;
+                return Some(item);
+            }
+
+            if state.in_flight == 0 {
+                return None;
+            }
+
+            self.cond.wait(&mut state);
+        }
+    }
+
+    /// Finished with something next() handed out.
+    pub fn done(&self) {
+        let mut state = self.state.lock();
+        state.in_flight -= 1;
+        let finished = state.in_flight == 0 && state.pending.is_empty();
+        drop(state);
+
+        if finished {
+            self.cond.notify_all();
+        }
+    }
+
+    /// Give up, everyone waiting in next() gets None.
+    pub fn close(&self) {
+        self.state.lock().closed = true;
+        self.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Fake tree, node n has children 2n+1 and 2n+2 up to max
+    fn children(n: usize, max: usize) -> Vec<usize> {
+        [2 * n + 1, 2 * n + 2]
+            .into_iter()
+            .filter(|c| *c < max)
+            .collect()
+    }
+
+    fn walk(order: ScanOrder) -> Vec<usize> {
+        let frontier = Frontier::new(order, 0usize);
+        let mut seen = Vec::new();
+        while let Some(n) = frontier.next() {
+            seen.push(n);
+            frontier.push_all(children(n, 7));
+            frontier.done();
+        }
+        seen
+    }
+
+    #[test]
+    fn test_bfs_order() {
+        assert_eq!(walk(ScanOrder::Bfs), vec![0, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_dfs_order() {
+        assert_eq!(walk(ScanOrder::Dfs), vec![0, 1, 3, 4, 2, 5, 6]);
+    }
+
+    #[test]
+    fn test_parallel_scan_visits_everything_once() {
+        const NODES: usize = 10_000;
+        let frontier = Frontier::new(ScanOrder::Bfs, 0usize);
+        let visited = AtomicUsize::new(0);
+
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    while let Some(n) = frontier.next() {
+                        visited.fetch_add(1, Ordering::Relaxed);
+                        frontier.push_all(children(n, NODES));
+                        frontier.done();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(visited.load(Ordering::Relaxed), NODES);
+    }
+
+    #[test]
+    fn test_close_stops_scanners() {
+        let frontier = Frontier::new(ScanOrder::Dfs, 0usize);
+        assert_eq!(frontier.next(), Some(0));
+        frontier.close();
+        assert_eq!(frontier.next(), None);
+    }
+
+    #[test]
+    fn test_parse_order() {
+        assert_eq!("DFS".parse::<ScanOrder>(), Ok(ScanOrder::Dfs));
+        assert_eq!("breadth-first".parse::<ScanOrder>(), Ok(ScanOrder::Bfs));
+        assert!("sideways".parse::<ScanOrder>().is_err());
+    }
+}
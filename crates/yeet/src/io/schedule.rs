@@ -0,0 +1,284 @@
+use std::path::Path;
+use std::time::Duration;
+
+use super::work::WorkItem;
+
+// Scheduling policies for the tree work queue.
+//
+// A policy turns a ready file work item into a sort key, the queue always pops
+// the smallest key. Keys are ordered by:
+//
+// 1. Files a watched sync saw change before the initial scan's backlog, the
+//    thing someone just saved shouldn't wait behind a 2TiB tree
+// 2. Path priority, index of the first matching glob (unmatched go last)
+// 3. Normal files before bulk files
+// 4. The order value, size for smallest first, inverted mtime for newest first
+// 5. Arrival order so ties are FIFO
+//
+// On top of that aging promotes anything that has been sitting around longer
+// than the aging window straight to the front, so a never ending stream of
+// small/new/high priority crap can't starve bulk files forever.
+
+/// Default aging window, long enough to not undo the policy for normal trees
+/// short enough that bulk stuff still moves.
+pub const DEFAULT_AGING: Duration = Duration::from_secs(30);
+
+/// Default number of files popped per directory in a batch
+pub const DEFAULT_FILES_PER_DIR: usize = 4;
+
+/// Order within a priority class
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Order {
+    /// First in first out, what the reader found first goes first
+    #[default]
+    Fifo,
+    /// Smallest files first
+    SmallestFirst,
+    /// Most recently modified files first
+    NewestFirst,
+}
+
+impl std::str::FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fifo" => Ok(Order::Fifo),
+            "smallest" | "smallest-first" => Ok(Order::SmallestFirst),
+            "newest" | "newest-first" => Ok(Order::NewestFirst),
+            _ => Err(format!(
+                "unknown schedule '{}', expected one of fifo, smallest-first, newest-first",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Order {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Order::Fifo => write!(f, "fifo"),
+            Order::SmallestFirst => write!(f, "smallest-first"),
+            Order::NewestFirst => write!(f, "newest-first"),
+        }
+    }
+}
+
+/// Minimal glob for relative dest paths. Supports `*` and `?` within a path
+/// component and `**` for any number of components, thats it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    pattern: String,
+    segments: Vec<String>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let trimmed = pattern.trim().trim_start_matches("./").trim_matches('/');
+        if trimmed.is_empty() {
+            return Err(format!("empty glob pattern '{}'", pattern));
+        }
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            segments: trimmed.split('/').map(|s| s.to_string()).collect(),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let parts: Vec<&str> = path
+            .components()
+            .filter_map(|c| match c {
+                std::path::Component::Normal(s) => s.to_str(),
+                _ => None,
+            })
+            .collect();
+        let segments: Vec<&str> = self.segments.iter().map(|s| s.as_str()).collect();
+
+        match_segments(&segments, &parts)
+    }
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((part, path_rest)) => {
+                match_component(first.as_bytes(), part.as_bytes())
+                    && match_segments(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+fn match_component(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_component(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_component(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_component(rest, &name[1..]),
+    }
+}
+
+/// How a sync wants its ready files ordered
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchedulePolicy {
+    pub order: Order,
+
+    /// Earlier globs win, anything not matching goes after all of them
+    pub priorities: Vec<Glob>,
+
+    /// Anything waiting longer than this jumps the queue, None turns aging off
+    pub aging: Option<Duration>,
+
+    /// Files to pop for every directory in a batch
+    pub files_per_dir: usize,
+}
+
+impl Default for SchedulePolicy {
+    fn default() -> Self {
+        Self {
+            order: Order::default(),
+            priorities: Vec::new(),
+            aging: Some(DEFAULT_AGING),
+            files_per_dir: DEFAULT_FILES_PER_DIR,
+        }
+    }
+}
+
+/// Sort key for a ready file, smallest pops first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ScheduleKey {
+    backlog: bool,
+    rank: usize,
+    bulk: bool,
+    value: u64,
+    seq: u64,
+}
+
+impl SchedulePolicy {
+    /// Build a policy from request time strings, the rpc/cli side of things.
+    pub fn parse(
+        order: Option<&str>,
+        priorities: &[String],
+        aging_secs: Option<u64>,
+    ) -> Result<Self, String> {
+        let order = match order {
+            Some(o) => o.parse()?,
+            None => Order::default(),
+        };
+
+        let priorities = priorities
+            .iter()
+            .map(|p| Glob::new(p))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // 0 is off, not "everything is always aged"
+        let aging = match aging_secs {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => Some(DEFAULT_AGING),
+        };
+
+        Ok(Self {
+            order,
+            priorities,
+            aging,
+            files_per_dir: DEFAULT_FILES_PER_DIR,
+        })
+    }
+
+    fn rank(&self, path: Option<&Path>) -> usize {
+        path.and_then(|p| self.priorities.iter().position(|g| g.matches(p)))
+            .unwrap_or(self.priorities.len())
+    }
+
+    /// Key for a ready (non directory) work item, seq is the arrival order.
+    pub fn key(&self, item: &WorkItem, seq: u64) -> ScheduleKey {
+        let (size, mtime) = match item {
+            WorkItem::CopySmallFile { metadata, .. }
+            | WorkItem::CopyLargeFile { metadata, .. }
+            | WorkItem::ApplyMetadata { metadata, .. } => (metadata.size, metadata.mtime),
+            _ => (0, 0),
+        };
+
+        let value = match self.order {
+            Order::Fifo => 0,
+            Order::SmallestFirst => size,
+            Order::NewestFirst => u64::MAX - mtime,
+        };
+
+        ScheduleKey {
+            backlog: true,
+            rank: self.rank(item.dest_path()),
+            bulk: item.is_bulk(),
+            value,
+            seq,
+        }
+    }
+}
+
+impl ScheduleKey {
+    /// Move ahead of everything from the initial scan, for watched changes
+    pub fn touched(mut self) -> Self {
+        self.backlog = false;
+        self
+    }
+}
+
+impl std::fmt::Display for SchedulePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.order)?;
+        if !self.priorities.is_empty() {
+            let globs: Vec<&str> = self.priorities.iter().map(|g| g.as_str()).collect();
+            write!(f, " priority: {}", globs.join(","))?;
+        }
+        match self.aging {
+            Some(aging) => write!(f, " aging: {}s", aging.as_secs()),
+            None => write!(f, " aging: off"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matching() {
+        let src = Glob::new("src/**").unwrap();
+        assert!(src.matches(Path::new("src/main.rs")));
+        assert!(src.matches(Path::new("src/a/b/c.rs")));
+        assert!(!src.matches(Path::new("assets/src/x")));
+
+        let rs = Glob::new("**/*.rs").unwrap();
+        assert!(rs.matches(Path::new("main.rs")));
+        assert!(rs.matches(Path::new("a/b/lib.rs")));
+        assert!(!rs.matches(Path::new("a/b/lib.rsx")));
+
+        let q = Glob::new("img?.png").unwrap();
+        assert!(q.matches(Path::new("img1.png")));
+        assert!(!q.matches(Path::new("img10.png")));
+
+        assert!(Glob::new("/").is_err());
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        let p = SchedulePolicy::parse(Some("smallest"), &["src/**".to_string()], Some(0)).unwrap();
+        assert_eq!(p.order, Order::SmallestFirst);
+        assert_eq!(p.priorities.len(), 1);
+        assert_eq!(p.aging, None);
+
+        let p = SchedulePolicy::parse(None, &[], None).unwrap();
+        assert_eq!(p, SchedulePolicy::default());
+
+        assert!(SchedulePolicy::parse(Some("biggest"), &[], None).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Copying a live tree file by file gets you whatever each file looked like
+// when the writer got to it. Fine for a pile of photos, garbage for a database
+// directory where the files only make sense together. So a sync can ask for a
+// read only snapshot of the source first, the reader scans that instead and
+// it gets torn down once the sync is done with it.
+//
+// Providers:
+// - btrfs: source has to be a subvolume, the snapshot goes next to it as
+//   .<name>.yeet-<uuid> so its on the same filesystem, which btrfs needs.
+// - cmd:<command>: anything else (LVM, zfs, overlayfs, some vendor tool...).
+//   The command is run through the shell twice, once with
+//   YEET_SNAPSHOT_ACTION=create and YEET_SOURCE/YEET_UUID set, the last line
+//   it prints is the snapshot path. Then again at the end with
+//   YEET_SNAPSHOT_ACTION=release and YEET_SNAPSHOT set to that path.
+//
+// Anything that changes the source between scanning a file and copying it
+// gets flagged by the writer regardless, a snapshot just means that can't
+// happen.
+
+/// How a sync gets a stable view of its source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotProvider {
+    Btrfs,
+    Command(String),
+}
+
+impl std::str::FromStr for SnapshotProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(cmd) = s.strip_prefix("cmd:") {
+            if cmd.trim().is_empty() {
+                return Err("snapshot command can't be empty".to_string());
+            }
+            return Ok(SnapshotProvider::Command(cmd.to_string()));
+        }
+        match s.to_ascii_lowercase().as_str() {
+            "btrfs" => Ok(SnapshotProvider::Btrfs),
+            _ => Err(format!(
+                "unknown snapshot provider '{}', expected btrfs or cmd:<command>",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for SnapshotProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotProvider::Btrfs => write!(f, "btrfs"),
+            SnapshotProvider::Command(cmd) => write!(f, "cmd:{}", cmd),
+        }
+    }
+}
+
+/// A read only snapshot of a source, released on drop. Fan-out legs read
+/// from it too so its shared behind an Arc and the last one out cleans up.
+#[derive(Debug)]
+pub struct Snapshot {
+    provider: SnapshotProvider,
+    path: PathBuf,
+}
+
+impl Snapshot {
+    /// Snapshot source, blocks until the provider is done.
+    pub fn take(provider: &SnapshotProvider, source: &Path, uuid: u128) -> std::io::Result<Self> {
+        let id = uuid::Uuid::from_u128(uuid);
+        let path = match provider {
+            SnapshotProvider::Btrfs => {
+                let name = source
+                    .file_name()
+                    .ok_or_else(|| std::io::Error::other("can't snapshot a root"))?;
+                let path =
+                    source.with_file_name(format!(".{}.yeet-{}", name.to_string_lossy(), id));
+                run(Command::new("btrfs")
+                    .args(["subvolume", "snapshot", "-r"])
+                    .arg(source)
+                    .arg(&path))?;
+                path
+            }
+            SnapshotProvider::Command(cmd) => {
+                let out = run(shell(cmd)
+                    .env("YEET_SNAPSHOT_ACTION", "create")
+                    .env("YEET_SOURCE", source)
+                    .env("YEET_UUID", id.to_string()))?;
+                let line = out
+                    .lines()
+                    .rev()
+                    .map(str::trim)
+                    .find(|l| !l.is_empty())
+                    .ok_or_else(|| std::io::Error::other("snapshot command printed no path"))?;
+                PathBuf::from(line)
+            }
+        };
+
+        let snapshot = Self {
+            provider: provider.clone(),
+            path,
+        };
+
+        // Dropping it releases whatever did get made
+        if !snapshot.path.is_dir() {
+            return Err(std::io::Error::other(format!(
+                "snapshot {} isn't a directory",
+                snapshot.path.display()
+            )));
+        }
+
+        tracing::info!(
+            "{} snapshotted {} to {}",
+            id,
+            source.display(),
+            snapshot.path.display()
+        );
+        Ok(snapshot)
+    }
+
+    /// Where to read the source from now
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Let go of a shared snapshot, if that was the last reference the
+    /// provider gets run on the blocking pool instead of whatever async task
+    /// happened to be holding it. Drop is only the fallback for when nobody
+    /// called this.
+    pub async fn release(snapshot: std::sync::Arc<Self>) {
+        if let Some(snapshot) = std::sync::Arc::into_inner(snapshot)
+            && let Err(e) = tokio::task::spawn_blocking(move || drop(snapshot)).await
+        {
+            tracing::warn!("snapshot release task failed: {}", e);
+        }
+    }
+
+    fn remove(&self) -> std::io::Result<()> {
+        match &self.provider {
+            SnapshotProvider::Btrfs => run(Command::new("btrfs")
+                .args(["subvolume", "delete"])
+                .arg(&self.path)),
+            SnapshotProvider::Command(cmd) => run(shell(cmd)
+                .env("YEET_SNAPSHOT_ACTION", "release")
+                .env("YEET_SNAPSHOT", &self.path)),
+        }
+        .map(|_| ())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        match self.remove() {
+            Ok(()) => tracing::debug!("released snapshot {}", self.path.display()),
+            Err(e) => tracing::warn!(
+                "couldn't release snapshot {}, clean it up by hand: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    command
+}
+
+#[cfg(not(unix))]
+fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(cmd);
+    command
+}
+
+// Run it, stdout if it worked and stderr in the error if not
+fn run(command: &mut Command) -> std::io::Result<String> {
+    let out = command.output()?;
+    if !out.status.success() {
+        return Err(std::io::Error::other(format!(
+            "{:?} failed ({}): {}",
+            command.get_program(),
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_parse_provider() {
+        assert_eq!("btrfs".parse(), Ok(SnapshotProvider::Btrfs));
+        assert_eq!(
+            "cmd:lvm-snap --ro".parse(),
+            Ok(SnapshotProvider::Command("lvm-snap --ro".to_string()))
+        );
+        assert!("cmd: ".parse::<SnapshotProvider>().is_err());
+        assert!("zfs".parse::<SnapshotProvider>().is_err());
+
+        let cmd = SnapshotProvider::Command("x y".to_string());
+        assert_eq!(cmd.to_string().parse(), Ok(cmd));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_provider() {
+        let dir = TestDir::new("snapshot");
+        let source = dir.join("src");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("db"), "before").unwrap();
+
+        // Poor man's snapshot, a copy
+        let provider = SnapshotProvider::Command(format!(
+            r#"snap="{}/snap-$YEET_UUID"
+case "$YEET_SNAPSHOT_ACTION" in
+  create) cp -R "$YEET_SOURCE" "$snap" && echo "making it" && echo "$snap" ;;
+  release) rm -rf "$YEET_SNAPSHOT" ;;
+esac"#,
+            dir.display()
+        ));
+
+        let snapshot = Snapshot::take(&provider, &source, 7).unwrap();
+        let path = snapshot.path().to_path_buf();
+        assert!(path.starts_with(&dir));
+
+        std::fs::write(source.join("db"), "after").unwrap();
+        assert_eq!(std::fs::read_to_string(path.join("db")).unwrap(), "before");
+
+        drop(snapshot);
+        assert!(!path.exists());
+
+        // Failing to make one is an error, not a half made snapshot
+        let broken = SnapshotProvider::Command("echo nope >&2; exit 3".to_string());
+        let err = Snapshot::take(&broken, &source, 8).unwrap_err();
+        assert!(err.to_string().contains("nope"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_release_last_one_out() {
+        let dir = TestDir::new("snapshot-release");
+        let source = dir.join("src");
+        std::fs::create_dir_all(&source).unwrap();
+
+        let provider = SnapshotProvider::Command(format!(
+            r#"snap="{}/snap"
+case "$YEET_SNAPSHOT_ACTION" in
+  create) cp -R "$YEET_SOURCE" "$snap" && echo "$snap" ;;
+  release) rm -rf "$YEET_SNAPSHOT" ;;
+esac"#,
+            dir.display()
+        ));
+
+        let snapshot = std::sync::Arc::new(Snapshot::take(&provider, &source, 9).unwrap());
+        let path = snapshot.path().to_path_buf();
+        let leg = snapshot.clone();
+
+        // Someone still reading from it, stays put
+        Snapshot::release(snapshot).await;
+        assert!(path.exists());
+
+        Snapshot::release(leg).await;
+        assert!(!path.exists());
+    }
+}
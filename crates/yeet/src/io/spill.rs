@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::work::WorkItem;
+
+// On disk overflow for work blocked on a parent directory.
+//
+// Each parent gets its own file of json lines, appended to whenever the tree
+// queue decides its holding too much blocked work. When the parent is ready
+// the whole file is read back in and removed. Nothing fancy, the files are
+// only ever appended and then read once so there is no need for anything
+// smarter.
+//
+// The directory is per sync and removed when the spill is dropped, if the
+// daemon dies mid sync it'll leave junk in the temp dir, future mitch problem.
+
+#[derive(Debug)]
+struct Spilled {
+    file: PathBuf,
+    count: usize,
+}
+
+#[derive(Debug)]
+pub struct Spill {
+    dir: PathBuf,
+    parents: HashMap<PathBuf, Spilled>,
+    next_file: u64,
+    items: usize,
+}
+
+impl Spill {
+    pub fn new(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            parents: HashMap::new(),
+            next_file: 0,
+            items: 0,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Items currently on disk
+    pub fn len(&self) -> usize {
+        self.items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items == 0
+    }
+
+    pub fn contains(&self, parent: &Path) -> bool {
+        self.parents.contains_key(parent)
+    }
+
+    /// Parents with anything spilled
+    pub fn parents(&self) -> impl Iterator<Item = &PathBuf> {
+        self.parents.keys()
+    }
+
+    /// Write items blocked on parent out to disk. On error nothing is
+    /// considered spilled and the items are handed back.
+    pub fn append(
+        &mut self,
+        parent: &Path,
+        items: Vec<WorkItem>,
+    ) -> Result<(), (std::io::Error, Vec<WorkItem>)> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let file = match self.parents.get(parent) {
+            Some(spilled) => spilled.file.clone(),
+            None => {
+                self.next_file += 1;
+                self.dir.join(format!("{}.jsonl", self.next_file))
+            }
+        };
+
+        let written = (|| -> std::io::Result<()> {
+            let f = OpenOptions::new().create(true).append(true).open(&file)?;
+            let mut w = BufWriter::new(f);
+            for item in &items {
+                serde_json::to_writer(&mut w, item)?;
+                w.write_all(b"\n")?;
+            }
+            w.flush()
+        })();
+
+        if let Err(e) = written {
+            return Err((e, items));
+        }
+
+        let count = items.len();
+        self.items += count;
+        self.parents
+            .entry(parent.to_path_buf())
+            .or_insert(Spilled { file, count: 0 })
+            .count += count;
+
+        Ok(())
+    }
+
+    /// Read everything spilled for parent back in and forget about it.
+    pub fn take(&mut self, parent: &Path) -> std::io::Result<Vec<WorkItem>> {
+        let Some(spilled) = self.parents.remove(parent) else {
+            return Ok(Vec::new());
+        };
+        self.items -= spilled.count;
+
+        let reader = BufReader::new(File::open(&spilled.file)?);
+        let mut items = Vec::with_capacity(spilled.count);
+        for line in reader.lines() {
+            items.push(serde_json::from_str(&line?)?);
+        }
+
+        let _ = std::fs::remove_file(&spilled.file);
+        Ok(items)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// The never lose data guarantee from the mvp adr, made concrete. With a trash
+// set up nothing on a destination gets overwritten or removed without the old
+// version being moved aside first, into:
+//
+//   <dest>/.yeet-trash/<stamp>/<path relative to dest>
+//
+// or the same layout under a configured dir. Stamps are utc, rfc3339 minus
+// the colons so they're fine on smb too, and sort oldest to newest. One stamp
+// per run so a tree removed in one go can come back in one go, if the same
+// path gets replaced twice in a run (watched syncs never end) the second one
+// gets a stamp of its own.
+//
+// Only files and symlinks get kept. Dirs are only ever removed empty and a
+// dir being replaced by a file means whatever was in it was already removed
+// (and kept) first. Resolving a conflict is the exception, the losing side
+// goes whole, dir and all.
+//
+// Two-way syncs have two destinations, each root gets its own trash. A
+// configured dir gets lhs/ and rhs/ under it so they don't step on each
+// other.
+//
+// Trash dirs are excluded from scans on every side, otherwise a two-way sync
+// would dutifully sync everyone's trash to everyone else.
+
+/// Name of the trash dir at a destination root
+pub const TRASH_DIR: &str = ".yeet-trash";
+
+/// Kept versions older than this get pruned by default
+pub const DEFAULT_TRASH_AGE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// Where old versions go and how long they stick around
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashConfig {
+    /// None means TRASH_DIR at the destination root
+    pub dir: Option<PathBuf>,
+    /// Prune versions older than this, None keeps them forever
+    pub max_age: Option<Duration>,
+    /// Keep at most this many versions of any one path, None is unlimited
+    pub max_versions: Option<usize>,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_age: Some(DEFAULT_TRASH_AGE),
+            max_versions: None,
+        }
+    }
+}
+
+impl TrashConfig {
+    /// Trash dir for a destination root
+    pub fn dir_for(&self, root: &Path) -> PathBuf {
+        self.dir.clone().unwrap_or_else(|| root.join(TRASH_DIR))
+    }
+
+    /// Same config for one side of a two-way sync
+    pub fn side(&self, side: impl std::fmt::Display) -> Self {
+        Self {
+            dir: self.dir.as_ref().map(|d| d.join(side.to_string())),
+            ..self.clone()
+        }
+    }
+}
+
+/// A destination root's trash for one run.
+#[derive(Debug, Clone)]
+pub struct Trash {
+    root: PathBuf,
+    dir: PathBuf,
+    stamp: String,
+    config: TrashConfig,
+}
+
+impl Trash {
+    pub fn new(root: PathBuf, config: &TrashConfig) -> Self {
+        Self {
+            dir: config.dir_for(&root),
+            root,
+            stamp: stamp(SystemTime::now()),
+            config: config.clone(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Move whatever is at relative (to the root) into the trash before it
+    /// gets replaced or removed. Returns where it went, None if there was
+    /// nothing there to keep.
+    pub fn keep(&self, relative: &Path) -> std::io::Result<Option<PathBuf>> {
+        match std::fs::symlink_metadata(self.root.join(relative)) {
+            Ok(meta) if meta.is_dir() => Ok(None),
+            Ok(_) => self.keep_tree(relative),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// keep() but dirs go too, along with everything in them
+    pub fn keep_tree(&self, relative: &Path) -> std::io::Result<Option<PathBuf>> {
+        let from = self.root.join(relative);
+        if let Err(e) = std::fs::symlink_metadata(&from) {
+            return match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(None),
+                _ => Err(e),
+            };
+        }
+
+        let mut to = self.dir.join(&self.stamp).join(relative);
+        while std::fs::symlink_metadata(&to).is_ok() {
+            std::thread::sleep(Duration::from_millis(1));
+            to = self.dir.join(stamp(SystemTime::now())).join(relative);
+        }
+
+        move_aside(&from, &to)?;
+        tracing::debug!("kept {} as {}", from.display(), to.display());
+        Ok(Some(to))
+    }
+
+    /// Drop versions past the retention policy, returns how many went.
+    pub fn prune(&self, now: SystemTime) -> std::io::Result<usize> {
+        prune(&self.dir, &self.config, now)
+    }
+}
+
+/// One kept version of a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub stamp: String,
+    pub path: PathBuf,
+}
+
+/// Every kept version of relative in a trash dir, newest first
+pub fn versions(dir: &Path, relative: &Path) -> std::io::Result<Vec<Version>> {
+    let mut versions = Vec::new();
+    for stamp in stamps(dir)? {
+        let path = dir.join(&stamp).join(relative);
+        if std::fs::symlink_metadata(&path).is_ok() {
+            versions.push(Version { stamp, path });
+        }
+    }
+    versions.reverse();
+    Ok(versions)
+}
+
+/// Put a kept version back at relative under root, newest if no stamp is
+/// given. Whatever is there now gets kept first, restoring is an overwrite
+/// like any other. Dirs are restored by merging everything kept under them
+/// back in. Returns the paths restored, relative to root.
+pub fn restore(
+    root: &Path,
+    config: &TrashConfig,
+    relative: &Path,
+    stamp: Option<&str>,
+) -> std::io::Result<Vec<PathBuf>> {
+    let trash = Trash::new(root.to_path_buf(), config);
+    let versions = versions(trash.dir(), relative)?;
+    let version = match stamp {
+        Some(stamp) => versions.into_iter().find(|v| v.stamp == stamp),
+        None => versions.into_iter().next(),
+    }
+    .ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!(
+                "no kept version of {} in {}",
+                relative.display(),
+                trash.dir().display()
+            ),
+        )
+    })?;
+
+    let mut restored = Vec::new();
+    // join("") would tack a / on the end, which a file doesn't like
+    let mut stack = vec![(version.path.clone(), relative.to_path_buf())];
+    while let Some((from, path)) = stack.pop() {
+        if std::fs::symlink_metadata(&from)?.is_dir() {
+            for entry in std::fs::read_dir(&from)? {
+                let name = entry?.file_name();
+                stack.push((from.join(&name), path.join(&name)));
+            }
+            continue;
+        }
+
+        // Empty dir in the way, nothing to lose by removing it
+        let to = root.join(&path);
+        if std::fs::symlink_metadata(&to).is_ok_and(|m| m.is_dir()) {
+            std::fs::remove_dir(&to)?;
+        }
+        trash.keep(&path)?;
+        move_aside(&from, &to)?;
+        restored.push(path);
+    }
+
+    // Don't leave empty husks of what got restored
+    let _ = remove_empty(&version.path);
+    let _ = remove_empty(&trash.dir().join(&version.stamp));
+    Ok(restored)
+}
+
+// Rename if we can, copy and remove if its on another filesystem
+fn move_aside(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    let meta = std::fs::symlink_metadata(from)?;
+    if meta.is_dir() {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let name = entry?.file_name();
+            move_aside(&from.join(&name), &to.join(&name))?;
+        }
+        return std::fs::remove_dir(from);
+    }
+    if meta.file_type().is_symlink() {
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(std::fs::read_link(from)?, to)?;
+        #[cfg(not(unix))]
+        return Err(std::io::Error::other(
+            "can't move symlinks across filesystems here",
+        ));
+    } else {
+        std::fs::copy(from, to)?;
+        let _ = std::fs::File::options()
+            .write(true)
+            .open(to)
+            .and_then(|f| f.set_modified(meta.modified()?));
+    }
+    std::fs::remove_file(from)
+}
+
+fn prune(dir: &Path, config: &TrashConfig, now: SystemTime) -> std::io::Result<usize> {
+    let mut pruned = 0;
+
+    // Whole stamps past the age go in one go
+    let mut kept = Vec::new();
+    for stamp in stamps(dir)? {
+        let old = config.max_age.is_some_and(|age| {
+            parse_stamp(&stamp).is_some_and(|at| now.duration_since(at).unwrap_or_default() > age)
+        });
+        if old {
+            pruned += count_leaves(&dir.join(&stamp));
+            std::fs::remove_dir_all(dir.join(&stamp))?;
+        } else {
+            kept.push(stamp);
+        }
+    }
+
+    // Then the oldest versions of anything with too many
+    if let Some(max) = config.max_versions {
+        let mut by_path: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+        for stamp in &kept {
+            for leaf in leaves(&dir.join(stamp)) {
+                by_path.entry(leaf).or_default().push(stamp.clone());
+            }
+        }
+        for (path, stamps) in by_path {
+            // stamps are oldest first
+            for stamp in stamps.iter().rev().skip(max) {
+                std::fs::remove_file(dir.join(stamp).join(&path))?;
+                pruned += 1;
+            }
+        }
+        for stamp in &kept {
+            let _ = remove_empty(&dir.join(stamp));
+        }
+    }
+
+    Ok(pruned)
+}
+
+// Stamp dirs in a trash, oldest first
+fn stamps(dir: &Path) -> std::io::Result<Vec<String>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut stamps: Vec<String> = entries
+        .flatten()
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|name| parse_stamp(name).is_some())
+        .collect();
+    stamps.sort();
+    Ok(stamps)
+}
+
+// Non dirs under dir, relative to it
+fn leaves(dir: &Path) -> Vec<PathBuf> {
+    let mut leaves = Vec::new();
+    let mut stack = vec![PathBuf::new()];
+    while let Some(relative) = stack.pop() {
+        let Ok(entries) = std::fs::read_dir(dir.join(&relative)) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = relative.join(entry.file_name());
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                stack.push(path);
+            } else {
+                leaves.push(path);
+            }
+        }
+    }
+    leaves
+}
+
+fn count_leaves(dir: &Path) -> usize {
+    leaves(dir).len()
+}
+
+// Remove dir and any dirs under it if there's nothing but dirs in there
+fn remove_empty(dir: &Path) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            let _ = remove_empty(&entry.path());
+        }
+    }
+    std::fs::remove_dir(dir)
+}
+
+fn stamp(at: SystemTime) -> String {
+    humantime::format_rfc3339_millis(at)
+        .to_string()
+        .replace(':', "")
+}
+
+// 2026-10-19T120102.123Z back into something humantime will parse
+fn parse_stamp(stamp: &str) -> Option<SystemTime> {
+    let (date, time) = stamp.split_once('T')?;
+    if time.len() < 6 || !time.is_char_boundary(6) {
+        return None;
+    }
+    let (hms, rest) = time.split_at(6);
+    let rfc = format!(
+        "{}T{}:{}:{}{}",
+        date,
+        &hms[..2],
+        &hms[2..4],
+        &hms[4..],
+        rest
+    );
+    humantime::parse_rfc3339(&rfc).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_stamp_round_trip() {
+        let at = SystemTime::UNIX_EPOCH + Duration::from_millis(1_792_000_000_123);
+        let s = stamp(at);
+        assert!(!s.contains(':'));
+        assert_eq!(parse_stamp(&s), Some(at));
+        assert_eq!(parse_stamp("not-a-stamp"), None);
+    }
+
+    #[test]
+    fn test_keep_and_restore() {
+        let root = TestDir::new("trash-restore");
+        let config = TrashConfig::default();
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::write(root.join("a/f"), "one").unwrap();
+
+        let trash = Trash::new(root.to_path_buf(), &config);
+        let kept = trash.keep(Path::new("a/f")).unwrap().unwrap();
+        assert!(kept.starts_with(root.join(TRASH_DIR)));
+        assert!(!root.join("a/f").exists());
+
+        // Nothing there is nothing to keep, dirs aren't kept
+        assert_eq!(trash.keep(Path::new("a/f")).unwrap(), None);
+        assert_eq!(trash.keep(Path::new("a")).unwrap(), None);
+
+        // Same path twice in a run gets its own stamp
+        std::fs::write(root.join("a/f"), "two").unwrap();
+        trash.keep(Path::new("a/f")).unwrap().unwrap();
+        std::fs::write(root.join("a/f"), "three").unwrap();
+
+        let all = versions(trash.dir(), Path::new("a/f")).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(std::fs::read_to_string(&all[0].path).unwrap(), "two");
+
+        // Oldest back, what was there is kept in turn
+        let restored = restore(&root, &config, Path::new("a/f"), Some(&all[1].stamp)).unwrap();
+        assert_eq!(restored, vec![PathBuf::from("a/f")]);
+        assert_eq!(std::fs::read_to_string(root.join("a/f")).unwrap(), "one");
+        let all = versions(trash.dir(), Path::new("a/f")).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(std::fs::read_to_string(&all[0].path).unwrap(), "three");
+
+        assert!(restore(&root, &config, Path::new("nope"), None).is_err());
+    }
+
+    #[test]
+    fn test_prune() {
+        let root = TestDir::new("trash-prune");
+        let dir = root.join(TRASH_DIR);
+        let day = Duration::from_secs(24 * 3600);
+        let now = SystemTime::UNIX_EPOCH + 100 * day;
+        for (days_ago, name) in [(40, "f"), (3, "f"), (2, "f"), (1, "f"), (1, "g")] {
+            let path = dir.join(stamp(now - days_ago * day)).join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "x").unwrap();
+        }
+
+        let config = TrashConfig {
+            dir: None,
+            max_age: Some(30 * day),
+            max_versions: Some(2),
+        };
+        assert_eq!(prune(&dir, &config, now).unwrap(), 2);
+        assert_eq!(versions(&dir, Path::new("f")).unwrap().len(), 2);
+        assert_eq!(versions(&dir, Path::new("g")).unwrap().len(), 1);
+        // the 3 day old stamp only had f in it
+        assert_eq!(stamps(&dir).unwrap().len(), 2);
+    }
+}
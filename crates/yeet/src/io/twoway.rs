@@ -0,0 +1,1120 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use super::baseline::{Baseline, rekey};
+use super::controller::Controllers;
+use super::error::IoError;
+use super::exclude::ExcludeRules;
+use super::limits::Limits;
+use super::metadata::{FileKind, FileMetadata};
+use super::progress::AtomicOperationProgress;
+use super::reconcile::{ConflictKind, Decision, Observed, Planned, Side, Tree, Verdict};
+use super::trash::{Trash, TrashConfig};
+use super::work::WorkItem;
+use super::work_tree::TreeWorkQueue;
+use super::writer::{Apply, WriteJob, WriterPool};
+
+// /a <-> /b. Scan both sides, reconcile them against the baseline, apply
+// whatever work that comes up with and save the new baseline.
+//
+// Nothing gets overwritten or removed without checking first that it still
+// looks like what the reconcile saw, which was itself checked against the
+// baseline. If someone changed it in the meantime the item is skipped and the
+// next run sorts it out. Copies go to a temp file next to the target and get
+// renamed over it so nothing ever sees half a file. There is still a tiny
+// window between the check and the rename, closing that needs something like
+// renameat2(RENAME_EXCHANGE) and checking what we swapped out, future mitch.
+//
+// With a trash set up anything overwritten or removed on either side gets
+// moved to that side's trash first, see trash.rs.
+//
+// Conflicts are left alone on both sides and stay as they were in the
+// baseline so they keep showing up as conflicts until someone sorts them out.
+//
+// Copies, new dirs and removals go through the writer pool like any other
+// sync's writes, one side at a time, so rate limits, the dest's controller and
+// the fair queue all apply. The pool calls back into us for each item (see
+// Round) so the guards still happen right before anything is touched. Moves
+// are just renames and the reconcile needs them done first, they still happen
+// right here. Removals go deepest first, a round per depth, so kids are gone
+// before their dirs.
+
+// Prefix for in flight copies, never synced and never left around on purpose
+pub(super) const TMP_PREFIX: &str = ".yeet-tmp.";
+
+/// What a two-way run did
+#[derive(Debug, Default, Clone)]
+pub struct TwoWayReport {
+    pub unchanged: usize,
+    pub to_lhs: usize,
+    pub to_rhs: usize,
+    pub removed: usize,
+    /// Moved on one side, moved the same on the other
+    pub renamed: usize,
+    pub conflicts: Vec<(PathBuf, ConflictKind)>,
+    /// Changed under us between reconcile and apply, left for next time
+    pub raced: Vec<PathBuf>,
+    /// Under a conflicted path so not touched
+    pub held: usize,
+}
+
+// Why applying a planned item didn't happen
+enum Skip {
+    Raced(String),
+    Failed(std::io::Error),
+}
+
+impl From<std::io::Error> for Skip {
+    fn from(e: std::io::Error) -> Self {
+        Skip::Failed(e)
+    }
+}
+
+#[derive(Clone)]
+pub struct TwoWay {
+    uuid: u128,
+    lhs: PathBuf,
+    rhs: PathBuf,
+    baseline: PathBuf,
+    exclude: ExcludeRules,
+    progress: Arc<AtomicOperationProgress>,
+    errors: Arc<tokio::sync::Mutex<Vec<IoError>>>,
+    /// lhs and rhs trash
+    trash: Option<(Trash, Trash)>,
+    writers: Arc<WriterPool>,
+    limits: Limits,
+    controllers: Controllers,
+    weight: u32,
+}
+
+impl TwoWay {
+    pub fn new(
+        uuid: u128,
+        lhs: PathBuf,
+        rhs: PathBuf,
+        baseline: PathBuf,
+        progress: Arc<AtomicOperationProgress>,
+        errors: Arc<tokio::sync::Mutex<Vec<IoError>>>,
+    ) -> Self {
+        Self {
+            uuid,
+            lhs,
+            rhs,
+            baseline,
+            exclude: ExcludeRules::new(),
+            progress,
+            errors,
+            trash: None,
+            writers: Arc::new(WriterPool::new()),
+            limits: Limits::default(),
+            controllers: Controllers::default(),
+            weight: super::fair::DEFAULT_WEIGHT,
+        }
+    }
+
+    /// Write through these and not a pool of our own, aka the daemon's
+    pub fn with_writers(
+        mut self,
+        writers: Arc<WriterPool>,
+        limits: Limits,
+        controllers: Controllers,
+        weight: u32,
+    ) -> Self {
+        self.writers = writers;
+        self.limits = limits;
+        self.controllers = controllers;
+        self.weight = weight;
+        self
+    }
+
+    /// Keep old versions of anything replaced or removed on either side
+    pub fn with_trash(mut self, config: Option<&TrashConfig>) -> Self {
+        self.trash = config.map(|config| {
+            (
+                Trash::new(self.lhs.clone(), &config.side(Side::Lhs)),
+                Trash::new(self.rhs.clone(), &config.side(Side::Rhs)),
+            )
+        });
+        self
+    }
+
+    fn root(&self, side: Side) -> &Path {
+        match side {
+            Side::Lhs => &self.lhs,
+            Side::Rhs => &self.rhs,
+        }
+    }
+
+    fn error(&self, side: Side, msg: String, path: PathBuf) {
+        tracing::error!("{}: {}", msg, path.display());
+        self.errors.blocking_lock().push(io_error(side, msg, path));
+    }
+
+    /// Do one full reconcile and apply pass.
+    pub fn run_blocking(&self) -> std::io::Result<TwoWayReport> {
+        // Only the rhs gets made if its missing, a missing lhs is more likely
+        // a typo or an unmounted disk than a request to delete everything.
+        std::fs::create_dir_all(&self.rhs)?;
+
+        if let Some((lhs, rhs)) = &self.trash {
+            for trash in [lhs, rhs] {
+                match trash.prune(std::time::SystemTime::now()) {
+                    Ok(0) => (),
+                    Ok(n) => {
+                        tracing::info!("pruned {} old versions from {}", n, trash.dir().display())
+                    }
+                    Err(e) => tracing::warn!("couldn't prune {}: {}", trash.dir().display(), e),
+                }
+            }
+        }
+
+        let mut lhs = self.scan(Side::Lhs)?;
+        let mut rhs = self.scan(Side::Rhs)?;
+        let mut baseline = Baseline::open(&self.baseline)?;
+
+        tracing::debug!(
+            "{} two-way: {} lhs, {} rhs, {} baseline entries",
+            uuid::Uuid::from_u128(self.uuid),
+            lhs.len(),
+            rhs.len(),
+            baseline.len()
+        );
+
+        let mut report = TwoWayReport::default();
+
+        // Moves go first. Once one is applied the baseline and the other
+        // side's tree get re-keyed to the new name, so the reconcile sees
+        // anything that didn't change as unchanged instead of a delete and a
+        // full copy.
+        let renames = super::reconcile::detect_renames(&baseline, &lhs, &rhs, |side, path| {
+            hash_file(&self.root(side).join(path))
+        });
+        for rename in renames {
+            let (moved, other) = match rename.side {
+                Side::Lhs => (&lhs, &mut rhs),
+                Side::Rhs => (&rhs, &mut lhs),
+            };
+            let planned = rename.plan(self.uuid, other);
+            if self.apply(&planned, &mut report).is_some() {
+                self.made_parents(&planned, moved, other, &rename.to);
+                tracing::debug!(
+                    "{} moved to {} on {}",
+                    rename.from.display(),
+                    rename.to.display(),
+                    rename.side
+                );
+                baseline.rename_tree(&rename.from, &rename.to);
+                rekey(other, &rename.from, &rename.to);
+                report.renamed += 1;
+            }
+        }
+
+        let decisions = super::reconcile::reconcile(&baseline, &lhs, &rhs, |side, path| {
+            hash_file(&self.root(side).join(path))
+        });
+
+        for d in &decisions {
+            let Some(o) = d.lhs.as_ref().or(d.rhs.as_ref()) else {
+                continue;
+            };
+            if o.kind() == FileKind::Directory {
+                self.progress.dirs_found.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.progress.files_found.fetch_add(1, Ordering::Relaxed);
+                self.progress
+                    .total_size
+                    .fetch_add(o.metadata.size, Ordering::Relaxed);
+            }
+        }
+
+        let mut conflicted: Vec<PathBuf> = Vec::new();
+        let mut changes: Vec<(&Decision, Side, Planned)> = Vec::new();
+        let mut removals: Vec<(&Decision, Planned)> = Vec::new();
+
+        for d in &decisions {
+            if conflicted.iter().any(|c| d.path.starts_with(c)) {
+                report.held += 1;
+                continue;
+            }
+
+            match d.verdict {
+                Verdict::Conflict(kind) => {
+                    tracing::warn!("conflict: {} {}", d.path.display(), kind);
+                    report.conflicts.push((d.path.clone(), kind));
+                    conflicted.push(d.path.clone());
+                }
+                Verdict::Unchanged | Verdict::Converged => {
+                    report.unchanged += 1;
+                    if let Some(entry) = d.agreed(d.lhs.as_ref(), d.rhs.as_ref()) {
+                        baseline.insert(d.path.clone(), entry);
+                    }
+                }
+                Verdict::BothDeleted => {
+                    baseline.remove(&d.path);
+                }
+                Verdict::Changed(side) => {
+                    if let Some(planned) = d.plan(self.uuid, |s| self.root(s).to_path_buf()) {
+                        changes.push((d, side, planned));
+                    }
+                }
+                Verdict::Deleted(_) => {
+                    if let Some(planned) = d.plan(self.uuid, |s| self.root(s).to_path_buf()) {
+                        removals.push((d, planned));
+                    }
+                }
+            }
+        }
+
+        let applied = self.apply_all(changes.iter().map(|(_, _, p)| p), &mut report);
+        for (d, side, planned) in &changes {
+            let Some(after) = applied.get(&(planned.target, d.path.clone())) else {
+                continue;
+            };
+
+            let (lhs, rhs) = match side {
+                Side::Lhs => (d.lhs.as_ref(), Some(after)),
+                Side::Rhs => (Some(after), d.rhs.as_ref()),
+            };
+            if let Some(entry) = d.agreed(lhs, rhs) {
+                baseline.insert(d.path.clone(), entry);
+            }
+            match side {
+                Side::Lhs => report.to_rhs += 1,
+                Side::Rhs => report.to_lhs += 1,
+            }
+        }
+
+        // Kids before their dirs
+        let deepest = removals
+            .iter()
+            .map(|(d, _)| d.path.components().count())
+            .max()
+            .unwrap_or_default();
+        for depth in (1..=deepest).rev() {
+            let removing: Vec<_> = removals
+                .iter()
+                .filter(|(d, _)| d.path.components().count() == depth)
+                .collect();
+            let applied = self.apply_all(removing.iter().map(|(_, p)| p), &mut report);
+            for (d, planned) in removing {
+                if applied.contains_key(&(planned.target, d.path.clone()))
+                    || !planned.target_exists(self)
+                {
+                    baseline.remove(&d.path);
+                    report.removed += 1;
+                }
+            }
+        }
+
+        baseline.save()?;
+
+        tracing::info!(
+            "{} two-way done: {} to lhs, {} to rhs, {} removed, {} renamed, {} unchanged, {} conflicts",
+            uuid::Uuid::from_u128(self.uuid),
+            report.to_lhs,
+            report.to_rhs,
+            report.removed,
+            report.renamed,
+            report.unchanged,
+            report.conflicts.len()
+        );
+
+        Ok(report)
+    }
+
+    // Run planned items through the writer pool, a round per side. Returns
+    // what everything that got applied looks like now, keyed by side and path.
+    fn apply_all<'a>(
+        &self,
+        planned: impl Iterator<Item = &'a Planned>,
+        report: &mut TwoWayReport,
+    ) -> HashMap<(Side, PathBuf), Observed> {
+        let mut by_side: HashMap<Side, HashMap<PathBuf, Planned>> = HashMap::new();
+        for planned in planned {
+            if let Some(path) = planned.item.dest_path() {
+                by_side
+                    .entry(planned.target)
+                    .or_default()
+                    .insert(path.to_path_buf(), planned.clone());
+            }
+        }
+
+        let mut applied = HashMap::new();
+        for side in [Side::Lhs, Side::Rhs] {
+            let Some(planned) = by_side.remove(&side) else {
+                continue;
+            };
+            let round = Arc::new(Round {
+                two_way: self.clone(),
+                planned,
+                applied: parking_lot::Mutex::new(HashMap::new()),
+                raced: parking_lot::Mutex::new(Vec::new()),
+            });
+            self.write(side, round.clone());
+
+            report.raced.append(&mut round.raced.lock());
+            applied.extend(
+                round
+                    .applied
+                    .lock()
+                    .drain()
+                    .map(|(path, after)| ((side, path), after)),
+            );
+        }
+        applied
+    }
+
+    // Hand a round to the writer pool and wait for it to get through it
+    fn write(&self, side: Side, round: Arc<Round>) {
+        let root = self.root(side).to_path_buf();
+
+        // Anything that isn't being made this round is already there, the
+        // queue has to know or whatever is in it waits forever.
+        let making: HashSet<&Path> = round
+            .planned
+            .values()
+            .filter(|p| p.item.is_dir())
+            .filter_map(|p| p.item.dest_path())
+            .collect();
+        let mut queue = TreeWorkQueue::new();
+        let mut there = HashSet::new();
+        for path in round.planned.keys() {
+            for parent in path.ancestors().skip(1) {
+                if !parent.as_os_str().is_empty()
+                    && !making.contains(parent)
+                    && there.insert(parent.to_path_buf())
+                {
+                    queue.mark_dir_created(parent.to_path_buf());
+                }
+            }
+        }
+        for planned in round.planned.values() {
+            queue.push(planned.item.clone());
+        }
+        queue.push(WorkItem::ScanComplete { uuid: self.uuid });
+
+        let fs_features = super::writer::detect_fs_features(&root);
+        let controller = self.controllers.for_dest(
+            &root,
+            fs_features.is_network(),
+            super::writer::get_num_workers(),
+        );
+        let done = Arc::new(super::done::Done::new());
+        let job = WriteJob::new(
+            self.uuid,
+            root,
+            Arc::new(tokio::sync::Mutex::new(queue)),
+            // Round keeps track of progress, this is just so the job has one
+            Default::default(),
+            self.errors.clone(),
+            self.limits.clone(),
+            controller,
+            fs_features,
+            done.clone(),
+        )
+        .with_apply(round);
+        self.writers.add(job, self.weight, None);
+
+        // Done gets set just before the pool drops the job, the next round
+        // has the same uuid so it has to be gone for good first.
+        done.wait_blocking();
+        while self.writers.contains(self.uuid) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    // Apply one item right here, returns what the target looks like after or
+    // None if it was skipped. Removals return the (now missing) expected
+    // state.
+    fn apply(&self, planned: &Planned, report: &mut TwoWayReport) -> Option<Observed> {
+        let path = planned.item.dest_path()?.to_path_buf();
+
+        match self.applied(planned) {
+            Ok(after) => after,
+            Err(Skip::Raced(why)) => {
+                raced(planned, &path, &why);
+                report.raced.push(path);
+                None
+            }
+            Err(Skip::Failed(e)) => {
+                self.error(
+                    planned.target,
+                    format!("two-way sync failed: {}", e),
+                    self.root(planned.target).join(&path),
+                );
+                None
+            }
+        }
+    }
+
+    // apply_item() plus progress, what apply() and Round have in common
+    fn applied(&self, planned: &Planned) -> Result<Option<Observed>, Skip> {
+        let after = self.apply_item(planned)?;
+
+        // Renames didn't write anything
+        if let Some(after) = &after
+            && !matches!(planned.item, WorkItem::Rename { .. })
+        {
+            if after.kind() == FileKind::Directory {
+                self.progress.dirs_written.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.progress.files_written.fetch_add(1, Ordering::Relaxed);
+                self.progress.record_write(after.metadata.size);
+            }
+        }
+        Ok(after.or_else(|| planned.expect.clone()))
+    }
+
+    // A rename into a dir the other side doesn't have yet made it along the
+    // way, give it the same mode and let the reconcile know its there so it
+    // doesn't look like it just showed up.
+    fn made_parents(&self, planned: &Planned, moved: &Tree, other: &mut Tree, to: &Path) {
+        let root = self.root(planned.target);
+        for parent in to.ancestors().skip(1) {
+            if parent.as_os_str().is_empty() || other.contains_key(parent) {
+                break;
+            }
+            #[cfg(unix)]
+            if let Some(source) = moved.get(parent) {
+                use std::os::unix::fs::PermissionsExt;
+                let _ = std::fs::set_permissions(
+                    root.join(parent),
+                    std::fs::Permissions::from_mode(source.metadata.mode),
+                );
+            }
+            #[cfg(not(unix))]
+            let _ = moved;
+            if let Ok(Some(observed)) = observe(&root.join(parent)) {
+                other.insert(parent.to_path_buf(), observed);
+            }
+        }
+    }
+
+    // The guard, target has to look like it did when we decided.
+    fn check(&self, planned: &Planned, dest: &Path) -> Result<(), Skip> {
+        let now = observe(dest)?;
+        match (&planned.expect, &now) {
+            (None, None) => Ok(()),
+            (Some(expect), Some(now)) if expect.same_as(now) => Ok(()),
+            (None, Some(_)) => Err(Skip::Raced("appeared".to_string())),
+            (Some(_), None) => Err(Skip::Raced("went away".to_string())),
+            (Some(_), Some(_)) => Err(Skip::Raced("modified".to_string())),
+        }
+    }
+
+    // Move the target to the trash if there is one and the target is
+    // something worth keeping. Returns if it was moved, aka nothing is left
+    // to remove.
+    fn keep(&self, planned: &Planned) -> Result<bool, Skip> {
+        let Some((lhs, rhs)) = &self.trash else {
+            return Ok(false);
+        };
+        let trash = match planned.target {
+            Side::Lhs => lhs,
+            Side::Rhs => rhs,
+        };
+        let (Some(expect), Some(relative)) = (&planned.expect, planned.item.dest_path()) else {
+            return Ok(false);
+        };
+        if expect.kind() == FileKind::Directory {
+            return Ok(false);
+        }
+        Ok(trash.keep(relative)?.is_some())
+    }
+
+    // Source has to still be what we decided to copy
+    fn check_source(&self, planned: &Planned, source: &Path) -> Result<(), Skip> {
+        match (&planned.source, observe(source)?) {
+            (Some(expect), Some(now)) if expect.same_as(&now) => Ok(()),
+            _ => Err(Skip::Raced("source changed while copying".to_string())),
+        }
+    }
+
+    fn apply_item(&self, planned: &Planned) -> Result<Option<Observed>, Skip> {
+        let root = self.root(planned.target);
+
+        match &planned.item {
+            WorkItem::CreateDir {
+                dest_path,
+                metadata,
+                ..
+            } => {
+                let dest = root.join(dest_path);
+                self.check(planned, &dest)?;
+                if planned
+                    .expect
+                    .as_ref()
+                    .is_some_and(|e| e.kind() != FileKind::Directory)
+                    && !self.keep(planned)?
+                {
+                    std::fs::remove_file(&dest)?;
+                }
+                match std::fs::create_dir(&dest) {
+                    Ok(()) => (),
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+                    Err(e) => return Err(e.into()),
+                }
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    let _ = std::fs::set_permissions(
+                        &dest,
+                        std::fs::Permissions::from_mode(metadata.mode),
+                    );
+                }
+                #[cfg(not(unix))]
+                let _ = metadata;
+                Ok(observe(&dest)?)
+            }
+            WorkItem::CopySmallFile {
+                source_path,
+                dest_path,
+                metadata,
+                ..
+            }
+            | WorkItem::CopyLargeFile {
+                source_path,
+                dest_path,
+                metadata,
+                ..
+            } => {
+                let dest = root.join(dest_path);
+                self.check(planned, &dest)?;
+
+                let tmp = tmp_path(&dest);
+                let result = self.copy_into_place(planned, source_path, &tmp, &dest, metadata);
+                if result.is_err() {
+                    let _ = std::fs::remove_file(&tmp);
+                }
+                result?;
+                Ok(observe(&dest)?)
+            }
+            WorkItem::CreateSymlink {
+                source_path,
+                dest_path,
+                metadata,
+                ..
+            } => {
+                let dest = root.join(dest_path);
+                self.check(planned, &dest)?;
+
+                let tmp = tmp_path(&dest);
+                #[cfg(unix)]
+                std::os::unix::fs::symlink(&metadata.target, &tmp)?;
+                #[cfg(not(unix))]
+                return Err(Skip::Failed(std::io::Error::other(format!(
+                    "symlinks not supported here: {}",
+                    metadata.target.display()
+                ))));
+
+                #[allow(unreachable_code)]
+                let result = self
+                    .check_source(planned, source_path)
+                    .and_then(|()| self.check(planned, &dest))
+                    .and_then(|()| self.replace(planned, &tmp, &dest));
+                if result.is_err() {
+                    let _ = std::fs::remove_file(&tmp);
+                }
+                result?;
+                Ok(observe(&dest)?)
+            }
+            WorkItem::Remove {
+                dest_path, kind, ..
+            } => {
+                let dest = root.join(dest_path);
+                self.check(planned, &dest)?;
+                // Dirs only go if they're empty, anything new in there stays
+                match kind {
+                    FileKind::Directory => std::fs::remove_dir(&dest)?,
+                    _ if self.keep(planned)? => (),
+                    _ => std::fs::remove_file(&dest)?,
+                }
+                Ok(None)
+            }
+            WorkItem::Rename {
+                from, dest_path, ..
+            } => {
+                let (old, new) = (root.join(from), root.join(dest_path));
+                self.check(planned, &old)?;
+                if observe(&new)?.is_some() {
+                    return Err(Skip::Raced("something else has the new name".to_string()));
+                }
+                if let Some(parent) = new.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::rename(&old, &new)?;
+                Ok(observe(&new)?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn copy_into_place(
+        &self,
+        planned: &Planned,
+        source: &Path,
+        tmp: &Path,
+        dest: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<(), Skip> {
+        std::fs::copy(source, tmp)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(tmp, std::fs::Permissions::from_mode(metadata.mode))?;
+        }
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(metadata.mtime);
+        if let Err(e) = std::fs::File::options()
+            .write(true)
+            .open(tmp)
+            .and_then(|f| f.set_modified(mtime))
+        {
+            tracing::debug!("couldn't set mtime on {}: {}", tmp.display(), e);
+        }
+
+        // Both ends get checked again right before the rename, the source so
+        // we didn't copy something mid write and the target so we don't
+        // clobber something that changed while we were copying.
+        self.check_source(planned, source)?;
+        self.check(planned, dest)?;
+        self.replace(planned, tmp, dest)
+    }
+
+    // Rename tmp over dest, an empty dir in the way (a dir replaced by a file
+    // on the other side) has to go first.
+    fn replace(&self, planned: &Planned, tmp: &Path, dest: &Path) -> Result<(), Skip> {
+        if planned
+            .expect
+            .as_ref()
+            .is_some_and(|e| e.kind() == FileKind::Directory)
+        {
+            std::fs::remove_dir(dest)?;
+        } else {
+            self.keep(planned)?;
+        }
+        std::fs::rename(tmp, dest)?;
+        Ok(())
+    }
+
+    /// Everything under one root, minus excludes and anything we can't sync
+    fn scan(&self, side: Side) -> std::io::Result<Tree> {
+        let root = self.root(side);
+        let mut tree = Tree::new();
+        let mut stack = vec![PathBuf::new()];
+
+        while let Some(relative) = stack.pop() {
+            let entries = match std::fs::read_dir(root.join(&relative)) {
+                Ok(entries) => entries,
+                // The root not being there is fatal, anything under it is
+                // just skipped.
+                Err(e) if relative.as_os_str().is_empty() => return Err(e),
+                Err(e) => {
+                    self.error(
+                        side,
+                        format!("can't read directory: {}", e),
+                        root.join(&relative),
+                    );
+                    continue;
+                }
+            };
+
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+                if name.to_string_lossy().starts_with(TMP_PREFIX) {
+                    continue;
+                }
+                let path = relative.join(&name);
+
+                let Ok(Some(observed)) = observe(&entry.path()) else {
+                    continue;
+                };
+
+                match observed.kind() {
+                    FileKind::Directory => {
+                        if self.exclude.should_exclude_dir_path(&path) {
+                            continue;
+                        }
+                        stack.push(path.clone());
+                    }
+                    FileKind::File | FileKind::Symlink => {
+                        if self.exclude.should_exclude_file_path(&path) {
+                            continue;
+                        }
+                    }
+                    FileKind::Special | FileKind::Unknown => {
+                        self.progress.skipped_count.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                }
+                tree.insert(path, observed);
+            }
+        }
+
+        Ok(tree)
+    }
+}
+
+// One side's share of planned work, the writer pool calls apply() for each of
+// its items and we keep track of how that went.
+struct Round {
+    two_way: TwoWay,
+    planned: HashMap<PathBuf, Planned>,
+    applied: parking_lot::Mutex<HashMap<PathBuf, Observed>>,
+    raced: parking_lot::Mutex<Vec<PathBuf>>,
+}
+
+impl Apply for Round {
+    fn apply(&self, item: &WorkItem) -> Result<(), IoError> {
+        let Some((path, planned)) = item
+            .dest_path()
+            .and_then(|path| self.planned.get_key_value(path))
+        else {
+            return Ok(());
+        };
+
+        match self.two_way.applied(planned) {
+            Ok(after) => {
+                if let Some(after) = after {
+                    self.applied.lock().insert(path.clone(), after);
+                }
+                Ok(())
+            }
+            Err(Skip::Raced(why)) => {
+                raced(planned, path, &why);
+                self.raced.lock().push(path.clone());
+                Ok(())
+            }
+            Err(Skip::Failed(e)) => Err(io_error(
+                planned.target,
+                format!("two-way sync failed: {}", e),
+                self.two_way.root(planned.target).join(path),
+            )),
+        }
+    }
+}
+
+fn raced(planned: &Planned, path: &Path, why: &str) {
+    tracing::info!(
+        "{} changed on {} during sync, leaving it for next time: {}",
+        path.display(),
+        planned.target,
+        why
+    );
+}
+
+// Errors are by side, lhs is the source and rhs the dest as far as the rest
+// of the daemon is concerned
+fn io_error(side: Side, msg: String, path: PathBuf) -> IoError {
+    match side {
+        Side::Lhs => IoError::source(msg, path),
+        Side::Rhs => IoError::destination(msg, path),
+    }
+}
+
+impl Planned {
+    fn target_exists(&self, two_way: &TwoWay) -> bool {
+        self.item
+            .dest_path()
+            .is_some_and(|p| two_way.root(self.target).join(p).symlink_metadata().is_ok())
+    }
+}
+
+pub(super) fn tmp_path(dest: &Path) -> PathBuf {
+    let name = dest
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    dest.with_file_name(format!("{}{}.{}", TMP_PREFIX, name, std::process::id()))
+}
+
+/// stat() a path without following symlinks, None if its not there
+pub fn observe(path: &Path) -> std::io::Result<Option<Observed>> {
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let file_type = meta.file_type();
+    let kind = if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_dir() {
+        FileKind::Directory
+    } else if file_type.is_file() {
+        FileKind::File
+    } else {
+        FileKind::Special
+    };
+
+    let target = if kind == FileKind::Symlink {
+        Some(std::fs::read_link(path)?)
+    } else {
+        None
+    };
+
+    #[cfg(unix)]
+    let (metadata, inode) = {
+        use std::os::unix::fs::MetadataExt;
+        (
+            FileMetadata {
+                path: path.to_path_buf(),
+                size: meta.len(),
+                mode: meta.mode(),
+                uid: meta.uid(),
+                gid: meta.gid(),
+                kind,
+                mtime: meta.mtime() as u64,
+            },
+            meta.ino(),
+        )
+    };
+
+    #[cfg(not(unix))]
+    let (metadata, inode) = (
+        FileMetadata {
+            path: path.to_path_buf(),
+            size: meta.len(),
+            kind,
+            mtime: meta
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        },
+        0,
+    );
+
+    Ok(Some(Observed {
+        metadata,
+        inode,
+        target,
+    }))
+}
+
+/// blake3 of a file's contents as hex, None if it can't be read
+pub fn hash_file(path: &Path) -> Option<String> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher).ok()?;
+    Some(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    fn dir(name: &str) -> TestDir {
+        let dir = TestDir::new(&format!("twoway-{}", name));
+        std::fs::create_dir_all(dir.join("lhs")).unwrap();
+        std::fs::create_dir_all(dir.join("rhs")).unwrap();
+        dir
+    }
+
+    fn two_way(dir: &Path) -> TwoWay {
+        TwoWay::new(
+            1,
+            dir.join("lhs"),
+            dir.join("rhs"),
+            dir.join("baseline.jsonl"),
+            Arc::new(AtomicOperationProgress::new()),
+            Arc::new(tokio::sync::Mutex::new(Vec::new())),
+        )
+    }
+
+    fn read(path: PathBuf) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    // mtimes are to the second, make sure a rewrite looks different
+    fn write_later(path: PathBuf, contents: &str) {
+        std::fs::write(&path, contents).unwrap();
+        let f = std::fs::File::options().write(true).open(&path).unwrap();
+        f.set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_two_way_round_trips() {
+        let dir = dir("roundtrip");
+        let (l, r) = (dir.join("lhs"), dir.join("rhs"));
+
+        std::fs::create_dir(l.join("sub")).unwrap();
+        std::fs::write(l.join("sub/a"), "a").unwrap();
+        std::fs::write(r.join("b"), "b").unwrap();
+        std::fs::write(l.join("same"), "same").unwrap();
+        std::fs::write(r.join("same"), "same").unwrap();
+
+        let report = two_way(&dir).run_blocking().unwrap();
+        assert_eq!((report.to_rhs, report.to_lhs), (2, 1));
+        assert!(report.conflicts.is_empty(), "{:?}", report.conflicts);
+        assert_eq!(read(r.join("sub/a")).as_deref(), Some("a"));
+        assert_eq!(read(l.join("b")).as_deref(), Some("b"));
+
+        // Nothing changed, nothing to do
+        let report = two_way(&dir).run_blocking().unwrap();
+        assert_eq!(report.to_rhs + report.to_lhs + report.removed, 0);
+        assert_eq!(report.unchanged, 4);
+
+        // Edit on one side, delete on the other
+        write_later(r.join("sub/a"), "a2");
+        std::fs::remove_file(l.join("b")).unwrap();
+        let report = two_way(&dir).run_blocking().unwrap();
+        assert_eq!((report.to_lhs, report.removed), (1, 1));
+        assert_eq!(read(l.join("sub/a")).as_deref(), Some("a2"));
+        assert!(!r.join("b").exists());
+    }
+
+    #[test]
+    fn test_removes_kids_before_dirs() {
+        let dir = dir("nested");
+        let (l, r) = (dir.join("lhs"), dir.join("rhs"));
+
+        std::fs::create_dir_all(l.join("a/b/c")).unwrap();
+        std::fs::write(l.join("a/1"), "1").unwrap();
+        std::fs::write(l.join("a/b/2"), "2").unwrap();
+        std::fs::write(l.join("a/b/c/3"), "3").unwrap();
+        let report = two_way(&dir).run_blocking().unwrap();
+        assert_eq!(report.to_rhs, 6);
+        assert_eq!(read(r.join("a/b/c/3")).as_deref(), Some("3"));
+
+        std::fs::remove_dir_all(l.join("a")).unwrap();
+        let report = two_way(&dir).run_blocking().unwrap();
+        assert_eq!(report.removed, 6, "{:?}", report);
+        assert!(!r.join("a").exists());
+    }
+
+    #[test]
+    fn test_trash_keeps_overwritten_and_removed() {
+        use super::super::trash::{TRASH_DIR, versions};
+
+        let dir = dir("trash");
+        let (l, r) = (dir.join("lhs"), dir.join("rhs"));
+        let config = TrashConfig::default();
+        let two_way = |dir: &Path| two_way(dir).with_trash(Some(&config));
+
+        std::fs::write(l.join("a"), "a").unwrap();
+        std::fs::write(l.join("b"), "b").unwrap();
+        two_way(&dir).run_blocking().unwrap();
+
+        write_later(r.join("a"), "a2");
+        std::fs::remove_file(l.join("b")).unwrap();
+        let report = two_way(&dir).run_blocking().unwrap();
+        assert_eq!((report.to_lhs, report.removed), (1, 1));
+
+        let old_a = versions(&l.join(TRASH_DIR), Path::new("a")).unwrap();
+        assert_eq!(old_a.len(), 1);
+        assert_eq!(read(old_a[0].path.clone()).as_deref(), Some("a"));
+        let old_b = versions(&r.join(TRASH_DIR), Path::new("b")).unwrap();
+        assert_eq!(read(old_b[0].path.clone()).as_deref(), Some("b"));
+
+        // Trash stays on its own side
+        let report = two_way(&dir).run_blocking().unwrap();
+        assert_eq!(report.to_rhs + report.to_lhs + report.removed, 0);
+        assert!(
+            versions(&r.join(TRASH_DIR), Path::new("a"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_moves_are_renamed_not_copied() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = dir("moves");
+        let (l, r) = (dir.join("lhs"), dir.join("rhs"));
+
+        std::fs::create_dir(l.join("old")).unwrap();
+        std::fs::write(l.join("old/a"), "a").unwrap();
+        std::fs::write(l.join("old/b"), "b").unwrap();
+        two_way(&dir).run_blocking().unwrap();
+        let inode = std::fs::metadata(r.join("old/a")).unwrap().ino();
+
+        // Move the dir and edit one thing in it while we're at it
+        std::fs::create_dir(l.join("new")).unwrap();
+        std::fs::rename(l.join("old"), l.join("new/moved")).unwrap();
+        write_later(l.join("new/moved/b"), "b2");
+
+        let report = two_way(&dir).run_blocking().unwrap();
+        assert_eq!(report.renamed, 1);
+        assert_eq!((report.to_rhs, report.removed), (1, 0), "{:?}", report);
+        assert!(report.raced.is_empty(), "{:?}", report.raced);
+        assert!(!r.join("old").exists());
+        assert_eq!(
+            std::fs::metadata(r.join("new/moved/a")).unwrap().ino(),
+            inode,
+            "a was renamed into place, not copied"
+        );
+        assert_eq!(read(r.join("new/moved/b")).as_deref(), Some("b2"));
+
+        // Settled
+        let report = two_way(&dir).run_blocking().unwrap();
+        assert_eq!(report.renamed + report.to_rhs + report.to_lhs, 0);
+    }
+
+    #[test]
+    fn test_two_way_conflicts_lose_nothing() {
+        let dir = dir("conflict");
+        let (l, r) = (dir.join("lhs"), dir.join("rhs"));
+
+        std::fs::write(l.join("both"), "base").unwrap();
+        std::fs::write(l.join("delmod"), "base").unwrap();
+        two_way(&dir).run_blocking().unwrap();
+
+        write_later(l.join("both"), "lhs edit");
+        write_later(r.join("both"), "rhs edit, longer");
+        std::fs::remove_file(l.join("delmod")).unwrap();
+        write_later(r.join("delmod"), "rhs edit");
+
+        let report = two_way(&dir).run_blocking().unwrap();
+        let mut conflicts = report.conflicts.clone();
+        conflicts.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            conflicts,
+            vec![
+                (PathBuf::from("both"), ConflictKind::BothChanged),
+                (
+                    PathBuf::from("delmod"),
+                    ConflictKind::DeleteChange { deleted: Side::Lhs }
+                ),
+            ]
+        );
+
+        // Both edits are still where they were
+        assert_eq!(read(l.join("both")).as_deref(), Some("lhs edit"));
+        assert_eq!(read(r.join("both")).as_deref(), Some("rhs edit, longer"));
+        assert_eq!(read(r.join("delmod")).as_deref(), Some("rhs edit"));
+
+        // And still conflicts next time around
+        let report = two_way(&dir).run_blocking().unwrap();
+        assert_eq!(report.conflicts.len(), 2);
+    }
+
+    #[test]
+    fn test_guard_skips_changed_target() {
+        let dir = dir("guard");
+        let (l, r) = (dir.join("lhs"), dir.join("rhs"));
+        std::fs::write(l.join("f"), "one").unwrap();
+        two_way(&dir).run_blocking().unwrap();
+
+        write_later(l.join("f"), "two");
+        let tw = two_way(&dir);
+        let baseline = Baseline::open(dir.join("baseline.jsonl")).unwrap();
+        let lhs = tw.scan(Side::Lhs).unwrap();
+        let rhs = tw.scan(Side::Rhs).unwrap();
+        let decisions = super::super::reconcile::reconcile(&baseline, &lhs, &rhs, |_, _| None);
+        let planned = decisions[0].plan(1, |s| tw.root(s).to_path_buf()).unwrap();
+
+        // Someone edits rhs after we decided to overwrite it
+        write_later(r.join("f"), "rhs snuck in");
+        let mut report = TwoWayReport::default();
+        assert!(tw.apply(&planned, &mut report).is_none());
+        assert_eq!(report.raced, vec![PathBuf::from("f")]);
+        assert_eq!(read(r.join("f")).as_deref(), Some("rhs snuck in"));
+    }
+}
@@ -0,0 +1,1039 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration, SystemTime};
+
+// Continuous syncing, aka the whole point of this thing. A watcher sits on
+// the source tree and hands back absolute paths that changed, the io subsystem
+// turns those into incremental WorkItems through the reader.
+//
+// Backends in the order auto tries them:
+// - inotify: a watch per directory, works for normal users but big trees can
+//   run into fs.inotify.max_user_watches. If that happens we fall back to the
+//   next one instead of half watching things.
+// - fanotify: one mark for the whole mount, no per directory watches to run
+//   out of. Needs CAP_SYS_ADMIN so mostly a root daemon thing. Mount marks only
+//   report file writes, not dirs being made/renamed, so a dir only mtime poll
+//   runs alongside it to catch that. That poll wakes up every interval so its
+//   only first if asked for by name.
+// - poll: walk the tree every so often and compare mtime/size. Slow, dumb and
+//   works everywhere which is the point. The "worst case" fallback past mitch
+//   kept writing TODOs about in main.rs.
+//
+// Removals are still not a thing, anything that vanishes is just ignored.
+// Renames inside the tree are though, but only inotify can pair up both ends
+// of one, everything else sees a new name show up and the rescan copies it.
+//
+// Idle should mean asleep. inotify blocks in poll() until the kernel or a stop
+// says otherwise, no timeouts. Polling wakes up once per interval by
+// definition, as does the fanotify dir poll, which is the price of not having
+// anything better.
+
+/// How often the polling backend walks the tree
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Which mechanism to watch the source with, Auto picks the best available
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WatchBackend {
+    #[default]
+    Auto,
+    Inotify,
+    Fanotify,
+    Poll,
+}
+
+impl WatchBackend {
+    fn to_u8(self) -> u8 {
+        match self {
+            WatchBackend::Auto => 0,
+            WatchBackend::Inotify => 1,
+            WatchBackend::Fanotify => 2,
+            WatchBackend::Poll => 3,
+        }
+    }
+
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => WatchBackend::Inotify,
+            2 => WatchBackend::Fanotify,
+            3 => WatchBackend::Poll,
+            _ => WatchBackend::Auto,
+        }
+    }
+}
+
+impl std::str::FromStr for WatchBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(WatchBackend::Auto),
+            "inotify" => Ok(WatchBackend::Inotify),
+            "fanotify" => Ok(WatchBackend::Fanotify),
+            "poll" | "polling" => Ok(WatchBackend::Poll),
+            _ => Err(format!(
+                "unknown watch backend '{}', expected one of auto, inotify, fanotify, poll",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for WatchBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchBackend::Auto => write!(f, "auto"),
+            WatchBackend::Inotify => write!(f, "inotify"),
+            WatchBackend::Fanotify => write!(f, "fanotify"),
+            WatchBackend::Poll => write!(f, "poll"),
+        }
+    }
+}
+
+/// How a sync wants its source watched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchConfig {
+    pub backend: WatchBackend,
+    pub poll_interval: Duration,
+    /// How changes get coalesced before they're synced
+    pub debounce: super::debounce::DebounceConfig,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            backend: WatchBackend::default(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            debounce: super::debounce::DebounceConfig::default(),
+        }
+    }
+}
+
+/// What the watcher saw happen, paths are absolute
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// Something at or under path changed
+    Changed(PathBuf),
+    /// from was moved to to, both inside the watched tree. A Changed for to
+    /// follows so anything the rename can't handle still gets synced.
+    Renamed { from: PathBuf, to: PathBuf },
+}
+
+/// Drop any path that has an ancestor in the list, dirs get rescanned whole
+/// so the kids would just be done twice. Returned sorted.
+pub fn collapse(mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    paths.sort();
+    paths.dedup();
+
+    let mut out: Vec<PathBuf> = Vec::with_capacity(paths.len());
+    for path in paths {
+        // Sorted, so an ancestor is always somewhere before its kids. Only
+        // need to check the last kept one since anything between would be
+        // under it too.
+        if out.last().is_some_and(|last| path.starts_with(last)) {
+            continue;
+        }
+        out.push(path);
+    }
+    out
+}
+
+/// Background thread watching a source tree, what changed goes out tx. Stops
+/// when dropped or when nobody is listening on the other end anymore.
+pub struct Watcher {
+    stop: Arc<Stop>,
+    backend: Arc<AtomicU8>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Watcher {
+    pub fn start(
+        root: PathBuf,
+        config: WatchConfig,
+        tx: std::sync::mpsc::Sender<WatchEvent>,
+    ) -> std::io::Result<Self> {
+        let stop = Arc::new(Stop::new()?);
+        let backend = Arc::new(AtomicU8::new(WatchBackend::Auto.to_u8()));
+
+        let thread_stop = stop.clone();
+        let thread_backend = backend.clone();
+        let handle = std::thread::Builder::new()
+            .name("yeet-watcher".to_string())
+            .spawn(move || run(root, config, tx, thread_stop, thread_backend))?;
+
+        Ok(Self {
+            stop,
+            backend,
+            handle: Some(handle),
+        })
+    }
+
+    /// What we're actually watching with, can change if we had to fall back
+    pub fn backend(&self) -> WatchBackend {
+        WatchBackend::from_u8(self.backend.load(Ordering::Relaxed))
+    }
+
+    pub fn stop(&mut self) {
+        self.stop.set();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Why a backend loop returned
+enum Outcome {
+    // Asked to stop or the receiver went away
+    Stopped,
+    // Backend gave up, try the next one
+    Fallback(String),
+}
+
+// Tells the watcher thread to quit. Anything in the watcher that waits, waits
+// on this too, so stopping doesn't depend on something timing out.
+struct Stop {
+    stopped: std::sync::Mutex<bool>,
+    cond: std::sync::Condvar,
+    // poll() can't wait on a condvar so linux gets a pipe too, the read end
+    // goes readable once we're stopped.
+    #[cfg(target_os = "linux")]
+    pipe: (std::os::fd::OwnedFd, std::os::fd::OwnedFd),
+}
+
+impl Stop {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            stopped: std::sync::Mutex::new(false),
+            cond: std::sync::Condvar::new(),
+            #[cfg(target_os = "linux")]
+            pipe: {
+                use std::os::fd::FromRawFd;
+
+                let mut fds = [0 as libc::c_int; 2];
+                if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                unsafe {
+                    (
+                        std::os::fd::OwnedFd::from_raw_fd(fds[0]),
+                        std::os::fd::OwnedFd::from_raw_fd(fds[1]),
+                    )
+                }
+            },
+        })
+    }
+
+    fn set(&self) {
+        let mut stopped = self.stopped.lock().unwrap_or_else(|e| e.into_inner());
+        if *stopped {
+            return;
+        }
+        *stopped = true;
+        self.cond.notify_all();
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            let byte = 1u8;
+            unsafe {
+                libc::write(
+                    self.pipe.1.as_raw_fd(),
+                    &byte as *const u8 as *const libc::c_void,
+                    1,
+                )
+            };
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        *self.stopped.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Sleep for duration or until stopped, whichever is first.
+    fn sleep(&self, duration: Duration) {
+        let stopped = self.stopped.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = self.cond.wait_timeout_while(stopped, duration, |s| !*s);
+    }
+}
+
+// Sends events out, stops the watcher if nobody is listening anymore.
+struct Emitter {
+    tx: std::sync::mpsc::Sender<WatchEvent>,
+    stop: Arc<Stop>,
+}
+
+impl Emitter {
+    fn emit(&self, path: PathBuf) {
+        self.send(WatchEvent::Changed(path));
+    }
+
+    fn send(&self, event: WatchEvent) {
+        tracing::trace!("watch: {:?}", event);
+        if self.tx.send(event).is_err() {
+            self.stop.set();
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.is_set()
+    }
+}
+
+fn run(
+    root: PathBuf,
+    config: WatchConfig,
+    tx: std::sync::mpsc::Sender<WatchEvent>,
+    stop: Arc<Stop>,
+    backend: Arc<AtomicU8>,
+) {
+    let emitter = Emitter { tx, stop };
+    let set = |b: WatchBackend| {
+        tracing::info!("watching {} with {}", root.display(), b);
+        backend.store(b.to_u8(), Ordering::Relaxed);
+    };
+
+    #[cfg(target_os = "linux")]
+    {
+        // Auto goes with inotify first, it sleeps until the kernel says
+        // something happened where fanotify needs its dir poll. fanotify still
+        // beats polling the whole tree if inotify runs out of watches.
+        if config.backend == WatchBackend::Fanotify
+            && matches!(
+                watch_fanotify(&root, &config, &emitter, &set),
+                Outcome::Stopped
+            )
+        {
+            return;
+        }
+
+        if config.backend != WatchBackend::Poll {
+            match inotify::Inotify::new(&root) {
+                Ok(ino) => {
+                    set(WatchBackend::Inotify);
+                    match run_inotify(ino, &root, &emitter) {
+                        Outcome::Stopped => return,
+                        Outcome::Fallback(why) => {
+                            tracing::warn!("inotify gave up on {}: {}", root.display(), why);
+                            emitter.emit(root.clone());
+                        }
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("inotify unavailable for {}: {}", root.display(), e);
+                    if is_watch_limit(&e) {
+                        emitter.emit(root.clone());
+                    }
+                }
+            }
+        }
+
+        if config.backend == WatchBackend::Auto
+            && matches!(
+                watch_fanotify(&root, &config, &emitter, &set),
+                Outcome::Stopped
+            )
+        {
+            return;
+        }
+    }
+
+    set(WatchBackend::Poll);
+    let mut poller = Poller::new(root, true);
+    run_poll(&mut poller, config.poll_interval, &emitter);
+}
+
+// Fallback means try whatever is next, including fanotify not being there at
+// all (needs CAP_SYS_ADMIN).
+#[cfg(target_os = "linux")]
+fn watch_fanotify(
+    root: &Path,
+    config: &WatchConfig,
+    emitter: &Emitter,
+    set: &dyn Fn(WatchBackend),
+) -> Outcome {
+    match fanotify::Fanotify::new(root) {
+        Ok(fan) => {
+            set(WatchBackend::Fanotify);
+            let outcome = run_fanotify(fan, root, config.poll_interval, emitter);
+            if let Outcome::Fallback(why) = &outcome {
+                tracing::warn!("fanotify gave up on {}: {}", root.display(), why);
+                // Whatever happened we might have missed stuff
+                emitter.emit(root.to_path_buf());
+            }
+            outcome
+        }
+        Err(e) => {
+            if config.backend == WatchBackend::Fanotify {
+                tracing::warn!("fanotify unavailable: {}", e);
+            } else {
+                tracing::debug!("fanotify unavailable (needs CAP_SYS_ADMIN): {}", e);
+            }
+            Outcome::Fallback(e.to_string())
+        }
+    }
+}
+
+// Ran out of inotify watches (or kernel memory for them), not much else to do
+// but poll.
+#[cfg(target_os = "linux")]
+fn is_watch_limit(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ENOSPC) | Some(libc::ENOMEM))
+}
+
+fn run_poll(poller: &mut Poller, interval: Duration, emitter: &Emitter) {
+    while !emitter.stopped() {
+        poller.pass(&mut |p| emitter.emit(p));
+        emitter.stop.sleep(interval);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn run_inotify(mut ino: inotify::Inotify, root: &Path, emitter: &Emitter) -> Outcome {
+    loop {
+        if emitter.stopped() {
+            return Outcome::Stopped;
+        }
+
+        // Nothing to do until the kernel says so
+        match ino.read(&emitter.stop, None, &mut |e| emitter.send(e)) {
+            Ok(true) => {
+                tracing::warn!(
+                    "inotify queue overflowed for {}, rescanning it all",
+                    root.display()
+                );
+                emitter.emit(root.to_path_buf());
+            }
+            Ok(false) => (),
+            Err(e) if is_watch_limit(&e) => {
+                return Outcome::Fallback(format!(
+                    "ran out of watches, see fs.inotify.max_user_watches: {}",
+                    e
+                ));
+            }
+            Err(e) => return Outcome::Fallback(e.to_string()),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn run_fanotify(
+    fan: fanotify::Fanotify,
+    root: &Path,
+    dir_interval: Duration,
+    emitter: &Emitter,
+) -> Outcome {
+    // Dirs only, fanotify already has file writes covered
+    let mut dirs = Poller::new(root.to_path_buf(), false);
+    dirs.pass(&mut |_| ());
+    let mut next_poll = std::time::Instant::now() + dir_interval;
+
+    loop {
+        if emitter.stopped() {
+            return Outcome::Stopped;
+        }
+
+        let until_poll = next_poll.saturating_duration_since(std::time::Instant::now());
+        match fan.read(&emitter.stop, Some(until_poll), &mut |p| emitter.emit(p)) {
+            Ok(true) => {
+                tracing::warn!(
+                    "fanotify queue overflowed for {}, rescanning it all",
+                    root.display()
+                );
+                emitter.emit(root.to_path_buf());
+            }
+            Ok(false) => (),
+            Err(e) => return Outcome::Fallback(e.to_string()),
+        }
+
+        if std::time::Instant::now() >= next_poll {
+            dirs.pass(&mut |p| emitter.emit(p));
+            next_poll = std::time::Instant::now() + dir_interval;
+        }
+    }
+}
+
+// What we compare between passes, mtime and size like quick-check
+type Stamp = (Option<SystemTime>, u64);
+
+/// Tree walker that reports what changed since the last pass. The first pass
+/// just records what is there.
+///
+/// With files on every file gets stat()ed each pass to catch in place writes.
+/// With files off only directories whose mtime changed get looked at, all of
+/// their non dir entries are reported, quick-check sorts out what actually
+/// changed.
+pub struct Poller {
+    root: PathBuf,
+    files: bool,
+    dirs: HashMap<PathBuf, Option<SystemTime>>,
+    stamps: HashMap<PathBuf, Stamp>,
+    primed: bool,
+}
+
+impl Poller {
+    pub fn new(root: PathBuf, files: bool) -> Self {
+        Self {
+            root,
+            files,
+            dirs: HashMap::new(),
+            stamps: HashMap::new(),
+            primed: false,
+        }
+    }
+
+    pub fn pass(&mut self, emit: &mut impl FnMut(PathBuf)) {
+        let primed = self.primed;
+        let mut dirs = HashMap::with_capacity(self.dirs.len());
+        let mut stamps = HashMap::with_capacity(self.stamps.len());
+        let mut stack = vec![self.root.clone()];
+
+        while let Some(dir) = stack.pop() {
+            let mtime = std::fs::metadata(&dir).and_then(|m| m.modified()).ok();
+            let changed = self.dirs.get(&dir) != Some(&mtime);
+            dirs.insert(dir.clone(), mtime);
+
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+
+            for entry in entries.flatten() {
+                let Ok(file_type) = entry.file_type() else {
+                    continue;
+                };
+                let path = entry.path();
+
+                if file_type.is_dir() {
+                    if primed && !self.dirs.contains_key(&path) {
+                        emit(path.clone());
+                    }
+                    stack.push(path);
+                    continue;
+                }
+
+                if self.files {
+                    let Ok(meta) = std::fs::symlink_metadata(&path) else {
+                        continue;
+                    };
+                    let stamp = (meta.modified().ok(), meta.len());
+                    if primed && self.stamps.get(&path) != Some(&stamp) {
+                        emit(path.clone());
+                    }
+                    stamps.insert(path, stamp);
+                } else if primed && changed {
+                    emit(path);
+                }
+            }
+        }
+
+        // Swapping in the new maps drops anything that went away
+        self.dirs = dirs;
+        self.stamps = stamps;
+        self.primed = true;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use super::WatchEvent;
+
+    // Files get reported once they're closed after writing so we don't copy
+    // half of something. Creates only matter for dirs/symlinks, regular
+    // files will have a close write coming. Moved from is only for pairing
+    // up renames with the moved to.
+    const MASK: u32 = libc::IN_CREATE
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_ATTRIB
+        | libc::IN_DONT_FOLLOW
+        | libc::IN_EXCL_UNLINK;
+
+    pub struct Inotify {
+        fd: OwnedFd,
+        watches: HashMap<i32, PathBuf>,
+        /// Moved from paths by cookie waiting on their moved to
+        moves: HashMap<u32, PathBuf>,
+    }
+
+    impl Inotify {
+        pub fn new(root: &Path) -> std::io::Result<Self> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            let mut ino = Self {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                watches: HashMap::new(),
+                moves: HashMap::new(),
+            };
+            ino.add_tree(root)?;
+            Ok(ino)
+        }
+
+        /// Watch dir and everything under it. Only running out of watches is
+        /// an error, dirs vanishing or being unreadable are just skipped.
+        fn add_tree(&mut self, dir: &Path) -> std::io::Result<()> {
+            let mut stack = vec![dir.to_path_buf()];
+            while let Some(dir) = stack.pop() {
+                match self.add_watch(&dir) {
+                    Ok(()) => (),
+                    Err(e) if super::is_watch_limit(&e) => return Err(e),
+                    Err(e) => {
+                        tracing::debug!("can't watch {}: {}", dir.display(), e);
+                        continue;
+                    }
+                }
+
+                let Ok(entries) = std::fs::read_dir(&dir) else {
+                    continue;
+                };
+                for entry in entries.flatten() {
+                    if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                        stack.push(entry.path());
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn add_watch(&mut self, dir: &Path) -> std::io::Result<()> {
+            let path = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+            let wd = unsafe {
+                libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), MASK | libc::IN_ONLYDIR)
+            };
+            if wd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            self.watches.insert(wd, dir.to_path_buf());
+            Ok(())
+        }
+
+        /// Wait for events, up to timeout if there is one, and emit whatever
+        /// changed. Returns true if the kernel queue overflowed and events
+        /// were lost.
+        pub(super) fn read(
+            &mut self,
+            stop: &super::Stop,
+            timeout: Option<Duration>,
+            emit: &mut impl FnMut(WatchEvent),
+        ) -> std::io::Result<bool> {
+            if !super::wait_readable(&self.fd, stop, timeout)? {
+                return Ok(false);
+            }
+
+            let mut buf = vec![0u8; 64 * 1024];
+            let n = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if n < 0 {
+                let e = std::io::Error::last_os_error();
+                return match e.kind() {
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(e),
+                };
+            }
+
+            let header = std::mem::size_of::<libc::inotify_event>();
+            let mut overflow = false;
+            let mut offset = 0usize;
+
+            while offset + header <= n as usize {
+                let event: libc::inotify_event = unsafe {
+                    std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                let name_start = offset + header;
+                let name_end = name_start + event.len as usize;
+                offset = name_end;
+
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    overflow = true;
+                    continue;
+                }
+
+                if event.mask & libc::IN_IGNORED != 0 {
+                    self.watches.remove(&event.wd);
+                    continue;
+                }
+
+                let Some(dir) = self.watches.get(&event.wd) else {
+                    continue;
+                };
+
+                let name = &buf[name_start..name_end.min(n as usize)];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                let path = if name.is_empty() {
+                    dir.clone()
+                } else {
+                    dir.join(std::ffi::OsStr::from_bytes(name))
+                };
+
+                if event.mask & libc::IN_MOVED_FROM != 0 {
+                    self.moves.insert(event.cookie, path);
+                    continue;
+                }
+
+                if event.mask & libc::IN_MOVED_TO != 0
+                    && let Some(from) = self.moves.remove(&event.cookie)
+                {
+                    emit(WatchEvent::Renamed {
+                        from,
+                        to: path.clone(),
+                    });
+                }
+
+                let is_dir = event.mask & libc::IN_ISDIR != 0;
+
+                if is_dir && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    // Anything made in there before the watch lands gets
+                    // picked up by the rescan of the whole dir. Re-adding a
+                    // renamed dir hands back the same wd so the watches
+                    // under it get their new paths too.
+                    self.add_tree(&path)?;
+                    emit(WatchEvent::Changed(path));
+                } else if event.mask & libc::IN_CREATE != 0 {
+                    // TODO: hard links only get a create too, future mitch
+                    // problem if anyone cares.
+                    if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink()) {
+                        emit(WatchEvent::Changed(path));
+                    }
+                } else {
+                    emit(WatchEvent::Changed(path));
+                }
+            }
+
+            // Whatever didn't get a moved to left the tree, we don't do
+            // deletes one way so just forget them. The kernel puts both
+            // halves of a rename next to each other, so one split across
+            // reads is rare and just means a copy instead of a rename.
+            self.moves.clear();
+
+            Ok(overflow)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod fanotify {
+    use std::ffi::CString;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    pub struct Fanotify {
+        fd: OwnedFd,
+        root: PathBuf,
+    }
+
+    impl Fanotify {
+        /// EPERM without CAP_SYS_ADMIN, which is the normal case
+        pub fn new(root: &Path) -> std::io::Result<Self> {
+            let fd = unsafe {
+                libc::fanotify_init(
+                    libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK,
+                    (libc::O_RDONLY | libc::O_LARGEFILE | libc::O_CLOEXEC) as u32,
+                )
+            };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            let path = CString::new(root.as_os_str().as_bytes())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+            // Mount marks see everything on the fs, we filter down to root
+            // when reading.
+            let rc = unsafe {
+                libc::fanotify_mark(
+                    fd.as_raw_fd(),
+                    libc::FAN_MARK_ADD | libc::FAN_MARK_MOUNT,
+                    libc::FAN_CLOSE_WRITE,
+                    libc::AT_FDCWD,
+                    path.as_ptr(),
+                )
+            };
+            if rc < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            Ok(Self {
+                fd,
+                root: root.to_path_buf(),
+            })
+        }
+
+        /// Same deal as Inotify::read()
+        pub(super) fn read(
+            &self,
+            stop: &super::Stop,
+            timeout: Option<Duration>,
+            emit: &mut impl FnMut(PathBuf),
+        ) -> std::io::Result<bool> {
+            if !super::wait_readable(&self.fd, stop, timeout)? {
+                return Ok(false);
+            }
+
+            let mut buf = vec![0u8; 64 * 1024];
+            let n = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if n < 0 {
+                let e = std::io::Error::last_os_error();
+                return match e.kind() {
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(e),
+                };
+            }
+
+            let header = std::mem::size_of::<libc::fanotify_event_metadata>();
+            let mut overflow = false;
+            let mut offset = 0usize;
+
+            while offset + header <= n as usize {
+                let event: libc::fanotify_event_metadata = unsafe {
+                    std::ptr::read_unaligned(
+                        buf[offset..].as_ptr() as *const libc::fanotify_event_metadata
+                    )
+                };
+                if event.event_len < header as u32 {
+                    break;
+                }
+                offset += event.event_len as usize;
+
+                if event.vers != libc::FANOTIFY_METADATA_VERSION {
+                    return Err(std::io::Error::other(format!(
+                        "fanotify metadata version {} unsupported",
+                        event.vers
+                    )));
+                }
+
+                if event.mask & libc::FAN_Q_OVERFLOW != 0 {
+                    overflow = true;
+                }
+
+                if event.fd == libc::FAN_NOFD {
+                    continue;
+                }
+
+                // We own the fd the kernel handed us, close it no matter what
+                let fd = unsafe { OwnedFd::from_raw_fd(event.fd) };
+                let path = std::fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+                drop(fd);
+
+                if let Ok(path) = path
+                    && path.starts_with(&self.root)
+                {
+                    emit(path);
+                }
+            }
+
+            Ok(overflow)
+        }
+    }
+}
+
+// poll() fd for reading until it is, we're stopped or the timeout is up. None
+// waits forever. False unless fd is readable.
+#[cfg(target_os = "linux")]
+fn wait_readable(
+    fd: &std::os::fd::OwnedFd,
+    stop: &Stop,
+    timeout: Option<Duration>,
+) -> std::io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let mut pfds = [
+        libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: stop.pipe.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    let timeout = match timeout {
+        // Round up so we don't spin on sub millisecond leftovers
+        Some(t) => t
+            .as_millis()
+            .saturating_add(1)
+            .min(libc::c_int::MAX as u128) as libc::c_int,
+        None => -1,
+    };
+    let rc = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, timeout) };
+    if rc < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() == std::io::ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(e);
+    }
+    Ok(pfds[0].revents & libc::POLLIN != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::time::Instant;
+
+    fn drain(rx: &std::sync::mpsc::Receiver<WatchEvent>, want: &[WatchEvent]) -> Vec<WatchEvent> {
+        let mut seen = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline && !want.iter().all(|w| seen.contains(w)) {
+            if let Ok(e) = rx.recv_timeout(Duration::from_millis(100)) {
+                seen.push(e);
+            }
+        }
+        seen
+    }
+
+    fn changed(paths: &[PathBuf]) -> Vec<WatchEvent> {
+        paths.iter().cloned().map(WatchEvent::Changed).collect()
+    }
+
+    #[test]
+    fn test_collapse() {
+        let paths = vec![
+            PathBuf::from("/a/b/c"),
+            PathBuf::from("/a/b"),
+            PathBuf::from("/a/bc"),
+            PathBuf::from("/z"),
+            PathBuf::from("/a/b"),
+        ];
+        assert_eq!(
+            collapse(paths),
+            vec![
+                PathBuf::from("/a/b"),
+                PathBuf::from("/a/bc"),
+                PathBuf::from("/z")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_poller_sees_changes() {
+        let root = TestDir::new("watch-poller");
+        std::fs::write(root.join("same"), b"x").unwrap();
+        std::fs::write(root.join("grows"), b"x").unwrap();
+
+        let mut poller = Poller::new(root.to_path_buf(), true);
+        let mut seen = Vec::new();
+        poller.pass(&mut |p| seen.push(p));
+        assert!(seen.is_empty(), "first pass is a baseline");
+
+        std::fs::write(root.join("grows"), b"xx").unwrap();
+        std::fs::create_dir(root.join("newdir")).unwrap();
+        std::fs::write(root.join("newfile"), b"y").unwrap();
+
+        poller.pass(&mut |p| seen.push(p));
+        seen.sort();
+        assert_eq!(
+            seen,
+            vec![
+                root.join("grows"),
+                root.join("newdir"),
+                root.join("newfile")
+            ]
+        );
+
+        seen.clear();
+        poller.pass(&mut |p| seen.push(p));
+        assert!(seen.is_empty(), "nothing changed {:?}", seen);
+    }
+
+    #[test]
+    fn test_poll_watcher() {
+        let root = TestDir::new("watch-pollwatch");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let config = WatchConfig {
+            backend: WatchBackend::Poll,
+            poll_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut watcher = Watcher::start(root.to_path_buf(), config, tx).unwrap();
+
+        // Let the baseline pass happen first
+        std::thread::sleep(Duration::from_millis(200));
+        std::fs::write(root.join("file"), b"z").unwrap();
+
+        let want = changed(&[root.join("file")]);
+        let seen = drain(&rx, &want);
+        assert!(seen.contains(&want[0]), "{:?}", seen);
+        assert_eq!(watcher.backend(), WatchBackend::Poll);
+
+        watcher.stop();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_inotify_watcher() {
+        let root = TestDir::new("watch-inotify");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let config = WatchConfig {
+            backend: WatchBackend::Inotify,
+            ..Default::default()
+        };
+        let mut watcher = Watcher::start(root.to_path_buf(), config, tx).unwrap();
+
+        // Give the watches a moment to land
+        std::thread::sleep(Duration::from_millis(200));
+        if watcher.backend() != WatchBackend::Inotify {
+            eprintln!("inotify not usable here, skipping");
+            return;
+        }
+
+        std::fs::create_dir(root.join("sub")).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        std::fs::write(root.join("sub/file"), b"z").unwrap();
+
+        let want = changed(&[root.join("sub"), root.join("sub/file")]);
+        let seen = drain(&rx, &want);
+        assert!(want.iter().all(|w| seen.contains(w)), "{:?}", seen);
+
+        // Renames come through as a pair, with a change for the new name
+        std::fs::rename(root.join("sub"), root.join("moved")).unwrap();
+        let want = vec![
+            WatchEvent::Renamed {
+                from: root.join("sub"),
+                to: root.join("moved"),
+            },
+            WatchEvent::Changed(root.join("moved")),
+        ];
+        let seen = drain(&rx, &want);
+        assert!(want.iter().all(|w| seen.contains(w)), "{:?}", seen);
+
+        watcher.stop();
+    }
+}
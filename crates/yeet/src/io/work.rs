@@ -2,7 +2,9 @@ use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::path::PathBuf;
 
-use super::metadata::{DirMetadata, FileMetadata, SymlinkMetadata};
+use serde::{Deserialize, Serialize};
+
+use super::metadata::{DirMetadata, FileKind, FileMetadata, SymlinkMetadata};
 
 // TODO: Need to brain up how I an have a work stealing/priority queue for this
 // stuff that a user can control.
@@ -35,7 +37,7 @@ pub enum Priority {
 }
 
 /// Types of work that can be queued
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WorkItem {
     /// Create a directory
     CreateDir {
@@ -76,6 +78,22 @@ pub enum WorkItem {
         metadata: FileMetadata,
     },
 
+    /// Remove a file, symlink or empty directory, two-way syncs passing a
+    /// delete on to the other side. Non empty dirs are left alone.
+    Remove {
+        uuid: u128,
+        dest_path: PathBuf,
+        kind: FileKind,
+    },
+
+    /// Rename from to dest_path on the destination, both relative to the
+    /// root. A moved tree costs one rename(2) instead of a recopy.
+    Rename {
+        uuid: u128,
+        from: PathBuf,
+        dest_path: PathBuf,
+    },
+
     /// Sentinel: Reader has finished scanning a directory's immediate contents
     /// This allows the queue to mark children as ready for processing
     DirectoryScanned { uuid: u128, dest_path: PathBuf },
@@ -95,6 +113,8 @@ impl WorkItem {
             WorkItem::ApplyMetadata { .. } => Priority::Normal,
             WorkItem::CopySmallFile { .. } => Priority::Normal,
             WorkItem::CreateSymlink { .. } => Priority::Normal,
+            WorkItem::Remove { .. } => Priority::Normal,
+            WorkItem::Rename { .. } => Priority::Normal,
             WorkItem::CopyLargeFile { .. } => Priority::Bulk,
             // Sentinels are not queued for workers
             WorkItem::DirectoryScanned { .. } | WorkItem::ScanComplete { .. } => Priority::Normal,
@@ -111,6 +131,63 @@ impl WorkItem {
         matches!(self, WorkItem::CreateDir { .. })
     }
 
+    /// Rough idea of how much memory this item takes up, used for queue
+    /// budgets. Paths are the bulk of it, everything else is fixed size.
+    pub fn mem_size(&self) -> usize {
+        let paths = match self {
+            WorkItem::CreateDir {
+                source_path,
+                dest_path,
+                metadata,
+                ..
+            } => {
+                source_path.as_os_str().len()
+                    + dest_path.as_os_str().len()
+                    + metadata.path.as_os_str().len()
+            }
+            WorkItem::CopySmallFile {
+                source_path,
+                dest_path,
+                metadata,
+                ..
+            }
+            | WorkItem::CopyLargeFile {
+                source_path,
+                dest_path,
+                metadata,
+                ..
+            } => {
+                source_path.as_os_str().len()
+                    + dest_path.as_os_str().len()
+                    + metadata.path.as_os_str().len()
+            }
+            WorkItem::CreateSymlink {
+                source_path,
+                dest_path,
+                metadata,
+                ..
+            } => {
+                source_path.as_os_str().len()
+                    + dest_path.as_os_str().len()
+                    + metadata.path.as_os_str().len()
+                    + metadata.target.as_os_str().len()
+            }
+            WorkItem::ApplyMetadata {
+                dest_path,
+                metadata,
+                ..
+            } => dest_path.as_os_str().len() + metadata.path.as_os_str().len(),
+            WorkItem::Remove { dest_path, .. } | WorkItem::DirectoryScanned { dest_path, .. } => {
+                dest_path.as_os_str().len()
+            }
+            WorkItem::Rename {
+                from, dest_path, ..
+            } => from.as_os_str().len() + dest_path.as_os_str().len(),
+            WorkItem::ScanComplete { .. } => 0,
+        };
+        std::mem::size_of::<WorkItem>() + paths
+    }
+
     /// Check if this is a sentinel (not actual work)
     pub fn is_sentinel(&self) -> bool {
         matches!(
@@ -127,11 +204,29 @@ impl WorkItem {
             WorkItem::CopyLargeFile { uuid, .. } => *uuid,
             WorkItem::CreateSymlink { uuid, .. } => *uuid,
             WorkItem::ApplyMetadata { uuid, .. } => *uuid,
+            WorkItem::Remove { uuid, .. } => *uuid,
+            WorkItem::Rename { uuid, .. } => *uuid,
             WorkItem::DirectoryScanned { uuid, .. } => *uuid,
             WorkItem::ScanComplete { uuid } => *uuid,
         }
     }
 
+    /// Hand this item to another uuid, fan-out copies every item the scan
+    /// makes to each destination's own sync.
+    pub fn set_uuid(&mut self, to: u128) {
+        match self {
+            WorkItem::CreateDir { uuid, .. }
+            | WorkItem::CopySmallFile { uuid, .. }
+            | WorkItem::CopyLargeFile { uuid, .. }
+            | WorkItem::CreateSymlink { uuid, .. }
+            | WorkItem::ApplyMetadata { uuid, .. }
+            | WorkItem::Remove { uuid, .. }
+            | WorkItem::Rename { uuid, .. }
+            | WorkItem::DirectoryScanned { uuid, .. }
+            | WorkItem::ScanComplete { uuid } => *uuid = to,
+        }
+    }
+
     /// Get the destination path for this work item (None for ScanComplete)
     pub fn dest_path(&self) -> Option<&std::path::Path> {
         match self {
@@ -140,6 +235,8 @@ impl WorkItem {
             WorkItem::CopyLargeFile { dest_path, .. } => Some(dest_path),
             WorkItem::CreateSymlink { dest_path, .. } => Some(dest_path),
             WorkItem::ApplyMetadata { dest_path, .. } => Some(dest_path),
+            WorkItem::Remove { dest_path, .. } => Some(dest_path),
+            WorkItem::Rename { dest_path, .. } => Some(dest_path),
             WorkItem::DirectoryScanned { dest_path, .. } => Some(dest_path),
             WorkItem::ScanComplete { .. } => None,
         }
@@ -1,6 +1,10 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
 
+use super::schedule::{ScheduleKey, SchedulePolicy};
+use super::spill::Spill;
 use super::work::WorkItem;
 
 // TODO: This is the stupidest approach I could think of for now.
@@ -24,19 +28,39 @@ pub struct TreeWorkQueue {
     /// Ready directory work (parent dir exists or is root)
     ready_dirs: VecDeque<WorkItem>,
 
-    // TODO: Need to have a way to control how files are prioritized for this
-    // deque... size && date first maybe? Prioritize new/small crap over
-    // older/larger seems an ok approach methinks mayhaps.
-    /// Ready file work (parent dir is scanned and created)
-    ready_files_priority: VecDeque<WorkItem>,
+    /// Ready file work (parent dir is scanned and created), ordered by the
+    /// scheduling policy. Bulk files sort after normal ones in the same class.
+    ready_files: BTreeMap<ScheduleKey, WorkItem>,
 
-    /// Ready bulk file work
-    ready_files_bulk: VecDeque<WorkItem>,
+    /// Arrival time of ready files for aging, oldest first. Entries whose key
+    /// was already popped are skipped lazily.
+    ready_files_age: VecDeque<(Instant, ScheduleKey)>,
+
+    /// How ready files get ordered
+    policy: SchedulePolicy,
+
+    /// Arrival counter, keeps keys unique and ties FIFO
+    next_seq: u64,
 
     /// Pending work blocked on parent directory creation
     /// Key: parent directory path, Value: list of child work items
     blocked_on_parent: HashMap<PathBuf, Vec<WorkItem>>,
 
+    /// Blocked work that didn't fit in memory, see spill_threshold
+    spill: Option<Spill>,
+
+    /// Once blocked work in memory goes over this it gets spilled to disk
+    spill_threshold: usize,
+
+    /// Estimated memory used by ready and blocked work, WorkItem::mem_size()
+    ready_bytes: usize,
+    blocked_bytes: usize,
+
+    /// Poked whenever ready work is at or under low_water, aka the reader
+    /// can have at it again
+    low_water: usize,
+    drained: Arc<tokio::sync::Notify>,
+
     /// Directories that have been scanned (reader finished)
     scanned_dirs: HashSet<PathBuf>,
 
@@ -51,11 +75,20 @@ pub struct TreeWorkQueue {
 
     /// Whether scanning is complete
     scan_complete: bool,
+
+    /// Files that showed up after the initial scan, aka a watcher saw them
+    /// change. They go ahead of the backlog once ready.
+    touched: HashSet<PathBuf>,
 }
 
 impl TreeWorkQueue {
     /// Create a new tree-aware work queue
     pub fn new() -> Self {
+        Self::with_policy(SchedulePolicy::default())
+    }
+
+    /// Create a new tree-aware work queue with a specific scheduling policy
+    pub fn with_policy(policy: SchedulePolicy) -> Self {
         let mut scanned_dirs = HashSet::new();
         let mut created_dirs = HashSet::new();
 
@@ -65,17 +98,50 @@ impl TreeWorkQueue {
 
         Self {
             ready_dirs: VecDeque::new(),
-            ready_files_priority: VecDeque::new(),
-            ready_files_bulk: VecDeque::new(),
+            ready_files: BTreeMap::new(),
+            ready_files_age: VecDeque::new(),
+            policy,
+            next_seq: 0,
             blocked_on_parent: HashMap::new(),
+            spill: None,
+            spill_threshold: usize::MAX,
+            ready_bytes: 0,
+            blocked_bytes: 0,
+            low_water: 0,
+            drained: Arc::new(tokio::sync::Notify::new()),
             scanned_dirs,
             created_dirs,
             total_received: 0,
             _total_completed: 0,
             scan_complete: false,
+            touched: HashSet::new(),
         }
     }
 
+    /// Spill blocked work to dir once it takes more than threshold bytes
+    pub fn enable_spill(&mut self, dir: PathBuf, threshold: usize) -> std::io::Result<()> {
+        self.spill = Some(Spill::new(dir)?);
+        self.spill_threshold = threshold;
+        Ok(())
+    }
+
+    /// Notified once writers get ready work down to low_water, only one
+    /// waiter and it gets a permit if its not waiting yet.
+    pub fn watch_low_water(&mut self, low_water: usize) -> Arc<tokio::sync::Notify> {
+        self.low_water = low_water;
+        self.drained.clone()
+    }
+
+    /// Estimated memory held by work that writers can pick up right now
+    pub fn ready_bytes(&self) -> usize {
+        self.ready_bytes
+    }
+
+    /// Estimated memory held by work waiting on a parent, not counting spill
+    pub fn blocked_bytes(&self) -> usize {
+        self.blocked_bytes
+    }
+
     /// Add a work item from the reader (via channel)
     /// This processes sentinels and manages the tree structure
     pub fn push(&mut self, item: WorkItem) {
@@ -92,10 +158,32 @@ impl TreeWorkQueue {
             WorkItem::ScanComplete { .. } => {
                 self.scan_complete = true;
                 tracing::info!("Scan complete - {} items received", self.total_received);
+
+                // Every directory is scanned at this point, if a sentinel went
+                // missing somewhere don't leave its kids blocked forever.
+                let spilled = self.spill.iter().flat_map(|s| s.parents());
+                let created: Vec<PathBuf> = self
+                    .blocked_on_parent
+                    .keys()
+                    .chain(spilled)
+                    .filter(|p| self.created_dirs.contains(*p))
+                    .cloned()
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .collect();
+                for dir in created {
+                    self.unblock_children(&dir);
+                }
                 return;
             }
             _ => {
                 self.total_received += 1;
+                if self.scan_complete
+                    && !item.is_dir()
+                    && let Some(path) = item.dest_path()
+                {
+                    self.touched.insert(path.to_path_buf());
+                }
             }
         }
 
@@ -112,7 +200,8 @@ impl TreeWorkQueue {
                         .unwrap_or_default(),
                     parent.display()
                 );
-                self.blocked_on_parent.entry(parent).or_default().push(item);
+                self.block(parent, item);
+                self.maybe_spill();
             } else {
                 // No parent (root) - always ready
                 self.enqueue_ready(item);
@@ -140,7 +229,8 @@ impl TreeWorkQueue {
                     if parent == std::path::Path::new("") {
                         return true; // Root level
                     }
-                    self.scanned_dirs.contains(&parent) && self.created_dirs.contains(&parent)
+                    (self.scan_complete || self.scanned_dirs.contains(&parent))
+                        && self.created_dirs.contains(&parent)
                 } else {
                     true
                 }
@@ -148,20 +238,140 @@ impl TreeWorkQueue {
         }
     }
 
+    fn block(&mut self, parent: PathBuf, item: WorkItem) {
+        self.blocked_bytes += item.mem_size();
+        self.blocked_on_parent.entry(parent).or_default().push(item);
+    }
+
+    /// Push blocked work out to disk, biggest parents first, until we're back
+    /// under half the threshold.
+    fn maybe_spill(&mut self) {
+        if self.blocked_bytes <= self.spill_threshold {
+            return;
+        }
+        let Some(spill) = self.spill.as_mut() else {
+            return;
+        };
+
+        let mut parents: Vec<(PathBuf, usize)> = self
+            .blocked_on_parent
+            .iter()
+            .map(|(p, items)| (p.clone(), items.len()))
+            .collect();
+        parents.sort_by_key(|p| std::cmp::Reverse(p.1));
+
+        let target = self.spill_threshold / 2;
+        for (parent, _) in parents {
+            if self.blocked_bytes <= target {
+                break;
+            }
+
+            let Some(items) = self.blocked_on_parent.remove(&parent) else {
+                continue;
+            };
+            let bytes: usize = items.iter().map(|i| i.mem_size()).sum();
+
+            match spill.append(&parent, items) {
+                Ok(()) => self.blocked_bytes -= bytes,
+                Err((e, items)) => {
+                    // Disk full or whatever, memory it is then
+                    tracing::error!(
+                        "failed to spill blocked work to {}: {}",
+                        spill.dir().display(),
+                        e
+                    );
+                    self.blocked_on_parent.insert(parent, items);
+                    break;
+                }
+            }
+        }
+
+        tracing::trace!(
+            "spilled blocked work, {} items on disk, {} bytes blocked in memory",
+            spill.len(),
+            self.blocked_bytes
+        );
+    }
+
     /// Enqueue a ready work item into the appropriate queue
     fn enqueue_ready(&mut self, item: WorkItem) {
-        if item.is_dir() {
+        self.ready_bytes += item.mem_size();
+        // Renames are cheap and need to land before anything gets copied
+        // under the new name, so they jump the line with the dirs.
+        if item.is_dir() || matches!(item, WorkItem::Rename { .. }) {
             self.ready_dirs.push_back(item);
-        } else if item.is_bulk() {
-            self.ready_files_bulk.push_back(item);
         } else {
-            self.ready_files_priority.push_back(item);
+            let mut key = self.policy.key(&item, self.next_seq);
+            self.next_seq += 1;
+
+            if !self.touched.is_empty() && item.dest_path().is_some_and(|p| self.touched.remove(p))
+            {
+                key = key.touched();
+            }
+
+            if self.policy.aging.is_some() {
+                self.ready_files_age.push_back((Instant::now(), key));
+            }
+            self.ready_files.insert(key, item);
+        }
+    }
+
+    /// Pop the next file, anything that has aged out goes first, otherwise
+    /// whatever the policy says.
+    fn pop_file(&mut self, now: Instant) -> Option<WorkItem> {
+        if let Some(aging) = self.policy.aging {
+            while let Some(&(when, key)) = self.ready_files_age.front() {
+                if !self.ready_files.contains_key(&key) {
+                    // Already went out the front door
+                    self.ready_files_age.pop_front();
+                    continue;
+                }
+
+                if now.saturating_duration_since(when) >= aging {
+                    self.ready_files_age.pop_front();
+                    return self.ready_files.remove(&key).map(|i| self.popped(i));
+                }
+                break;
+            }
         }
+
+        self.ready_files
+            .pop_first()
+            .map(|(_, item)| self.popped(item))
+    }
+
+    fn popped(&mut self, item: WorkItem) -> WorkItem {
+        self.ready_bytes = self.ready_bytes.saturating_sub(item.mem_size());
+        if self.ready_bytes <= self.low_water {
+            self.drained.notify_one();
+        }
+        item
     }
 
     /// Unblock children after a directory is scanned or created
     fn unblock_children(&mut self, dir_path: &std::path::Path) {
-        if let Some(children) = self.blocked_on_parent.remove(dir_path) {
+        let mut children = self.blocked_on_parent.remove(dir_path).unwrap_or_default();
+        self.blocked_bytes = self
+            .blocked_bytes
+            .saturating_sub(children.iter().map(|c| c.mem_size()).sum());
+
+        // Spilled kids only come back once the parent exists, otherwise they'd
+        // just end up right back in memory blocked.
+        if self.created_dirs.contains(dir_path)
+            && let Some(spill) = self.spill.as_mut()
+            && spill.contains(dir_path)
+        {
+            match spill.take(dir_path) {
+                Ok(spilled) => children.extend(spilled),
+                Err(e) => tracing::error!(
+                    "failed to read spilled work for {}, its children won't be copied: {}",
+                    dir_path.display(),
+                    e
+                ),
+            }
+        }
+
+        if !children.is_empty() {
             tracing::trace!(
                 "Unblocking {} children of {}",
                 children.len(),
@@ -173,10 +383,7 @@ impl TreeWorkQueue {
                 } else {
                     // Still blocked - reinsert
                     if let Some(parent) = child.parent_path() {
-                        self.blocked_on_parent
-                            .entry(parent)
-                            .or_default()
-                            .push(child);
+                        self.block(parent, child);
                     }
                 }
             }
@@ -191,40 +398,42 @@ impl TreeWorkQueue {
 
     /// Pop work - prioritizes directories, then files
     pub fn pop(&mut self) -> Option<WorkItem> {
-        self.ready_dirs
-            .pop_front()
-            .or_else(|| self.ready_files_priority.pop_front())
-            .or_else(|| self.ready_files_bulk.pop_front())
+        match self.ready_dirs.pop_front() {
+            Some(item) => Some(self.popped(item)),
+            None => self.pop_file(Instant::now()),
+        }
     }
 
     /// Pop batch with interleaved dirs and files
-    /// Uses ratio: 1 dir : 4 files to ensure dirs created just-in-time
-    /// but most time spent copying files
+    /// Uses ratio: 1 dir : files_per_dir (default 4) files to ensure dirs
+    /// created just-in-time but most time spent copying files
     pub fn pop_batch(&mut self, batch_size: usize) -> Vec<WorkItem> {
+        self.pop_batch_at(batch_size, Instant::now())
+    }
+
+    fn pop_batch_at(&mut self, batch_size: usize, now: Instant) -> Vec<WorkItem> {
         let mut batch = Vec::with_capacity(batch_size);
+        let files_per_dir = self.policy.files_per_dir.max(1);
 
-        // Interleave: for every directory, try to get 4 files
+        // Interleave: for every directory, try to get a few files
         // This ensures directories are created as needed without blocking file I/O
         while batch.len() < batch_size {
             let mut made_progress = false;
 
             // Get 1 directory if available
             if let Some(item) = self.ready_dirs.pop_front() {
+                let item = self.popped(item);
                 batch.push(item);
                 made_progress = true;
             }
 
-            // Get up to 4 files (priority first, then bulk)
-            for _ in 0..4 {
+            // Get up to files_per_dir files in policy order
+            for _ in 0..files_per_dir {
                 if batch.len() >= batch_size {
                     break;
                 }
 
-                if let Some(item) = self
-                    .ready_files_priority
-                    .pop_front()
-                    .or_else(|| self.ready_files_bulk.pop_front())
-                {
+                if let Some(item) = self.pop_file(now) {
                     batch.push(item);
                     made_progress = true;
                 } else {
@@ -243,22 +452,24 @@ impl TreeWorkQueue {
 
     /// Check if all ready queues are empty
     pub fn is_empty(&self) -> bool {
-        self.ready_dirs.is_empty()
-            && self.ready_files_priority.is_empty()
-            && self.ready_files_bulk.is_empty()
+        self.ready_dirs.is_empty() && self.ready_files.is_empty()
     }
 
     /// Check if we're truly complete (scan done, no ready/blocked work)
     pub fn is_complete(&self) -> bool {
-        self.scan_complete && self.is_empty() && self.blocked_on_parent.is_empty()
+        self.scan_complete
+            && self.is_empty()
+            && self.blocked_on_parent.is_empty()
+            && self.spill.as_ref().is_none_or(|s| s.is_empty())
     }
 
     /// Get stats for debugging
     pub fn stats(&self) -> QueueStats {
         QueueStats {
             ready_dirs: self.ready_dirs.len(),
-            ready_files: self.ready_files_priority.len() + self.ready_files_bulk.len(),
+            ready_files: self.ready_files.len(),
             blocked: self.blocked_on_parent.values().map(|v| v.len()).sum(),
+            spilled: self.spill.as_ref().map(|s| s.len()).unwrap_or(0),
             total_received: self.total_received,
             scan_complete: self.scan_complete,
         }
@@ -277,6 +488,236 @@ pub struct QueueStats {
     pub ready_dirs: usize,
     pub ready_files: usize,
     pub blocked: usize,
+    pub spilled: usize,
     pub total_received: usize,
     pub scan_complete: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::metadata::{DirMetadata, FileKind, FileMetadata};
+    use crate::io::schedule::Order;
+    use crate::testdir::TestDir;
+    use std::time::Duration;
+
+    fn file(path: &str, size: u64, mtime: u64) -> WorkItem {
+        let metadata = FileMetadata {
+            path: PathBuf::from(path),
+            size,
+            #[cfg(unix)]
+            mode: 0o644,
+            #[cfg(unix)]
+            uid: 0,
+            #[cfg(unix)]
+            gid: 0,
+            kind: FileKind::File,
+            mtime,
+        };
+        if size >= crate::io::LARGE_FILE_THRESHOLD {
+            WorkItem::CopyLargeFile {
+                uuid: 1,
+                source_path: PathBuf::from(path),
+                dest_path: PathBuf::from(path),
+                metadata,
+            }
+        } else {
+            WorkItem::CopySmallFile {
+                uuid: 1,
+                source_path: PathBuf::from(path),
+                dest_path: PathBuf::from(path),
+                metadata,
+            }
+        }
+    }
+
+    fn dir(path: &str) -> WorkItem {
+        WorkItem::CreateDir {
+            uuid: 1,
+            source_path: PathBuf::from(path),
+            dest_path: PathBuf::from(path),
+            metadata: DirMetadata {
+                path: PathBuf::from(path),
+                #[cfg(unix)]
+                mode: 0o755,
+                #[cfg(unix)]
+                uid: 0,
+                #[cfg(unix)]
+                gid: 0,
+            },
+        }
+    }
+
+    fn paths(batch: &[WorkItem]) -> Vec<String> {
+        batch
+            .iter()
+            .map(|i| i.dest_path().unwrap().display().to_string())
+            .collect()
+    }
+
+    fn policy(order: Order, priorities: &[&str]) -> SchedulePolicy {
+        let priorities: Vec<String> = priorities.iter().map(|s| s.to_string()).collect();
+        SchedulePolicy {
+            order,
+            ..SchedulePolicy::parse(None, &priorities, Some(0)).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_smallest_first() {
+        let mut q = TreeWorkQueue::with_policy(policy(Order::SmallestFirst, &[]));
+        q.push(file("c", 300, 0));
+        q.push(file("a", 100, 0));
+        q.push(file("huge", crate::io::LARGE_FILE_THRESHOLD, 0));
+        q.push(file("b", 200, 0));
+
+        assert_eq!(paths(&q.pop_batch(10)), vec!["a", "b", "c", "huge"]);
+    }
+
+    #[test]
+    fn test_touched_before_backlog() {
+        let mut q = TreeWorkQueue::with_policy(policy(Order::SmallestFirst, &["important"]));
+        q.push(file("important", 100, 0));
+        q.push(file("backlog", 1, 0));
+        q.push(WorkItem::ScanComplete { uuid: 1 });
+
+        // What a watcher hands over after the initial scan
+        q.push(file("saved", 500, 0));
+        q.push(file("also-saved", 400, 0));
+
+        assert_eq!(
+            paths(&q.pop_batch(10)),
+            vec!["also-saved", "saved", "important", "backlog"]
+        );
+    }
+
+    #[test]
+    fn test_newest_first() {
+        let mut q = TreeWorkQueue::with_policy(policy(Order::NewestFirst, &[]));
+        q.push(file("old", 1, 100));
+        q.push(file("new", 1, 300));
+        q.push(file("mid", 1, 200));
+
+        assert_eq!(paths(&q.pop_batch(10)), vec!["new", "mid", "old"]);
+    }
+
+    #[test]
+    fn test_path_priority_beats_order() {
+        let mut q = TreeWorkQueue::with_policy(policy(Order::SmallestFirst, &["src/**"]));
+        q.push(dir("assets"));
+        q.push(dir("src"));
+        q.mark_dir_created(PathBuf::from("assets"));
+        q.mark_dir_created(PathBuf::from("src"));
+        q.push(file("assets/tiny.png", 1, 0));
+        q.push(file("src/big.rs", 1000, 0));
+        q.push(WorkItem::DirectoryScanned {
+            uuid: 1,
+            dest_path: PathBuf::from("assets"),
+        });
+        q.push(WorkItem::DirectoryScanned {
+            uuid: 1,
+            dest_path: PathBuf::from("src"),
+        });
+
+        let batch = q.pop_batch(10);
+        let files: Vec<String> = paths(&batch)
+            .into_iter()
+            .filter(|p| p.contains('.'))
+            .collect();
+        assert_eq!(files, vec!["src/big.rs", "assets/tiny.png"]);
+    }
+
+    #[test]
+    fn test_aging_promotes_bulk() {
+        let mut p = policy(Order::SmallestFirst, &[]);
+        p.aging = Some(Duration::from_secs(5));
+        let mut q = TreeWorkQueue::with_policy(p);
+
+        q.push(file("huge", crate::io::LARGE_FILE_THRESHOLD, 0));
+        q.push(file("small", 1, 0));
+
+        // Nothing has aged yet, policy order
+        assert_eq!(paths(&q.pop_batch_at(1, Instant::now())), vec!["small"]);
+
+        q.push(file("small2", 1, 0));
+        let later = Instant::now() + Duration::from_secs(10);
+        assert_eq!(paths(&q.pop_batch_at(1, later)), vec!["huge"]);
+        assert_eq!(paths(&q.pop_batch_at(1, later)), vec!["small2"]);
+        assert!(q.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_low_water_notifies() {
+        let mut q = TreeWorkQueue::new();
+        let drained = q.watch_low_water(0);
+        q.push(file("a", 1, 0));
+        q.push(file("b", 1, 0));
+
+        // Still something ready, no permit yet
+        q.pop_batch(1);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), drained.notified())
+                .await
+                .is_err()
+        );
+
+        q.pop_batch(1);
+        tokio::time::timeout(Duration::from_secs(1), drained.notified())
+            .await
+            .expect("draining to low water didn't notify");
+    }
+
+    #[test]
+    fn test_blocked_work_spills_and_comes_back() {
+        let tmp = TestDir::new("spill");
+        let spill_dir = tmp.join("spill");
+        let mut q = TreeWorkQueue::new();
+        q.enable_spill(spill_dir.clone(), 4096).unwrap();
+
+        q.push(dir("a"));
+        for i in 0..100 {
+            q.push(file(&format!("a/f{i}"), 1, 0));
+        }
+
+        let stats = q.stats();
+        assert!(stats.spilled > 0, "nothing spilled: {:?}", stats);
+        assert!(q.blocked_bytes() <= 4096);
+        assert_eq!(stats.blocked + stats.spilled, 100);
+
+        assert_eq!(paths(&q.pop_batch(10)), vec!["a"]);
+        q.mark_dir_created(PathBuf::from("a"));
+        q.push(WorkItem::DirectoryScanned {
+            uuid: 1,
+            dest_path: PathBuf::from("a"),
+        });
+        q.push(WorkItem::ScanComplete { uuid: 1 });
+
+        assert_eq!(q.pop_batch(1000).len(), 100);
+        assert_eq!(q.ready_bytes(), 0);
+        assert!(q.is_complete());
+
+        drop(q);
+        assert!(!spill_dir.exists());
+    }
+
+    #[test]
+    fn test_files_wait_for_parent() {
+        let mut q = TreeWorkQueue::new();
+        q.push(dir("a"));
+        q.push(file("a/f", 1, 0));
+
+        assert_eq!(paths(&q.pop_batch(10)), vec!["a"]);
+        assert!(q.pop_batch(10).is_empty());
+
+        q.mark_dir_created(PathBuf::from("a"));
+        assert!(q.pop_batch(10).is_empty());
+
+        q.push(WorkItem::DirectoryScanned {
+            uuid: 1,
+            dest_path: PathBuf::from("a"),
+        });
+        q.push(WorkItem::ScanComplete { uuid: 1 });
+        assert_eq!(paths(&q.pop_batch(10)), vec!["a/f"]);
+        assert!(q.is_complete());
+    }
+}
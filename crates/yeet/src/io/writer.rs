@@ -1,36 +1,46 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use tokio::sync::Mutex;
 
+use super::controller::Controller;
 use super::error::IoError;
+use super::fair::FairQueue;
+use super::limits::Limits;
 use super::metadata::FileMetadata;
 use super::progress::Progress;
 use super::work::WorkItem;
-use super::work_simple::SimpleWorkQueue;
+use super::work_tree::TreeWorkQueue;
 
 #[cfg(target_os = "linux")]
 use std::os::unix::io::AsRawFd;
 
 /// Filesystem feature detection for handling quirks of different filesystem types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum FsFeatures {
+pub enum FsFeatures {
     /// Normal POSIX-compliant filesystem
     Normal,
     /// CIFS/Samba mount - chmod/chown may fail with EPERM even after successful copy
     Samba,
+    /// NFS mount, works like a normal fs but is still over the network
+    Nfs,
+}
+
+impl FsFeatures {
+    /// Network filesystems get pinned to low writer concurrency by the
+    /// controller.
+    pub fn is_network(&self) -> bool {
+        matches!(self, FsFeatures::Samba | FsFeatures::Nfs)
+    }
 }
 
-// TODO: Need to probably use
-// https://docs.rs/nix/latest/nix/sys/statvfs/index.html to control if we are
-// writing to a network fs or not. and if socontrol those workers to 1/2. This
-// is probably a good thing to tackle once I get syncing in place.
 const DEFAULT_WORKERS: usize = 4;
 
-/// Get number of workers based off the core count for now. Default is 4 if we
-/// can't detect that, god knows if thats right or not. Should probably be 1 or
-/// 2 then.
-fn get_num_workers() -> usize {
+/// Get the max number of workers based off the core count. Default is 4 if we
+/// can't detect that, god knows if thats right or not. The controller decides
+/// how many of these are actually active.
+pub(crate) fn get_num_workers() -> usize {
     std::thread::available_parallelism()
         .map(|n| n.get())
         .unwrap_or(DEFAULT_WORKERS)
@@ -40,7 +50,7 @@ fn get_num_workers() -> usize {
 /// CIFS/Samba mount that lacks support for fchmod/chmod support. If so, we
 /// can't really trust errors from libc copy function calls.
 #[cfg(target_os = "linux")]
-fn detect_fs_features(dest_dir: &std::path::Path) -> FsFeatures {
+pub(crate) fn detect_fs_features(dest_dir: &std::path::Path) -> FsFeatures {
     use std::fs::File;
     use std::io::Write;
 
@@ -51,6 +61,7 @@ fn detect_fs_features(dest_dir: &std::path::Path) -> FsFeatures {
     // Note: f_type is u64 in musl and i64 in glibc
     const CIFS_MAGIC_1: u32 = 0xFF534D42;
     const CIFS_MAGIC_2: u32 = 0xFE534D42;
+    const NFS_MAGIC: u32 = 0x6969;
 
     let test_file_path = dest_dir.join(".yeet_fs_feature_detection");
 
@@ -72,6 +83,13 @@ fn detect_fs_features(dest_dir: &std::path::Path) -> FsFeatures {
                     f_type
                 );
                 Ok(FsFeatures::Samba)
+            } else if f_type == NFS_MAGIC as u64 {
+                tracing::info!(
+                    "detected an NFS filesystem {} (f_type: 0x{:X})",
+                    dest_dir.display(),
+                    f_type
+                );
+                Ok(FsFeatures::Nfs)
             } else {
                 tracing::debug!(
                     "detected a normal filesystem {} (f_type: 0x{:X})",
@@ -100,184 +118,218 @@ fn detect_fs_features(dest_dir: &std::path::Path) -> FsFeatures {
 
 // TODO: on non unix/linux what goes here? Only the shadow knows.
 #[cfg(not(target_os = "linux"))]
-fn detect_fs_features(_dest_dir: &std::path::Path) -> FsFeatures {
+pub(crate) fn detect_fs_features(_dest_dir: &std::path::Path) -> FsFeatures {
     FsFeatures::Normal
 }
 
-/// Writer pool that processes work items from the queue I played around with
-/// multiple writers, its... not as useful as I had hoped need to rethink my
-/// solution here.
-pub struct WriterPool {
+/// How writers actually do their I/O
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriterBackend {
+    /// Dedicated blocking threads doing normal syscalls
+    Threads,
+    /// Small files are batched through a per thread io_uring, everything else
+    /// same as Threads
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring,
+}
+
+impl WriterBackend {
+    /// Pick the best backend this build/kernel supports. Falls back to the
+    /// thread pool at runtime if io_uring can't be setup.
+    pub fn detect() -> Self {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if super::uring::UringCopier::probe().is_some() {
+            return WriterBackend::Uring;
+        }
+
+        WriterBackend::Threads
+    }
+}
+
+// Removes a staged source on drop, see WriteJob::unstage()
+struct Unstage(PathBuf);
+
+impl Drop for Unstage {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            tracing::warn!("couldn't remove staged {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Writes work items itself instead of the usual writes, the pool still
+/// decides when and how fast. Two-way syncs use this so every target gets
+/// checked against what they decided on right before its touched, see
+/// twoway.rs.
+pub trait Apply: Send + Sync {
+    /// Err gets recorded like any other failed write
+    fn apply(&self, item: &WorkItem) -> Result<(), IoError>;
+}
+
+/// Everything a writer needs to know about one sync/uuid, the pool hands out
+/// batches from these as the fair queue sees fit.
+pub struct WriteJob {
+    uuid: u128,
     dest: PathBuf,
-    work_queue: Arc<Mutex<SimpleWorkQueue>>,
+    work_queue: Arc<Mutex<TreeWorkQueue>>,
     progress: Progress,
     errors: Arc<Mutex<Vec<IoError>>>,
-    shutdown: Arc<Mutex<bool>>,
-    active_workers: Arc<Mutex<usize>>,
-    _reader_done: Arc<Mutex<bool>>, // TODO: keep?
-    done: Arc<Mutex<bool>>,
+    limits: Limits,
+    controller: Arc<Controller>,
+    /// Writers currently chewing on a batch from this job, only bumped with
+    /// the queue lock held so completion checks can't race a pop.
+    active_workers: AtomicUsize,
+    /// Exactly this many writers, aka -w. Overrides whatever the device
+    /// controller would allow for this job only, the controller is shared by
+    /// every sync to the device so this can't go there.
+    max_writers: Option<usize>,
+    done: Arc<super::done::Done>,
     fs_features: FsFeatures,
+    /// Skip files the dest already has with the same size/mtime
+    quick_check: bool,
+    /// Watched syncs never finish, more work can always show up
+    persistent: bool,
+    /// Old versions of anything we overwrite or remove go here first
+    trash: Option<super::trash::Trash>,
+    /// Sources are staged copies of a remote daemon's files, see remote.rs
+    staged: bool,
+    /// Does the writing instead of us, see Apply
+    apply: Option<Arc<dyn Apply>>,
 }
 
-impl WriterPool {
+impl WriteJob {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        uuid: u128,
         dest: PathBuf,
-        work_queue: Arc<Mutex<SimpleWorkQueue>>,
+        work_queue: Arc<Mutex<TreeWorkQueue>>,
         progress: Progress,
         errors: Arc<Mutex<Vec<IoError>>>,
-        reader_done: Arc<Mutex<bool>>,
-        done: Arc<Mutex<bool>>,
+        limits: Limits,
+        controller: Arc<Controller>,
+        fs_features: FsFeatures,
+        done: Arc<super::done::Done>,
     ) -> Self {
-        // Try to figure out if our dest is a problematic fs or not that might not
-        // support chmod
-        let fs_features = detect_fs_features(&dest);
-
         Self {
+            uuid,
             dest,
             work_queue,
             progress,
             errors,
-            shutdown: Arc::new(Mutex::new(false)),
-            active_workers: Arc::new(Mutex::new(0)),
-            _reader_done: reader_done,
+            limits,
+            controller,
+            active_workers: AtomicUsize::new(0),
+            max_writers: None,
             done,
             fs_features,
+            quick_check: false,
+            persistent: false,
+            trash: None,
+            staged: false,
+            apply: None,
         }
     }
 
-    pub async fn is_idle(&self) -> bool {
-        let active = self.active_workers.lock().await;
-        *active == 0
+    pub fn with_apply(mut self, apply: Arc<dyn Apply>) -> Self {
+        self.apply = Some(apply);
+        self
+    }
+
+    pub fn with_persistent(mut self, persistent: bool) -> Self {
+        self.persistent = persistent;
+        self
+    }
+
+    pub fn with_trash(mut self, trash: Option<super::trash::Trash>) -> Self {
+        self.trash = trash;
+        self
+    }
+
+    /// Sources are ours to remove once they're copied, or not
+    pub fn with_staged(mut self, staged: bool) -> Self {
+        self.staged = staged;
+        self
+    }
+
+    pub fn with_quick_check(mut self, quick_check: bool) -> Self {
+        self.quick_check = quick_check;
+        self
+    }
+
+    /// Workers past -w sit out, otherwise its up to the controller
+    fn may_run(&self, worker_id: usize) -> bool {
+        match self.max_writers {
+            Some(max) => worker_id < max,
+            None => self.controller.may_run(worker_id),
+        }
+    }
+
+    pub fn uuid(&self) -> u128 {
+        self.uuid
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.active_workers.load(Ordering::SeqCst) == 0
     }
 
-    /// Check if work is complete and set done flag
-    /// Writers only mark themselves as done iff:
+    /// Check if work is complete and set done flag, returns if we're done.
+    /// Writers only mark a job as done iff:
     /// - The reader has finished traversing
     /// - The work queue is empty
     /// - No workers are actively processing data
-    async fn check_completion(&self) {
+    async fn check_completion(&self) -> bool {
+        if self.persistent {
+            return false;
+        }
+
         // Check if already marked as done
-        {
-            let done = self.done.lock().await;
-            if *done {
-                return;
-            }
+        if self.done.is_set() {
+            return true;
         }
 
         // Check if queue reports complete (scan done, no ready/blocked work)
         let queue = self.work_queue.lock().await;
         let queue_complete = queue.is_complete();
-        drop(queue);
 
         // Check if no workers are active
-        let active = self.active_workers.lock().await;
-        let workers_idle = *active == 0;
-        drop(active);
+        let workers_idle = self.is_idle();
+        drop(queue);
 
         // Only mark as done if queue is complete and workers are idle
         if queue_complete && workers_idle {
-            let mut done = self.done.lock().await;
-            if !*done {
-                *done = true;
-                tracing::debug!("writer pool marked as complete");
+            if self.done.set() {
+                tracing::debug!(
+                    "{} writes marked as complete",
+                    uuid::Uuid::from_u128(self.uuid)
+                );
             }
+            return true;
         }
+        false
     }
 
-    pub async fn start(self: Arc<Self>, num_workers_override: Option<usize>) {
-        let num_workers = num_workers_override.unwrap_or_else(get_num_workers);
-        let detected_cores = std::thread::available_parallelism()
-            .map(|n| n.get())
-            .unwrap_or(0);
-
-        if let Some(_override_val) = num_workers_override {
-            tracing::debug!(
-                "writer pool starting with {} parallel workers (user specified, {} cores detected)",
-                num_workers,
-                detected_cores
-            );
-        } else {
-            tracing::debug!(
-                "writer pool starting with {} parallel workers (auto-detected from {} cores)",
-                num_workers,
-                detected_cores
-            );
-        }
-
-        // Start multiple parallel workers as dedicated blocking threads
-        // This avoids spawn_blocking overhead for every file operation
-        for worker_id in 0..num_workers {
-            let pool = self.clone();
-            std::thread::spawn(move || {
-                tracing::trace!("worker {} starting in blocking thread", worker_id);
-
-                // Create a tokio runtime for this worker thread
-                let rt = tokio::runtime::Builder::new_current_thread()
-                    .enable_all()
-                    .build()
-                    .expect("failed to create worker runtime");
-
-                rt.block_on(async move {
-                    pool.worker_blocking(worker_id).await;
-                });
-
-                tracing::debug!("worker {} thread exiting", worker_id);
-            });
-        }
-    }
+    async fn process_work_item(&self, item: WorkItem) -> Result<(), Box<dyn std::error::Error>> {
+        let uuid = item.uuid();
 
-    /// Blocking worker loop - runs in dedicated thread, uses blocking I/O directly
-    // may need a revisit once I get inter node copies/syncs working. I think
-    // network i/o and disk i/o likely need to be separated.
-    async fn worker_blocking(&self, worker_id: usize) {
-        // TODO: Numbers pulled out of my ass, need to make it dynamic with a
-        // PID controller probably somehow..... more future mitch problems.
-        const BATCH_SIZE: usize = 100;
+        if let Some(apply) = &self.apply {
+            let (bytes, ops) = batch_cost(std::slice::from_ref(&item));
+            self.limits.throttle_async(uuid, bytes, ops).await;
 
-        loop {
-            // Check shutdown signal
-            if *self.shutdown.lock().await {
-                tracing::info!("worker {} received shutdown signal, exiting", worker_id);
-                break;
+            let result = apply.apply(&item);
+            // Same as below, kids go either way
+            if let WorkItem::CreateDir { dest_path, .. } = item {
+                self.work_queue.lock().await.mark_dir_created(dest_path);
             }
-
-            // Get a batch of work items (dirs first, then files to avoid parentage missing issues via logical sanity)
-            let work_batch = {
-                let mut queue = self.work_queue.lock().await;
-                queue.pop_batch(BATCH_SIZE)
-            };
-
-            if !work_batch.is_empty() {
-                {
-                    let mut active = self.active_workers.lock().await;
-                    *active += 1;
-                }
-
-                // Process items in parallel
-                for item in work_batch {
-                    tracing::trace!("worker {} processing: {:?}", worker_id, item);
-                    if let Err(e) = self.process_work_item(item).await {
-                        tracing::error!("worker {} error: {}", worker_id, e);
-                    }
-                }
-
-                {
-                    let mut active = self.active_workers.lock().await;
-                    *active -= 1;
-                }
-
-                self.check_completion().await;
-            } else {
-                self.check_completion().await;
-
-                // Sleep briefly to let locks/mutexes settle
-                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            if let Err(e) = result {
+                tracing::error!("{}: {}", e.error, e.path.display());
+                let error_msg = e.error.clone();
+                self.errors.lock().await.push(e);
+                return Err(error_msg.into());
             }
+            return Ok(());
         }
-    }
-
-    async fn process_work_item(&self, item: WorkItem) -> Result<(), Box<dyn std::error::Error>> {
-        let uuid = item.uuid();
 
         match item {
             WorkItem::CreateDir {
@@ -285,7 +337,14 @@ impl WriterPool {
                 metadata,
                 ..
             } => {
-                self.create_directory(uuid, dest_path, metadata).await?;
+                let result = self
+                    .create_directory(uuid, dest_path.clone(), metadata)
+                    .await;
+
+                // Children are blocked on this dir in the queue, let them go
+                // even if we failed, they'll report their own errors.
+                self.work_queue.lock().await.mark_dir_created(dest_path);
+                result?;
             }
             WorkItem::CopySmallFile {
                 source_path,
@@ -319,6 +378,16 @@ impl WriterPool {
             } => {
                 self.apply_metadata(uuid, dest_path, metadata).await?;
             }
+            WorkItem::Remove {
+                dest_path, kind, ..
+            } => {
+                self.remove(uuid, dest_path, kind).await?;
+            }
+            WorkItem::Rename {
+                from, dest_path, ..
+            } => {
+                self.rename(uuid, from, dest_path).await;
+            }
             // Sentinel items should never hit a worker, should be a panic/todo
             // but for now whatever lets see if it matters first.
             WorkItem::DirectoryScanned { .. } | WorkItem::ScanComplete { .. } => {
@@ -330,6 +399,224 @@ impl WriterPool {
         Ok(())
     }
 
+    /// Copy every io_uring eligible small file in the batch through the ring,
+    /// returns the work items that need to go through the normal path.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    async fn process_uring_batch(
+        &self,
+        copier: &mut super::uring::UringCopier,
+        batch: Vec<WorkItem>,
+    ) -> Vec<WorkItem> {
+        use super::uring::{SmallCopy, URING_MAX_FILE_SIZE};
+
+        // CIFS and friends get the chunked path, see copy_file(). Anything
+        // with its own Apply does its own copies.
+        if self.fs_features != FsFeatures::Normal || self.apply.is_some() {
+            return batch;
+        }
+
+        let (eligible, mut rest): (Vec<WorkItem>, Vec<WorkItem>) =
+            batch.into_iter().partition(|item| {
+                matches!(item, WorkItem::CopySmallFile { metadata, .. } if metadata.size <= URING_MAX_FILE_SIZE)
+            });
+
+        if eligible.is_empty() {
+            return rest;
+        }
+
+        let mut copies = Vec::with_capacity(eligible.len());
+        let mut items = Vec::with_capacity(eligible.len());
+        let mut unstaged = Vec::with_capacity(eligible.len());
+
+        for item in eligible {
+            let WorkItem::CopySmallFile {
+                uuid,
+                source_path,
+                dest_path,
+                metadata,
+            } = item
+            else {
+                continue;
+            };
+
+            let relative_path = dest_path;
+            let dest_path = self.dest.join(&relative_path);
+
+            // Skipped or copied a staged source is done with either way
+            let unstage = self.unstage(&source_path);
+
+            self.limits.throttle_async(uuid, metadata.size, 1).await;
+
+            // Same safety net as copy_file()
+            if let Some(parent) = dest_path.parent()
+                && let Err(e) = std::fs::create_dir_all(parent)
+            {
+                let error_msg = format!("failed to create parent directory: {}", e);
+                tracing::error!("{}: {}", error_msg, parent.display());
+                let mut errors = self.errors.lock().await;
+                errors.push(IoError::destination(error_msg, parent.to_path_buf()));
+                continue;
+            }
+
+            if self.keep(&relative_path).await.is_err() {
+                continue;
+            }
+
+            copies.push(SmallCopy {
+                source: source_path,
+                dest: dest_path,
+                mode: metadata.mode,
+                uid: metadata.uid,
+                gid: metadata.gid,
+                mtime: metadata.mtime,
+            });
+            items.push((uuid, relative_path, metadata));
+            unstaged.push(unstage);
+        }
+
+        let results = copier.copy_batch(&copies);
+
+        for (((copy, (uuid, relative_path, metadata)), result), unstage) in
+            copies.into_iter().zip(items).zip(results).zip(unstaged)
+        {
+            let size = metadata.size;
+            match result {
+                // Grew since the scan, copy_file() gets it and unstages it
+                Err(e) if super::uring::too_big(&e) => {
+                    std::mem::forget(unstage);
+                    rest.push(WorkItem::CopySmallFile {
+                        uuid,
+                        source_path: copy.source,
+                        dest_path: relative_path,
+                        metadata,
+                    });
+                }
+                Ok(bytes_copied) => {
+                    tracing::trace!(
+                        "io_uring cp complete: {} ({} bytes)",
+                        copy.dest.display(),
+                        bytes_copied
+                    );
+                    self.flag_changed(&copy.source, size, copy.mtime, bytes_copied)
+                        .await;
+                    self.update_file_progress(uuid, bytes_copied, size);
+                }
+                Err(e) => {
+                    let error_msg = format!("failed to copy file: {}", e);
+                    tracing::error!(
+                        "{}: {} -> {}",
+                        error_msg,
+                        copy.source.display(),
+                        copy.dest.display()
+                    );
+                    let mut errors = self.errors.lock().await;
+                    errors.push(IoError::destination(error_msg, copy.dest));
+                }
+            }
+        }
+
+        rest
+    }
+
+    /// Drop any file copies quick-check says the dest already has, they
+    /// count as written so completion still adds up.
+    fn skip_unchanged(&self, batch: Vec<WorkItem>) -> Vec<WorkItem> {
+        if !self.quick_check {
+            return batch;
+        }
+
+        batch
+            .into_iter()
+            .filter(|item| match item {
+                WorkItem::CopySmallFile {
+                    uuid,
+                    dest_path,
+                    metadata,
+                    ..
+                }
+                | WorkItem::CopyLargeFile {
+                    uuid,
+                    dest_path,
+                    metadata,
+                    ..
+                } => {
+                    let full = self.dest.join(dest_path);
+                    if !metadata.unchanged(&full) {
+                        return true;
+                    }
+                    tracing::trace!("quick-check unchanged: {}", full.display());
+                    if self.staged
+                        && let WorkItem::CopySmallFile { source_path, .. }
+                        | WorkItem::CopyLargeFile { source_path, .. } = item
+                    {
+                        let _ = std::fs::remove_file(source_path);
+                    }
+                    self.progress
+                        .get_or_create(*uuid)
+                        .files_written
+                        .fetch_add(1, Ordering::Relaxed);
+                    false
+                }
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// A file that isn't what the scan saw once its copied changed under us,
+    /// what landed on the dest could be half old half new. Its still copied
+    /// but counts as an error so the sync doesn't look clean, next run (or
+    /// the watcher) gets it right. Snapshotted sources can't hit this.
+    async fn flag_changed(
+        &self,
+        source_path: &std::path::Path,
+        size: u64,
+        mtime: u64,
+        copied: u64,
+    ) {
+        // Same stat the scan did so the mtimes compare like for like
+        let now = match FileMetadata::from_path(source_path.to_path_buf()).await {
+            Ok(now) => now,
+            // Gone is the reader/watcher's problem, not an inconsistent copy
+            Err(_) => return,
+        };
+
+        if copied == size && now.size == size && now.mtime == mtime {
+            return;
+        }
+
+        let error_msg = format!(
+            "changed during copy, scanned {} bytes, copied {}, now {}",
+            size, copied, now.size
+        );
+        tracing::warn!("{}: {}", error_msg, source_path.display());
+        let mut errors = self.errors.lock().await;
+        errors.push(IoError::source(error_msg, source_path.to_path_buf()));
+    }
+
+    /// Staged sources get removed when this goes out of scope, after the
+    /// copy and the changed check are done with them.
+    fn unstage(&self, source_path: &std::path::Path) -> Option<Unstage> {
+        self.staged.then(|| Unstage(source_path.to_path_buf()))
+    }
+
+    /// Move the old version of relative_path to the trash before it gets
+    /// clobbered, if there is a trash. If it can't be kept it doesn't get
+    /// clobbered either.
+    async fn keep(&self, relative_path: &std::path::Path) -> std::io::Result<()> {
+        let Some(trash) = &self.trash else {
+            return Ok(());
+        };
+        if let Err(e) = trash.keep(relative_path) {
+            let dest_path = self.dest.join(relative_path);
+            let error_msg = format!("couldn't keep old version, leaving it be: {}", e);
+            tracing::error!("{}: {}", error_msg, dest_path.display());
+            let mut errors = self.errors.lock().await;
+            errors.push(IoError::destination(error_msg, dest_path));
+            return Err(e);
+        }
+        Ok(())
+    }
+
     /// Update progress counters for a file copy operation
     fn update_file_progress(&self, uuid: u128, bytes_copied: u64, file_size: u64) {
         const FAST_COPY_THRESHOLD: u64 = super::LARGE_FILE_THRESHOLD;
@@ -361,6 +648,8 @@ impl WriterPool {
     ) -> Result<(), Box<dyn std::error::Error>> {
         let dest_path = self.dest.join(&relative_path);
 
+        self.limits.throttle_async(uuid, 0, 1).await;
+
         // Use blocking I/O directly (worker already on blocking thread)
         match std::fs::create_dir_all(&dest_path) {
             Ok(_) => {
@@ -388,6 +677,9 @@ impl WriterPool {
     ) -> Result<(), Box<dyn std::error::Error>> {
         let dest_path = self.dest.join(&relative_path);
 
+        // Whatever happens below we're done with a staged source
+        let _unstage = self.unstage(&source_path);
+
         // Ensure parent directory exists
         // Normally the reader queues CreateDir before files, but this is a safety net
         // in case work items are processed out of order by different workers
@@ -401,6 +693,9 @@ impl WriterPool {
             return Err(Box::new(e));
         }
 
+        // Even if it looks the same, same size and mtime is a guess
+        self.keep(&relative_path).await?;
+
         tracing::trace!(
             "cp file {} -> {} ({} bytes)",
             source_path.display(),
@@ -411,6 +706,18 @@ impl WriterPool {
         // Limit to small files for now is same as "large file threshold" for no reason than cause.
         const FAST_COPY_THRESHOLD: u64 = super::LARGE_FILE_THRESHOLD;
 
+        // Rate limited copies of anything bigger than a chunk go through the
+        // chunked path so bandwidth is spent as a trickle and not one big
+        // burst after sleeping off the whole file up front.
+        let limited = self.limits.is_limited(uuid);
+        let small_limit = if limited {
+            super::limits::THROTTLE_CHUNK_SIZE as u64
+        } else {
+            FAST_COPY_THRESHOLD
+        };
+
+        self.limits.throttle_async(uuid, 0, 1).await;
+
         // Ok this is jank af but, we need to detect if we're copying to a CIFS
         // mount, if so we can't use std::fs::copy() as it implicitly tries to
         // chmod permissions after it copies data. But unless CIFS is mounted
@@ -428,7 +735,8 @@ impl WriterPool {
             // I'll sleep on it first.
             self.copy_file_chunked(uuid, &source_path, &dest_path, metadata.size)
                 .await
-        } else if metadata.size < FAST_COPY_THRESHOLD {
+        } else if metadata.size < small_limit {
+            self.limits.throttle_async(uuid, metadata.size, 0).await;
             std::fs::copy(&source_path, &dest_path)
         } else {
             // Slow path for large files - use chunked copy with progress
@@ -452,6 +760,8 @@ impl WriterPool {
                     errors.push(IoError::destination(error_msg, dest_path.clone()));
                 }
 
+                self.flag_changed(&source_path, metadata.size, metadata.mtime, bytes_copied)
+                    .await;
                 self.update_file_progress(uuid, bytes_copied, metadata.size);
                 Ok(())
             }
@@ -483,6 +793,7 @@ impl WriterPool {
         let source = source_path.to_path_buf();
         let dest = dest_path.to_path_buf();
         let progress = self.progress.clone();
+        let limits = self.limits.clone();
 
         // tokio task to periodically update progress
         let progress_task = {
@@ -528,8 +839,13 @@ impl WriterPool {
             let mut dest_file = std::fs::File::create(&dest)?;
 
             // Note, because this uses underlying vfs hacks, iff the filesystem
-            // is COW this can avoid actually copying data.
-            let bytes_copied = std::io::copy(&mut source_file, &mut dest_file)?;
+            // is COW this can avoid actually copying data. Rate limited copies
+            // lose that as every chunk has to be paid for.
+            let bytes_copied = if limits.is_limited(uuid) {
+                super::limits::copy_throttled(&limits, uuid, &mut source_file, &mut dest_file)?
+            } else {
+                std::io::copy(&mut source_file, &mut dest_file)?
+            };
 
             // Hopefully the device driver listens....
             dest_file.flush()?;
@@ -584,6 +900,13 @@ impl WriterPool {
             metadata.target.display()
         );
 
+        self.limits.throttle_async(uuid, 0, 1).await;
+
+        // Same link already there isn't worth keeping a copy of
+        if std::fs::read_link(&dest_path).ok().as_ref() != Some(&metadata.target) {
+            self.keep(&relative_path).await?;
+        }
+
         // Remove existing symlink/file if it exists first. Future me be less derp.
         if tokio::fs::symlink_metadata(&dest_path).await.is_ok() {
             let _ = tokio::fs::remove_file(&dest_path).await;
@@ -643,6 +966,11 @@ impl WriterPool {
             metadata.target.display()
         );
 
+        // Same link already there isn't worth keeping a copy of
+        if std::fs::read_link(&dest_path).ok().as_ref() != Some(&metadata.target) {
+            self.keep(&relative_path).await?;
+        }
+
         // Remove existing symlink/file if it exists? This is mostly copy/pasted code to let things build on like windows. None of this craps tested.
         if tokio::fs::symlink_metadata(&dest_path).await.is_ok() {
             let _ = tokio::fs::remove_file(&dest_path).await;
@@ -720,9 +1048,383 @@ impl WriterPool {
         }
     }
 
-    pub async fn shutdown(&self) {
+    async fn remove(
+        &self,
+        _uuid: u128,
+        relative_path: PathBuf,
+        kind: super::metadata::FileKind,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let dest_path = self.dest.join(&relative_path);
+
+        // Dirs only go if they're empty, anything new in there stays put
+        if kind != super::metadata::FileKind::Directory {
+            self.keep(&relative_path).await?;
+        }
+        let result = match kind {
+            super::metadata::FileKind::Directory => tokio::fs::remove_dir(&dest_path).await,
+            _ => tokio::fs::remove_file(&dest_path).await,
+        };
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => {
+                let error_msg = format!("failed to remove: {}", e);
+                tracing::error!("{}: {}", error_msg, dest_path.display());
+                let mut errors = self.errors.lock().await;
+                errors.push(IoError::destination(error_msg, dest_path));
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    /// Move from to relative_path on the destination. Purely an optimization
+    /// so a moved tree isn't recopied, if the old name is gone or the new one
+    /// is already there we leave it be and the rescan of the new name copies
+    /// whatever is needed. Failures are likewise not fatal.
+    async fn rename(&self, _uuid: u128, from: PathBuf, relative_path: PathBuf) {
+        let old = self.dest.join(&from);
+        let new = self.dest.join(&relative_path);
+
+        let exists = |p: &PathBuf| std::fs::symlink_metadata(p).is_ok();
+        if !exists(&old) || exists(&new) {
+            tracing::trace!(
+                "not renaming {} to {}, leaving it to the rescan",
+                old.display(),
+                new.display()
+            );
+            return;
+        }
+
+        if let Some(parent) = new.parent()
+            && let Err(e) = tokio::fs::create_dir_all(parent).await
+        {
+            tracing::debug!("rename parent {}: {}", parent.display(), e);
+            return;
+        }
+
+        if let Err(e) = tokio::fs::rename(&old, &new).await {
+            tracing::debug!(
+                "rename {} to {} failed, rescan will copy it: {}",
+                old.display(),
+                new.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Bytes and ops in a batch, for the fair queue and controller
+fn batch_cost(batch: &[WorkItem]) -> (u64, u64) {
+    let bytes = batch
+        .iter()
+        .map(|item| match item {
+            WorkItem::CopySmallFile { metadata, .. } | WorkItem::CopyLargeFile { metadata, .. } => {
+                metadata.size
+            }
+            _ => 0,
+        })
+        .sum();
+    (bytes, batch.len() as u64)
+}
+
+/// Daemon wide writer pool, every sync registers a WriteJob and the workers
+/// share themselves between jobs using weighted fair queuing. Previously each
+/// sync got its own pool and concurrent syncs just fought over the disk.
+pub struct WriterPool {
+    jobs: parking_lot::Mutex<HashMap<u128, Arc<WriteJob>>>,
+    fair: parking_lot::Mutex<FairQueue>,
+    /// Worker threads spawned so far, only ever grows
+    workers: parking_lot::Mutex<usize>,
+    shutdown: AtomicBool,
+    backend: WriterBackend,
+    /// Idle workers sleep on this instead of polling, anything that could
+    /// make work show up pokes it via wake().
+    work_ready: tokio::sync::Notify,
+}
+
+impl WriterPool {
+    pub fn new() -> Self {
+        Self {
+            jobs: parking_lot::Mutex::new(HashMap::new()),
+            fair: parking_lot::Mutex::new(FairQueue::new()),
+            workers: parking_lot::Mutex::new(0),
+            shutdown: AtomicBool::new(false),
+            backend: WriterBackend::detect(),
+            work_ready: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Hand a job to the pool, spawns more workers if its dest can use more
+    /// than we have. num_workers_override is how many writers this job gets,
+    /// more or less than the controller would allow, other syncs to the same
+    /// device don't care what it asked for.
+    pub fn add(
+        self: &Arc<Self>,
+        mut job: WriteJob,
+        weight: u32,
+        num_workers_override: Option<usize>,
+    ) {
+        // The controller decides how many of the workers are actually allowed
+        // to do anything for this dest.
+        let max_writers = num_workers_override.map(|n| n.max(1));
+        let num_workers = max_writers.unwrap_or_else(|| job.controller.max_writers());
+        job.max_writers = max_writers;
+        let uuid = job.uuid;
+
+        self.jobs.lock().insert(uuid, Arc::new(job));
+        self.fair.lock().add(uuid, weight);
+
+        tracing::debug!(
+            "{} added to writer pool with weight {} and up to {} writers",
+            uuid::Uuid::from_u128(uuid),
+            weight,
+            num_workers
+        );
+
+        self.ensure_workers(num_workers);
+        self.wake();
+    }
+
+    /// Kick idle workers, call after pushing work onto a job's queue.
+    pub fn wake(&self) {
+        self.work_ready.notify_waiters();
+    }
+
+    /// Drop a job, whatever is left in its queue is abandoned.
+    pub fn remove(&self, uuid: u128) {
+        self.jobs.lock().remove(&uuid);
+        self.fair.lock().remove(uuid);
+    }
+
+    pub fn contains(&self, uuid: u128) -> bool {
+        self.jobs.lock().contains_key(&uuid)
+    }
+
+    pub fn weight(&self, uuid: u128) -> Option<u32> {
+        self.fair.lock().weight(uuid)
+    }
+
+    /// Change how much of the pool a job gets, false if its not in the pool.
+    pub fn set_weight(&self, uuid: u128, weight: u32) -> bool {
+        self.fair.lock().set_weight(uuid, weight)
+    }
+
+    pub fn num_workers(&self) -> usize {
+        *self.workers.lock()
+    }
+
+    fn ensure_workers(self: &Arc<Self>, wanted: usize) {
+        let mut workers = self.workers.lock();
+        if *workers >= wanted {
+            return;
+        }
+
+        let detected_cores = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(0);
+        tracing::debug!(
+            "writer pool growing from {} to {} parallel {:?} workers ({} cores detected)",
+            *workers,
+            wanted,
+            self.backend,
+            detected_cores
+        );
+
+        // Workers are dedicated blocking threads, this avoids spawn_blocking
+        // overhead for every file operation
+        for worker_id in *workers..wanted {
+            let pool = self.clone();
+            std::thread::spawn(move || {
+                tracing::trace!("worker {} starting in blocking thread", worker_id);
+
+                // Create a tokio runtime for this worker thread
+                let rt = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("failed to create worker runtime");
+
+                rt.block_on(async move {
+                    pool.worker_blocking(worker_id).await;
+                });
+
+                tracing::debug!("worker {} thread exiting", worker_id);
+            });
+        }
+        *workers = wanted;
+    }
+
+    /// Get the next batch of work, offered to jobs in fair queue order. Only
+    /// jobs whose controller lets this worker run are considered.
+    async fn next_batch(&self, worker_id: usize) -> Option<(Arc<WriteJob>, Vec<WorkItem>)> {
+        let order = self.fair.lock().order();
+
+        for uuid in order {
+            let Some(job) = self.jobs.lock().get(&uuid).cloned() else {
+                continue;
+            };
+
+            // Controller (or -w) has us benched for this dest
+            if !job.may_run(worker_id) {
+                continue;
+            }
+
+            // Dirs first, then files to avoid parentage missing issues via logical sanity
+            let batch = {
+                let mut queue = job.work_queue.lock().await;
+
+                let batch = queue.pop_batch(job.controller.writer_batch());
+                if !batch.is_empty() {
+                    job.active_workers.fetch_add(1, Ordering::SeqCst);
+                }
+                batch
+            };
+
+            if batch.is_empty() {
+                self.fair.lock().idle(uuid);
+                continue;
+            }
+
+            // Charge up front so other workers see it right away
+            let (bytes, ops) = batch_cost(&batch);
+            self.fair.lock().charge(uuid, bytes, ops);
+
+            return Some((job, batch));
+        }
+
+        None
+    }
+
+    /// Mark anything that finished as done and kick it out of the pool
+    async fn reap(&self) {
+        let jobs: Vec<Arc<WriteJob>> = self.jobs.lock().values().cloned().collect();
+        for job in jobs {
+            if job.check_completion().await {
+                self.remove(job.uuid);
+            }
+        }
+    }
+
+    /// Blocking worker loop - runs in dedicated thread, uses blocking I/O directly
+    // may need a revisit once I get inter node copies/syncs working. I think
+    // network i/o and disk i/o likely need to be separated.
+    async fn worker_blocking(&self, worker_id: usize) {
+        // Each worker thread gets its own ring, if that fails for whatever
+        // reason this worker just does things the normal way.
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let mut uring = if self.backend == WriterBackend::Uring {
+            super::uring::UringCopier::probe()
+        } else {
+            None
+        };
+
+        loop {
+            // Check shutdown signal
+            if self.shutdown.load(Ordering::Relaxed) {
+                tracing::info!("worker {} received shutdown signal, exiting", worker_id);
+                break;
+            }
+
+            // Has to be listening before we go looking for work, otherwise a
+            // wake() between finding nothing and waiting gets lost.
+            let notified = self.work_ready.notified();
+            let mut notified = std::pin::pin!(notified);
+            notified.as_mut().enable();
+
+            let Some((job, work_batch)) = self.next_batch(worker_id).await else {
+                self.reap().await;
+
+                // Nothing to do for anyone, sleep until that changes. Used to
+                // be a 10ms nap which for a watched sync meant 100 wakeups a
+                // second per worker doing nothing.
+                notified.await;
+                continue;
+            };
+
+            let started = std::time::Instant::now();
+            let (bytes, ops) = batch_cost(&work_batch);
+
+            let work_batch = job.skip_unchanged(work_batch);
+
+            // Small files get yeeted through io_uring in one go, whatever
+            // is left over is handled one at a time like normal.
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            let work_batch = match uring.as_mut() {
+                Some(copier) => job.process_uring_batch(copier, work_batch).await,
+                None => work_batch,
+            };
+
+            // Process items in parallel
+            for item in work_batch {
+                tracing::trace!("worker {} processing: {:?}", worker_id, item);
+                if let Err(e) = job.process_work_item(item).await {
+                    tracing::error!("worker {} error: {}", worker_id, e);
+                }
+            }
+
+            job.controller.record(bytes, ops, started.elapsed());
+            job.active_workers.fetch_sub(1, Ordering::SeqCst);
+
+            // Dirs we just made can unblock their kids and the controller may
+            // have let more writers in, let anyone idle have a look.
+            self.wake();
+
+            if job.check_completion().await {
+                self.remove(job.uuid);
+            }
+        }
+    }
+
+    pub fn shutdown(&self) {
         tracing::info!("shutting down writer pool - signaling workers to exit");
-        let mut shutdown = self.shutdown.lock().await;
-        *shutdown = true;
+        self.shutdown.store(true, Ordering::Relaxed);
+        self.wake();
+    }
+}
+
+impl Default for WriterPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(uuid: u128, controller: Arc<Controller>) -> WriteJob {
+        WriteJob::new(
+            uuid,
+            std::env::temp_dir(),
+            Arc::new(Mutex::new(TreeWorkQueue::new())),
+            Progress::default(),
+            Arc::new(Mutex::new(Vec::new())),
+            Limits::default(),
+            controller,
+            FsFeatures::Normal,
+            Arc::new(crate::io::done::Done::new()),
+        )
+    }
+
+    #[test]
+    fn test_override_past_controller() {
+        // Network dests only get a couple writers out of the controller
+        let controller = Arc::new(Controller::new(0, true, get_num_workers()));
+        let wanted = get_num_workers() * 2 + 3;
+
+        let pool = Arc::new(WriterPool::new());
+        pool.add(job(1, controller.clone()), 100, Some(wanted));
+        pool.add(job(2, controller.clone()), 100, None);
+        assert!(pool.num_workers() >= wanted);
+
+        let jobs = pool.jobs.lock();
+        assert!(jobs[&1].may_run(wanted - 1));
+        assert!(!jobs[&1].may_run(wanted));
+
+        // Everyone else on the device is still up to the controller
+        assert!(!jobs[&2].may_run(controller.max_writers()));
+        drop(jobs);
+
+        pool.shutdown();
     }
 }
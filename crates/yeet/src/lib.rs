@@ -2,6 +2,9 @@ pub mod io;
 pub mod rpc;
 pub mod systems;
 
+#[cfg(test)]
+mod testdir;
+
 use bevy::prelude::*;
 
 // Common components for ecs systems/rpc... TODO: better spot for this crap?
@@ -29,6 +32,57 @@ pub struct SimpleCopy;
 #[derive(Debug, Component, Deref)]
 pub struct NumWriters(pub Option<usize>);
 
+// How ready files get ordered for a sync
+#[derive(Debug, Component, Deref)]
+pub struct Schedule(pub crate::io::schedule::SchedulePolicy);
+
+// How to scan the source tree for a sync, None scanners is one per core
+#[derive(Debug, Clone, Copy, Component)]
+pub struct ScanOptions {
+    pub order: crate::io::scan::ScanOrder,
+    pub scanners: Option<usize>,
+}
+
+// Keep watching the source and resyncing changes after the initial copy
+#[derive(Debug, Clone, Copy, Component, Deref)]
+pub struct Watch(pub crate::io::watch::WatchConfig);
+
+// Sync both ways, changes on either side go to the other
+#[derive(Debug, Default, Component)]
+pub struct TwoWay;
+
+// What a finished two-way sync couldn't reconcile, relative paths
+#[derive(Debug, Clone, Component, Deref)]
+pub struct SyncConflicts(pub Vec<(std::path::PathBuf, crate::io::reconcile::ConflictKind)>);
+
+// Star topology, the uuids of the other destinations this sync's scan feeds.
+// Each of those is its own entity with a FanOutLeg pointing back here.
+#[derive(Debug, Clone, Component, Deref)]
+pub struct FanOut(pub Vec<u128>);
+
+// A destination fed by another sync's scan, the uuid is the one scanning.
+// These don't start on their own, the lead starts them.
+#[derive(Debug, Clone, Copy, Component, Deref)]
+pub struct FanOutLeg(pub u128);
+
+// Copy from a read only snapshot of the source instead of the live tree
+#[derive(Debug, Clone, Component, Deref)]
+pub struct SnapshotSource(pub crate::io::snapshot::SnapshotProvider);
+
+// Move whatever gets overwritten or removed on the dest aside instead of
+// losing it, also where resolving a conflict puts the loser.
+#[derive(Debug, Clone, Component, Deref)]
+pub struct Trash(pub crate::io::trash::TrashConfig);
+
+// Skip files the dest already has with the same size/mtime, restarted syncs
+// get this so they don't redo everything.
+#[derive(Debug, Default, Component)]
+pub struct QuickCheck;
+
+// Share of the daemon's writer pool relative to other syncs
+#[derive(Debug, Clone, Copy, Component, Deref, PartialEq, Eq)]
+pub struct Weight(pub u32);
+
 // Successful completion time in seconds since unix epoch
 #[derive(Debug, Component, Deref)]
 pub struct SyncComplete(pub u64);
@@ -37,6 +91,23 @@ pub struct SyncComplete(pub u64);
 #[derive(Debug, Component, Deref)]
 pub struct RemoteHost(pub String);
 
+// The RemoteHost is where the dest is, not the source. The source is local
+// and gets scanned here, the daemon on the remote host does the writing.
+#[derive(Debug, Default, Clone, Copy, Component)]
+pub struct RemoteDest;
+
+// Bandwidth/iops limit for an entity, 0 is unlimited. On a sync entity its
+// that uuid's limit, on the GlobalLimits entity its the daemon wide limit.
+#[derive(Debug, Default, Clone, Copy, Component, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_sec: u64,
+    pub ops_per_sec: u64,
+}
+
+// Marker for the one entity holding the daemon wide RateLimit
+#[derive(Debug, Default, Component)]
+pub struct GlobalLimits;
+
 // SSH port forwarding info
 #[derive(Debug, Component)]
 pub struct SshForwarding {
@@ -51,10 +122,71 @@ pub enum RpcEvent {
         rhs: String,
         uuid: u128,
         writers: Option<usize>,
+        schedule: crate::io::schedule::SchedulePolicy,
+        weight: Option<u32>,
+        scan_order: crate::io::scan::ScanOrder,
+        scanners: Option<usize>,
+        // None means copy once and finish
+        watch: Option<crate::io::watch::WatchConfig>,
+        two_way: bool,
+        // More destinations fed from the same scan, each with its own uuid
+        fan_out: Vec<(u128, String)>,
+        // Snapshot the source first and copy from that
+        snapshot: Option<crate::io::snapshot::SnapshotProvider>,
+        // Keep old versions of dest files, None means no trash
+        trash: Option<crate::io::trash::TrashConfig>,
+        // Journal restart of an op from a previous daemon run
+        resume: bool,
+    },
+    // Decrypted ssh keys for the daemon to keep using
+    AddIdentities {
+        identities: Vec<crate::systems::ssh::Identity>,
     },
     LogLevel {
         level: crate::rpc::loglevel::Level,
     },
+    // None for any field means leave it alone, uuid None means global. Err
+    // back means the uuid isn't a running sync.
+    SetLimits {
+        uuid: Option<u128>,
+        bytes_per_sec: Option<u64>,
+        ops_per_sec: Option<u64>,
+        #[allow(clippy::type_complexity)]
+        response_tx: Arc<Mutex<Option<tokio::sync::oneshot::Sender<Result<(), String>>>>>,
+    },
+    // Err back means the uuid isn't a running sync
+    SetWeight {
+        uuid: u128,
+        weight: u32,
+        #[allow(clippy::type_complexity)]
+        response_tx: Arc<Mutex<Option<tokio::sync::oneshot::Sender<Result<(), String>>>>>,
+    },
+    ListHistory {
+        since: Option<u64>,
+        failed: bool,
+        #[allow(clippy::type_complexity)]
+        response_tx:
+            Arc<Mutex<Option<tokio::sync::oneshot::Sender<Vec<crate::systems::journal::Op>>>>>,
+    },
+    ListConflicts {
+        #[allow(clippy::type_complexity)]
+        response_tx: Arc<
+            Mutex<Option<tokio::sync::oneshot::Sender<Vec<crate::systems::conflicts::Conflict>>>>,
+        >,
+    },
+    // Path is absolute, either side's copy of the conflicted path
+    ResolveConflict {
+        path: std::path::PathBuf,
+        keep: crate::io::resolve::Keep,
+        #[allow(clippy::type_complexity)]
+        response_tx: Arc<Mutex<Option<tokio::sync::oneshot::Sender<Result<String, String>>>>>,
+    },
+    // Internal, sent once a resolve has done its i/o
+    ConflictResolved {
+        lhs: std::path::PathBuf,
+        rhs: std::path::PathBuf,
+        path: std::path::PathBuf,
+    },
     Heartbeat {
         target: String,
         #[allow(clippy::type_complexity)]
@@ -77,6 +209,27 @@ pub struct SyncEventSender(pub Arc<Mutex<UnboundedSender<RpcEvent>>>);
 #[derive(Resource, Clone)]
 pub struct IoSubsystemResource(pub io::IoSubsystem);
 
+/// Daemon wide rate limit registry, every IoSubsystem shares this so the
+/// global limit applies across all syncs.
+#[derive(Resource, Clone, Default)]
+pub struct RateLimits(pub io::limits::Limits);
+
+/// Daemon wide writer concurrency controllers, one per destination device.
+#[derive(Resource, Clone, Default)]
+pub struct WriterControllers(pub io::controller::Controllers);
+
+/// Memory budget every sync's queued work gets, set at daemon startup.
+#[derive(Resource, Clone, Copy, Default)]
+pub struct MemoryBudget(pub io::budget::MemoryBudget);
+
+/// What other daemons can sync to or from through us, set at daemon startup.
+#[derive(Resource, Clone, Default)]
+pub struct Exports(pub io::remote::Exports);
+
+/// Daemon wide writer pool, syncs get a share of it based on their Weight.
+#[derive(Resource, Clone, Default)]
+pub struct SharedWriters(pub Arc<io::writer::WriterPool>);
+
 /// Component that holds a handle to an active I/O operation
 #[derive(Component, Clone)]
 pub struct IoOperation {
@@ -102,6 +255,18 @@ pub struct IoProgress {
     pub throughput_bps: f64,
 }
 
+/// What the writer concurrency controller for a sync's destination is doing,
+/// updated along with IoProgress.
+#[derive(Component, Clone, Debug, Default, PartialEq)]
+pub struct WriterConcurrency {
+    pub active_writers: usize,
+    pub max_writers: usize,
+    pub writer_batch: usize,
+    pub queue_batch: usize,
+    pub avg_latency_us: f64,
+    pub network: bool,
+}
+
 // This is crap code but whatever its good enough for gov work
 //
 // For a quick mvp going to implement this sync inside a system directly. Which
@@ -156,13 +321,46 @@ pub enum DebugError {
 
 // Parse "host:/path" or "/path" syntax
 // Returns (host, path) where host is None for local paths
+//
+// A port needs the host in brackets, "[host]:port:/path" or
+// "user@[host]:port:/path", same as v6 addresses. The host comes back as
+// "[host]:port" for SshTarget to pull apart. Without brackets "nas:2222:/vol"
+// is host nas and path "2222:/vol" like scp would do.
 pub fn parse_remote_spec(spec: &str) -> Result<(Option<String>, std::path::PathBuf), String> {
-    if let Some((host, path)) = spec.split_once(':') {
-        // Remote: "user@host:/path"
-        Ok((Some(host.to_string()), std::path::PathBuf::from(path)))
+    // Same rules as scp, the first : not inside [] splits host from path
+    // unless a / comes first, then its all a local path. "./a:b" is how you
+    // say a local file with a : in it.
+    let split = if spec.starts_with('[') || spec.contains("@[") {
+        spec.find("]:").map(|idx| {
+            let after = &spec[idx + 2..];
+            match after.split_once(':') {
+                Some((port, _)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+                    idx + 2 + port.len()
+                }
+                _ => idx + 1,
+            }
+        })
     } else {
+        spec.find(':')
+    };
+
+    match split {
+        Some(idx) if !spec[..idx].contains('/') => {
+            // Remote: "user@host:/path", host can be an ~/.ssh/config alias
+            let host = &spec[..idx];
+            if host.is_empty() || host.ends_with('@') {
+                return Err(format!("no host in {}", spec));
+            }
+            if host.starts_with('@') {
+                return Err(format!("no user in {}", spec));
+            }
+            Ok((
+                Some(host.to_string()),
+                std::path::PathBuf::from(&spec[idx + 1..]),
+            ))
+        }
         // Local: "/path"
-        Ok((None, std::path::PathBuf::from(spec)))
+        _ => Ok((None, std::path::PathBuf::from(spec))),
     }
 }
 
@@ -306,3 +504,90 @@ pub fn get_uds_file() -> Result<std::path::PathBuf> {
 
     Ok(proj_cache.cache_dir().join("local.uds"))
 }
+
+// Data dir not cache dir, history shouldn't vanish if someone cleans caches.
+pub fn get_journal_file() -> Result<std::path::PathBuf> {
+    let proj = directories::ProjectDirs::from("net", "mitchty", "yeet")
+        .expect("couldn't determine project directory location");
+
+    Ok(proj.data_dir().join("journal.jsonl"))
+}
+
+// Two-way sync baselines, one per lhs/rhs pair so running the same two-way
+// sync again picks up where the last one left off. Data dir for the same
+// reason as the journal, losing these turns every difference into a conflict.
+pub fn get_baseline_file(
+    lhs: &std::path::Path,
+    rhs: &std::path::Path,
+) -> Result<std::path::PathBuf> {
+    let proj = directories::ProjectDirs::from("net", "mitchty", "yeet")
+        .expect("couldn't determine project directory location");
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(lhs.as_os_str().as_encoded_bytes());
+    hasher.update(b"\0");
+    hasher.update(rhs.as_os_str().as_encoded_bytes());
+
+    Ok(proj
+        .data_dir()
+        .join("baselines")
+        .join(format!("{}.jsonl", &hasher.finalize().to_hex()[..32])))
+}
+
+pub fn get_conflicts_file() -> Result<std::path::PathBuf> {
+    let proj = directories::ProjectDirs::from("net", "mitchty", "yeet")
+        .expect("couldn't determine project directory location");
+
+    Ok(proj.data_dir().join("conflicts.jsonl"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_remote_spec() {
+        let remote = |host: &str, path: &str| Ok((Some(host.to_string()), PathBuf::from(path)));
+        let local = |path: &str| Ok((None, PathBuf::from(path)));
+
+        assert_eq!(parse_remote_spec("/a/b"), local("/a/b"));
+        assert_eq!(parse_remote_spec("a/b"), local("a/b"));
+        assert_eq!(parse_remote_spec("nas:/vol"), remote("nas", "/vol"));
+        assert_eq!(parse_remote_spec("me@nas:/vol"), remote("me@nas", "/vol"));
+        assert_eq!(parse_remote_spec("nas:vol"), remote("nas", "vol"));
+        assert_eq!(parse_remote_spec("nas:"), remote("nas", ""));
+
+        // A / before the : means its all local
+        assert_eq!(parse_remote_spec("./a:b"), local("./a:b"));
+        assert_eq!(parse_remote_spec("/a:b"), local("/a:b"));
+
+        // v6 needs brackets, the :'s inside don't count
+        assert_eq!(parse_remote_spec("[::1]:/vol"), remote("[::1]", "/vol"));
+        assert_eq!(
+            parse_remote_spec("me@[fe80::1%eth0]:/vol"),
+            remote("me@[fe80::1%eth0]", "/vol")
+        );
+
+        // So does a port
+        assert_eq!(
+            parse_remote_spec("[nas]:2222:/vol"),
+            remote("[nas]:2222", "/vol")
+        );
+        assert_eq!(
+            parse_remote_spec("me@[nas]:2222:/vol"),
+            remote("me@[nas]:2222", "/vol")
+        );
+        assert_eq!(parse_remote_spec("[::1]:22:vol"), remote("[::1]:22", "vol"));
+        assert_eq!(
+            parse_remote_spec("me@nas:2222:/vol"),
+            remote("me@nas", "2222:/vol")
+        );
+
+        assert!(parse_remote_spec(":/vol").is_err());
+        assert!(parse_remote_spec("@nas:/vol").is_err());
+        assert!(parse_remote_spec("@[nas]:22:/vol").is_err());
+        assert!(parse_remote_spec("me@:/vol").is_err());
+        assert!(parse_remote_spec("@:/vol").is_err());
+    }
+}
@@ -54,6 +54,40 @@ enum SubCommands {
         /// Default tick frequency
         #[arg(short, long, default_value_t = 30)]
         ticks: usize,
+
+        /// Memory each sync's queued work can use before the reader gets
+        /// slowed down and blocked work spills to disk, K/M/G suffixes are binary
+        #[arg(short, long, default_value = "256M")]
+        memory_budget: String,
+
+        /// How long finished syncs stay in history, 0 keeps them forever
+        #[arg(long, default_value = "30days")]
+        history_keep: String,
+
+        /// Most finished syncs to keep in history, 0 is no limit
+        #[arg(long, default_value_t = lib::systems::journal::DEFAULT_RETENTION_OPS)]
+        history_max: usize,
+
+        /// What to do with ssh host keys, strict only allows hosts already in
+        /// known_hosts, accept-new adds hosts it hasn't seen, off doesn't check
+        #[arg(long, default_value = "accept-new", value_parser = ["strict", "accept-new", "off"])]
+        host_key_checking: String,
+
+        /// ssh private key files to try after ssh-agent, can be repeated
+        /// (default: ~/.ssh/id_ed25519, id_ecdsa and id_rsa)
+        #[arg(short, long)]
+        identity: Vec<std::path::PathBuf>,
+
+        /// Ways to get to a remote, name=spec,spec... where each spec is a
+        /// host or ~/.ssh/config alias. Syncs to name go over whichever
+        /// heartbeats fastest, can be repeated.
+        #[arg(long)]
+        route: Vec<String>,
+
+        /// Absolute dir other daemons can sync to or from through this one,
+        /// can be repeated. Nothing is exported by default.
+        #[arg(long)]
+        export: Vec<std::path::PathBuf>,
     },
 
     /// Monitor daemon state and sync progress
@@ -73,15 +107,171 @@ enum SubCommands {
     // mitch problem if ever.
     #[cfg(unix)]
     Cp {
-        /// Source path
+        /// Source path, host:/path for one on another machine,
+        /// [host]:port:/path for ssh on another port
         source: String,
 
-        /// Destination path
-        dest: String,
+        /// Destination path, more than one copies to each of them from a
+        /// single scan of the source. host:/path for one on another machine,
+        /// [host]:port:/path for ssh on another port.
+        #[arg(required = true, num_args = 1..)]
+        dest: Vec<String>,
 
         /// Number of parallel writer workers (default: CPU core count)
         #[arg(short = 'w', long, default_value = None)]
         writers: Option<usize>,
+
+        /// File order: fifo, smallest-first or newest-first
+        #[arg(short, long)]
+        schedule: Option<String>,
+
+        /// Dest path glob to copy first e.g. 'src/**', can be repeated
+        #[arg(short, long)]
+        priority: Vec<String>,
+
+        /// Seconds a file can wait before it jumps the queue, 0 disables
+        #[arg(short, long)]
+        aging: Option<u64>,
+
+        /// Share of the daemon's writers relative to other syncs (default: 100)
+        #[arg(long)]
+        weight: Option<u32>,
+
+        /// Directory scan order: bfs or dfs
+        #[arg(long)]
+        scan: Option<String>,
+
+        /// Number of parallel directory scanners (default: CPU core count, max 8)
+        #[arg(long)]
+        scanners: Option<u32>,
+
+        /// Keep syncing changes after the initial copy, optionally picking
+        /// the backend: auto, inotify, fanotify or poll
+        #[arg(long, num_args = 0..=1, default_missing_value = "auto")]
+        watch: Option<String>,
+
+        /// Seconds between polling passes when watching with poll (default: 5)
+        #[arg(long, requires = "watch")]
+        poll_interval: Option<u64>,
+
+        /// Sync both ways, changes on either side go to the other. Paths
+        /// changed on both sides are left alone, see yeet conflicts.
+        #[arg(long, conflicts_with = "watch")]
+        two_way: bool,
+
+        /// Copy from a read only snapshot of the source: btrfs, or
+        /// cmd:<command> which gets YEET_SNAPSHOT_ACTION=create|release and
+        /// prints the snapshot path when creating
+        #[arg(long, conflicts_with_all = ["watch", "two_way"])]
+        snapshot: Option<String>,
+
+        /// Move anything overwritten or removed on the dest into
+        /// .yeet-trash/<timestamp>/ there first, see yeet restore
+        #[arg(long)]
+        trash: bool,
+
+        /// Keep old versions here instead of .yeet-trash at the dest, implies
+        /// --trash
+        #[arg(long)]
+        trash_dir: Option<String>,
+
+        /// How long to keep old versions, 0 keeps them forever (default:
+        /// 30days), implies --trash
+        #[arg(long)]
+        trash_keep: Option<String>,
+
+        /// Most old versions of any one path to keep, implies --trash
+        #[arg(long)]
+        trash_versions: Option<u32>,
+
+        /// ssh private key for remote hosts, can be repeated. Encrypted ones
+        /// get asked for their passphrase here, the daemon keeps using them
+        /// after like ssh-add.
+        #[arg(short, long)]
+        identity: Vec<std::path::PathBuf>,
+    },
+
+    /// Set bandwidth/iops limits for the daemon or a single sync
+    #[cfg(unix)]
+    Limit {
+        /// Sync uuid to limit, without this the limit is daemon wide
+        #[arg(short, long)]
+        uuid: Option<String>,
+
+        /// Bytes per second, K/M/G/T suffixes are binary, 0 removes the limit
+        #[arg(short, long)]
+        bytes: Option<String>,
+
+        /// File operations per second, 0 removes the limit
+        #[arg(short, long)]
+        ops: Option<u64>,
+    },
+
+    /// Change how much of the daemon's writers a running sync gets
+    #[cfg(unix)]
+    Weight {
+        /// Sync uuid to change
+        uuid: String,
+
+        /// New weight, relative to other syncs which default to 100
+        weight: u32,
+    },
+
+    /// Show history of syncs the daemon has done, one line each
+    #[cfg(unix)]
+    Log {
+        /// Only syncs requested since, either a duration ago like 2h/3days or
+        /// a timestamp like 2025-01-01T00:00:00Z
+        #[arg(short, long)]
+        since: Option<String>,
+
+        /// Only syncs that finished with errors
+        #[arg(short, long)]
+        failed: bool,
+    },
+
+    /// List two-way sync conflicts waiting on someone to resolve them, one
+    /// per line: detected, uuid, kind, lhs path, rhs path
+    #[cfg(unix)]
+    Conflicts,
+
+    /// Resolve a two-way sync conflict by picking what to keep
+    #[cfg(unix)]
+    Resolve {
+        /// Either side's copy of the conflicted path
+        path: std::path::PathBuf,
+
+        /// What to keep, both-renamed keeps both sides as name.lhs/name.rhs
+        #[arg(short, long, value_parser = ["lhs", "rhs", "both-renamed", "newest"])]
+        keep: String,
+    },
+
+    /// Bring back a version of a path kept in a sync's trash, the newest one
+    /// unless told otherwise. Whatever is there now gets kept in turn.
+    Restore {
+        /// Path on the dest to restore, doesn't have to exist anymore
+        path: std::path::PathBuf,
+
+        /// Timestamp of the version to restore, see --list
+        #[arg(short, long)]
+        version: Option<String>,
+
+        /// Trash dir if the sync used --trash-dir
+        #[arg(short, long)]
+        trash: Option<std::path::PathBuf>,
+
+        /// List the kept versions newest first instead of restoring
+        #[arg(short, long)]
+        list: bool,
+    },
+
+    /// What a daemon runs on hosts with no yeet daemon, serves remote syncs
+    /// over stdin/stdout. Not for running by hand.
+    #[command(hide = true)]
+    Agent {
+        /// Talk grpc over stdin/stdout, the only way an agent talks for now
+        #[arg(long)]
+        stdio: bool,
     },
 }
 
@@ -103,30 +293,13 @@ enum SubCommands {
 
 use log::Level;
 
+// Connect to the local daemon over its unix domain socket, exits if the
+// daemon isn't running.
 #[cfg(unix)]
-async fn request_local_cp(
-    source: &str,
-    dest: &str,
-    writers: Option<usize>,
-) -> Result<(), Box<dyn Error>> {
-    use std::path::Path;
+async fn connect_local() -> Result<tonic::transport::Channel, Box<dyn Error>> {
     use tokio::net::UnixStream;
     use tonic::transport::{Endpoint, Uri};
 
-    // TODO: this needs more panache, : is perfectly valid within a uri but I
-    // need to add parsing logic to better handle host:some/path For now
-    // whatever this is good enough for government work v0 code.
-    if !source.contains(':') {
-        let source_path = Path::new(source);
-        if !source_path.exists() {
-            eprintln!(
-                "fatal: source '{}' does not exist, cannot copy non existent things",
-                source
-            );
-            std::process::exit(1);
-        }
-    }
-
     let uds_path = lib::get_uds_file().expect("couldn't get uds path");
     if !uds_path.exists() {
         eprintln!(
@@ -154,27 +327,373 @@ async fn request_local_cp(
             .await?
     };
 
-    use lib::rpc::yeet::SyncSimpleCopyRequest;
+    Ok(channel)
+}
+
+#[cfg(unix)]
+async fn request_local_cp(
+    request: lib::rpc::yeet::SyncSimpleCopyRequest,
+) -> Result<(), Box<dyn Error>> {
+    use std::path::Path;
+
+    let source = request.lhs.as_str();
+
+    // TODO: this needs more panache, : is perfectly valid within a uri but I
+    // need to add parsing logic to better handle host:some/path For now
+    // whatever this is good enough for government work v0 code.
+    if !source.contains(':') {
+        let source_path = Path::new(source);
+        if !source_path.exists() {
+            eprintln!(
+                "fatal: source '{}' does not exist, cannot copy non existent things",
+                source
+            );
+            std::process::exit(1);
+        }
+    }
+
+    use lib::rpc::yeet::yeet_client::YeetClient;
+
+    let mut client = YeetClient::new(connect_local().await?);
+
+    let response = client.simple_copy(tonic::Request::new(request)).await?;
+    let reply = response.into_inner();
+
+    // TODO: need a query grpc at some point to complement rpc like approach.
+    // This is intended for scripted usage of things. One uuid per
+    // destination in the order they were given.
+    println!("{}", reply.uuid);
+    for uuid in reply.fan_out_uuids {
+        println!("{uuid}");
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn request_local_limit(
+    uuid: Option<String>,
+    bytes: Option<String>,
+    ops: Option<u64>,
+) -> Result<(), Box<dyn Error>> {
+    use lib::rpc::yeet::SetLimitsRequest;
     use lib::rpc::yeet::yeet_client::YeetClient;
 
-    let mut client = YeetClient::new(channel);
+    if bytes.is_none() && ops.is_none() {
+        eprintln!("fatal: need at least one of --bytes or --ops to set");
+        std::process::exit(1);
+    }
 
-    let request = tonic::Request::new(SyncSimpleCopyRequest {
-        lhs: source.to_string(),
-        rhs: dest.to_string(),
-        writers: writers.map(|w| w as u32),
+    let bytes_per_sec = match bytes {
+        Some(b) => match lib::io::limits::parse_size(&b) {
+            Ok(n) => Some(n),
+            Err(e) => {
+                eprintln!("fatal: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut client = YeetClient::new(connect_local().await?);
+
+    let request = tonic::Request::new(SetLimitsRequest {
+        uuid,
+        bytes_per_sec,
+        ops_per_sec: ops,
     });
 
-    let response = client.simple_copy(request).await?;
-    let uuid = response.into_inner().uuid;
+    client.set_limits(request).await?;
 
-    // TODO: need a query grpc at some point to complement rpc like approach.
-    // This is intended for scripted usage of things.
-    println!("{uuid}");
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn request_local_weight(uuid: String, weight: u32) -> Result<(), Box<dyn Error>> {
+    use lib::rpc::yeet::SetWeightRequest;
+    use lib::rpc::yeet::yeet_client::YeetClient;
+
+    let mut client = YeetClient::new(connect_local().await?);
+
+    let request = tonic::Request::new(SetWeightRequest { uuid, weight });
+
+    client.set_weight(request).await?;
+
+    Ok(())
+}
+
+// Accept either "some duration ago" or an actual timestamp
+fn parse_since(since: &str) -> Result<u64, String> {
+    let when = match humantime::parse_duration(since) {
+        Ok(ago) => std::time::SystemTime::now()
+            .checked_sub(ago)
+            .unwrap_or(std::time::UNIX_EPOCH),
+        Err(_) => humantime::parse_rfc3339_weak(since).map_err(|e| {
+            format!(
+                "invalid --since '{}', not a duration or timestamp: {}",
+                since, e
+            )
+        })?,
+    };
+
+    Ok(when
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs())
+}
+
+#[cfg(unix)]
+fn history_line(op: &lib::rpc::yeet::HistoryEntry) -> String {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let when = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(op.requested));
+    let mut line = format!(
+        "{} {} {:<9} {} -> {}",
+        when, op.uuid, op.state, op.lhs, op.rhs
+    );
+
+    if op.files > 0 || op.bytes > 0 {
+        line.push_str(&format!(
+            " {} files {}",
+            op.files,
+            humansize::format_size(op.bytes, humansize::BINARY)
+        ));
+    }
+
+    if let (Some(started), Some(finished)) = (op.started, op.finished) {
+        line.push_str(&format!(
+            " in {}",
+            humantime::format_duration(Duration::from_secs(finished.saturating_sub(started)))
+        ));
+    }
+
+    if op.throughput_bps > 0 {
+        line.push_str(&format!(
+            " ({}/s)",
+            humansize::format_size(op.throughput_bps, humansize::BINARY)
+        ));
+    }
+
+    if op.errors > 0 {
+        line.push_str(&format!(", {} errors", op.errors));
+    }
+
+    if op.restarts > 0 {
+        line.push_str(&format!(", {} restarts", op.restarts));
+    }
+
+    line
+}
+
+#[cfg(unix)]
+async fn request_local_log(since: Option<String>, failed: bool) -> Result<(), Box<dyn Error>> {
+    use lib::rpc::yeet::ListHistoryRequest;
+    use lib::rpc::yeet::yeet_client::YeetClient;
+
+    let since = match since {
+        Some(s) => match parse_since(&s) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                eprintln!("fatal: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut client = YeetClient::new(connect_local().await?);
+
+    let response = client
+        .list_history(tonic::Request::new(ListHistoryRequest { since, failed }))
+        .await?;
+
+    for op in response.into_inner().ops {
+        println!("{}", history_line(&op));
+    }
 
     Ok(())
 }
 
+#[cfg(unix)]
+async fn request_local_conflicts() -> Result<(), Box<dyn Error>> {
+    use lib::rpc::yeet::yeet_client::YeetClient;
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let mut client = YeetClient::new(connect_local().await?);
+
+    let response = client.list_conflicts(tonic::Request::new(())).await?;
+
+    for c in response.into_inner().conflicts {
+        println!(
+            "{} {} {:<12} {} {}",
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(c.detected)),
+            c.uuid,
+            c.kind,
+            Path::new(&c.lhs).join(&c.path).display(),
+            Path::new(&c.rhs).join(&c.path).display()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn request_local_resolve(
+    path: std::path::PathBuf,
+    keep: String,
+) -> Result<(), Box<dyn Error>> {
+    use lib::rpc::yeet::ResolveConflictRequest;
+    use lib::rpc::yeet::yeet_client::YeetClient;
+
+    // Daemon's cwd isn't ours
+    let path = std::path::absolute(&path)?;
+
+    let mut client = YeetClient::new(connect_local().await?);
+
+    match client
+        .resolve_conflict(tonic::Request::new(ResolveConflictRequest {
+            path: path.display().to_string(),
+            keep,
+        }))
+        .await
+    {
+        Ok(response) => {
+            println!("{}: {}", path.display(), response.into_inner().message);
+            Ok(())
+        }
+        Err(status) => {
+            eprintln!("fatal: {}", status.message());
+            std::process::exit(1);
+        }
+    }
+}
+
+// No daemon needed, the trash is just files next to (or wherever) the dest.
+// We don't know which dest root the path is under so try each parent in turn
+// until one has a trash with this path in it.
+fn restore(
+    path: std::path::PathBuf,
+    version: Option<String>,
+    dir: Option<std::path::PathBuf>,
+    list: bool,
+) -> Result<(), Box<dyn Error>> {
+    use lib::io::trash;
+
+    let path = std::path::absolute(&path)?;
+    let found = path.ancestors().skip(1).find_map(|root| {
+        let relative = path.strip_prefix(root).ok()?;
+        let config = trash::TrashConfig {
+            dir: dir.clone(),
+            ..Default::default()
+        };
+        let versions = trash::versions(&config.dir_for(root), relative).ok()?;
+        (!versions.is_empty())
+            .then(|| (root.to_path_buf(), relative.to_path_buf(), config, versions))
+    });
+
+    let Some((root, relative, config, versions)) = found else {
+        eprintln!("fatal: no kept versions of {}", path.display());
+        std::process::exit(1);
+    };
+
+    if list {
+        for v in versions {
+            println!("{} {}", v.stamp, v.path.display());
+        }
+        return Ok(());
+    }
+
+    match trash::restore(&root, &config, &relative, version.as_deref()) {
+        Ok(restored) => {
+            for path in restored {
+                println!("restored {}", root.join(path).display());
+            }
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("fatal: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Check the key here so an encrypted one can be asked about, the daemon has
+// nobody to ask.
+#[cfg(unix)]
+fn unlock_identity(
+    path: std::path::PathBuf,
+) -> Result<lib::rpc::yeet::SshIdentity, Box<dyn Error>> {
+    use russh::keys;
+
+    let path = std::path::absolute(&path)?;
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| format!("can't read key {}: {}", path.display(), e))?;
+
+    let mut passphrase = None;
+    let mut tries = 0;
+    loop {
+        match keys::decode_secret_key(&contents, passphrase.as_deref()) {
+            Ok(_) => {
+                return Ok(lib::rpc::yeet::SshIdentity {
+                    path: path.display().to_string(),
+                    passphrase,
+                });
+            }
+            Err(keys::Error::KeyIsEncrypted) if passphrase.is_none() => {}
+            // ssh gives you three goes too
+            Err(_) if passphrase.is_some() && tries < 3 => {
+                eprintln!("bad passphrase, try again");
+            }
+            Err(e) => return Err(format!("can't use key {}: {}", path.display(), e).into()),
+        }
+        tries += 1;
+        passphrase = Some(read_passphrase(&format!(
+            "Enter passphrase for key '{}': ",
+            path.display()
+        ))?);
+    }
+}
+
+// No echo, raw mode is the only way crossterm gets us that
+#[cfg(unix)]
+fn read_passphrase(prompt: &str) -> Result<String, Box<dyn Error>> {
+    use crossterm::event::{Event, KeyEvent, KeyEventKind, read};
+    use std::io::Write;
+
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+
+    crossterm::terminal::enable_raw_mode()?;
+    let mut passphrase = String::new();
+    let result = loop {
+        match read() {
+            Ok(Event::Key(KeyEvent {
+                code,
+                modifiers,
+                kind: KeyEventKind::Press,
+                ..
+            })) => match code {
+                KeyCode::Enter => break Ok(()),
+                KeyCode::Backspace => {
+                    passphrase.pop();
+                }
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    break Err("interrupted".into());
+                }
+                KeyCode::Char(c) => passphrase.push(c),
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(e) => break Err(e.into()),
+        }
+    };
+    let _ = crossterm::terminal::disable_raw_mode();
+    eprintln!();
+
+    result.map(|_| passphrase)
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
@@ -209,14 +728,165 @@ fn main() -> Result<(), Box<dyn Error>> {
             source,
             dest,
             writers,
+            schedule,
+            priority,
+            aging,
+            weight,
+            scan,
+            scanners,
+            watch,
+            poll_interval,
+            two_way,
+            snapshot,
+            trash,
+            trash_dir,
+            trash_keep,
+            trash_versions,
+            identity,
         } => {
+            if two_way && dest.len() > 1 {
+                eprintln!("fatal: --two-way only works with one destination");
+                std::process::exit(1);
+            }
+            let trash_max_age_secs =
+                trash_keep.map(|keep| match humantime::parse_duration(&keep) {
+                    Ok(d) => d.as_secs(),
+                    Err(_) if keep == "0" => 0,
+                    Err(e) => {
+                        eprintln!("fatal: invalid --trash-keep '{}': {}", keep, e);
+                        std::process::exit(1);
+                    }
+                });
+            let mut dest = dest.into_iter();
+            let request = lib::rpc::yeet::SyncSimpleCopyRequest {
+                lhs: source,
+                rhs: dest.next().unwrap_or_default(),
+                writers: writers.map(|w| w as u32),
+                schedule,
+                priority,
+                aging_secs: aging,
+                weight,
+                scan_order: scan,
+                scanners,
+                watch,
+                poll_secs: poll_interval,
+                two_way: two_way.then_some(true),
+                fan_out: dest.collect(),
+                snapshot,
+                trash: trash.then_some(true),
+                trash_dir,
+                trash_max_age_secs,
+                trash_max_versions: trash_versions,
+                identities: identity
+                    .into_iter()
+                    .map(unlock_identity)
+                    .collect::<Result<_, _>>()?,
+            };
+            let runtime = tokio::runtime::Runtime::new()?;
+            return runtime.block_on(request_local_cp(request));
+        }
+        #[cfg(unix)]
+        SubCommands::Limit { uuid, bytes, ops } => {
             let runtime = tokio::runtime::Runtime::new()?;
-            return runtime.block_on(request_local_cp(&source, &dest, writers));
+            return runtime.block_on(request_local_limit(uuid, bytes, ops));
+        }
+        #[cfg(unix)]
+        SubCommands::Weight { uuid, weight } => {
+            let runtime = tokio::runtime::Runtime::new()?;
+            return runtime.block_on(request_local_weight(uuid, weight));
+        }
+        #[cfg(unix)]
+        SubCommands::Log { since, failed } => {
+            let runtime = tokio::runtime::Runtime::new()?;
+            return runtime.block_on(request_local_log(since, failed));
+        }
+        #[cfg(unix)]
+        SubCommands::Conflicts => {
+            let runtime = tokio::runtime::Runtime::new()?;
+            return runtime.block_on(request_local_conflicts());
+        }
+        #[cfg(unix)]
+        SubCommands::Resolve { path, keep } => {
+            let runtime = tokio::runtime::Runtime::new()?;
+            return runtime.block_on(request_local_resolve(path, keep));
+        }
+        SubCommands::Restore {
+            path,
+            version,
+            trash,
+            list,
+        } => {
+            return restore(path, version, trash, list);
+        }
+        SubCommands::Agent { stdio } => {
+            if !stdio {
+                eprintln!("fatal: agent only knows --stdio");
+                std::process::exit(1);
+            }
+            // stdout is the connection, logging goes to stderr which the
+            // daemon on the other end logs for us.
+            tracing_subscriber::fmt()
+                .with_writer(std::io::stderr)
+                .with_ansi(false)
+                .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+                .init();
+            let runtime = tokio::runtime::Runtime::new()?;
+            runtime.block_on(lib::systems::ssh::agent::serve_stdio())?;
+            return Ok(());
         }
         SubCommands::Serve {
             verbose: _verbose,
             ticks,
+            memory_budget,
+            history_keep,
+            history_max,
+            host_key_checking,
+            identity,
+            route,
+            export,
         } => {
+            if let Some(path) = export.iter().find(|p| !p.is_absolute()) {
+                eprintln!("fatal: export {} has to be absolute", path.display());
+                std::process::exit(1);
+            }
+            appbinding.insert_resource(lib::Exports(lib::io::remote::Exports::Roots(export)));
+
+            appbinding.insert_resource(lib::systems::ssh::Identities::new(identity));
+
+            match route
+                .iter()
+                .map(|r| lib::systems::ssh::Routes::parse(r))
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(routes) => {
+                    appbinding.insert_resource(lib::systems::ssh::Routes::new(routes));
+                }
+                Err(e) => {
+                    eprintln!("fatal: {}", e);
+                    std::process::exit(1);
+                }
+            }
+
+            match host_key_checking.parse::<lib::systems::ssh::HostKeyPolicy>() {
+                Ok(policy) => {
+                    appbinding.insert_resource(policy);
+                }
+                Err(e) => {
+                    eprintln!("fatal: {}", e);
+                    std::process::exit(1);
+                }
+            }
+
+            match lib::io::budget::MemoryBudget::parse(&memory_budget) {
+                Ok(budget) => {
+                    appbinding.insert_resource(lib::MemoryBudget(budget));
+                }
+                Err(e) => {
+                    eprintln!("fatal: invalid memory budget: {}", e);
+                    std::process::exit(1);
+                }
+            }
+
             if ticks > 0 {
                 appbinding.add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(
                     Duration::from_secs_f64(1.0 / ticks as f64),
@@ -248,6 +918,38 @@ fn main() -> Result<(), Box<dyn Error>> {
                 lib::systems::grpc::GrpcPlugin,
                 lib::systems::netcode::server::LightYearServerPlugin,
             ));
+            // Heartbeats are how remotes probe our routes to us
+            appbinding.add_plugins((
+                lib::systems::heartbeat::Heartbeat,
+                lib::systems::ssh::Router,
+            ));
+            let max_age = match humantime::parse_duration(&history_keep) {
+                Ok(d) if d.is_zero() => None,
+                Ok(d) => Some(d),
+                Err(_) if history_keep == "0" => None,
+                Err(e) => {
+                    eprintln!("fatal: invalid --history-keep '{}': {}", history_keep, e);
+                    std::process::exit(1);
+                }
+            };
+            let retention = lib::systems::journal::Retention {
+                max_age,
+                max_ops: (history_max > 0).then_some(history_max),
+            };
+
+            match lib::get_journal_file() {
+                Ok(path) => {
+                    appbinding
+                        .add_plugins(lib::systems::journal::JournalPlugin { path, retention });
+                }
+                Err(e) => warn!("no journal, syncs won't survive a restart: {}", e),
+            }
+            match lib::get_conflicts_file() {
+                Ok(path) => {
+                    appbinding.add_plugins(lib::systems::conflicts::ConflictsPlugin { path });
+                }
+                Err(e) => warn!("two-way conflicts won't be tracked: {}", e),
+            }
             appbinding.add_systems(Update, toggle_logging_level_debug);
         }
         SubCommands::Monitor { host: _, ticks } => {
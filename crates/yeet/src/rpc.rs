@@ -1,2 +1,3 @@
 pub mod loglevel;
+pub mod transfer;
 pub mod yeet;
@@ -0,0 +1,1014 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bevy::prelude::debug;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::io::remote::{self, CHUNK_SIZE, Receiver};
+use crate::io::scan::ScanOrder;
+use crate::io::snapshot::SnapshotProvider;
+use crate::io::work::WorkItem;
+use crate::rpc::transfer::transfer_client::TransferClient;
+use crate::rpc::transfer::transfer_server::Transfer;
+
+tonic::include_proto!("transfer");
+
+// Both ends of remote dests and sources, see io/remote.rs for the why.
+//
+// MyTransfer is the remote daemon's side, send() and pull() are ours.
+//
+// Sending, small stuff goes down one PutFiles stream in scan order so dirs
+// still come before what's in them, big files get their own PutLargeFile call
+// each (a few at once) so one giant file doesn't hold up everything behind it.
+// The scan complete sentinel waits until every big file is over there,
+// otherwise the other end would think its done early.
+//
+// Pulling, the Scan stream is everything in scan order and every file gets
+// its own GetFile call, a few at once. Same deal with scan complete, it waits
+// on every file being here.
+//
+// TODO: a GetFile per small file is a lot of round trips for a tree of tiny
+// files, batch them up like PutFiles does if it turns out to matter.
+
+/// Small files and work items that can be in flight on the stream
+const STREAM_DEPTH: usize = 16;
+
+/// Big files being sent at once
+const LARGE_UPLOADS: usize = 4;
+
+/// Files being pulled at once
+const PULLS: usize = 8;
+
+/// How often to ask the other end how its going
+const STATUS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Remote end of remote syncs. Every sync being sent here gets its own
+/// receiver with the same daemon wide writers/limits a local sync would get,
+/// every one being pulled from here gets its own scan.
+#[derive(Clone)]
+pub struct MyTransfer {
+    exports: remote::Exports,
+    limits: crate::io::limits::Limits,
+    controllers: crate::io::controller::Controllers,
+    writers: Arc<crate::io::writer::WriterPool>,
+    budget: crate::io::budget::MemoryBudget,
+    receivers: Arc<tokio::sync::Mutex<HashMap<u128, Arc<Receiver>>>>,
+    scans: Arc<tokio::sync::Mutex<HashMap<u128, Arc<remote::Scan>>>>,
+}
+
+impl MyTransfer {
+    pub fn new(
+        exports: remote::Exports,
+        limits: crate::io::limits::Limits,
+        controllers: crate::io::controller::Controllers,
+        writers: Arc<crate::io::writer::WriterPool>,
+        budget: crate::io::budget::MemoryBudget,
+    ) -> Self {
+        Self {
+            exports,
+            limits,
+            controllers,
+            writers,
+            budget,
+            receivers: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            scans: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn receiver(&self, uuid: &str) -> Result<Arc<Receiver>, Status> {
+        let uuid = parse_uuid(uuid)?;
+        self.receivers
+            .lock()
+            .await
+            .get(&uuid)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("{} isn't open", uuid::Uuid::from_u128(uuid))))
+    }
+
+    async fn scanning(&self, uuid: &str) -> Result<Arc<remote::Scan>, Status> {
+        let uuid = parse_uuid(uuid)?;
+        self.scans.lock().await.get(&uuid).cloned().ok_or_else(|| {
+            Status::not_found(format!("{} isn't scanning", uuid::Uuid::from_u128(uuid)))
+        })
+    }
+
+    /// Close everything still open, for when whoever opened it is gone for
+    /// good, aka an agent's ssh channel closing.
+    pub async fn close_all(&self) {
+        let receivers: Vec<_> = self.receivers.lock().await.drain().collect();
+        for (_, receiver) in receivers {
+            receiver.close().await;
+        }
+        let scans: Vec<_> = self.scans.lock().await.drain().collect();
+        for (_, scan) in scans {
+            scan.close().await;
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Transfer for MyTransfer {
+    type ScanStream = ReceiverStream<Result<ScanReply, Status>>;
+    type GetFileStream = ReceiverStream<Result<FileData, Status>>;
+
+    async fn open(&self, request: Request<OpenRequest>) -> Result<Response<()>, Status> {
+        let open = request.into_inner();
+        debug!("transfer open {:?}", open);
+
+        let uuid = parse_uuid(&open.uuid)?;
+        let dest = PathBuf::from(&open.dest);
+        remote::check_root(&dest, &self.exports).map_err(io_status)?;
+
+        let mut subsystem = crate::io::IoSubsystem::new()
+            .with_limits(self.limits.clone())
+            .with_controllers(self.controllers.clone())
+            .with_writers(self.writers.clone())
+            .with_memory_budget(self.budget)
+            .with_persistent(open.persistent);
+        if let Some(weight) = open.weight {
+            subsystem = subsystem.with_weight(weight.max(1));
+        }
+
+        let mut receivers = self.receivers.lock().await;
+
+        // The sender restarted, it starts over so we do too
+        if let Some(old) = receivers.remove(&uuid) {
+            debug!("{} reopened, starting over", open.uuid);
+            old.close().await;
+        }
+
+        let receiver = Receiver::start(subsystem, uuid, dest, open.writers.map(|w| w as usize))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        receivers.insert(uuid, Arc::new(receiver));
+
+        Ok(Response::new(()))
+    }
+
+    async fn put_files(
+        &self,
+        request: Request<Streaming<PutFileRequest>>,
+    ) -> Result<Response<PutReply>, Status> {
+        let mut stream = request.into_inner();
+        let mut items = 0;
+
+        while let Some(put) = stream.message().await? {
+            let receiver = self.receiver(&put.uuid).await?;
+            receiver
+                .put(decode(&put.item)?, put.data)
+                .await
+                .map_err(io_status)?;
+            items += 1;
+        }
+
+        Ok(Response::new(PutReply { items }))
+    }
+
+    async fn put_large_file(
+        &self,
+        request: Request<Streaming<FileChunk>>,
+    ) -> Result<Response<PutReply>, Status> {
+        let mut stream = request.into_inner();
+
+        let Some(first) = stream.message().await? else {
+            return Err(Status::invalid_argument("no chunks"));
+        };
+        let receiver = self.receiver(&first.uuid).await?;
+        let item = match &first.item {
+            Some(item) => decode(item)?,
+            None => return Err(Status::invalid_argument("first chunk has no work item")),
+        };
+
+        let mut staged = receiver.stage(item).await.map_err(io_status)?;
+        staged.write(first.data).await.map_err(io_status)?;
+        while let Some(chunk) = stream.message().await? {
+            staged.write(chunk.data).await.map_err(io_status)?;
+        }
+
+        let item = staged.finish().await.map_err(io_status)?;
+        receiver.send(item).await.map_err(io_status)?;
+
+        Ok(Response::new(PutReply { items: 1 }))
+    }
+
+    async fn status(
+        &self,
+        request: Request<StatusRequest>,
+    ) -> Result<Response<StatusReply>, Status> {
+        let request = request.into_inner();
+        let receiver = self.receiver(&request.uuid).await?;
+        let status = receiver.status(request.errors_since as usize).await;
+
+        Ok(Response::new(StatusReply {
+            dirs_written: status.dirs_written,
+            files_written: status.files_written,
+            bytes_written: status.bytes_written,
+            errors: status.errors.into_iter().map(transfer_error).collect(),
+            done: status.done,
+        }))
+    }
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let request = request.into_inner();
+        debug!("transfer scan {:?}", request);
+
+        let uuid = parse_uuid(&request.uuid)?;
+        let source = PathBuf::from(&request.source);
+        remote::check_root(&source, &self.exports).map_err(io_status)?;
+
+        let mut subsystem = crate::io::IoSubsystem::new()
+            .with_limits(self.limits.clone())
+            .with_memory_budget(self.budget);
+        if request.scan_order.is_some() || request.scanners.is_some() {
+            let order = match request.scan_order.as_deref() {
+                Some(order) => order.parse().map_err(Status::invalid_argument)?,
+                None => ScanOrder::default(),
+            };
+            subsystem = subsystem.with_scan(order, request.scanners.map(|s| s as usize));
+        }
+        if let Some(snapshot) = request.snapshot.as_deref() {
+            let snapshot: SnapshotProvider = snapshot.parse().map_err(Status::invalid_argument)?;
+            // Running whatever someone over the network says is a hard no
+            if matches!(snapshot, SnapshotProvider::Command(_)) && !self.exports.trusted() {
+                return Err(Status::permission_denied(
+                    "snapshot commands only run through an ssh agent",
+                ));
+            }
+            subsystem = subsystem.with_snapshot(Some(snapshot));
+        }
+
+        let mut scans = self.scans.lock().await;
+
+        // Same as open, the other end restarted
+        if let Some(old) = scans.remove(&uuid) {
+            debug!("{} rescanned, starting over", request.uuid);
+            old.close().await;
+        }
+
+        let (scan, mut rx) = remote::Scan::start(subsystem, uuid, source)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let scan = Arc::new(scan);
+        scans.insert(uuid, scan.clone());
+        drop(scans);
+
+        let (tx, replies) = tokio::sync::mpsc::channel(STREAM_DEPTH);
+        let scans = self.scans.clone();
+        tokio::spawn(async move {
+            let mut errors = 0;
+            while let Some(item) = rx.recv().await {
+                let complete = matches!(item, WorkItem::ScanComplete { .. });
+                let reply = match encode(&item) {
+                    Ok(item) => Ok(scan_reply(&scan, item, &mut errors).await),
+                    Err(e) => Err(e),
+                };
+
+                // Nobody is listening anymore, no point scanning
+                if tx.send(reply).await.is_err() {
+                    if let Some(scan) = scans.lock().await.remove(&uuid) {
+                        scan.close().await;
+                    }
+                    return;
+                }
+                if complete {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(replies)))
+    }
+
+    async fn get_file(
+        &self,
+        request: Request<GetFileRequest>,
+    ) -> Result<Response<Self::GetFileStream>, Status> {
+        let request = request.into_inner();
+        let scan = self.scanning(&request.uuid).await?;
+        let uuid = parse_uuid(&request.uuid)?;
+        let path = scan.path(Path::new(&request.path)).map_err(io_status)?;
+
+        let file = std::fs::File::open(&path).map_err(io_status)?;
+        let limits = self.limits.clone();
+        let (tx, rx) = tokio::sync::mpsc::channel(2);
+        tokio::task::spawn_blocking(move || {
+            use std::io::Read;
+
+            let mut file = file;
+            limits.throttle(uuid, 0, 1);
+            loop {
+                let mut data = Vec::with_capacity(CHUNK_SIZE);
+                let chunk = match (&mut file).take(CHUNK_SIZE as u64).read_to_end(&mut data) {
+                    Ok(0) => return,
+                    Ok(read) => {
+                        limits.throttle(uuid, read as u64, 0);
+                        Ok(FileData { data })
+                    }
+                    // Failing the stream is how the other end knows not to
+                    // keep what it has so far.
+                    Err(e) => Err(io_status(e)),
+                };
+                let failed = chunk.is_err();
+                if tx.blocking_send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn close(&self, request: Request<CloseRequest>) -> Result<Response<()>, Status> {
+        let uuid = parse_uuid(&request.into_inner().uuid)?;
+
+        // Closing twice is fine
+        let receiver = self.receivers.lock().await.remove(&uuid);
+        if let Some(receiver) = receiver {
+            receiver.close().await;
+        }
+        let scan = self.scans.lock().await.remove(&uuid);
+        if let Some(scan) = scan {
+            scan.close().await;
+        }
+
+        Ok(Response::new(()))
+    }
+}
+
+/// Where a remote dest is and what the daemon there needs to start writing
+#[derive(Debug, Clone)]
+pub struct RemoteDest {
+    /// Local port that gets to the remote daemon, aka the ssh forward
+    pub port: u16,
+    /// Dest path on the remote host
+    pub dest: PathBuf,
+    pub writers: Option<usize>,
+    pub weight: Option<u32>,
+    /// Watched, more work can always show up
+    pub persistent: bool,
+    /// Our limits, sending counts against them like writing would
+    pub limits: crate::io::limits::Limits,
+}
+
+/// Ship everything the scan sends to rx to the remote daemon and mirror how
+/// its going into report. Runs until the other end is done writing (or the
+/// scan stops for a watched sync), if we can't talk to it at all the sync
+/// finishes with that error.
+pub async fn send(
+    uuid: u128,
+    remote: RemoteDest,
+    rx: tokio::sync::mpsc::Receiver<WorkItem>,
+    mut report: remote::Report,
+) {
+    if let Err(e) = try_send(uuid, &remote, rx, &mut report).await {
+        report
+            .fail(format!("remote dest failed: {}", e), remote.dest.clone())
+            .await;
+    }
+}
+
+async fn try_send(
+    uuid: u128,
+    remote: &RemoteDest,
+    mut rx: tokio::sync::mpsc::Receiver<WorkItem>,
+    report: &mut remote::Report,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut client = connect(remote.port).await?;
+    let id = uuid::Uuid::from_u128(uuid).to_string();
+
+    client
+        .open(OpenRequest {
+            uuid: id.clone(),
+            dest: remote.dest.display().to_string(),
+            writers: remote.writers.map(|w| w as u32),
+            weight: remote.weight,
+            persistent: remote.persistent,
+        })
+        .await?;
+
+    let (small_tx, small_rx) = tokio::sync::mpsc::channel::<PutFileRequest>(STREAM_DEPTH);
+    let small = {
+        let mut client = client.clone();
+        tokio::spawn(async move { client.put_files(ReceiverStream::new(small_rx)).await })
+    };
+    let put = |item: &WorkItem, data: Vec<u8>| -> Result<PutFileRequest, Status> {
+        Ok(PutFileRequest {
+            uuid: id.clone(),
+            item: encode(item)?,
+            data,
+        })
+    };
+
+    let mut large = tokio::task::JoinSet::new();
+    let mut complete = None;
+    let mut scanning = true;
+    let mut tick = tokio::time::interval(STATUS_INTERVAL);
+
+    loop {
+        tokio::select! {
+            item = rx.recv(), if scanning => {
+                let Some(item) = item else {
+                    scanning = false;
+                    continue;
+                };
+
+                let request = match item {
+                    WorkItem::ScanComplete { .. } => {
+                        complete = Some(item);
+                        None
+                    }
+                    WorkItem::CopySmallFile { ref metadata, .. }
+                    | WorkItem::CopyLargeFile { ref metadata, .. }
+                        if metadata.size > CHUNK_SIZE as u64 =>
+                    {
+                        while large.len() >= LARGE_UPLOADS {
+                            if let Some(Ok(Err((path, e)))) = large.join_next().await {
+                                report.error(e, path).await;
+                            }
+                        }
+                        large.spawn(put_large(
+                            client.clone(),
+                            uuid,
+                            id.clone(),
+                            item,
+                            remote.limits.clone(),
+                        ));
+                        None
+                    }
+                    WorkItem::CopySmallFile { .. } | WorkItem::CopyLargeFile { .. } => {
+                        read_small(item, &remote.limits, report)
+                            .await
+                            .map(|(item, data)| put(&item, data))
+                            .transpose()?
+                    }
+                    _ => Some(put(&item, vec![])?),
+                };
+
+                if let Some(request) = request
+                    && small_tx.send(request).await.is_err()
+                {
+                    return Err(finished(small)
+                        .await
+                        .err()
+                        .unwrap_or_else(|| "put stream ended early".into()));
+                }
+            }
+            Some(joined) = large.join_next(), if !large.is_empty() => {
+                if let Ok(Err((path, e))) = joined {
+                    report.error(e, path).await;
+                }
+            }
+            _ = tick.tick() => {
+                let status = client
+                    .status(StatusRequest {
+                        uuid: id.clone(),
+                        errors_since: report.errors_seen() as u64,
+                    })
+                    .await?
+                    .into_inner();
+                if report.update(remote_status(status)).await {
+                    break;
+                }
+            }
+        }
+
+        // Big files are all over there, now its done
+        if large.is_empty()
+            && let Some(item) = complete.take()
+            && small_tx.send(put(&item, vec![])?).await.is_err()
+        {
+            return Err(finished(small)
+                .await
+                .err()
+                .unwrap_or_else(|| "put stream ended early".into()));
+        }
+
+        // Scan went away, a watched sync is never done otherwise
+        if !scanning && remote.persistent {
+            break;
+        }
+    }
+
+    drop(small_tx);
+    finished(small).await?;
+    client.close(CloseRequest { uuid: id }).await?;
+    Ok(())
+}
+
+/// Where a remote source is and how the daemon there should scan it
+#[derive(Debug, Clone)]
+pub struct RemoteSource {
+    /// Local port that gets to the remote daemon, aka the ssh forward
+    pub port: u16,
+    /// Source path on the remote host
+    pub source: PathBuf,
+    pub scan_order: Option<ScanOrder>,
+    pub scanners: Option<usize>,
+    /// Taken over there, its their filesystem
+    pub snapshot: Option<SnapshotProvider>,
+}
+
+/// Write everything a scan of the remote source finds with receiver, pulling
+/// file data as it goes. Runs until our writers are done, if we can't talk to
+/// the other end at all the sync finishes with that error.
+pub async fn pull(uuid: u128, remote: RemoteSource, receiver: Arc<Receiver>) {
+    if let Err(e) = try_pull(uuid, &remote, &receiver).await {
+        receiver
+            .fail(
+                format!("remote source failed: {}", e),
+                remote.source.clone(),
+            )
+            .await;
+    }
+    receiver.close().await;
+}
+
+async fn try_pull(
+    uuid: u128,
+    remote: &RemoteSource,
+    receiver: &Arc<Receiver>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut client = connect(remote.port).await?;
+    let id = uuid::Uuid::from_u128(uuid).to_string();
+
+    let pulled = pull_scan(&mut client, &id, remote, receiver).await;
+
+    // Done with the other end either way, lets go of its snapshot
+    let _ = client.close(CloseRequest { uuid: id }).await;
+    pulled?;
+
+    receiver.finished().await;
+    Ok(())
+}
+
+// Everything from the scan to the writers, files once they're here
+async fn pull_scan(
+    client: &mut TransferClient<tonic::transport::Channel>,
+    id: &str,
+    remote: &RemoteSource,
+    receiver: &Arc<Receiver>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut scan = client
+        .scan(ScanRequest {
+            uuid: id.to_string(),
+            source: remote.source.display().to_string(),
+            scan_order: remote.scan_order.map(|o| o.to_string()),
+            scanners: remote.scanners.map(|s| s as u32),
+            snapshot: remote.snapshot.as_ref().map(|s| s.to_string()),
+        })
+        .await?
+        .into_inner();
+
+    let mut pulls = tokio::task::JoinSet::new();
+
+    while let Some(reply) = scan.message().await? {
+        receiver.found(remote::Found {
+            dirs: reply.dirs_found,
+            files: reply.files_found,
+            size: reply.total_size,
+        });
+        for error in reply.errors {
+            receiver.error(error.error, PathBuf::from(error.path)).await;
+        }
+
+        let item = decode(&reply.item)?;
+        match item {
+            WorkItem::ScanComplete { .. } => {
+                while let Some(joined) = pulls.join_next().await {
+                    pulled(receiver, joined).await;
+                }
+                receiver.send(item).await?;
+                return Ok(());
+            }
+            WorkItem::CopySmallFile { .. } | WorkItem::CopyLargeFile { .. } => {
+                while pulls.len() >= PULLS {
+                    if let Some(joined) = pulls.join_next().await {
+                        pulled(receiver, joined).await;
+                    }
+                }
+                pulls.spawn(get_file(
+                    client.clone(),
+                    id.to_string(),
+                    receiver.clone(),
+                    item,
+                ));
+            }
+            _ => receiver.send(item).await?,
+        }
+    }
+
+    Err("scan ended early".into())
+}
+
+async fn pulled(
+    receiver: &Receiver,
+    joined: Result<Result<(), (PathBuf, String)>, tokio::task::JoinError>,
+) {
+    match joined {
+        Ok(Err((path, e))) => receiver.error(e, path).await,
+        Err(e) => tracing::error!("file pull went away: {}", e),
+        Ok(Ok(())) => (),
+    }
+}
+
+// One file, staged here as it comes in
+async fn get_file(
+    mut client: TransferClient<tonic::transport::Channel>,
+    id: String,
+    receiver: Arc<Receiver>,
+    item: WorkItem,
+) -> Result<(), (PathBuf, String)> {
+    let (WorkItem::CopySmallFile {
+        source_path,
+        dest_path,
+        ..
+    }
+    | WorkItem::CopyLargeFile {
+        source_path,
+        dest_path,
+        ..
+    }) = &item
+    else {
+        unreachable!("only files get pulled");
+    };
+    let source = source_path.clone();
+    let failed =
+        |e: &dyn std::fmt::Display| (source.clone(), format!("failed to pull file: {}", e));
+
+    let mut data = client
+        .get_file(GetFileRequest {
+            uuid: id,
+            path: dest_path.display().to_string(),
+        })
+        .await
+        .map_err(|e| failed(&e.message()))?
+        .into_inner();
+
+    let mut staged = receiver.stage(item).await.map_err(|e| failed(&e))?;
+    while let Some(chunk) = data.message().await.map_err(|e| failed(&e.message()))? {
+        staged.write(chunk.data).await.map_err(|e| failed(&e))?;
+    }
+    let item = staged.finish().await.map_err(|e| failed(&e))?;
+    receiver.send(item).await.map_err(|e| failed(&e))
+}
+
+async fn connect(
+    port: u16,
+) -> Result<TransferClient<tonic::transport::Channel>, Box<dyn std::error::Error + Send + Sync>> {
+    let channel = tonic::transport::Channel::from_shared(format!("http://localhost:{}", port))?
+        .connect()
+        .await?;
+    Ok(TransferClient::new(channel))
+}
+
+// Small files go over whole. Whatever size it is now is what gets sent, the
+// other end only takes what the item says it should get.
+async fn read_small(
+    mut item: WorkItem,
+    limits: &crate::io::limits::Limits,
+    report: &remote::Report,
+) -> Option<(WorkItem, Vec<u8>)> {
+    let (WorkItem::CopySmallFile {
+        uuid,
+        source_path,
+        metadata,
+        ..
+    }
+    | WorkItem::CopyLargeFile {
+        uuid,
+        source_path,
+        metadata,
+        ..
+    }) = &mut item
+    else {
+        unreachable!("only files get read");
+    };
+
+    limits.throttle_async(*uuid, metadata.size, 1).await;
+    match tokio::fs::read(&source_path).await {
+        Ok(data) => {
+            metadata.size = data.len() as u64;
+            Some((item, data))
+        }
+        Err(e) => {
+            report
+                .error(format!("failed to read file: {}", e), source_path.clone())
+                .await;
+            None
+        }
+    }
+}
+
+// Result of a PutFiles stream once its over
+async fn finished(
+    small: tokio::task::JoinHandle<Result<Response<PutReply>, Status>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    small.await??;
+    Ok(())
+}
+
+// One big file, chunks get read on a blocking thread as the stream wants them
+async fn put_large(
+    mut client: TransferClient<tonic::transport::Channel>,
+    uuid: u128,
+    id: String,
+    item: WorkItem,
+    limits: crate::io::limits::Limits,
+) -> Result<(), (PathBuf, String)> {
+    let (WorkItem::CopySmallFile { source_path, .. } | WorkItem::CopyLargeFile { source_path, .. }) =
+        &item
+    else {
+        unreachable!("only files are large");
+    };
+    let source = source_path.clone();
+    let first = encode(&item).map_err(|e| (source.clone(), e.message().to_string()))?;
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<FileChunk>(2);
+    let reading = {
+        let source = source.clone();
+        tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+            use std::io::Read;
+
+            let mut file = std::fs::File::open(&source)?;
+            let mut item = Some(first);
+            limits.throttle(uuid, 0, 1);
+            loop {
+                let mut data = Vec::with_capacity(CHUNK_SIZE);
+                (&mut file).take(CHUNK_SIZE as u64).read_to_end(&mut data)?;
+                if data.is_empty() && item.is_none() {
+                    return Ok(());
+                }
+                let last = data.len() < CHUNK_SIZE;
+                limits.throttle(uuid, data.len() as u64, 0);
+
+                // Other end gave up, it'll say why
+                if tx
+                    .blocking_send(FileChunk {
+                        uuid: id.clone(),
+                        item: item.take(),
+                        data,
+                    })
+                    .is_err()
+                    || last
+                {
+                    return Ok(());
+                }
+            }
+        })
+    };
+
+    let sent = client.put_large_file(ReceiverStream::new(rx)).await;
+    let read = reading.await;
+
+    match (read, sent) {
+        (Ok(Err(e)), _) => Err((source, format!("failed to read file: {}", e))),
+        (Err(e), _) => Err((source, format!("failed to read file: {}", e))),
+        (_, Err(e)) => Err((source, format!("failed to send file: {}", e.message()))),
+        (Ok(Ok(())), Ok(_)) => Ok(()),
+    }
+}
+
+async fn scan_reply(scan: &remote::Scan, item: Vec<u8>, errors_seen: &mut usize) -> ScanReply {
+    let errors = scan.errors(*errors_seen).await;
+    *errors_seen += errors.len();
+    let found = scan.found();
+
+    ScanReply {
+        item,
+        errors: errors.into_iter().map(transfer_error).collect(),
+        dirs_found: found.dirs,
+        files_found: found.files,
+        total_size: found.size,
+    }
+}
+
+fn transfer_error((path, error): (PathBuf, String)) -> TransferError {
+    TransferError {
+        path: path.display().to_string(),
+        error,
+    }
+}
+
+fn remote_status(status: StatusReply) -> remote::Status {
+    remote::Status {
+        dirs_written: status.dirs_written,
+        files_written: status.files_written,
+        bytes_written: status.bytes_written,
+        errors: status
+            .errors
+            .into_iter()
+            .map(|e| (PathBuf::from(e.path), e.error))
+            .collect(),
+        done: status.done,
+    }
+}
+
+fn parse_uuid(uuid: &str) -> Result<u128, Status> {
+    uuid::Uuid::parse_str(uuid)
+        .map(|u| u.as_u128())
+        .map_err(|e| Status::invalid_argument(format!("invalid uuid {}: {}", uuid, e)))
+}
+
+fn encode(item: &WorkItem) -> Result<Vec<u8>, Status> {
+    serde_json::to_vec(item).map_err(|e| Status::invalid_argument(e.to_string()))
+}
+
+fn decode(item: &[u8]) -> Result<WorkItem, Status> {
+    serde_json::from_slice(item)
+        .map_err(|e| Status::invalid_argument(format!("bad work item: {}", e)))
+}
+
+fn io_status(e: std::io::Error) -> Status {
+    match e.kind() {
+        std::io::ErrorKind::InvalidInput => Status::invalid_argument(e.to_string()),
+        std::io::ErrorKind::NotFound => Status::not_found(e.to_string()),
+        std::io::ErrorKind::InvalidData => Status::data_loss(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::transfer::transfer_server::TransferServer;
+    use crate::testdir::TestDir;
+
+    // Small, big enough to be chunked, nested and a symlink
+    fn tree(source: &Path) -> Vec<u8> {
+        std::fs::create_dir_all(source.join("sub/deeper")).unwrap();
+        std::fs::write(source.join("small"), "small").unwrap();
+        std::fs::write(source.join("sub/deeper/other"), "other").unwrap();
+        let big: Vec<u8> = (0..(CHUNK_SIZE * 3 + 17))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(source.join("sub/big"), &big).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("small", source.join("link")).unwrap();
+        big
+    }
+
+    fn check_tree(dest: &Path, big: &[u8]) {
+        assert_eq!(std::fs::read(dest.join("small")).unwrap(), b"small");
+        assert_eq!(
+            std::fs::read(dest.join("sub/deeper/other")).unwrap(),
+            b"other"
+        );
+        assert_eq!(std::fs::read(dest.join("sub/big")).unwrap(), big);
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::read_link(dest.join("link")).unwrap(),
+            PathBuf::from("small")
+        );
+        assert!(!dest.join(remote::STAGING_DIR).exists());
+    }
+
+    // The remote daemon minus the ssh, same process but nothing shared
+    async fn serve() -> (MyTransfer, u16) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let remote = MyTransfer::new(
+            remote::Exports::Roots(vec![std::env::temp_dir()]),
+            Default::default(),
+            Default::default(),
+            Arc::new(crate::io::writer::WriterPool::new()),
+            Default::default(),
+        );
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TransferServer::new(remote.clone()))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        (remote, port)
+    }
+
+    // Nothing listening on it
+    async fn closed_port() -> u16 {
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        closed.local_addr().unwrap().port()
+    }
+
+    async fn failed_with(subsystem: &crate::io::IoSubsystem, uuid: u128, error: &str) -> bool {
+        subsystem.is_complete(uuid).await
+            && subsystem
+                .get_errors()
+                .await
+                .iter()
+                .any(|e| e.error.contains(error))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send() {
+        let dir = TestDir::new("transfer-send");
+        let source = dir.join("source");
+        let dest = dir.join("dest");
+        let big = tree(&source);
+        let (receiving, port) = serve().await;
+
+        let remote = RemoteDest {
+            port,
+            dest: dest.clone(),
+            writers: Some(2),
+            weight: None,
+            persistent: false,
+            limits: Default::default(),
+        };
+
+        let uuid = 42;
+        let mut sending = crate::io::IoSubsystem::new();
+        let (rx, report) = sending
+            .start_remote(uuid, source.clone(), None)
+            .await
+            .unwrap();
+        send(uuid, remote.clone(), rx, report).await;
+
+        assert!(sending.is_complete(uuid).await);
+        assert!(
+            sending.get_errors().await.is_empty(),
+            "{:?}",
+            sending.get_errors().await
+        );
+        let progress = sending.get_progress(uuid).await.unwrap();
+        assert_eq!(progress.files_written, progress.files_found);
+        check_tree(&dest, &big);
+
+        // Closed and cleaned up after itself
+        assert!(receiving.receivers.lock().await.is_empty());
+
+        // Can't talk to it, the sync finishes with an error rather than hang
+        let mut orphan = crate::io::IoSubsystem::new();
+        let (rx, report) = orphan.start_remote(43, source.clone(), None).await.unwrap();
+        let remote = RemoteDest {
+            port: closed_port().await,
+            ..remote
+        };
+        send(43, remote, rx, report).await;
+        assert!(failed_with(&orphan, 43, "remote dest failed").await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pull() {
+        let dir = TestDir::new("transfer-pull");
+        let source = dir.join("source");
+        let dest = dir.join("dest");
+        let big = tree(&source);
+        let (scanning, port) = serve().await;
+
+        let remote = RemoteSource {
+            port,
+            source: source.clone(),
+            scan_order: Some(ScanOrder::Dfs),
+            scanners: None,
+            snapshot: None,
+        };
+
+        let uuid = 44;
+        let pulling = crate::io::IoSubsystem::new();
+        let receiver = Receiver::start(pulling.clone(), uuid, dest.clone(), Some(2))
+            .await
+            .unwrap();
+        pull(uuid, remote.clone(), Arc::new(receiver)).await;
+
+        assert!(pulling.is_complete(uuid).await);
+        assert!(
+            pulling.get_errors().await.is_empty(),
+            "{:?}",
+            pulling.get_errors().await
+        );
+        let progress = pulling.get_progress(uuid).await.unwrap();
+        assert!(progress.files_found > 0);
+        assert_eq!(progress.files_written, progress.files_found);
+        check_tree(&dest, &big);
+
+        // The other end let go of its scan
+        assert!(scanning.scans.lock().await.is_empty());
+
+        // Source isn't there, a typo shouldn't look like a done sync
+        let missing = crate::io::IoSubsystem::new();
+        let receiver = Receiver::start(missing.clone(), 45, dir.join("missing"), None)
+            .await
+            .unwrap();
+        let remote = RemoteSource {
+            source: dir.join("nope"),
+            ..remote
+        };
+        pull(45, remote.clone(), Arc::new(receiver)).await;
+        assert!(failed_with(&missing, 45, "remote source failed").await);
+
+        // Can't talk to it at all
+        let orphan = crate::io::IoSubsystem::new();
+        let receiver = Receiver::start(orphan.clone(), 46, dir.join("orphan"), None)
+            .await
+            .unwrap();
+        let remote = RemoteSource {
+            port: closed_port().await,
+            ..remote
+        };
+        pull(46, remote, Arc::new(receiver)).await;
+        assert!(failed_with(&orphan, 46, "remote source failed").await);
+    }
+}
@@ -14,6 +14,10 @@ tonic::include_proto!("yeet");
 #[derive(Debug, Clone)]
 pub struct MyYeet {
     event_sender: std::sync::Arc<std::sync::Mutex<tokio::sync::mpsc::UnboundedSender<RpcEvent>>>,
+    // Over the unix domain socket, aka from someone already on this box as
+    // us. Anything that runs commands or hands us secrets only comes in this
+    // way, never over tcp.
+    uds: bool,
 }
 
 impl MyYeet {
@@ -21,8 +25,9 @@ impl MyYeet {
         event_sender: std::sync::Arc<
             std::sync::Mutex<tokio::sync::mpsc::UnboundedSender<RpcEvent>>,
         >,
+        uds: bool,
     ) -> Self {
-        Self { event_sender }
+        Self { event_sender, uds }
     }
 }
 
@@ -34,7 +39,14 @@ impl Yeet for MyYeet {
     ) -> Result<Response<SyncSimpleCopyReply>, Status> {
         use uuid::Uuid;
 
-        debug!("Got a request: {:?}", request);
+        // Not the passphrases though, debug logs end up pasted all over
+        let mut logged = request.get_ref().clone();
+        for identity in logged.identities.iter_mut() {
+            if identity.passphrase.is_some() {
+                identity.passphrase = Some("<redacted>".to_string());
+            }
+        }
+        debug!("Got a request: {:?}", logged);
 
         // Note the u128 is split into two 64 bit uints to make protobuf
         // "happy", its really a 128 bit uuid, I'm too lazy for bytes for this
@@ -57,19 +69,204 @@ impl Yeet for MyYeet {
 
         let writers = binding.writers.map(|w| w as usize);
 
+        let schedule = crate::io::schedule::SchedulePolicy::parse(
+            binding.schedule.as_deref(),
+            &binding.priority,
+            binding.aging_secs,
+        )
+        .map_err(Status::invalid_argument)?;
+
+        let scan_order = match binding.scan_order.as_deref() {
+            Some(order) => order.parse().map_err(Status::invalid_argument)?,
+            None => crate::io::scan::ScanOrder::default(),
+        };
+        let scanners = binding.scanners.map(|s| s as usize);
+
+        let watch = match binding.watch.as_deref() {
+            Some(backend) => Some(crate::io::watch::WatchConfig {
+                backend: backend.parse().map_err(Status::invalid_argument)?,
+                poll_interval: match binding.poll_secs {
+                    Some(0) => {
+                        return Err(Status::invalid_argument(
+                            "poll interval must be at least 1 second",
+                        ));
+                    }
+                    Some(secs) => std::time::Duration::from_secs(secs),
+                    None => crate::io::watch::DEFAULT_POLL_INTERVAL,
+                },
+                ..Default::default()
+            }),
+            None => None,
+        };
+
+        let two_way = binding.two_way.unwrap_or(false);
+        if two_way && watch.is_some() {
+            return Err(Status::invalid_argument(
+                "two-way syncs can't be watched yet",
+            ));
+        }
+
+        if two_way && !binding.fan_out.is_empty() {
+            return Err(Status::invalid_argument(
+                "two-way syncs only have the one rhs",
+            ));
+        }
+        let fan_out: Vec<(u128, String)> = binding
+            .fan_out
+            .iter()
+            .map(|rhs| (Uuid::new_v4().as_u128(), rhs.clone()))
+            .collect();
+
+        let snapshot: Option<crate::io::snapshot::SnapshotProvider> = match binding.snapshot {
+            Some(provider) => Some(provider.parse().map_err(Status::invalid_argument)?),
+            None => None,
+        };
+        if matches!(
+            snapshot,
+            Some(crate::io::snapshot::SnapshotProvider::Command(_))
+        ) && !self.uds
+        {
+            return Err(Status::permission_denied(
+                "snapshot commands are only taken over the local socket",
+            ));
+        }
+        if snapshot.is_some() && (watch.is_some() || two_way) {
+            return Err(Status::invalid_argument(
+                "snapshots are for one way one shot copies, not watch or two-way",
+            ));
+        }
+
+        let trash = if binding.trash.unwrap_or(false)
+            || binding.trash_dir.is_some()
+            || binding.trash_max_age_secs.is_some()
+            || binding.trash_max_versions.is_some()
+        {
+            let defaults = crate::io::trash::TrashConfig::default();
+            Some(crate::io::trash::TrashConfig {
+                dir: binding.trash_dir.map(std::path::PathBuf::from),
+                max_age: match binding.trash_max_age_secs {
+                    Some(0) => None,
+                    Some(secs) => Some(std::time::Duration::from_secs(secs)),
+                    None => defaults.max_age,
+                },
+                max_versions: match binding.trash_max_versions {
+                    Some(0) => {
+                        return Err(Status::invalid_argument(
+                            "trash has to keep at least 1 version",
+                        ));
+                    }
+                    Some(n) => Some(n as usize),
+                    None => defaults.max_versions,
+                },
+            })
+        } else {
+            None
+        };
+
+        // Every fan-out dest would be keeping the same paths in the same place
+        if trash.as_ref().is_some_and(|t| t.dir.is_some()) && !fan_out.is_empty() {
+            return Err(Status::invalid_argument(
+                "a trash dir is per destination, leave it unset to fan out",
+            ));
+        }
+
+        // Garbage hosts fail here and not as a connection that never happens
+        for spec in std::iter::once(&lhs)
+            .chain(std::iter::once(&rhs))
+            .chain(binding.fan_out.iter())
+        {
+            match crate::parse_remote_spec(spec) {
+                Ok((Some(host), _)) => {
+                    if let Err(e) = host.parse::<crate::systems::ssh::SshTarget>() {
+                        return Err(Status::invalid_argument(e));
+                    }
+                }
+                Ok((None, _)) => (),
+                Err(e) => return Err(Status::invalid_argument(e)),
+            }
+        }
+
+        // A remote dest only gets plain copies for now, fanned out or not, the
+        // rest needs the dest to be here to look at.
+        let remote_dest = std::iter::once(&rhs)
+            .chain(binding.fan_out.iter())
+            .any(|rhs| matches!(crate::parse_remote_spec(rhs), Ok((Some(_), _))));
+        if remote_dest && (two_way || trash.is_some()) {
+            return Err(Status::invalid_argument(
+                "remote dests can't be two-way or trashed yet",
+            ));
+        }
+        let remote_source = matches!(crate::parse_remote_spec(&lhs), Ok((Some(_), _)));
+        if remote_dest && remote_source {
+            return Err(Status::invalid_argument(
+                "only one of lhs and rhs can be remote",
+            ));
+        }
+
+        // Same for a remote source, there's nothing here to watch or compare
+        if remote_source && (watch.is_some() || two_way || !fan_out.is_empty()) {
+            return Err(Status::invalid_argument(
+                "remote sources can't be watched, two-way or fanned out yet",
+            ));
+        }
+
+        if binding.weight == Some(0) {
+            return Err(Status::invalid_argument("weight must be at least 1"));
+        }
+
+        // Keys and passphrases only come from someone on this box, the tcp
+        // side is for other daemons
+        if !binding.identities.is_empty() && !self.uds {
+            return Err(Status::permission_denied(
+                "ssh identities are only taken over the local socket",
+            ));
+        }
+
+        // Unlocking here means a bad passphrase fails the request and not
+        // some connection later on
+        let identities = binding
+            .identities
+            .iter()
+            .map(|identity| {
+                let path = std::path::PathBuf::from(&identity.path);
+                crate::systems::ssh::Identity::load(&path, identity.passphrase.as_deref()).map_err(
+                    |e| {
+                        Status::invalid_argument(format!("can't use key {}: {}", path.display(), e))
+                    },
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
         let s = self
             .event_sender
             .lock()
             .expect("could not lock event sender");
+        if !identities.is_empty() {
+            let _ = s.send(RpcEvent::AddIdentities { identities });
+        }
         let _ = s.send(RpcEvent::SimpleCopySync {
             lhs,
             rhs,
             uuid,
             writers,
+            schedule,
+            weight: binding.weight,
+            scan_order,
+            scanners,
+            watch,
+            two_way,
+            fan_out: fan_out.clone(),
+            snapshot,
+            trash,
+            resume: false,
         });
 
         let reply = SyncSimpleCopyReply {
             uuid: uuid::Uuid::from_u128(uuid).to_string(),
+            fan_out_uuids: fan_out
+                .iter()
+                .map(|(uuid, _)| uuid::Uuid::from_u128(*uuid).to_string())
+                .collect(),
         };
 
         Ok(Response::new(reply))
@@ -81,9 +278,14 @@ impl Yeet for MyYeet {
     ) -> Result<Response<HeartbeatReply>, Status> {
         use std::sync::{Arc, Mutex};
 
-        debug!("Got a heartbeat request: {:?}", request);
+        let HeartbeatRequest { target, payload } = request.into_inner();
 
-        let target = request.into_inner().target;
+        // Throughput probes carry a big payload, nobody wants that in the log
+        debug!(
+            "Got a heartbeat request: target {} payload {} bytes",
+            target,
+            payload.len()
+        );
 
         // Create a oneshot channel to receive the response from the ECS
         let (response_tx, response_rx) = tokio::sync::oneshot::channel();
@@ -112,8 +314,231 @@ impl Yeet for MyYeet {
                 ),
             };
 
-        let reply = HeartbeatReply { success, message };
+        let reply = HeartbeatReply {
+            success,
+            message,
+            payload,
+        };
 
         Ok(Response::new(reply))
     }
+
+    async fn set_limits(&self, request: Request<SetLimitsRequest>) -> Result<Response<()>, Status> {
+        use std::sync::{Arc, Mutex};
+
+        debug!("Got a set limits request: {:?}", request);
+
+        let binding = request.into_inner();
+
+        let uuid = match binding.uuid {
+            Some(u) => Some(
+                uuid::Uuid::parse_str(&u)
+                    .map_err(|e| Status::invalid_argument(format!("invalid uuid {}: {}", u, e)))?
+                    .as_u128(),
+            ),
+            None => None,
+        };
+
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+
+        {
+            let s = self
+                .event_sender
+                .lock()
+                .expect("could not lock event sender");
+            let _ = s.send(RpcEvent::SetLimits {
+                uuid,
+                bytes_per_sec: binding.bytes_per_sec,
+                ops_per_sec: binding.ops_per_sec,
+                response_tx: Arc::new(Mutex::new(Some(response_tx))),
+            });
+        }
+
+        // Dropped without an answer means there's no limits to set
+        match tokio::time::timeout(std::time::Duration::from_secs(30), response_rx).await {
+            Ok(Ok(Ok(()))) => Ok(Response::new(())),
+            Ok(Ok(Err(e))) => Err(Status::not_found(e)),
+            Ok(Err(_)) => Err(Status::unavailable("daemon has no rate limits")),
+            Err(_) => Err(Status::deadline_exceeded("timeout setting limits")),
+        }
+    }
+
+    async fn set_weight(&self, request: Request<SetWeightRequest>) -> Result<Response<()>, Status> {
+        use std::sync::{Arc, Mutex};
+
+        debug!("Got a set weight request: {:?}", request);
+
+        let binding = request.into_inner();
+
+        let uuid = uuid::Uuid::parse_str(&binding.uuid)
+            .map_err(|e| Status::invalid_argument(format!("invalid uuid {}: {}", binding.uuid, e)))?
+            .as_u128();
+
+        if binding.weight == 0 {
+            return Err(Status::invalid_argument("weight must be at least 1"));
+        }
+
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+
+        {
+            let s = self
+                .event_sender
+                .lock()
+                .expect("could not lock event sender");
+            let _ = s.send(RpcEvent::SetWeight {
+                uuid,
+                weight: binding.weight,
+                response_tx: Arc::new(Mutex::new(Some(response_tx))),
+            });
+        }
+
+        match tokio::time::timeout(std::time::Duration::from_secs(30), response_rx).await {
+            Ok(Ok(Ok(()))) => Ok(Response::new(())),
+            Ok(Ok(Err(e))) => Err(Status::not_found(e)),
+            Ok(Err(_)) => Err(Status::internal("weight request dropped")),
+            Err(_) => Err(Status::deadline_exceeded("timeout setting weight")),
+        }
+    }
+
+    async fn list_history(
+        &self,
+        request: Request<ListHistoryRequest>,
+    ) -> Result<Response<ListHistoryReply>, Status> {
+        use std::sync::{Arc, Mutex};
+
+        debug!("Got a list history request: {:?}", request);
+
+        let binding = request.into_inner();
+
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+
+        {
+            let s = self
+                .event_sender
+                .lock()
+                .expect("could not lock event sender");
+
+            let _ = s.send(RpcEvent::ListHistory {
+                since: binding.since,
+                failed: binding.failed,
+                response_tx: Arc::new(Mutex::new(Some(response_tx))),
+            });
+        }
+
+        // If nothing answers the event gets dropped along with the sender,
+        // means the daemon is running without a journal.
+        let ops = match tokio::time::timeout(std::time::Duration::from_secs(30), response_rx).await
+        {
+            Ok(Ok(ops)) => ops,
+            Ok(Err(_)) => return Err(Status::unavailable("daemon has no history journal")),
+            Err(_) => return Err(Status::deadline_exceeded("timeout waiting for history")),
+        };
+
+        let ops = ops
+            .into_iter()
+            .map(|op| HistoryEntry {
+                uuid: uuid::Uuid::from_u128(op.uuid).to_string(),
+                lhs: op.sync.lhs,
+                rhs: op.sync.rhs,
+                state: op.state.to_string(),
+                requested: op.requested,
+                started: op.started,
+                finished: op.finished,
+                files: op.files,
+                bytes: op.bytes,
+                errors: op.errors as u64,
+                throughput_bps: op.throughput,
+                restarts: op.restarts,
+            })
+            .collect();
+
+        Ok(Response::new(ListHistoryReply { ops }))
+    }
+
+    async fn list_conflicts(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<ListConflictsReply>, Status> {
+        use std::sync::{Arc, Mutex};
+
+        debug!("Got a list conflicts request: {:?}", request);
+
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+
+        {
+            let s = self
+                .event_sender
+                .lock()
+                .expect("could not lock event sender");
+
+            let _ = s.send(RpcEvent::ListConflicts {
+                response_tx: Arc::new(Mutex::new(Some(response_tx))),
+            });
+        }
+
+        let conflicts =
+            match tokio::time::timeout(std::time::Duration::from_secs(30), response_rx).await {
+                Ok(Ok(conflicts)) => conflicts,
+                Ok(Err(_)) => return Err(Status::unavailable("daemon isn't tracking conflicts")),
+                Err(_) => return Err(Status::deadline_exceeded("timeout waiting for conflicts")),
+            };
+
+        let conflicts = conflicts
+            .into_iter()
+            .map(|c| ConflictEntry {
+                uuid: uuid::Uuid::from_u128(c.uuid).to_string(),
+                lhs: c.lhs.display().to_string(),
+                rhs: c.rhs.display().to_string(),
+                path: c.path.display().to_string(),
+                kind: c.kind.tag().to_string(),
+                detected: c.detected,
+            })
+            .collect();
+
+        Ok(Response::new(ListConflictsReply { conflicts }))
+    }
+
+    async fn resolve_conflict(
+        &self,
+        request: Request<ResolveConflictRequest>,
+    ) -> Result<Response<ResolveConflictReply>, Status> {
+        use std::sync::{Arc, Mutex};
+
+        debug!("Got a resolve conflict request: {:?}", request);
+
+        let binding = request.into_inner();
+
+        let keep: crate::io::resolve::Keep =
+            binding.keep.parse().map_err(Status::invalid_argument)?;
+        let path = std::path::PathBuf::from(&binding.path);
+        if !path.is_absolute() {
+            return Err(Status::invalid_argument(format!(
+                "path must be absolute: {}",
+                binding.path
+            )));
+        }
+
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+
+        {
+            let s = self
+                .event_sender
+                .lock()
+                .expect("could not lock event sender");
+
+            let _ = s.send(RpcEvent::ResolveConflict {
+                path,
+                keep,
+                response_tx: Arc::new(Mutex::new(Some(response_tx))),
+            });
+        }
+
+        // Could be copying a big file, give it a while
+        match tokio::time::timeout(std::time::Duration::from_secs(600), response_rx).await {
+            Ok(Ok(Ok(message))) => Ok(Response::new(ResolveConflictReply { message })),
+            Ok(Ok(Err(e))) => Err(Status::failed_precondition(e)),
+            Ok(Err(_)) => Err(Status::unavailable("daemon isn't tracking conflicts")),
+            Err(_) => Err(Status::deadline_exceeded("timeout resolving conflict")),
+        }
+    }
 }
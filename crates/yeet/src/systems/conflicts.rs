@@ -0,0 +1,407 @@
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use bevy_tokio_tasks::TokioTasksRuntime;
+use serde::{Deserialize, Serialize};
+
+use crate::io::reconcile::ConflictKind;
+use crate::{Dest, RpcEvent, Source, SyncConflicts, SyncEventSender, Uuid};
+
+// Two-way conflicts get bubbled up to the user instead of leaving .conflict
+// files all over the place for them to find later, or not. Every two-way run
+// reports what it couldn't reconcile, that replaces whatever we had for that
+// lhs/rhs pair so things sorted out by hand drop off on the next run.
+//
+// Kept in a json lines file in the data dir so they survive a restart, a
+// finished sync's entity is long gone by the time anyone gets around to
+// looking at these. Resolving one makes both sides agree on the path (see
+// io/resolve.rs) and drops it from here, the next run picks it up as
+// converged. Nothing else in the sync waits on a conflict, only the path and
+// anything under it is held back.
+pub struct ConflictsPlugin {
+    pub path: PathBuf,
+}
+
+impl Plugin for ConflictsPlugin {
+    fn build(&self, app: &mut App) {
+        let registry = match ConflictRegistry::open(&self.path) {
+            Ok(r) => r,
+            Err(e) => {
+                error!(
+                    "couldn't open conflicts {}, two-way conflicts won't be tracked: {}",
+                    self.path.display(),
+                    e
+                );
+                return;
+            }
+        };
+
+        if !registry.is_empty() {
+            warn!("{} two-way conflicts need resolving", registry.len());
+        }
+
+        app.insert_resource(registry).add_systems(
+            Update,
+            (
+                record_conflicts,
+                answer_conflicts,
+                resolve_conflicts,
+                forget_resolved,
+            ),
+        );
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A path a two-way sync couldn't reconcile on its own
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conflict {
+    /// Sync that last saw it
+    #[serde(with = "crate::systems::journal::uuid_string")]
+    pub uuid: u128,
+    pub lhs: PathBuf,
+    pub rhs: PathBuf,
+    /// Relative to both lhs and rhs
+    pub path: PathBuf,
+    pub kind: ConflictKind,
+    /// When it was first seen, seconds since the unix epoch
+    pub detected: u64,
+}
+
+impl Conflict {
+    fn key(&self) -> (&Path, &Path, &Path) {
+        (&self.lhs, &self.rhs, &self.path)
+    }
+
+    /// Is this the conflict for path, either side's full path works
+    pub fn matches(&self, path: &Path) -> bool {
+        self.lhs.join(&self.path) == path || self.rhs.join(&self.path) == path
+    }
+}
+
+/// Every unresolved two-way conflict
+#[derive(Debug, Resource)]
+pub struct ConflictRegistry {
+    path: PathBuf,
+    conflicts: Vec<Conflict>,
+}
+
+impl ConflictRegistry {
+    /// Load whatever was pending when the daemon last ran, no file is none.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut conflicts: Vec<Conflict> = Vec::new();
+
+        match std::fs::File::open(&path) {
+            Ok(f) => {
+                for line in std::io::BufReader::new(f).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<Conflict>(&line) {
+                        Ok(c) => conflicts.push(c),
+                        Err(e) => warn!("{} skipping bad conflict: {}", path.display(), e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        conflicts.sort_by(|a, b| a.key().cmp(&b.key()));
+        conflicts.dedup_by(|a, b| a.key() == b.key());
+
+        Ok(Self { path, conflicts })
+    }
+
+    pub fn len(&self) -> usize {
+        self.conflicts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    /// Oldest first
+    pub fn list(&self) -> Vec<Conflict> {
+        let mut list = self.conflicts.clone();
+        list.sort_by_key(|c| c.detected);
+        list
+    }
+
+    /// What a run of lhs <-> rhs found, replacing what we had for the pair.
+    /// Conflicts that are still around keep their original detected time.
+    pub fn replace(
+        &mut self,
+        uuid: u128,
+        lhs: &Path,
+        rhs: &Path,
+        found: &[(PathBuf, ConflictKind)],
+        at: u64,
+    ) {
+        let (old, mut kept): (Vec<Conflict>, Vec<Conflict>) = self
+            .conflicts
+            .drain(..)
+            .partition(|c| c.lhs == lhs && c.rhs == rhs);
+
+        for (path, kind) in found {
+            let detected = old
+                .iter()
+                .find(|c| &c.path == path && c.kind == *kind)
+                .map_or(at, |c| c.detected);
+            kept.push(Conflict {
+                uuid,
+                lhs: lhs.to_path_buf(),
+                rhs: rhs.to_path_buf(),
+                path: path.clone(),
+                kind: *kind,
+                detected,
+            });
+        }
+
+        kept.sort_by(|a, b| a.key().cmp(&b.key()));
+        self.conflicts = kept;
+    }
+
+    /// Conflicts for path, more than one means the same dir is in more than
+    /// one two-way sync.
+    pub fn find(&self, path: &Path) -> Vec<&Conflict> {
+        self.conflicts.iter().filter(|c| c.matches(path)).collect()
+    }
+
+    pub fn remove(&mut self, lhs: &Path, rhs: &Path, path: &Path) -> bool {
+        let before = self.conflicts.len();
+        self.conflicts.retain(|c| c.key() != (lhs, rhs, path));
+        before != self.conflicts.len()
+    }
+
+    /// Rewrite the file, tmp + rename like the baselines.
+    pub fn save(&self) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut out = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+            for c in &self.conflicts {
+                serde_json::to_writer(&mut out, c)?;
+                out.write_all(b"\n")?;
+            }
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+fn save(registry: &ConflictRegistry) {
+    if let Err(e) = registry.save() {
+        warn!("couldn't save conflicts {}: {}", registry.path.display(), e);
+    }
+}
+
+// io_bridge drops SyncConflicts on a two-way sync once its done
+fn record_conflicts(
+    mut registry: ResMut<ConflictRegistry>,
+    query: Query<(&Uuid, &Source, &Dest, &SyncConflicts), Added<SyncConflicts>>,
+) {
+    for (uuid, source, dest, conflicts) in &query {
+        for (path, kind) in conflicts.iter() {
+            warn!(
+                "conflict {} {} {}",
+                kind.tag(),
+                source.join(path).display(),
+                dest.join(path).display()
+            );
+        }
+        registry.replace(**uuid, &source.0, &dest.0, &conflicts.0, now());
+        save(&registry);
+    }
+}
+
+fn answer_conflicts(mut events: MessageReader<RpcEvent>, registry: Res<ConflictRegistry>) {
+    for event in events.read() {
+        let RpcEvent::ListConflicts { response_tx } = event else {
+            continue;
+        };
+
+        if let Ok(mut guard) = response_tx.lock()
+            && let Some(tx) = guard.take()
+        {
+            let _ = tx.send(registry.list());
+        }
+    }
+}
+
+// Resolving does i/o, possibly copying a big file, so it goes off to a
+// blocking thread. Once its done the conflict gets dropped from the registry
+// through a ConflictResolved event back through the normal event path.
+//
+// The loser goes to the sync's trash, or the default one at each root if the
+// sync didn't set one up or its entity is long gone.
+fn resolve_conflicts(
+    mut events: MessageReader<RpcEvent>,
+    registry: Res<ConflictRegistry>,
+    trashes: Query<(&Uuid, &crate::Trash)>,
+    runtime: ResMut<TokioTasksRuntime>,
+    sender: Res<SyncEventSender>,
+) {
+    for event in events.read() {
+        let RpcEvent::ResolveConflict {
+            path,
+            keep,
+            response_tx,
+        } = event
+        else {
+            continue;
+        };
+
+        let Some(tx) = response_tx.lock().ok().and_then(|mut g| g.take()) else {
+            continue;
+        };
+
+        let conflict = match registry.find(path).as_slice() {
+            [] => {
+                let _ = tx.send(Err(format!("no conflict for {}", path.display())));
+                continue;
+            }
+            [c] => (*c).clone(),
+            many => {
+                let syncs: Vec<String> = many
+                    .iter()
+                    .map(|c| format!("{} <-> {}", c.lhs.display(), c.rhs.display()))
+                    .collect();
+                let _ = tx.send(Err(format!(
+                    "{} is conflicted in more than one sync: {}",
+                    path.display(),
+                    syncs.join(", ")
+                )));
+                continue;
+            }
+        };
+
+        let keep = *keep;
+        let trash = trashes
+            .iter()
+            .find(|(uuid, _)| uuid.0 == conflict.uuid)
+            .map(|(_, trash)| trash.0.clone())
+            .unwrap_or_default();
+        let sender = sender.0.clone();
+        runtime.spawn_background_task(move |_ctx| async move {
+            let c = conflict.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                crate::io::resolve::resolve(&c.lhs, &c.rhs, &c.path, keep, &trash)
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|r| r.map_err(|e| e.to_string()));
+
+            match &result {
+                Ok(what) => {
+                    info!(
+                        "resolved {} <-> {} {}: {}",
+                        conflict.lhs.display(),
+                        conflict.rhs.display(),
+                        conflict.path.display(),
+                        what
+                    );
+                    if let Ok(s) = sender.lock() {
+                        let _ = s.send(RpcEvent::ConflictResolved {
+                            lhs: conflict.lhs.clone(),
+                            rhs: conflict.rhs.clone(),
+                            path: conflict.path.clone(),
+                        });
+                    }
+                }
+                Err(e) => warn!(
+                    "couldn't resolve {}: {}",
+                    conflict.lhs.join(&conflict.path).display(),
+                    e
+                ),
+            }
+            let _ = tx.send(result);
+        });
+    }
+}
+
+fn forget_resolved(mut events: MessageReader<RpcEvent>, mut registry: ResMut<ConflictRegistry>) {
+    for event in events.read() {
+        let RpcEvent::ConflictResolved { lhs, rhs, path } = event else {
+            continue;
+        };
+
+        if registry.remove(lhs, rhs, path) {
+            save(&registry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::reconcile::Side;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_runs_replace_their_pair() {
+        let dir = TestDir::new("conflicts-replace");
+        let path = dir.join("conflicts.jsonl");
+        let mut r = ConflictRegistry::open(&path).unwrap();
+        let (a, b, c) = (Path::new("/a"), Path::new("/b"), Path::new("/c"));
+
+        r.replace(
+            1,
+            a,
+            b,
+            &[
+                (PathBuf::from("x"), ConflictKind::BothChanged),
+                (
+                    PathBuf::from("y"),
+                    ConflictKind::DeleteChange { deleted: Side::Rhs },
+                ),
+            ],
+            10,
+        );
+        r.replace(
+            2,
+            a,
+            c,
+            &[(PathBuf::from("x"), ConflictKind::BothChanged)],
+            20,
+        );
+        assert_eq!(r.len(), 3);
+
+        // Same dir in two syncs, full path has to say which
+        assert_eq!(r.find(Path::new("/a/x")).len(), 2);
+        assert_eq!(r.find(Path::new("/b/x")).len(), 1);
+        assert!(r.find(Path::new("x")).is_empty());
+
+        // y got sorted out by hand, x is still there and keeps its time
+        r.replace(
+            3,
+            a,
+            b,
+            &[(PathBuf::from("x"), ConflictKind::BothChanged)],
+            30,
+        );
+        let ab: Vec<&Conflict> = r.find(Path::new("/b/x"));
+        assert_eq!((ab[0].uuid, ab[0].detected), (3, 10));
+        assert!(r.find(Path::new("/b/y")).is_empty());
+
+        // Survives a restart
+        r.save().unwrap();
+        let mut r = ConflictRegistry::open(&path).unwrap();
+        assert_eq!(r.list().len(), 2);
+        assert!(r.remove(a, c, Path::new("x")));
+        assert!(!r.remove(a, c, Path::new("x")));
+        assert_eq!(r.list()[0].rhs, b);
+    }
+}
@@ -5,11 +5,12 @@ use bevy_tokio_tasks::{TaskContext, TokioTasksRuntime};
 
 use crate::rpc::{
     loglevel::{MyLogLevel, log_level_server::LogLevelServer},
+    transfer::{MyTransfer, transfer_server::TransferServer},
     yeet::{MyYeet, yeet_server::YeetServer},
 };
 use crate::{
-    Dest, RemoteHost, RpcEvent, SimpleCopy, Source, SyncEventReceiver, SyncEventSender, Uuid,
-    parse_remote_spec,
+    Dest, GlobalLimits, RateLimit, RateLimits, RemoteHost, RpcEvent, SharedWriters, SimpleCopy,
+    Source, SyncComplete, SyncEventReceiver, SyncEventSender, Uuid, parse_remote_spec,
 };
 
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
@@ -85,10 +86,16 @@ fn poll_rpc_events(mut event_writer: MessageWriter<RpcEvent>, receiver: Res<Sync
 }
 
 // This always runs after ^^^ to minimize the ecs seeing components between ticks.
+#[allow(clippy::too_many_arguments)]
 fn handle_rpc_event(
     mut commands: Commands,
     mut events: MessageReader<RpcEvent>,
     log_handle: Option<Res<crate::systems::loglevel::LogHandle>>,
+    rate_limits: Option<Res<RateLimits>>,
+    shared_writers: Option<Res<SharedWriters>>,
+    identities: Option<Res<crate::systems::ssh::Identities>>,
+    syncs: Query<(Entity, &Uuid), Without<SyncComplete>>,
+    mut global_limits: Query<&mut RateLimit, With<GlobalLimits>>,
 ) {
     use crate::rpc::loglevel::Level;
 
@@ -99,34 +106,106 @@ fn handle_rpc_event(
                 rhs,
                 uuid,
                 writers,
+                schedule,
+                weight,
+                scan_order,
+                scanners,
+                watch,
+                two_way,
+                fan_out,
+                snapshot,
+                trash,
+                resume,
             } => {
                 debug!(
                     "got a simple copy sync request lhs {lhs}, rhs {rhs}, uuid {uuid} {}",
                     uuid::Uuid::from_u128(*uuid)
                 );
 
-                // Parse source and dest (may be remote)
+                // Parse source (may be remote)
                 let (lhs_host, lhs_path) = parse_remote_spec(lhs)
                     .unwrap_or_else(|_| (None, std::path::PathBuf::from(lhs)));
-                let (rhs_host, rhs_path) = parse_remote_spec(rhs)
-                    .unwrap_or_else(|_| (None, std::path::PathBuf::from(rhs)));
 
-                let mut entity = commands.spawn((Uuid(*uuid), SimpleCopy {}));
-
-                // Add source components
-                if let Some(host) = lhs_host {
-                    entity.insert(RemoteHost(host));
+                // Every destination is its own entity, fan-out ones just
+                // don't get to scan for themselves.
+                let dests =
+                    std::iter::once((*uuid, rhs)).chain(fan_out.iter().map(|(u, r)| (*u, r)));
+                for (dest_uuid, rhs) in dests {
+                    let (rhs_host, rhs_path) = parse_remote_spec(rhs)
+                        .unwrap_or_else(|_| (None, std::path::PathBuf::from(rhs)));
+
+                    if lhs_host.is_some() && rhs_host.is_some() {
+                        error!(
+                            "remote to remote syncs aren't supported, not starting {}",
+                            uuid::Uuid::from_u128(dest_uuid)
+                        );
+                        continue;
+                    }
+
+                    let mut entity = commands.spawn((Uuid(dest_uuid), SimpleCopy {}));
+
+                    // Add source components
+                    if let Some(host) = &lhs_host {
+                        entity.insert(RemoteHost(host.clone()));
+                    }
+                    entity.insert(Source(lhs_path.clone()));
+
+                    // Add dest components, a remote dest gets written by the
+                    // daemon over there through the ssh forward to it.
+                    if let Some(host) = rhs_host {
+                        entity.insert((RemoteHost(host), crate::RemoteDest));
+                    }
+                    entity.insert(Dest(rhs_path));
+
+                    if dest_uuid == *uuid {
+                        if !fan_out.is_empty() {
+                            entity.insert(crate::FanOut(fan_out.iter().map(|(u, _)| *u).collect()));
+                        }
+                    } else {
+                        entity.insert(crate::FanOutLeg(*uuid));
+                    }
+
+                    // Add writer count if specified
+                    entity.insert(crate::NumWriters(*writers));
+                    entity.insert(crate::Schedule(schedule.clone()));
+                    entity.insert(crate::ScanOptions {
+                        order: *scan_order,
+                        scanners: *scanners,
+                    });
+                    entity.insert(crate::Weight(
+                        weight.unwrap_or(crate::io::fair::DEFAULT_WEIGHT),
+                    ));
+                    if let Some(watch) = watch {
+                        entity.insert(crate::Watch(*watch));
+                    }
+                    if *two_way {
+                        entity.insert(crate::TwoWay);
+                    }
+                    if let Some(snapshot) = snapshot {
+                        entity.insert(crate::SnapshotSource(snapshot.clone()));
+                    }
+                    if let Some(trash) = trash {
+                        entity.insert(crate::Trash(trash.clone()));
+                    }
+                    if *resume {
+                        entity.insert(crate::QuickCheck);
+                    }
                 }
-                entity.insert(Source(lhs_path));
-
-                // Add dest components
-                if let Some(_host) = rhs_host {
-                    warn!("Remote dest not yet implemented");
+            }
+            RpcEvent::AddIdentities {
+                identities: unlocked,
+            } => {
+                let Some(ref identities) = identities else {
+                    warn!("no ssh identities resource, ignoring keys");
+                    continue;
+                };
+                for identity in unlocked {
+                    info!(
+                        "keeping ssh key {} for connections",
+                        identity.path.display()
+                    );
+                    identities.unlock(identity.clone());
                 }
-                entity.insert(Dest(rhs_path));
-
-                // Add writer count if specified
-                entity.insert(crate::NumWriters(*writers));
             }
             RpcEvent::LogLevel { level } => {
                 debug!("handling loglevel event: {:?}", level);
@@ -142,9 +221,113 @@ fn handle_rpc_event(
                     let _ = handle.set_max_level(String::from(set_level));
                 }
             }
+            RpcEvent::SetLimits {
+                uuid,
+                bytes_per_sec,
+                ops_per_sec,
+                response_tx,
+            } => {
+                let Some(tx) = response_tx.lock().ok().and_then(|mut g| g.take()) else {
+                    continue;
+                };
+                let Some(ref limits) = rate_limits else {
+                    warn!("no rate limits resource, ignoring set limits request");
+                    continue;
+                };
+
+                let current = match uuid {
+                    Some(u) => limits.0.get(*u),
+                    None => limits.0.global(),
+                };
+
+                let limit = crate::io::limits::Limit {
+                    bytes_per_sec: bytes_per_sec.unwrap_or(current.bytes_per_sec),
+                    ops_per_sec: ops_per_sec.unwrap_or(current.ops_per_sec),
+                };
+
+                let component = RateLimit {
+                    bytes_per_sec: limit.bytes_per_sec,
+                    ops_per_sec: limit.ops_per_sec,
+                };
+
+                match uuid {
+                    Some(u) => {
+                        // Only bother with syncs that are still running,
+                        // limits for uuids that are done would just leak.
+                        if let Some((entity, _)) = syncs.iter().find(|(_, id)| id.0 == *u) {
+                            limits.0.set(*u, limit);
+                            commands.entity(entity).insert(component);
+                            info!(
+                                "limits for {} set to {:?}",
+                                uuid::Uuid::from_u128(*u),
+                                limit
+                            );
+                        } else {
+                            warn!(
+                                "no active sync with uuid {}, ignoring limits",
+                                uuid::Uuid::from_u128(*u)
+                            );
+                            let _ = tx.send(Err(format!(
+                                "no running sync with uuid {}",
+                                uuid::Uuid::from_u128(*u)
+                            )));
+                            continue;
+                        }
+                    }
+                    None => {
+                        limits.0.set_global(limit);
+                        for mut global in &mut global_limits {
+                            *global = component;
+                        }
+                        info!("global limits set to {:?}", limit);
+                    }
+                }
+                let _ = tx.send(Ok(()));
+            }
+            RpcEvent::SetWeight {
+                uuid,
+                weight,
+                response_tx,
+            } => {
+                let Some(tx) = response_tx.lock().ok().and_then(|mut g| g.take()) else {
+                    continue;
+                };
+                let Some((entity, _)) = syncs.iter().find(|(_, id)| id.0 == *uuid) else {
+                    warn!(
+                        "no active sync with uuid {}, ignoring weight",
+                        uuid::Uuid::from_u128(*uuid)
+                    );
+                    let _ = tx.send(Err(format!(
+                        "no running sync with uuid {}",
+                        uuid::Uuid::from_u128(*uuid)
+                    )));
+                    continue;
+                };
+
+                // Not in the pool yet is fine, spawn_sync_tasks picks up the
+                // component when it starts things.
+                if let Some(ref writers) = shared_writers {
+                    writers.0.set_weight(*uuid, *weight);
+                }
+                commands.entity(entity).insert(crate::Weight(*weight));
+                info!(
+                    "weight for {} set to {}",
+                    uuid::Uuid::from_u128(*uuid),
+                    weight
+                );
+                let _ = tx.send(Ok(()));
+            }
             RpcEvent::Heartbeat { .. } => {
                 debug!("heartbeat event received (handled by heartbeat system)");
             }
+            RpcEvent::ListHistory { .. } => {
+                debug!("list history event received (handled by journal system)");
+            }
+            RpcEvent::ListConflicts { .. }
+            | RpcEvent::ResolveConflict { .. }
+            | RpcEvent::ConflictResolved { .. } => {
+                debug!("conflict event received (handled by conflicts system)");
+            }
         }
     }
 }
@@ -166,10 +349,28 @@ fn start_uds(runtime: ResMut<'_, TokioTasksRuntime>, event_sender: Res<crate::Sy
     runtime.spawn_background_task(move |ctx| run_uds(ctx, sender));
 }
 
-fn start_tcp(runtime: ResMut<'_, TokioTasksRuntime>, event_sender: Res<crate::SyncEventSender>) {
+fn start_tcp(
+    runtime: ResMut<'_, TokioTasksRuntime>,
+    event_sender: Res<crate::SyncEventSender>,
+    rate_limits: Option<Res<RateLimits>>,
+    controllers: Option<Res<crate::WriterControllers>>,
+    shared_writers: Option<Res<SharedWriters>>,
+    budget: Option<Res<crate::MemoryBudget>>,
+    exports: Option<Res<crate::Exports>>,
+) {
     info!("starting tcp server in separate thread");
     let sender = event_sender.0.clone();
-    runtime.spawn_background_task(move |ctx| run_tcp(ctx, sender));
+
+    // Remote daemons sending us data write with the same everything local
+    // syncs do.
+    let transfer = MyTransfer::new(
+        exports.map(|e| e.0.clone()).unwrap_or_default(),
+        rate_limits.map(|l| l.0.clone()).unwrap_or_default(),
+        controllers.map(|c| c.0.clone()).unwrap_or_default(),
+        shared_writers.map(|w| w.0.clone()).unwrap_or_default(),
+        budget.map(|b| b.0).unwrap_or_default(),
+    );
+    runtime.spawn_background_task(move |ctx| run_tcp(ctx, sender, transfer));
 }
 
 // Note the tcp socket might come in more handy for stuff running non locally
@@ -183,6 +384,7 @@ fn start_tcp(runtime: ResMut<'_, TokioTasksRuntime>, event_sender: Res<crate::Sy
 // authentication, if the local system is compromised its game off anyway.
 pub mod proto {
     tonic::include_proto!("loglevel");
+    tonic::include_proto!("transfer");
     tonic::include_proto!("yeet");
 
     pub(crate) const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("reflection");
@@ -191,6 +393,12 @@ pub mod proto {
 #[derive(Default, Clone, Resource)]
 pub struct LogLevelService {}
 
+/// Where the tcp listener is, loopback only. Nothing on it checks who's
+/// asking, so the only way in from elsewhere is through ssh to this box and a
+/// forward to here. Forwards have to ask for this and not "localhost", that
+/// could be ::1 which nobody is listening on.
+pub const DAEMON_HOST: &str = "127.0.0.1";
+
 // For future mitch or anyone more able to do windows than me, until
 // https://github.com/rust-lang/rust/issues/56533 is resolved maybe this will
 // work for a unix domain socket solution on windows for client/daemon
@@ -198,14 +406,20 @@ pub struct LogLevelService {}
 //
 // Looks like it might be the best solution perf wise on windows
 // https://www.yanxurui.cc/posts/server/2023-11-28-benchmark-tcp-uds-namedpipe/
-async fn run_tcp(_ctx: TaskContext, event_sender: Arc<Mutex<UnboundedSender<RpcEvent>>>) {
+async fn run_tcp(
+    _ctx: TaskContext,
+    event_sender: Arc<Mutex<UnboundedSender<RpcEvent>>>,
+    transfer: MyTransfer,
+) {
     use tonic::transport::Server;
 
-    let addr = "[::]:50051".parse().expect("this shouldn't fail ever...");
+    let addr = format!("{}:50051", DAEMON_HOST)
+        .parse()
+        .expect("this shouldn't fail ever...");
     let event_sender = event_sender.clone();
 
     let loglevel = MyLogLevel::new(event_sender.clone());
-    let yeet = MyYeet::new(event_sender.clone());
+    let yeet = MyYeet::new(event_sender.clone(), false);
     let reflection = match tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
         .build_v1()
@@ -222,6 +436,9 @@ async fn run_tcp(_ctx: TaskContext, event_sender: Arc<Mutex<UnboundedSender<RpcE
     let tcp_server = Server::builder()
         .add_service(LogLevelServer::new(loglevel))
         .add_service(YeetServer::new(yeet))
+        // Only over tcp, its how other daemons get here through an ssh forward
+        // to our loopback
+        .add_service(TransferServer::new(transfer))
         .add_service(reflection)
         .serve(addr);
 
@@ -285,7 +502,7 @@ async fn run_uds(_ctx: TaskContext, event_sender: Arc<Mutex<UnboundedSender<RpcE
     let addr_uds = UnixListenerStream::new(uds);
 
     let loglevel = MyLogLevel::new(event_sender.clone());
-    let yeet = MyYeet::new(event_sender.clone());
+    let yeet = MyYeet::new(event_sender.clone(), true);
 
     let reflection = match tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
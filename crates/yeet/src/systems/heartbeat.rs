@@ -68,6 +68,8 @@ fn startup(_commands: Commands) {
 fn handle_heartbeat_requests(
     mut events: MessageReader<crate::RpcEvent>,
     runtime: Res<bevy_tokio_tasks::TokioTasksRuntime>,
+    policy: Option<Res<super::ssh::HostKeyPolicy>>,
+    identities: Option<Res<super::ssh::Identities>>,
 ) {
     use crate::RpcEvent;
 
@@ -82,10 +84,12 @@ fn handle_heartbeat_requests(
             // Clone what we need for the async task
             let target_clone = target.clone();
             let response_tx_clone = response_tx.clone();
+            let policy = policy.as_deref().copied().unwrap_or_default();
+            let identities = identities.as_deref().cloned().unwrap_or_default();
 
             // Spawn async task to handle the heartbeat
             runtime.spawn_background_task(move |_ctx| async move {
-                let (success, message) = perform_heartbeat(&target_clone).await;
+                let (success, message) = perform_heartbeat(&target_clone, policy, identities).await;
 
                 // Send response back through the channel
                 if let Ok(mut guard) = response_tx_clone.lock()
@@ -99,12 +103,16 @@ fn handle_heartbeat_requests(
 }
 
 /// Perform the actual heartbeat to the target
-async fn perform_heartbeat(target: &str) -> (bool, String) {
+async fn perform_heartbeat(
+    target: &str,
+    policy: super::ssh::HostKeyPolicy,
+    identities: super::ssh::Identities,
+) -> (bool, String) {
     // Parse target - if it contains '@', treat as SSH connection (user@host)
     // Otherwise, it's a local heartbeat
     if target.contains('@') {
         // Remote heartbeat via SSH
-        match perform_remote_heartbeat(target).await {
+        match perform_remote_heartbeat(target, policy, identities).await {
             Ok(msg) => (true, msg),
             Err(e) => (false, format!("Remote heartbeat failed: {}", e)),
         }
@@ -117,19 +125,16 @@ async fn perform_heartbeat(target: &str) -> (bool, String) {
 /// Perform a remote heartbeat via SSH with port forwarding
 async fn perform_remote_heartbeat(
     target: &str,
+    policy: super::ssh::HostKeyPolicy,
+    identities: super::ssh::Identities,
 ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-    use super::ssh::{connect, setup_port_forward};
+    use super::ssh::{connect_route, setup_port_forward};
     use crate::rpc::yeet::{HeartbeatRequest, yeet_client::YeetClient};
 
-    // Parse user@host format
-    let (user, host) = if let Some((u, h)) = target.split_once('@') {
-        (u.to_string(), h.to_string())
-    } else {
-        return Err(format!("Invalid target format '{}'. Expected 'user@host'", target).into());
-    };
+    let route = super::ssh::config::route(target)?;
 
-    // Establish SSH connection (using default SSH key)
-    let session = connect(host.clone(), user.clone(), None).await?;
+    // Establish SSH connection with whatever keys the daemon has
+    let session = connect_route(route, identities, policy).await?;
 
     // Set up port forward to remote yeet daemon (port 50051)
     let local_port = setup_port_forward(session, 50051).await?;
@@ -147,6 +152,7 @@ async fn perform_remote_heartbeat(
     // Use "localhost" as target since we're already connected to the remote
     let request = tonic::Request::new(HeartbeatRequest {
         target: "localhost".to_string(),
+        payload: Vec::new(),
     });
 
     let response = client
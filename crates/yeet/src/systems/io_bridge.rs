@@ -1,6 +1,6 @@
 use bevy::prelude::*;
 
-use crate::{IoOperation, IoProgress, SimpleCopy, SyncComplete};
+use crate::{IoOperation, IoProgress, RateLimits, SimpleCopy, SyncComplete, WriterConcurrency};
 
 /// Plugin that bridges the async I/O subsystem with the Bevy ECS
 pub struct IoBridge;
@@ -14,9 +14,40 @@ impl Plugin for IoBridge {
 /// System to update I/O progress from the async subsystem
 /// Rate-limited to ~10Hz by the Progress struct
 fn update_io_progress(
-    mut query: Query<(&IoOperation, &mut IoProgress), (With<SimpleCopy>, Without<SyncComplete>)>,
+    mut commands: Commands,
+    mut query: Query<
+        (
+            Entity,
+            &IoOperation,
+            &mut IoProgress,
+            Option<&mut WriterConcurrency>,
+        ),
+        (With<SimpleCopy>, Without<SyncComplete>),
+    >,
 ) -> bevy::prelude::Result {
-    for (io_op, mut progress) in &mut query {
+    for (entity, io_op, mut progress, concurrency) in &mut query {
+        if let Some(state) = io_op.subsystem.controller_state() {
+            let current = WriterConcurrency {
+                active_writers: state.active_writers,
+                max_writers: state.max_writers,
+                writer_batch: state.writer_batch,
+                queue_batch: state.queue_batch,
+                avg_latency_us: state.avg_latency_us,
+                network: state.network,
+            };
+
+            // Only touch the component if something changed so change
+            // detection/replication isn't triggered every tick.
+            match concurrency {
+                Some(mut c) => {
+                    c.set_if_neq(current);
+                }
+                None => {
+                    commands.entity(entity).insert(current);
+                }
+            }
+        }
+
         let subsystem = io_op.subsystem.clone();
         let uuid = io_op.uuid;
 
@@ -59,9 +90,13 @@ fn update_io_progress(
 
 fn check_io_completion(
     mut commands: Commands,
-    query: Query<(Entity, &IoOperation), (With<SimpleCopy>, Without<SyncComplete>)>,
+    limits: Option<Res<RateLimits>>,
+    mut query: Query<
+        (Entity, &IoOperation, Option<&mut IoProgress>),
+        (With<SimpleCopy>, Without<SyncComplete>),
+    >,
 ) -> bevy::prelude::Result {
-    for (entity, io_op) in &query {
+    for (entity, io_op, progress) in &mut query {
         let subsystem = io_op.subsystem.clone();
         let uuid = io_op.uuid;
 
@@ -81,6 +116,12 @@ fn check_io_completion(
             let error_count =
                 futures_lite::future::block_on(async move { subsystem.error_count().await });
 
+            // Progress only updates at ~10Hz, make sure the final count is
+            // what sticks around for the journal/history.
+            if let Some(mut progress) = progress {
+                progress.error_count = error_count;
+            }
+
             if error_count > 0 {
                 warn!(
                     "{} i/o operation completed with about {} errors",
@@ -104,10 +145,23 @@ fn check_io_completion(
                 .duration_since(std::time::UNIX_EPOCH)?
                 .as_secs();
 
+            // Even an empty list matters, it clears out conflicts that got
+            // sorted out since the last run.
+            if let Some(report) = io_op.subsystem.two_way_report() {
+                commands
+                    .entity(entity)
+                    .insert(crate::SyncConflicts(report.conflicts));
+            }
+
             let mut subsystem = io_op.subsystem.clone();
             futures_lite::future::block_on(async move {
                 subsystem.shutdown().await;
             });
+
+            // Done with this uuid, no need to keep its limits around
+            if let Some(ref limits) = limits {
+                limits.0.remove(uuid);
+            }
             info!(
                 "{} i/o subsystem shutdown complete",
                 uuid::Uuid::from_u128(uuid)
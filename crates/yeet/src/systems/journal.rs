@@ -0,0 +1,993 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{IoOperation, IoProgress, RpcEvent, SyncComplete, SyncEventSender, Uuid};
+
+// Everything about a sync lives in the ecs and IoSubsystem memory, so if the
+// daemon goes away so does all knowledge of what it was doing. The journal is
+// the dumb fix, an append only json lines file in the data dir with every
+// sync request, its config and what state it got to.
+//
+// On startup we read it back, anything that never finished gets requested
+// again with quick-check on so whatever already made it to the dest is
+// skipped. Restarting from scratch is simpler than trying to remember which
+// files made it, for now anyway.
+//
+// The file gets compacted to one line per op on every open so it can't grow
+// forever between restarts. Doubles as the history store for `yeet log`,
+// finished ops get pruned per the retention settings on open and every so
+// often after that.
+pub struct JournalPlugin {
+    pub path: PathBuf,
+    pub retention: Retention,
+}
+
+// How often finished ops get pruned while the daemon is running
+const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// How long finished ops stick around in history, None means forever for
+/// either. Unfinished ops are never pruned, they get restarted instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Resource)]
+pub struct Retention {
+    /// Drop finished ops older than this
+    pub max_age: Option<std::time::Duration>,
+    /// Keep at most this many finished ops, oldest go first
+    pub max_ops: Option<usize>,
+}
+
+pub const DEFAULT_RETENTION_AGE: std::time::Duration =
+    std::time::Duration::from_secs(30 * 24 * 3600);
+pub const DEFAULT_RETENTION_OPS: usize = 10_000;
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_age: Some(DEFAULT_RETENTION_AGE),
+            max_ops: Some(DEFAULT_RETENTION_OPS),
+        }
+    }
+}
+
+impl Plugin for JournalPlugin {
+    fn build(&self, app: &mut App) {
+        // Restarts go back through the normal rpc event path
+        assert!(app.is_plugin_added::<crate::systems::grpc::GrpcPlugin>());
+
+        let mut journal = match Journal::open(&self.path) {
+            Ok(j) => j,
+            Err(e) => {
+                error!(
+                    "couldn't open journal {}, syncs won't survive a restart: {}",
+                    self.path.display(),
+                    e
+                );
+                return;
+            }
+        };
+
+        prune(&mut journal, &self.retention);
+
+        info!(
+            "journal {} has {} ops, {} unfinished",
+            self.path.display(),
+            journal.len(),
+            journal.unfinished().len()
+        );
+
+        app.insert_resource(journal)
+            .insert_resource(self.retention)
+            .add_systems(Startup, restart_unfinished)
+            .add_systems(
+                Update,
+                (
+                    (record_requests, record_started, record_finished).chain(),
+                    answer_history,
+                    prune_history.run_if(bevy::time::common_conditions::on_timer(PRUNE_INTERVAL)),
+                ),
+            );
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Uuids are written out as the normal hyphenated string, easier to grep the
+// journal for whatever `yeet cp` printed.
+pub(crate) mod uuid_string {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(uuid: &u128, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&uuid::Uuid::from_u128(*uuid).to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u128, D::Error> {
+        let s = String::deserialize(d)?;
+        uuid::Uuid::parse_str(&s)
+            .map(|u| u.as_u128())
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// What a sync was asked to do, kept as the request time strings so the
+/// journal doesn't care how the io side represents any of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncRecord {
+    pub lhs: String,
+    pub rhs: String,
+    #[serde(default)]
+    pub writers: Option<usize>,
+    pub schedule: String,
+    #[serde(default)]
+    pub priority: Vec<String>,
+    #[serde(default)]
+    pub aging_secs: Option<u64>,
+    #[serde(default)]
+    pub weight: Option<u32>,
+    pub scan_order: String,
+    #[serde(default)]
+    pub scanners: Option<usize>,
+    // Watched syncs never finish so these always come back on restart
+    #[serde(default)]
+    pub watch: Option<String>,
+    #[serde(default)]
+    pub poll_secs: Option<u64>,
+    #[serde(default)]
+    pub two_way: bool,
+    // Other destinations fed from this one's scan, they get their own ops
+    // too, this is just so a restart scans once for all of them again.
+    #[serde(default)]
+    pub fan_out: Vec<FanOutRecord>,
+    // A snapshot command is just "cmd", the command itself never gets written
+    // down, see to_event().
+    #[serde(default)]
+    pub snapshot: Option<String>,
+    #[serde(default)]
+    pub trash: Option<TrashRecord>,
+}
+
+/// A sync's trash settings, max_age_secs of 0 keeps versions forever.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashRecord {
+    pub dir: Option<PathBuf>,
+    pub max_age_secs: u64,
+    pub max_versions: Option<usize>,
+}
+
+/// One of the extra destinations of a fan-out sync.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FanOutRecord {
+    #[serde(with = "uuid_string")]
+    pub uuid: u128,
+    pub rhs: String,
+}
+
+impl SyncRecord {
+    /// Pull the record out of a sync request, returns the uuid and whether
+    /// its a restart too. None for anything that isn't a sync request.
+    pub fn from_event(event: &RpcEvent) -> Option<(u128, bool, Self)> {
+        let RpcEvent::SimpleCopySync {
+            lhs,
+            rhs,
+            uuid,
+            writers,
+            schedule,
+            weight,
+            scan_order,
+            scanners,
+            watch,
+            two_way,
+            fan_out,
+            snapshot,
+            trash,
+            resume,
+        } = event
+        else {
+            return None;
+        };
+
+        let record = Self {
+            lhs: lhs.clone(),
+            rhs: rhs.clone(),
+            writers: *writers,
+            schedule: schedule.order.to_string(),
+            priority: schedule
+                .priorities
+                .iter()
+                .map(|g| g.as_str().to_string())
+                .collect(),
+            // Same convention as SchedulePolicy::parse(), 0 is off
+            aging_secs: Some(schedule.aging.map(|a| a.as_secs()).unwrap_or(0)),
+            weight: *weight,
+            scan_order: scan_order.to_string(),
+            scanners: *scanners,
+            watch: watch.map(|w| w.backend.to_string()),
+            poll_secs: watch.map(|w| w.poll_interval.as_secs()),
+            two_way: *two_way,
+            fan_out: fan_out
+                .iter()
+                .map(|(uuid, rhs)| FanOutRecord {
+                    uuid: *uuid,
+                    rhs: rhs.clone(),
+                })
+                .collect(),
+            snapshot: snapshot.as_ref().map(|s| match s {
+                crate::io::snapshot::SnapshotProvider::Command(_) => "cmd".to_string(),
+                s => s.to_string(),
+            }),
+            trash: trash.as_ref().map(|t| TrashRecord {
+                dir: t.dir.clone(),
+                max_age_secs: t.max_age.map(|a| a.as_secs()).unwrap_or(0),
+                max_versions: t.max_versions,
+            }),
+        };
+
+        Some((*uuid, *resume, record))
+    }
+
+    /// Turn the record back into a sync request for a restart.
+    pub fn to_event(&self, uuid: u128) -> Result<RpcEvent, String> {
+        let schedule = crate::io::schedule::SchedulePolicy::parse(
+            Some(&self.schedule),
+            &self.priority,
+            self.aging_secs,
+        )?;
+
+        let watch = match &self.watch {
+            Some(backend) => Some(crate::io::watch::WatchConfig {
+                backend: backend.parse()?,
+                poll_interval: self
+                    .poll_secs
+                    .filter(|s| *s > 0)
+                    .map(std::time::Duration::from_secs)
+                    .unwrap_or(crate::io::watch::DEFAULT_POLL_INTERVAL),
+                ..Default::default()
+            }),
+            None => None,
+        };
+
+        Ok(RpcEvent::SimpleCopySync {
+            lhs: self.lhs.clone(),
+            rhs: self.rhs.clone(),
+            uuid,
+            writers: self.writers,
+            schedule,
+            weight: self.weight,
+            scan_order: self.scan_order.parse()?,
+            scanners: self.scanners,
+            watch,
+            two_way: self.two_way,
+            fan_out: self
+                .fan_out
+                .iter()
+                .map(|leg| (leg.uuid, leg.rhs.clone()))
+                .collect(),
+            // Whoever can write the journal could put anything here, so a
+            // snapshot command only ever runs when someone asks for it.
+            snapshot: match self.snapshot.as_deref() {
+                Some(provider) if provider == "cmd" || provider.starts_with("cmd:") => {
+                    return Err(
+                        "syncs with a snapshot command aren't restarted, run it again".to_string(),
+                    );
+                }
+                Some(provider) => Some(provider.parse()?),
+                None => None,
+            },
+            trash: self.trash.as_ref().map(|t| crate::io::trash::TrashConfig {
+                dir: t.dir.clone(),
+                max_age: Some(t.max_age_secs)
+                    .filter(|s| *s > 0)
+                    .map(std::time::Duration::from_secs),
+                max_versions: t.max_versions,
+            }),
+            resume: true,
+        })
+    }
+}
+
+/// Where an op got to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// Asked for but no i/o started yet
+    Requested,
+    /// I/O started
+    Running,
+    /// Done, no errors
+    Complete,
+    /// Done, but with errors
+    Failed,
+}
+
+impl State {
+    pub fn is_finished(self) -> bool {
+        matches!(self, State::Complete | State::Failed)
+    }
+}
+
+impl std::fmt::Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            State::Requested => write!(f, "requested"),
+            State::Running => write!(f, "running"),
+            State::Complete => write!(f, "complete"),
+            State::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Everything the journal knows about one op, times are seconds since epoch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Op {
+    #[serde(with = "uuid_string")]
+    pub uuid: u128,
+    pub sync: SyncRecord,
+    pub state: State,
+    pub requested: u64,
+    #[serde(default)]
+    pub started: Option<u64>,
+    #[serde(default)]
+    pub finished: Option<u64>,
+    #[serde(default)]
+    pub errors: usize,
+    #[serde(default)]
+    pub restarts: u32,
+    #[serde(default)]
+    pub files: u64,
+    #[serde(default)]
+    pub bytes: u64,
+    /// Average write throughput in bytes/sec as the io side saw it
+    #[serde(default)]
+    pub throughput: u64,
+}
+
+impl Op {
+    /// Wall clock seconds between the i/o starting and finishing
+    pub fn duration(&self) -> Option<u64> {
+        Some(self.finished?.saturating_sub(self.started?))
+    }
+}
+
+/// One line in the journal file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Entry {
+    /// Compacted state of an op, what open() rewrites the file as
+    Op(Op),
+    Requested {
+        #[serde(with = "uuid_string")]
+        uuid: u128,
+        at: u64,
+        sync: SyncRecord,
+    },
+    Restarted {
+        #[serde(with = "uuid_string")]
+        uuid: u128,
+        at: u64,
+    },
+    Started {
+        #[serde(with = "uuid_string")]
+        uuid: u128,
+        at: u64,
+    },
+    Finished {
+        #[serde(with = "uuid_string")]
+        uuid: u128,
+        at: u64,
+        errors: usize,
+        #[serde(default)]
+        files: u64,
+        #[serde(default)]
+        bytes: u64,
+        #[serde(default)]
+        throughput: u64,
+    },
+}
+
+/// The journal, folded into the current state of every op plus the file
+/// handle new entries get appended to.
+#[derive(Debug, Resource)]
+pub struct Journal {
+    path: PathBuf,
+    file: std::fs::File,
+    ops: BTreeMap<u128, Op>,
+}
+
+impl Journal {
+    /// Read in whatever is at path, compact it and open it for appending.
+    /// A torn last line from a crash is just skipped.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut ops = BTreeMap::new();
+
+        match std::fs::File::open(&path) {
+            Ok(f) => {
+                for (n, line) in std::io::BufReader::new(f).lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<Entry>(&line) {
+                        Ok(entry) => apply(&mut ops, &entry),
+                        Err(e) => warn!("{}:{} skipping bad entry: {}", path.display(), n + 1, e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        let file = compact(&path, &ops)?;
+
+        Ok(Self { path, file, ops })
+    }
+
+    /// Drop finished ops per retention, the file is only rewritten if
+    /// something actually went away. Returns how many were pruned.
+    pub fn prune(&mut self, retention: &Retention, now: u64) -> std::io::Result<usize> {
+        let before = self.ops.len();
+
+        if let Some(max_age) = retention.max_age {
+            let cutoff = now.saturating_sub(max_age.as_secs());
+            self.ops
+                .retain(|_, op| !op.state.is_finished() || op.finished.unwrap_or(0) >= cutoff);
+        }
+
+        if let Some(max_ops) = retention.max_ops {
+            let mut finished: Vec<(u64, u128)> = self
+                .ops
+                .values()
+                .filter(|op| op.state.is_finished())
+                .map(|op| (op.finished.unwrap_or(0), op.uuid))
+                .collect();
+
+            if finished.len() > max_ops {
+                finished.sort_unstable();
+                for (_, uuid) in &finished[..finished.len() - max_ops] {
+                    self.ops.remove(uuid);
+                }
+            }
+        }
+
+        let pruned = before - self.ops.len();
+        if pruned > 0 {
+            self.file = compact(&self.path, &self.ops)?;
+        }
+        Ok(pruned)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Apply an entry and append it to the file. The in memory state gets
+    /// updated even if the write fails, no reason to lie to status callers.
+    pub fn record(&mut self, entry: Entry) -> std::io::Result<()> {
+        apply(&mut self.ops, &entry);
+
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()
+    }
+
+    pub fn get(&self, uuid: u128) -> Option<&Op> {
+        self.ops.get(&uuid)
+    }
+
+    pub fn ops(&self) -> impl Iterator<Item = &Op> {
+        self.ops.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Ops requested at or after since, optionally only failed ones, oldest
+    /// request first.
+    pub fn history(&self, since: Option<u64>, failed: bool) -> Vec<Op> {
+        let mut ops: Vec<Op> = self
+            .ops
+            .values()
+            .filter(|op| op.requested >= since.unwrap_or(0))
+            .filter(|op| !failed || op.state == State::Failed)
+            .cloned()
+            .collect();
+        ops.sort_by_key(|op| op.requested);
+        ops
+    }
+
+    /// Everything that never finished, oldest request first
+    pub fn unfinished(&self) -> Vec<Op> {
+        let mut ops: Vec<Op> = self
+            .ops
+            .values()
+            .filter(|op| !op.state.is_finished())
+            .cloned()
+            .collect();
+        ops.sort_by_key(|op| op.requested);
+        ops
+    }
+}
+
+// Rewrite the journal as one line per op into a temp file and rename over so a
+// crash here leaves either the old or new journal, never half of one. Returns
+// the new file opened for appending.
+fn compact(path: &Path, ops: &BTreeMap<u128, Op>) -> std::io::Result<std::fs::File> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+        for op in ops.values() {
+            serde_json::to_writer(&mut out, &Entry::Op(op.clone()))?;
+            out.write_all(b"\n")?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    }
+    std::fs::rename(&tmp, path)?;
+
+    std::fs::OpenOptions::new().append(true).open(path)
+}
+
+// Fold one entry into the op map. Transitions for uuids we never saw a
+// request for are dropped, nothing useful to do with them.
+fn apply(ops: &mut BTreeMap<u128, Op>, entry: &Entry) {
+    match entry {
+        Entry::Op(op) => {
+            ops.insert(op.uuid, op.clone());
+        }
+        Entry::Requested { uuid, at, sync } => {
+            ops.insert(
+                *uuid,
+                Op {
+                    uuid: *uuid,
+                    sync: sync.clone(),
+                    state: State::Requested,
+                    requested: *at,
+                    started: None,
+                    finished: None,
+                    errors: 0,
+                    restarts: 0,
+                    files: 0,
+                    bytes: 0,
+                    throughput: 0,
+                },
+            );
+        }
+        Entry::Restarted { uuid, .. } => {
+            if let Some(op) = ops.get_mut(uuid) {
+                op.state = State::Requested;
+                op.restarts += 1;
+            }
+        }
+        Entry::Started { uuid, at } => {
+            if let Some(op) = ops.get_mut(uuid) {
+                op.state = State::Running;
+                op.started = Some(*at);
+            }
+        }
+        Entry::Finished {
+            uuid,
+            at,
+            errors,
+            files,
+            bytes,
+            throughput,
+        } => {
+            if let Some(op) = ops.get_mut(uuid) {
+                op.files = *files;
+                op.bytes = *bytes;
+                op.throughput = *throughput;
+                op.state = if *errors > 0 {
+                    State::Failed
+                } else {
+                    State::Complete
+                };
+                op.finished = Some(*at);
+                op.errors = *errors;
+            }
+        }
+    }
+}
+
+fn record(journal: &mut Journal, entry: Entry) {
+    if let Err(e) = journal.record(entry) {
+        warn!(
+            "couldn't write to journal {}: {}",
+            journal.path().display(),
+            e
+        );
+    }
+}
+
+// Send anything that didn't finish last time back through the rpc event
+// channel, same uuid so history lines up.
+//
+// Fan-outs restart as one scan for whichever of their destinations didn't
+// finish, a destination whose lead did finish restarts on its own.
+fn restart_unfinished(mut journal: ResMut<Journal>, sender: Res<SyncEventSender>) {
+    let mut ops = journal.unfinished();
+    let unfinished: std::collections::HashSet<u128> = ops.iter().map(|op| op.uuid).collect();
+    let mut led = std::collections::HashSet::new();
+    for op in &mut ops {
+        op.sync.fan_out.retain(|leg| unfinished.contains(&leg.uuid));
+        led.extend(op.sync.fan_out.iter().map(|leg| leg.uuid));
+    }
+
+    for op in ops.into_iter().filter(|op| !led.contains(&op.uuid)) {
+        let id = uuid::Uuid::from_u128(op.uuid);
+
+        let event = match op.sync.to_event(op.uuid) {
+            Ok(event) => event,
+            Err(e) => {
+                // Someone editing the journal or a snapshot command, mark it
+                // failed so we don't whine about it every startup.
+                warn!("{} can't be restarted, giving up on it: {}", id, e);
+                record(
+                    &mut journal,
+                    Entry::Finished {
+                        uuid: op.uuid,
+                        at: now(),
+                        errors: 1,
+                        files: 0,
+                        bytes: 0,
+                        throughput: 0,
+                    },
+                );
+                continue;
+            }
+        };
+
+        info!(
+            "restarting {} {} -> {} (was {})",
+            id, op.sync.lhs, op.sync.rhs, op.state
+        );
+
+        for uuid in std::iter::once(op.uuid).chain(op.sync.fan_out.iter().map(|leg| leg.uuid)) {
+            record(&mut journal, Entry::Restarted { uuid, at: now() });
+        }
+
+        if let Ok(s) = sender.0.lock() {
+            let _ = s.send(event);
+        }
+    }
+}
+
+fn record_requests(mut events: MessageReader<RpcEvent>, mut journal: ResMut<Journal>) {
+    for event in events.read() {
+        let Some((uuid, resume, sync)) = SyncRecord::from_event(event) else {
+            continue;
+        };
+
+        // Restarts were already journaled by restart_unfinished
+        if resume {
+            continue;
+        }
+
+        // Every fan-out destination is its own op, same request otherwise
+        let legs: Vec<_> = sync
+            .fan_out
+            .iter()
+            .map(|leg| {
+                (
+                    leg.uuid,
+                    SyncRecord {
+                        rhs: leg.rhs.clone(),
+                        fan_out: vec![],
+                        ..sync.clone()
+                    },
+                )
+            })
+            .collect();
+
+        for (uuid, sync) in std::iter::once((uuid, sync)).chain(legs) {
+            record(
+                &mut journal,
+                Entry::Requested {
+                    uuid,
+                    at: now(),
+                    sync,
+                },
+            );
+        }
+    }
+}
+
+fn record_started(query: Query<&Uuid, Added<IoOperation>>, mut journal: ResMut<Journal>) {
+    for uuid in &query {
+        record(
+            &mut journal,
+            Entry::Started {
+                uuid: uuid.0,
+                at: now(),
+            },
+        );
+    }
+}
+
+fn record_finished(
+    query: Query<(&Uuid, Option<&IoProgress>), Added<SyncComplete>>,
+    mut journal: ResMut<Journal>,
+) {
+    for (uuid, progress) in &query {
+        let progress = progress.cloned().unwrap_or_default();
+        record(
+            &mut journal,
+            Entry::Finished {
+                uuid: uuid.0,
+                at: now(),
+                errors: progress.error_count,
+                files: progress.files_written,
+                bytes: progress.bytes_written,
+                throughput: progress.throughput_bps as u64,
+            },
+        );
+    }
+}
+
+fn prune(journal: &mut Journal, retention: &Retention) {
+    match journal.prune(retention, now()) {
+        Ok(0) => (),
+        Ok(n) => info!("pruned {} ops from history", n),
+        Err(e) => warn!("couldn't prune journal {}: {}", journal.path().display(), e),
+    }
+}
+
+fn prune_history(mut journal: ResMut<Journal>, retention: Res<Retention>) {
+    prune(&mut journal, &retention);
+}
+
+// ListHistory rpc requests, answered straight out of the journal.
+fn answer_history(mut events: MessageReader<RpcEvent>, journal: Res<Journal>) {
+    for event in events.read() {
+        let RpcEvent::ListHistory {
+            since,
+            failed,
+            response_tx,
+        } = event
+        else {
+            continue;
+        };
+
+        if let Ok(mut guard) = response_tx.lock()
+            && let Some(tx) = guard.take()
+        {
+            let _ = tx.send(journal.history(*since, *failed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    fn sync(lhs: &str) -> SyncRecord {
+        SyncRecord {
+            lhs: lhs.to_string(),
+            rhs: "/dest".to_string(),
+            writers: Some(2),
+            schedule: "smallest-first".to_string(),
+            priority: vec!["src/**".to_string()],
+            aging_secs: Some(0),
+            weight: None,
+            scan_order: "dfs".to_string(),
+            scanners: None,
+            watch: Some("poll".to_string()),
+            poll_secs: Some(7),
+            two_way: false,
+            fan_out: vec![],
+            snapshot: None,
+            trash: None,
+        }
+    }
+
+    fn requested(uuid: u128, lhs: &str) -> Entry {
+        Entry::Requested {
+            uuid,
+            at: uuid as u64,
+            sync: sync(lhs),
+        }
+    }
+
+    fn finished(uuid: u128, at: u64, errors: usize) -> Entry {
+        Entry::Finished {
+            uuid,
+            at,
+            errors,
+            files: 10,
+            bytes: 1024,
+            throughput: 512,
+        }
+    }
+
+    #[test]
+    fn test_reopen_folds_state() {
+        let dir = TestDir::new("journal-fold");
+        let path = dir.join("journal.jsonl");
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.record(requested(1, "/a")).unwrap();
+        journal.record(requested(2, "/b")).unwrap();
+        journal.record(requested(3, "/c")).unwrap();
+        journal.record(Entry::Started { uuid: 1, at: 10 }).unwrap();
+        journal.record(Entry::Started { uuid: 2, at: 11 }).unwrap();
+        journal.record(finished(2, 12, 0)).unwrap();
+        journal.record(finished(3, 13, 4)).unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.len(), 3);
+        assert_eq!(journal.get(1).unwrap().state, State::Running);
+        assert_eq!(journal.get(1).unwrap().started, Some(10));
+        assert_eq!(journal.get(2).unwrap().state, State::Complete);
+        assert_eq!(journal.get(3).unwrap().state, State::Failed);
+        assert_eq!(journal.get(3).unwrap().errors, 4);
+        assert_eq!(journal.get(2).unwrap().bytes, 1024);
+        assert_eq!(journal.get(2).unwrap().duration(), Some(1));
+
+        let unfinished = journal.unfinished();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].uuid, 1);
+        assert_eq!(unfinished[0].sync, sync("/a"));
+
+        // Compacted to one line per op
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 3);
+    }
+
+    #[test]
+    fn test_prune_and_history() {
+        let dir = TestDir::new("journal-prune");
+        let path = dir.join("journal.jsonl");
+
+        let mut journal = Journal::open(&path).unwrap();
+        for uuid in 1..=6 {
+            journal.record(requested(uuid, "/a")).unwrap();
+        }
+        // 1 and 2 are ancient, 3..5 recent with 4 failing, 6 never finished
+        journal.record(finished(1, 100, 0)).unwrap();
+        journal.record(finished(2, 200, 1)).unwrap();
+        journal.record(finished(3, 10_000, 0)).unwrap();
+        journal.record(finished(4, 10_001, 2)).unwrap();
+        journal.record(finished(5, 10_002, 0)).unwrap();
+
+        assert_eq!(journal.history(None, true).len(), 2);
+        assert_eq!(journal.history(Some(4), false).len(), 3);
+
+        let retention = Retention {
+            max_age: Some(std::time::Duration::from_secs(1000)),
+            max_ops: Some(2),
+        };
+        assert_eq!(journal.prune(&retention, 10_500).unwrap(), 3);
+
+        let left: Vec<u128> = journal
+            .history(None, false)
+            .iter()
+            .map(|op| op.uuid)
+            .collect();
+        assert_eq!(left, vec![4, 5, 6]);
+
+        // And it stuck on disk
+        drop(journal);
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.len(), 3);
+    }
+
+    #[test]
+    fn test_torn_line_skipped() {
+        let dir = TestDir::new("journal-torn");
+        let path = dir.join("journal.jsonl");
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.record(requested(1, "/a")).unwrap();
+        journal.record(Entry::Restarted { uuid: 1, at: 5 }).unwrap();
+        drop(journal);
+
+        // Crash mid write
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        f.write_all(b"{\"event\":\"finished\",\"uu").unwrap();
+        drop(f);
+
+        let journal = Journal::open(&path).unwrap();
+        let op = journal.get(1).unwrap();
+        assert_eq!(op.state, State::Requested);
+        assert_eq!(op.restarts, 1);
+    }
+
+    #[test]
+    fn test_record_event_round_trip() {
+        let record = sync("/a");
+        let event = record.to_event(42).unwrap();
+
+        let (uuid, resume, back) = SyncRecord::from_event(&event).unwrap();
+        assert_eq!(uuid, 42);
+        assert!(resume);
+        assert_eq!(back, record);
+    }
+
+    #[test]
+    fn test_record_event_round_trip_fan_out() {
+        let mut record = sync("/a");
+        record.fan_out = vec![FanOutRecord {
+            uuid: 43,
+            rhs: "/other".to_string(),
+        }];
+        let event = record.to_event(42).unwrap();
+
+        let (_, _, back) = SyncRecord::from_event(&event).unwrap();
+        assert_eq!(back, record);
+
+        // Old journals without the field still load
+        let line = serde_json::to_string(&sync("/a")).unwrap();
+        let line = line.replace(",\"fan_out\":[]", "");
+        assert!(!line.contains("fan_out"));
+        let old: SyncRecord = serde_json::from_str(&line).unwrap();
+        assert!(old.fan_out.is_empty());
+    }
+
+    #[test]
+    fn test_record_event_round_trip_trash() {
+        let mut record = sync("/a");
+        record.trash = Some(TrashRecord {
+            dir: Some(PathBuf::from("/backups")),
+            max_age_secs: 0,
+            max_versions: Some(3),
+        });
+        let event = record.to_event(42).unwrap();
+
+        // 0 is forever on the way back in
+        let RpcEvent::SimpleCopySync { trash, .. } = &event else {
+            panic!("not a sync");
+        };
+        assert_eq!(trash.as_ref().unwrap().max_age, None);
+
+        let (_, _, back) = SyncRecord::from_event(&event).unwrap();
+        assert_eq!(back, record);
+    }
+
+    #[test]
+    fn test_snapshot_command_not_restarted() {
+        let mut record = sync("/a");
+        record.watch = None;
+        record.poll_secs = None;
+        record.snapshot = Some("btrfs".to_string());
+        let event = record.to_event(42).unwrap();
+        let (_, _, back) = SyncRecord::from_event(&event).unwrap();
+        assert_eq!(back, record);
+
+        // Only ever "cmd" going in
+        let mut event = event;
+        let RpcEvent::SimpleCopySync { snapshot, .. } = &mut event else {
+            panic!("not a sync");
+        };
+        *snapshot = Some(crate::io::snapshot::SnapshotProvider::Command(
+            "rm -rf /".to_string(),
+        ));
+        let (_, _, back) = SyncRecord::from_event(&event).unwrap();
+        assert_eq!(back.snapshot.as_deref(), Some("cmd"));
+
+        // Never back out, old journals with the whole command either
+        assert!(back.to_event(42).is_err());
+        record.snapshot = Some("cmd:rm -rf /".to_string());
+        assert!(record.to_event(42).is_err());
+    }
+}
@@ -2,10 +2,12 @@
 // Bevy queries get complex but clippy can stop yappin about it for all systems
 // code its normal and I'm sick of annotating each system function.
 pub mod build;
+pub mod conflicts;
 pub mod grpc;
 pub mod heartbeat;
 pub mod inode;
 pub mod io_bridge;
+pub mod journal;
 pub mod loglevel;
 pub mod monitor;
 pub mod netcode;
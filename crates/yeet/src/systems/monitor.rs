@@ -1,9 +1,10 @@
 use bevy::prelude::*;
 
 use super::netcode::protocol::{
-    ReplicatedCompletionTime, ReplicatedDest, ReplicatedIoProgress, ReplicatedSimpleCopy,
-    ReplicatedSource, ReplicatedSyncComplete, ReplicatedSyncStartTime, ReplicatedSyncStopTime,
-    ReplicatedUuid,
+    ReplicatedCompletionTime, ReplicatedConnectionError, ReplicatedDest, ReplicatedGlobalLimits,
+    ReplicatedIoProgress, ReplicatedRateLimit, ReplicatedRoute, ReplicatedRoutes,
+    ReplicatedSimpleCopy, ReplicatedSource, ReplicatedSyncComplete, ReplicatedSyncStartTime,
+    ReplicatedSyncStopTime, ReplicatedUuid, ReplicatedWeight, ReplicatedWriterConcurrency,
 };
 use super::stats::{Cpu, Mem, Uptime};
 
@@ -74,6 +75,59 @@ impl StyleManager {
     }
 }
 
+// Human readable limits, None if there aren't any
+fn format_limit(limit: &ReplicatedRateLimit) -> Option<String> {
+    let mut parts = Vec::new();
+    if limit.bytes_per_sec > 0 {
+        parts.push(format!(
+            "{}/s",
+            humansize::format_size(limit.bytes_per_sec, humansize::BINARY)
+        ));
+    }
+    if limit.ops_per_sec > 0 {
+        parts.push(format!("{} ops/s", limit.ops_per_sec));
+    }
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(format!("limit: {}", parts.join(" ")))
+    }
+}
+
+// One line per remote, whats picked first
+fn format_routes(routes: &[ReplicatedRoute]) -> Vec<String> {
+    let mut lines: Vec<(String, Vec<String>)> = Vec::new();
+    for route in routes {
+        let mut line = route.spec.clone();
+        if route.chosen {
+            line.push_str(" (using)");
+        }
+        match (&route.error, route.rtt_us, route.throughput_bps) {
+            (Some(error), _, _) => line.push_str(&format!(" down: {}", error)),
+            (None, Some(rtt_us), Some(bps)) => line.push_str(&format!(
+                " rtt: {:.1}ms {}/s",
+                rtt_us as f64 / 1000.0,
+                humansize::format_size(bps as u64, humansize::BINARY)
+            )),
+            _ => line.push_str(" not probed yet"),
+        }
+
+        match lines.iter_mut().find(|(remote, _)| *remote == route.remote) {
+            Some((_, remote_lines)) => remote_lines.push(line),
+            None => lines.push((route.remote.clone(), vec![line])),
+        }
+    }
+
+    lines
+        .into_iter()
+        .map(|(remote, mut remote_lines)| {
+            remote_lines.sort_by_key(|line| !line.contains(" (using)"));
+            format!("routes to {}: {}", remote, remote_lines.join(", "))
+        })
+        .collect()
+}
+
 // Update display with ANSI colors
 #[allow(clippy::type_complexity)]
 fn update_display(
@@ -87,10 +141,16 @@ fn update_display(
             Option<&ReplicatedCompletionTime>,
             Option<&ReplicatedSyncStartTime>,
             Option<&ReplicatedSyncStopTime>,
+            Option<&ReplicatedRateLimit>,
+            Option<&ReplicatedWriterConcurrency>,
+            Option<&ReplicatedWeight>,
+            Option<&ReplicatedConnectionError>,
         ),
         With<ReplicatedSimpleCopy>,
     >,
     stats_query: Query<(&Uptime, &Mem, &Cpu)>,
+    global_limits: Query<&ReplicatedRateLimit, With<ReplicatedGlobalLimits>>,
+    routes: Query<&ReplicatedRoutes>,
 ) {
     // Detect if stdout is in raw mode by checking if it's a TTY
     // When using crossterm for input, we're in raw mode and need \r\n
@@ -139,16 +199,56 @@ fn update_display(
         ));
     }
 
+    if let Ok(limit) = global_limits.single()
+        && let Some(limit_str) = format_limit(limit)
+    {
+        output.push_str(&manager.apply(
+            &format!("global {limit_str}{nl}"),
+            nu_ansi_term::Style::default().fg(nu_ansi_term::Color::Cyan),
+        ));
+    }
+
+    if let Ok(routes) = routes.single() {
+        for line in format_routes(&routes.0) {
+            output.push_str(&manager.apply(
+                &format!("{line}{nl}"),
+                nu_ansi_term::Style::default().fg(nu_ansi_term::Color::Cyan),
+            ));
+        }
+    }
+
     let mut in_progress = Vec::new();
     let mut completed = Vec::new();
 
-    for (source, dest, uuid, io_progress, complete, completion_time, start_time, stop_time) in
-        query.iter()
+    for (
+        source,
+        dest,
+        uuid,
+        io_progress,
+        complete,
+        completion_time,
+        start_time,
+        stop_time,
+        limit,
+        concurrency,
+        weight,
+        connection_error,
+    ) in query.iter()
     {
         if complete.is_some() {
             completed.push((source, dest, uuid, completion_time, start_time, stop_time));
         } else {
-            in_progress.push((source, dest, uuid, io_progress, start_time));
+            in_progress.push((
+                source,
+                dest,
+                uuid,
+                io_progress,
+                start_time,
+                limit,
+                concurrency,
+                weight,
+                connection_error,
+            ));
         }
     }
 
@@ -179,7 +279,18 @@ fn update_display(
         }
 
         // in progress stuff
-        for (source, dest, uuid, io_progress, start_time) in in_progress {
+        for (
+            source,
+            dest,
+            uuid,
+            io_progress,
+            start_time,
+            limit,
+            concurrency,
+            weight,
+            connection_error,
+        ) in in_progress
+        {
             let uuid_str = uuid::Uuid::from_u128(uuid.0);
             let running_for = if let Some(st) = start_time {
                 let current_secs = std::time::SystemTime::now()
@@ -200,8 +311,14 @@ fn update_display(
             //     nu_ansi_term::Style::default().fg(nu_ansi_term::Color::Green),
             // ));
 
-            // Add progress information if available
-            let progress_str = if let Some(progress) = io_progress {
+            // Add progress information if available, unless it never got
+            // that far
+            let progress_str = if let Some(error) = connection_error {
+                manager.apply(
+                    &format!(" connection failed: {}", error.0),
+                    nu_ansi_term::Style::default().fg(nu_ansi_term::Color::Red),
+                )
+            } else if let Some(progress) = io_progress {
                 if progress.files_found > 0 {
                     let bytes_written_str =
                         humansize::format_size(progress.bytes_written, humansize::BINARY);
@@ -215,6 +332,25 @@ fn update_display(
                     if progress.skipped_count > 0 {
                         extras.push(format!("{} skipped", progress.skipped_count));
                     }
+                    if let Some(limit_str) = limit.and_then(format_limit) {
+                        extras.push(limit_str);
+                    }
+                    // Only worth mentioning if someone changed it
+                    if let Some(w) = weight
+                        && w.0 != crate::io::fair::DEFAULT_WEIGHT
+                    {
+                        extras.push(format!("weight: {}", w.0));
+                    }
+                    if let Some(c) = concurrency {
+                        extras.push(format!(
+                            "writers: {}/{}{} batch: {} {:.0}us/op",
+                            c.active_writers,
+                            c.max_writers,
+                            if c.network { " (network fs)" } else { "" },
+                            c.writer_batch,
+                            c.avg_latency_us
+                        ));
+                    }
 
                     // Format throughput
                     let throughput_str = if progress.throughput_bps > 0.0 {
@@ -65,6 +65,64 @@ pub struct ReplicatedIoProgress {
     pub throughput_bps: f64,
 }
 
+// 0 is unlimited, same as the RateLimit component this mirrors
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct ReplicatedRateLimit {
+    pub bytes_per_sec: u64,
+    pub ops_per_sec: u64,
+}
+
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplicatedGlobalLimits;
+
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct ReplicatedWriterConcurrency {
+    pub active_writers: usize,
+    pub max_writers: usize,
+    pub writer_batch: usize,
+    pub queue_batch: usize,
+    pub avg_latency_us: f64,
+    pub network: bool,
+}
+
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplicatedWeight(pub u32);
+
+// Why a remote sync couldn't connect, jump host and all
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplicatedConnectionError(pub String);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplicatedRoute {
+    pub remote: String,
+    pub spec: String,
+    pub chosen: bool,
+    // None until probed or if the probe failed, then error says why
+    pub rtt_us: Option<u64>,
+    pub throughput_bps: Option<f64>,
+    pub error: Option<String>,
+}
+
+// Every route to every remote with more than one, on its own entity like
+// conflicts.
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct ReplicatedRoutes(pub Vec<ReplicatedRoute>);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplicatedConflict {
+    pub uuid: u128,
+    pub lhs: PathBuf,
+    pub rhs: PathBuf,
+    pub path: PathBuf,
+    pub kind: String,
+    pub detected_secs: u64,
+}
+
+// Every pending two-way conflict, lives on its own entity like the global
+// limits as conflicts outlive the syncs that found them.
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct ReplicatedConflicts(pub Vec<ReplicatedConflict>);
+
 #[derive(Clone)]
 pub struct ProtocolPlugin;
 
@@ -85,6 +143,13 @@ impl Plugin for ProtocolPlugin {
         app.register_component::<ReplicatedSyncStopTime>();
         app.register_component::<ReplicatedCompletionTime>();
         app.register_component::<ReplicatedIoProgress>();
+        app.register_component::<ReplicatedRateLimit>();
+        app.register_component::<ReplicatedGlobalLimits>();
+        app.register_component::<ReplicatedWriterConcurrency>();
+        app.register_component::<ReplicatedWeight>();
+        app.register_component::<ReplicatedConnectionError>();
+        app.register_component::<ReplicatedConflicts>();
+        app.register_component::<ReplicatedRoutes>();
 
         app.register_component::<crate::systems::stats::Uptime>();
         app.register_component::<crate::systems::stats::Mem>();
@@ -37,6 +37,14 @@ impl Plugin for LightYearServerPlugin {
                 sync_entities_to_replicated,
                 update_completion_time,
                 update_io_progress,
+                update_rate_limits,
+                update_writer_concurrency,
+                update_weights,
+                update_connection_errors,
+                update_routes.run_if(bevy::time::common_conditions::on_timer(
+                    std::time::Duration::from_secs(1),
+                )),
+                update_conflicts,
                 update_stats,
                 despawn_simplecopies.run_if(bevy::time::common_conditions::on_timer(
                     std::time::Duration::from_secs(60),
@@ -242,6 +250,190 @@ fn update_io_progress(
     }
 }
 
+// Sync RateLimit to ReplicatedRateLimit. Sync entities pick up Replicate in
+// sync_entities_to_replicated, the global limits entity is on its own.
+fn update_rate_limits(
+    mut commands: Commands,
+    query: Query<
+        (
+            Entity,
+            &crate::RateLimit,
+            Has<crate::GlobalLimits>,
+            Has<Replicate>,
+        ),
+        Changed<crate::RateLimit>,
+    >,
+) {
+    for (entity, limit, global, replicated) in query.iter() {
+        let mut entity_commands = commands.entity(entity);
+        entity_commands.insert(ReplicatedRateLimit {
+            bytes_per_sec: limit.bytes_per_sec,
+            ops_per_sec: limit.ops_per_sec,
+        });
+
+        if global && !replicated {
+            entity_commands.insert((
+                ReplicatedGlobalLimits,
+                Replicate::to_clients(NetworkTarget::All),
+            ));
+        }
+    }
+}
+
+fn update_weights(
+    mut commands: Commands,
+    query: Query<(Entity, &crate::Weight), (Changed<crate::Weight>, With<ReplicatedSource>)>,
+) {
+    for (entity, weight) in query.iter() {
+        commands.entity(entity).insert(ReplicatedWeight(weight.0));
+    }
+}
+
+// Whatever ssh hop didn't work out, the error names it. A sync entity can get
+// its error before it gets replicated so look for both. Errors go away when
+// the Router gives a sync another route to try.
+fn update_connection_errors(
+    mut commands: Commands,
+    mut removed: RemovedComponents<crate::systems::ssh::ConnectionError>,
+    replicated: Query<(), With<ReplicatedConnectionError>>,
+    query: Query<
+        (Entity, &crate::systems::ssh::ConnectionError),
+        (
+            Or<(
+                Changed<crate::systems::ssh::ConnectionError>,
+                Added<ReplicatedSource>,
+            )>,
+            With<ReplicatedSource>,
+        ),
+    >,
+) {
+    for (entity, error) in query.iter() {
+        commands
+            .entity(entity)
+            .insert(ReplicatedConnectionError(error.0.clone()));
+    }
+    for entity in removed.read() {
+        if replicated.contains(entity) {
+            commands
+                .entity(entity)
+                .remove::<ReplicatedConnectionError>();
+        }
+    }
+}
+
+// Same idea as conflicts, every routed remote on one entity
+fn update_routes(
+    mut commands: Commands,
+    routes: Option<Res<crate::systems::ssh::Routes>>,
+    existing: Query<(Entity, &ReplicatedRoutes)>,
+) {
+    let Some(routes) = routes else {
+        return;
+    };
+
+    let replicated = ReplicatedRoutes(
+        routes
+            .snapshot()
+            .into_iter()
+            .flat_map(|(remote, routes)| {
+                let chosen = routes.chosen.clone();
+                routes.candidates.into_iter().map(move |candidate| {
+                    let (rtt_us, throughput_bps, error) = match candidate.probe {
+                        Some(Ok(probe)) => (
+                            Some(probe.rtt.as_micros() as u64),
+                            Some(probe.throughput_bps),
+                            None,
+                        ),
+                        Some(Err(e)) => (None, None, Some(e)),
+                        None => (None, None, None),
+                    };
+                    ReplicatedRoute {
+                        remote: remote.clone(),
+                        chosen: chosen.as_deref() == Some(candidate.spec.as_str()),
+                        spec: candidate.spec,
+                        rtt_us,
+                        throughput_bps,
+                        error,
+                    }
+                })
+            })
+            .collect(),
+    );
+
+    match existing.single() {
+        Ok((_, current)) if *current == replicated => {}
+        Ok((entity, _)) => {
+            commands.entity(entity).insert(replicated);
+        }
+        Err(_) if !replicated.0.is_empty() => {
+            commands.spawn((replicated, Replicate::to_clients(NetworkTarget::All)));
+        }
+        Err(_) => {}
+    }
+}
+
+fn update_writer_concurrency(
+    mut commands: Commands,
+    query: Query<
+        (Entity, &crate::WriterConcurrency),
+        (
+            Changed<crate::WriterConcurrency>,
+            With<ReplicatedSource>,
+            Without<crate::SyncComplete>,
+        ),
+    >,
+) {
+    for (entity, concurrency) in query.iter() {
+        commands.entity(entity).insert(ReplicatedWriterConcurrency {
+            active_writers: concurrency.active_writers,
+            max_writers: concurrency.max_writers,
+            writer_batch: concurrency.writer_batch,
+            queue_batch: concurrency.queue_batch,
+            avg_latency_us: concurrency.avg_latency_us,
+            network: concurrency.network,
+        });
+    }
+}
+
+// Mirror the conflict registry onto one replicated entity, spawned on the
+// first change so a daemon without conflicts has nothing extra to replicate.
+fn update_conflicts(
+    mut commands: Commands,
+    registry: Option<Res<crate::systems::conflicts::ConflictRegistry>>,
+    existing: Query<Entity, With<ReplicatedConflicts>>,
+) {
+    let Some(registry) = registry else {
+        return;
+    };
+    if !registry.is_changed() {
+        return;
+    }
+
+    let conflicts = ReplicatedConflicts(
+        registry
+            .list()
+            .into_iter()
+            .map(|c| ReplicatedConflict {
+                uuid: c.uuid,
+                lhs: c.lhs,
+                rhs: c.rhs,
+                path: c.path,
+                kind: c.kind.tag().to_string(),
+                detected_secs: c.detected,
+            })
+            .collect(),
+    );
+
+    match existing.single() {
+        Ok(entity) => {
+            commands.entity(entity).insert(conflicts);
+        }
+        Err(_) => {
+            commands.spawn((conflicts, Replicate::to_clients(NetworkTarget::All)));
+        }
+    }
+}
+
 // Server just updates the ReplicatedBlah from Blah components, lightyear
 // handles the replication to clients.
 fn update_stats(
@@ -0,0 +1,293 @@
+use bevy::prelude::*;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::core::{Session, exec, setup_agent_forward, setup_port_forward};
+
+// Hosts with no yeet daemon, aka appliances like the synology where I don't
+// control the os or what's installed.
+//
+// Same idea as mutagen, if nothing answers on the daemon port we copy our own
+// binary over, cache it under ~/.cache/yeet/agents/<version> and run it as
+// `yeet agent --stdio` over an ssh exec channel instead. The agent serves the
+// Transfer service over its stdin/stdout, one http2 connection, and exits once
+// the channel closes. Every forwarded connection gets its own agent, a sync
+// only ever makes the one connection so its agent lives as long as the sync.
+//
+// No fat binaries, if the other end isn't our os/arch it needs yeet installed.
+//
+// The version is ours plus a hash of the binary, so a dev build never picks
+// up some other dev build's agent with the same version number.
+
+/// Where agents get cached on the remote, relative to its home
+pub const AGENT_DIR: &str = ".cache/yeet/agents";
+
+/// Other versions' agents that haven't been touched in this many days get
+/// cleaned up whenever we upload one.
+const AGENT_MAX_AGE_DAYS: u32 = 30;
+
+/// Local port that gets to remote_port on the other end, or to an agent if
+/// there's no daemon listening there.
+pub async fn forward(
+    session: Session,
+    remote_port: u16,
+) -> Result<u16, Box<dyn std::error::Error + Send + Sync>> {
+    // Nothing listening and sshd refuses the channel
+    match session
+        .handle
+        .channel_open_direct_tcpip(
+            crate::systems::grpc::DAEMON_HOST,
+            remote_port as u32,
+            "localhost",
+            0,
+        )
+        .await
+    {
+        Ok(channel) => {
+            let _ = channel.close().await;
+            return setup_port_forward(session, remote_port).await;
+        }
+        Err(e) => debug!(
+            "no yeet daemon on {}:{} ({}), using an agent",
+            session.host, remote_port, e
+        ),
+    }
+
+    let command = bootstrap(&session).await?;
+    setup_agent_forward(session, command).await
+}
+
+/// Make sure the remote has our agent, returns the command that runs it.
+pub async fn bootstrap(
+    session: &Session,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let (status, uname) = exec(session, "uname -sm", None).await?;
+    let uname = String::from_utf8_lossy(&uname);
+    let platform = parse_uname(&uname).filter(|_| status == 0).ok_or_else(|| {
+        format!(
+            "couldn't figure out what {} is from uname: {}",
+            session.host,
+            uname.trim()
+        )
+    })?;
+    if platform != (std::env::consts::OS, std::env::consts::ARCH) {
+        return Err(format!(
+            "{} is {}/{} and we're {}/{}, install yeet there",
+            session.host,
+            platform.0,
+            platform.1,
+            std::env::consts::OS,
+            std::env::consts::ARCH
+        )
+        .into());
+    }
+
+    let exe = std::env::current_exe()?;
+    let version = {
+        let exe = exe.clone();
+        tokio::task::spawn_blocking(move || agent_version(&exe)).await??
+    };
+    let path = format!("{}/{}/yeet", AGENT_DIR, version);
+
+    let (cached, _) = exec(session, &format!("test -x {}", path), None).await?;
+    if cached != 0 {
+        info!("uploading yeet agent {} to {}", version, session.host);
+        let (status, _) = exec(session, &upload_command(&path), Some(exe)).await?;
+        if status != 0 {
+            return Err(format!(
+                "failed to upload agent to {}, exit status {}",
+                session.host, status
+            )
+            .into());
+        }
+
+        // Best effort, an old agent hanging around hurts nothing
+        let _ = exec(session, &cleanup_command(&version), None).await;
+    }
+
+    // Runs at all, aka it got there whole and the libc is happy with it
+    let (status, out) = exec(session, &format!("{} -V", path), None).await?;
+    if status != 0 || !String::from_utf8_lossy(&out).contains(env!("CARGO_PKG_VERSION")) {
+        // Don't leave a broken one cached for next time
+        let _ = exec(session, &format!("rm -f {}", path), None).await;
+        return Err(format!(
+            "yeet agent won't run on {}, install yeet there",
+            session.host
+        )
+        .into());
+    }
+
+    Ok(format!("{} agent --stdio", path))
+}
+
+// Our version and enough of a hash of the binary to tell builds apart
+fn agent_version(exe: &std::path::Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(std::fs::File::open(exe)?)?;
+    let hash = hasher.finalize().to_hex();
+    Ok(format!("{}-{}", env!("CARGO_PKG_VERSION"), &hash[..12]))
+}
+
+// Write to a temp file and rename so a dropped connection never leaves a half
+// an agent where a whole one should be.
+fn upload_command(path: &str) -> String {
+    format!(
+        "mkdir -p $(dirname {path}) && cat > {path}.$$ && chmod 755 {path}.$$ && mv -f {path}.$$ {path}"
+    )
+}
+
+fn cleanup_command(version: &str) -> String {
+    format!(
+        "find {} -mindepth 1 -maxdepth 1 -type d ! -name {} -mtime +{} -exec rm -rf {{}} +",
+        AGENT_DIR, version, AGENT_MAX_AGE_DAYS
+    )
+}
+
+// uname -sm into what std::env::consts calls the os and arch
+fn parse_uname(uname: &str) -> Option<(&'static str, &'static str)> {
+    let mut fields = uname.split_whitespace();
+    let os = match fields.next()? {
+        "Linux" => "linux",
+        "Darwin" => "macos",
+        "FreeBSD" => "freebsd",
+        "NetBSD" => "netbsd",
+        "OpenBSD" => "openbsd",
+        _ => return None,
+    };
+    let arch = match fields.next()? {
+        "x86_64" | "amd64" => "x86_64",
+        "aarch64" | "arm64" => "aarch64",
+        "i386" | "i486" | "i586" | "i686" => "x86",
+        "riscv64" => "riscv64",
+        arch if arch.starts_with("armv") => "arm",
+        _ => return None,
+    };
+    Some((os, arch))
+}
+
+/// The agent's side, serve remote syncs over stdin/stdout until whoever is on
+/// the other end of them goes away.
+pub async fn serve_stdio() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use crate::rpc::transfer::{MyTransfer, transfer_server::TransferServer};
+
+    let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+    let stdio = Stdio {
+        stdin: tokio::io::stdin(),
+        stdout: tokio::io::stdout(),
+        closed: Some(closed_tx),
+    };
+
+    // Everything a daemon would have, just ours alone. Whoever started us
+    // logged in over ssh to do it, they can touch anything we can.
+    let transfer = MyTransfer::new(
+        crate::io::remote::Exports::All,
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+    );
+
+    // The one connection and then nothing, ending the stream would have the
+    // server return before the connection is done.
+    let incoming = tokio_stream::StreamExt::chain(
+        tokio_stream::once(Ok::<_, std::io::Error>(stdio)),
+        tokio_stream::pending(),
+    );
+    let served = tonic::transport::Server::builder()
+        .add_service(TransferServer::new(transfer.clone()))
+        .serve_with_incoming_shutdown(incoming, async {
+            let _ = closed_rx.await;
+        })
+        .await;
+
+    // Nobody is coming back for whatever is still going, clean up after it
+    transfer.close_all().await;
+    served?;
+    Ok(())
+}
+
+// stdin and stdout as one connection, says when stdin is done
+struct Stdio {
+    stdin: tokio::io::Stdin,
+    stdout: tokio::io::Stdout,
+    closed: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl tonic::transport::server::Connected for Stdio {
+    type ConnectInfo = ();
+
+    fn connect_info(&self) -> Self::ConnectInfo {}
+}
+
+impl AsyncRead for Stdio {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.stdin).poll_read(cx, buf);
+        let eof = matches!(polled, Poll::Ready(Ok(()))) && buf.filled().len() == before;
+        if (eof || matches!(polled, Poll::Ready(Err(_))))
+            && let Some(closed) = self.closed.take()
+        {
+            let _ = closed.send(());
+        }
+        polled
+    }
+}
+
+impl AsyncWrite for Stdio {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stdout).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uname() {
+        assert_eq!(parse_uname("Linux x86_64\n"), Some(("linux", "x86_64")));
+        assert_eq!(parse_uname("Linux aarch64"), Some(("linux", "aarch64")));
+        assert_eq!(parse_uname("Linux armv7l"), Some(("linux", "arm")));
+        assert_eq!(parse_uname("Darwin arm64"), Some(("macos", "aarch64")));
+        assert_eq!(parse_uname("FreeBSD amd64"), Some(("freebsd", "x86_64")));
+        assert_eq!(parse_uname("Plan9 68000"), None);
+        assert_eq!(parse_uname("Linux"), None);
+        assert_eq!(parse_uname(""), None);
+    }
+
+    #[test]
+    fn test_agent_version() {
+        let exe = std::env::current_exe().unwrap();
+        let version = agent_version(&exe).unwrap();
+        assert!(version.starts_with(concat!(env!("CARGO_PKG_VERSION"), "-")));
+        assert_eq!(version, agent_version(&exe).unwrap());
+    }
+
+    #[test]
+    fn test_commands() {
+        let path = format!("{}/0.0.2-abc/yeet", AGENT_DIR);
+        assert_eq!(
+            upload_command(&path),
+            "mkdir -p $(dirname .cache/yeet/agents/0.0.2-abc/yeet) && cat > .cache/yeet/agents/0.0.2-abc/yeet.$$ && chmod 755 .cache/yeet/agents/0.0.2-abc/yeet.$$ && mv -f .cache/yeet/agents/0.0.2-abc/yeet.$$ .cache/yeet/agents/0.0.2-abc/yeet"
+        );
+        // Never the one we're using
+        assert!(cleanup_command("0.0.2-abc").contains("! -name 0.0.2-abc"));
+    }
+}
@@ -0,0 +1,547 @@
+use bevy::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::known_hosts::{glob, matches_patterns};
+use super::state::SshTarget;
+
+// ~/.ssh/config, the parts of it that matter for yeet. Goal is yeet cp
+// nas:/vol1 . goes wherever ssh nas would.
+//
+// Host blocks, Include and Match all, first value for an option wins like ssh
+// and IdentityFile adds up. Other Match criteria would need things like exec
+// that I'm not going anywhere near, so those blocks never match. Anything we
+// don't use gets ignored.
+
+/// Include inside include inside... ssh stops at 16 too
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Most hops a route can take, anything past this is a ProxyJump loop
+const MAX_HOPS: usize = 16;
+
+/// Where to connect to for a host spec, with ~/.ssh/config applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostConfig {
+    /// What was asked for, aka the alias
+    pub alias: String,
+    /// What actually gets connected to, HostName if there is one
+    pub hostname: String,
+    pub port: u16,
+    pub user: String,
+    /// Keys just for this host, instead of the daemon's
+    pub identity_files: Vec<PathBuf>,
+    /// Hosts to hop through first, in order
+    pub proxy_jump: Vec<String>,
+    pub server_alive_interval: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+enum Criteria {
+    Host(Vec<String>),
+    // Match all, or top of the file before any Host
+    All,
+    // Some Match we don't do
+    Never,
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    criteria: Criteria,
+    options: Vec<(String, String)>,
+}
+
+/// Every block from the config files in order
+#[derive(Debug)]
+pub struct SshConfig {
+    blocks: Vec<Block>,
+    // For ~ and %d
+    home: PathBuf,
+}
+
+fn home() -> PathBuf {
+    PathBuf::from(std::env::var("HOME").unwrap_or_default())
+}
+
+fn local_user() -> String {
+    std::env::var("USER").unwrap_or_else(|_| "root".to_string())
+}
+
+// key value, key=value or key = value, value maybe quoted
+fn split_line(line: &str) -> Option<(String, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let end = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let (key, rest) = line.split_at(end);
+    let value = rest.trim_start();
+    let value = value.strip_prefix('=').unwrap_or(value).trim();
+    Some((key.to_lowercase(), value))
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+// ~ and the % tokens ssh has that make sense here
+fn expand(value: &str, home: &Path, host: &str, user: &str) -> String {
+    let value = match value.strip_prefix("~/") {
+        Some(rest) => format!("{}/{}", home.display(), rest),
+        None if value == "~" => home.display().to_string(),
+        None => value.to_string(),
+    };
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => out.push('%'),
+            Some('d') => out.push_str(&home.display().to_string()),
+            Some('h') => out.push_str(host),
+            Some('r') => out.push_str(user),
+            Some('u') => out.push_str(&local_user()),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+impl SshConfig {
+    /// ~/.ssh/config then /etc/ssh/ssh_config, whichever exist
+    pub fn load() -> Self {
+        let home = home();
+        let mut config = SshConfig::new(home.clone());
+        config.read(&home.join(".ssh/config"), &home.join(".ssh"), 0);
+        config.read(Path::new("/etc/ssh/ssh_config"), Path::new("/etc/ssh"), 0);
+        config
+    }
+
+    pub fn new(home: PathBuf) -> Self {
+        SshConfig {
+            blocks: Vec::new(),
+            home,
+        }
+    }
+
+    fn read(&mut self, path: &Path, base: &Path, depth: usize) {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => self.parse(&contents, base, depth),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("couldn't read {}: {}", path.display(), e),
+        }
+    }
+
+    /// Add contents, relative Includes are relative to base like ssh does
+    /// with ~/.ssh for the user's config.
+    pub fn parse(&mut self, contents: &str, base: &Path, depth: usize) {
+        // Options before any Host apply to everything
+        self.blocks.push(Block {
+            criteria: Criteria::All,
+            options: Vec::new(),
+        });
+
+        for line in contents.lines() {
+            let Some((key, value)) = split_line(line) else {
+                continue;
+            };
+            match key.as_str() {
+                "host" => self.blocks.push(Block {
+                    criteria: Criteria::Host(
+                        value
+                            .split_whitespace()
+                            .map(|p| unquote(p).to_lowercase())
+                            .collect(),
+                    ),
+                    options: Vec::new(),
+                }),
+                "match" => {
+                    let criteria = if value.eq_ignore_ascii_case("all") {
+                        Criteria::All
+                    } else {
+                        debug!("Match {} isn't supported, ignoring that block", value);
+                        Criteria::Never
+                    };
+                    self.blocks.push(Block {
+                        criteria,
+                        options: Vec::new(),
+                    });
+                }
+                "include" => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        warn!("too many nested Includes, not including {}", value);
+                        continue;
+                    }
+                    // Included files are only in effect where the Include is,
+                    // so whatever block we're in carries on after them.
+                    let current = self.blocks.last().cloned().map(|b| b.criteria);
+                    for pattern in value.split_whitespace() {
+                        for path in include_paths(unquote(pattern), base, &self.home) {
+                            self.read_included(&path, current.clone(), base, depth + 1);
+                        }
+                    }
+                    if let Some(criteria) = current {
+                        self.blocks.push(Block {
+                            criteria,
+                            options: Vec::new(),
+                        });
+                    }
+                }
+                _ => {
+                    if let Some(block) = self.blocks.last_mut() {
+                        block.options.push((key, value.to_string()));
+                    }
+                }
+            }
+        }
+    }
+
+    fn read_included(
+        &mut self,
+        path: &Path,
+        criteria: Option<Criteria>,
+        base: &Path,
+        depth: usize,
+    ) {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("couldn't read {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let start = self.blocks.len();
+        self.parse(&contents, base, depth);
+        // The included file's leading options belong to the block it was
+        // included in, not everything.
+        if let Some(criteria) = criteria {
+            self.blocks[start].criteria = criteria;
+        }
+    }
+
+    /// Everything ssh would use for spec, aka [user@]host[:port]
+    pub fn resolve(&self, spec: &str) -> Result<HostConfig, String> {
+        let target: SshTarget = spec.parse()?;
+        let alias = target.host.to_lowercase();
+        let home = &self.home;
+
+        let mut hostname = None;
+        let mut port = None;
+        let mut user = None;
+        let mut identity_files = Vec::new();
+        let mut proxy_jump = None;
+        let mut server_alive_interval = None;
+
+        for block in &self.blocks {
+            let applies = match &block.criteria {
+                Criteria::Host(patterns) => matches_patterns(patterns, &alias),
+                Criteria::All => true,
+                Criteria::Never => false,
+            };
+            if !applies {
+                continue;
+            }
+            for (key, value) in &block.options {
+                let value = unquote(value);
+                match key.as_str() {
+                    "hostname" if hostname.is_none() => hostname = Some(value.to_string()),
+                    "port" if port.is_none() => {
+                        port = Some(value.parse::<u16>().map_err(|_| {
+                            format!("bad Port {} in ssh config for {}", value, alias)
+                        })?);
+                    }
+                    "user" if user.is_none() => user = Some(value.to_string()),
+                    "identityfile" => identity_files.push(value.to_string()),
+                    "proxyjump" if proxy_jump.is_none() => proxy_jump = Some(value.to_string()),
+                    "serveraliveinterval" if server_alive_interval.is_none() => {
+                        server_alive_interval = Some(value.parse::<u64>().map_err(|_| {
+                            format!(
+                                "bad ServerAliveInterval {} in ssh config for {}",
+                                value, alias
+                            )
+                        })?);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Whatever's in the spec beats the config, same as ssh -p/-l
+        let user = target.user.or(user).unwrap_or_else(local_user);
+        let hostname = hostname
+            .map(|h| expand(&h, home, &target.host, &user))
+            .unwrap_or_else(|| target.host.clone());
+        let identity_files = identity_files
+            .iter()
+            .filter(|f| !f.eq_ignore_ascii_case("none"))
+            .map(|f| PathBuf::from(expand(f, home, &hostname, &user)))
+            .collect();
+        let proxy_jump = match proxy_jump {
+            Some(jumps) if !jumps.eq_ignore_ascii_case("none") => jumps
+                .split(',')
+                .map(|j| j.trim().trim_start_matches("ssh://").to_string())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Ok(HostConfig {
+            alias: target.host,
+            hostname,
+            port: target.port.or(port).unwrap_or(22),
+            user,
+            identity_files,
+            proxy_jump,
+            server_alive_interval: server_alive_interval
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+        })
+    }
+
+    /// Every hop to get to spec with the spec they came from, jump hosts first
+    /// and spec last. Like ssh the first jump host's own ProxyJump counts, the
+    /// rest get reached through the one before them no matter what their
+    /// config says.
+    pub fn route(&self, spec: &str) -> Result<Vec<(String, HostConfig)>, String> {
+        let route = self.route_from(spec, 0)?;
+        if route.len() > MAX_HOPS {
+            return Err(format!("{} is more than {} hops away", spec, MAX_HOPS));
+        }
+        Ok(route)
+    }
+
+    fn route_from(&self, spec: &str, depth: usize) -> Result<Vec<(String, HostConfig)>, String> {
+        if depth > MAX_HOPS {
+            return Err(format!("ProxyJump loop getting to {}", spec));
+        }
+
+        let host = self.resolve(spec)?;
+        let mut route = match host.proxy_jump.split_first() {
+            Some((first, rest)) => {
+                let mut route = self.route_from(first, depth + 1)?;
+                for jump in rest {
+                    let mut hop = self.resolve(jump)?;
+                    hop.proxy_jump.clear();
+                    route.push((jump.clone(), hop));
+                }
+                route
+            }
+            None => Vec::new(),
+        };
+        route.push((spec.to_string(), host));
+        Ok(route)
+    }
+}
+
+/// Resolve spec against the usual config files
+pub fn resolve(spec: &str) -> Result<HostConfig, String> {
+    SshConfig::load().resolve(spec)
+}
+
+/// Same for every hop to spec
+pub fn route(spec: &str) -> Result<Vec<(String, HostConfig)>, String> {
+    SshConfig::load().route(spec)
+}
+
+// Include paths can be relative and have globs, only in the file name part
+// which is all anyone uses them for.
+fn include_paths(pattern: &str, base: &Path, home: &Path) -> Vec<PathBuf> {
+    let pattern = expand(pattern, home, "", "");
+    let path = if Path::new(&pattern).is_absolute() {
+        PathBuf::from(pattern)
+    } else {
+        base.join(pattern)
+    };
+
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return vec![path];
+    }
+
+    let Some(dir) = path.parent() else {
+        return Vec::new();
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| glob(&name, &e.file_name().to_string_lossy()))
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .collect();
+    // ssh includes them sorted
+    paths.sort();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    fn config(contents: &str) -> SshConfig {
+        let mut config = SshConfig::new(PathBuf::from("/home/me"));
+        config.parse(contents, Path::new("/nonexistent"), 0);
+        config
+    }
+
+    #[test]
+    fn test_resolve() {
+        let config = config(
+            "# comment
+Host nas
+    HostName nas.lan
+    Port 2222
+    User admin
+    IdentityFile ~/.ssh/nas
+    ServerAliveInterval 30
+
+Host *.lan !router.lan
+    User lan
+
+Host *
+    User everyone
+    Port 22
+    IdentityFile=%d/.ssh/id_%r
+",
+        );
+
+        let nas = config.resolve("nas").unwrap();
+        assert_eq!(nas.alias, "nas");
+        assert_eq!(nas.hostname, "nas.lan");
+        assert_eq!(nas.port, 2222);
+        assert_eq!(nas.user, "admin");
+        assert_eq!(
+            nas.identity_files,
+            vec![
+                PathBuf::from("/home/me/.ssh/nas"),
+                PathBuf::from("/home/me/.ssh/id_admin")
+            ]
+        );
+        assert_eq!(nas.server_alive_interval, Some(Duration::from_secs(30)));
+        assert!(nas.proxy_jump.is_empty());
+
+        // The spec wins over the config
+        let nas = config.resolve("root@nas:2200").unwrap();
+        assert_eq!((nas.user.as_str(), nas.port), ("root", 2200));
+
+        let lan = config.resolve("box.lan").unwrap();
+        assert_eq!(
+            (lan.hostname.as_str(), lan.user.as_str()),
+            ("box.lan", "lan")
+        );
+        let router = config.resolve("router.lan").unwrap();
+        assert_eq!(router.user, "everyone");
+    }
+
+    #[test]
+    fn test_top_level_and_match() {
+        let config = config(
+            "ServerAliveInterval 10
+Match exec \"true\"
+    User nope
+Match all
+    ProxyJump jump1,me@jump2:2222
+Host direct
+    ProxyJump none
+",
+        );
+        let host = config.resolve("anything").unwrap();
+        assert_eq!(host.server_alive_interval, Some(Duration::from_secs(10)));
+        assert_ne!(host.user, "nope");
+        assert_eq!(host.proxy_jump, vec!["jump1", "me@jump2:2222"]);
+        // First one wins even if its none
+        let config = self::config("Host direct\n    ProxyJump none\nHost *\n    ProxyJump jump\n");
+        assert!(config.resolve("direct").unwrap().proxy_jump.is_empty());
+    }
+
+    #[test]
+    fn test_route() {
+        let config = config(
+            "Host nas
+    ProxyJump gw,ssh://admin@inner:2222
+Host gw
+    HostName gw.example.com
+    ProxyJump bastion
+Host inner
+    ProxyJump ignored
+Host loop
+    ProxyJump loop
+",
+        );
+        let route = config.route("me@nas").unwrap();
+        let hops: Vec<&str> = route.iter().map(|(spec, _)| spec.as_str()).collect();
+        assert_eq!(hops, vec!["bastion", "gw", "admin@inner:2222", "me@nas"]);
+        assert_eq!(route[1].1.hostname, "gw.example.com");
+        assert_eq!(route[2].1.user, "admin");
+        assert_eq!(route[2].1.port, 2222);
+        // Only the first jump host's ProxyJump gets followed
+        assert!(route[2].1.proxy_jump.is_empty());
+
+        let route = config.route("bastion").unwrap();
+        assert_eq!(route.len(), 1);
+        assert_eq!(route[0].1.alias, "bastion");
+
+        assert!(config.route("loop").unwrap_err().contains("loop"));
+    }
+
+    #[test]
+    fn test_tokens() {
+        let config = config("Host *.example.com\n    HostName %h.internal\n");
+        assert_eq!(
+            config.resolve("a.example.com").unwrap().hostname,
+            "a.example.com.internal"
+        );
+        assert_eq!(
+            expand("~/%%/%r", Path::new("/h"), "host", "user"),
+            "/h/%/user"
+        );
+    }
+
+    #[test]
+    fn test_bad_values() {
+        assert!(config("Host a\n  Port lots\n").resolve("a").is_err());
+        assert!(config("Host a\n  Port lots\n").resolve("b").is_ok());
+        assert!(config("").resolve("a:port").is_err());
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = TestDir::new("ssh-config");
+        std::fs::create_dir_all(dir.join("config.d")).unwrap();
+        std::fs::write(dir.join("config.d/b.conf"), "Host b\n  Port 2\n").unwrap();
+        std::fs::write(dir.join("config.d/a.conf"), "Host a\n  Port 1\n").unwrap();
+        std::fs::write(dir.join("config.d/skip"), "Host a\n  Port 3\n").unwrap();
+        std::fs::write(dir.join("nas"), "User nasuser\n").unwrap();
+
+        let mut config = SshConfig::new(PathBuf::from("/home/me"));
+        config.parse(
+            "Include config.d/*.conf
+Host nas
+    Include nas
+    Port 22
+",
+            &dir,
+            0,
+        );
+        assert_eq!(config.resolve("a").unwrap().port, 1);
+        assert_eq!(config.resolve("b").unwrap().port, 2);
+        // Included inside Host nas, only applies to it
+        assert_eq!(config.resolve("nas").unwrap().user, "nasuser");
+        assert_ne!(config.resolve("a").unwrap().user, "nasuser");
+        // And the block carries on after the include
+        assert_eq!(config.resolve("nas").unwrap().port, 22);
+    }
+}
@@ -3,7 +3,7 @@ use bevy_tokio_tasks::TokioTasksRuntime;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-use super::core::setup_port_forward;
+use super::agent;
 use super::pool::Ref as ConnectionRef;
 use crate::SshForwarding;
 
@@ -221,7 +221,7 @@ fn setup_requested_forwarding(
             let session = ssh_ref.session.clone();
             let result_clone = result.clone();
             runtime.spawn_background_task(move |_ctx| async move {
-                let res = setup_port_forward(session, remote_port)
+                let res = agent::forward(session, remote_port)
                     .await
                     .map_err(|e| e.to_string());
 
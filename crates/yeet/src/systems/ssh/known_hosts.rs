@@ -0,0 +1,519 @@
+use bevy::prelude::*;
+use russh::keys::{HashAlg, PublicKey};
+use std::path::{Path, PathBuf};
+
+// OpenSSH known_hosts, enough of it to not be a liability anyway.
+//
+// Handles plain/wildcard/negated host patterns, [host]:port for non 22 ports,
+// hashed hosts aka |1|salt|hmac-sha1, @revoked and @cert-authority markers.
+// @cert-authority lines get parsed but that's it, russh doesn't hand us the
+// host's certificate to check against them. Hosts with ca signed keys need a
+// plain known_hosts line like any other host, accept-new won't add one for
+// them.
+// What we don't do is ask, a daemon has nobody to ask, so the policy decides
+// what happens with a host we've never seen. A key that doesn't match what we
+// have for a host always fails unless checking is off entirely.
+
+/// What to do about host keys, same idea as ssh's StrictHostKeyChecking
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HostKeyPolicy {
+    /// Only hosts already in known_hosts
+    Strict,
+    /// Unknown hosts get added to ~/.ssh/known_hosts, changed keys still fail
+    #[default]
+    AcceptNew,
+    /// Don't check at all, for testing and nothing else
+    Off,
+}
+
+impl std::str::FromStr for HostKeyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" | "yes" => Ok(HostKeyPolicy::Strict),
+            "accept-new" => Ok(HostKeyPolicy::AcceptNew),
+            "off" | "no" => Ok(HostKeyPolicy::Off),
+            _ => Err(format!(
+                "unknown host key policy '{}', use strict, accept-new or off",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Marker {
+    None,
+    CertAuthority,
+    Revoked,
+}
+
+#[derive(Debug, Clone)]
+enum Hosts {
+    Patterns(Vec<String>),
+    Hashed { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    marker: Marker,
+    hosts: Hosts,
+    key: PublicKey,
+    // Where it came from for error messages
+    path: PathBuf,
+    line: usize,
+}
+
+/// What known_hosts has to say about a host's key
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Known,
+    /// Nothing for this host, or nothing for this key type
+    Unknown,
+    /// We know this host with other keys, file:line of each
+    Changed(Vec<String>),
+    /// Explicitly revoked, file:line
+    Revoked(String),
+}
+
+/// Every known_hosts line we could make sense of
+#[derive(Debug, Default)]
+pub struct KnownHosts {
+    entries: Vec<Entry>,
+}
+
+/// Where ssh looks by default, user first
+pub fn default_files() -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Ok(home) = std::env::var("HOME") {
+        files.push(PathBuf::from(format!("{}/.ssh/known_hosts", home)));
+        files.push(PathBuf::from(format!("{}/.ssh/known_hosts2", home)));
+    }
+    files.push(PathBuf::from("/etc/ssh/ssh_known_hosts"));
+    files.push(PathBuf::from("/etc/ssh/ssh_known_hosts2"));
+    files
+}
+
+/// Where accept-new writes new hosts
+pub fn user_file() -> Option<PathBuf> {
+    std::env::var("HOME")
+        .ok()
+        .map(|home| PathBuf::from(format!("{}/.ssh/known_hosts", home)))
+}
+
+// How a host is written in known_hosts, ssh only uses [host]:port off 22
+fn host_name(host: &str, port: u16) -> String {
+    let host = host.to_lowercase();
+    if port == 22 {
+        host
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+// ssh's glob, * and ? only
+pub(super) fn glob(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((sp, sn)) = star {
+            p = sp + 1;
+            n = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn hashed(salt: &[u8], name: &str) -> Vec<u8> {
+    use hmac::{Hmac, Mac};
+    let mut mac =
+        Hmac::<sha1::Sha1>::new_from_slice(salt).expect("hmac takes any size key, this can't fail");
+    mac.update(name.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl Hosts {
+    fn parse(field: &str) -> Option<Self> {
+        use base64::Engine;
+        if let Some(hashed) = field.strip_prefix("|1|") {
+            let (salt, hash) = hashed.split_once('|')?;
+            let engine = base64::engine::general_purpose::STANDARD;
+            return Some(Hosts::Hashed {
+                salt: engine.decode(salt).ok()?,
+                hash: engine.decode(hash).ok()?,
+            });
+        }
+        Some(Hosts::Patterns(
+            field.split(',').map(|p| p.to_lowercase()).collect(),
+        ))
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Hosts::Hashed { salt, hash } => hashed(salt, name) == *hash,
+            Hosts::Patterns(patterns) => matches_patterns(patterns, name),
+        }
+    }
+}
+
+// A pattern list like known_hosts and ssh_config Host lines have, any negated
+// match and the whole list doesn't match.
+pub(super) fn matches_patterns(patterns: &[String], name: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        if let Some(negated) = pattern.strip_prefix('!') {
+            if glob(negated, name) {
+                return false;
+            }
+        } else if glob(pattern, name) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+impl KnownHosts {
+    /// Read whichever of files exist
+    pub fn load(files: &[PathBuf]) -> Self {
+        let mut known = KnownHosts::default();
+        for path in files {
+            match std::fs::read_to_string(path) {
+                Ok(contents) => known.parse(path, &contents),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("couldn't read {}: {}", path.display(), e),
+            }
+        }
+        known
+    }
+
+    /// Add the lines in contents, anything we can't parse gets skipped like
+    /// ssh does.
+    pub fn parse(&mut self, path: &Path, contents: &str) {
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Self::parse_line(line) {
+                Some((marker, hosts, key)) => self.entries.push(Entry {
+                    marker,
+                    hosts,
+                    key,
+                    path: path.to_path_buf(),
+                    line: idx + 1,
+                }),
+                None => debug!("skipping bad line {}:{}", path.display(), idx + 1),
+            }
+        }
+    }
+
+    fn parse_line(line: &str) -> Option<(Marker, Hosts, PublicKey)> {
+        let (first, rest) = line.split_once(char::is_whitespace)?;
+        let (marker, line) = match first {
+            "@cert-authority" => (Marker::CertAuthority, rest.trim_start()),
+            "@revoked" => (Marker::Revoked, rest.trim_start()),
+            marker if marker.starts_with('@') => return None,
+            _ => (Marker::None, line),
+        };
+        let (hosts, key) = line.split_once(char::is_whitespace)?;
+        let key = PublicKey::from_openssh(key.trim()).ok()?;
+        Some((marker, Hosts::parse(hosts)?, key))
+    }
+
+    fn matching(&self, name: &str, marker: Marker) -> impl Iterator<Item = &Entry> {
+        self.entries
+            .iter()
+            .filter(move |e| e.marker == marker && e.hosts.matches(name))
+    }
+
+    /// Check a plain host key
+    pub fn check(&self, host: &str, port: u16, key: &PublicKey) -> Verdict {
+        let name = host_name(host, port);
+
+        // Revoked keys are revoked no matter what else says otherwise
+        if let Some(revoked) = self
+            .matching(&name, Marker::Revoked)
+            .find(|e| e.key.key_data() == key.key_data())
+        {
+            return Verdict::Revoked(revoked.location());
+        }
+
+        let mut changed = Vec::new();
+        for entry in self.matching(&name, Marker::None) {
+            if entry.key.key_data() == key.key_data() {
+                return Verdict::Known;
+            }
+            // A host can have a key for every type, only the same type
+            // with a different key is a change
+            if entry.key.algorithm() == key.algorithm() {
+                changed.push(entry.location());
+            }
+        }
+
+        if changed.is_empty() {
+            Verdict::Unknown
+        } else {
+            Verdict::Changed(changed)
+        }
+    }
+
+    /// Is there a ca for this host, those never get their keys learned.
+    fn has_authority(&self, host: &str, port: u16) -> bool {
+        self.matching(&host_name(host, port), Marker::CertAuthority)
+            .next()
+            .is_some()
+    }
+}
+
+impl Entry {
+    fn location(&self) -> String {
+        format!("{}:{}", self.path.display(), self.line)
+    }
+}
+
+/// Append host's key to path, making ~/.ssh if need be
+pub fn append(path: &Path, host: &str, port: u16, key: &PublicKey) -> std::io::Result<()> {
+    use std::io::Write;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let key = key
+        .to_openssh()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    // Don't want the comment, which is whatever the server sent anyway
+    let key = key.split_whitespace().take(2).collect::<Vec<_>>().join(" ");
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{} {}", host_name(host, port), key)
+}
+
+/// Is key ok for host:port under policy, Err is why not.
+pub fn verify(policy: HostKeyPolicy, host: &str, port: u16, key: &PublicKey) -> Result<(), String> {
+    if policy == HostKeyPolicy::Off {
+        debug!(
+            "not checking {} host key {}",
+            host,
+            key.fingerprint(HashAlg::Sha256)
+        );
+        return Ok(());
+    }
+
+    let known = KnownHosts::load(&default_files());
+    if judge(policy, &known, host, port, key)? {
+        let path = user_file().ok_or("no HOME to find ~/.ssh/known_hosts with")?;
+        append(&path, host, port, key)
+            .map_err(|e| format!("couldn't add {} to {}: {}", host, path.display(), e))?;
+        info!(
+            "added {} host key {} to {}",
+            host_name(host, port),
+            key.fingerprint(HashAlg::Sha256),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+// Ok(true) means its a new host we're allowed to learn. A host with a
+// @cert-authority line is never new, its key is whatever the ca signed and
+// we can't check that, so learning the first key we're shown would trust
+// anybody. Those act like strict no matter the policy.
+fn judge(
+    policy: HostKeyPolicy,
+    known: &KnownHosts,
+    host: &str,
+    port: u16,
+    key: &PublicKey,
+) -> Result<bool, String> {
+    let fingerprint = key.fingerprint(HashAlg::Sha256);
+    match known.check(host, port, key) {
+        Verdict::Known => Ok(false),
+        Verdict::Revoked(at) => Err(format!(
+            "host key {} for {} is revoked at {}",
+            fingerprint,
+            host_name(host, port),
+            at
+        )),
+        Verdict::Changed(at) => Err(format!(
+            "host key for {} has changed, got {} but known_hosts has another at {}, possible man in the middle, remove the old key if the change is expected",
+            host_name(host, port),
+            fingerprint,
+            at.join(", ")
+        )),
+        Verdict::Unknown if known.has_authority(host, port) => Err(format!(
+            "{} isn't in known_hosts and its key is {}, there's a @cert-authority for it but host certificates aren't supported, add its plain key to known_hosts",
+            host_name(host, port),
+            fingerprint
+        )),
+        Verdict::Unknown if policy == HostKeyPolicy::Strict => Err(format!(
+            "{} isn't in known_hosts and host key checking is strict, its key is {}",
+            host_name(host, port),
+            fingerprint
+        )),
+        Verdict::Unknown => Ok(true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    const A: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOTv0TkwB7s2lkVTR0sfh/QCb+mCkXvH3q17968GALBC";
+    const B: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKsR/djjkIoKbyH6qfgtdEDOpCgB+FRtOFjB9wcxXwdz";
+
+    fn key(key: &str) -> PublicKey {
+        PublicKey::from_openssh(key).unwrap()
+    }
+
+    fn known(contents: &str) -> KnownHosts {
+        let mut known = KnownHosts::default();
+        known.parse(Path::new("known_hosts"), contents);
+        known
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("*", "anything"));
+        assert!(glob("*.example.com", "a.example.com"));
+        assert!(!glob("*.example.com", "example.com"));
+        assert!(glob("host?", "host1"));
+        assert!(!glob("host?", "host10"));
+        assert!(glob("[*]:2222", "[a]:2222"));
+        assert!(!glob("exact", "exactly"));
+    }
+
+    #[test]
+    fn test_plain() {
+        let known = known(&format!("# comment\n\nexample.com,other {}\n", A));
+        assert_eq!(known.check("example.com", 22, &key(A)), Verdict::Known);
+        assert_eq!(known.check("OTHER", 22, &key(A)), Verdict::Known);
+        assert_eq!(
+            known.check("example.com", 22, &key(B)),
+            Verdict::Changed(vec!["known_hosts:3".to_string()])
+        );
+        assert_eq!(known.check("elsewhere", 22, &key(A)), Verdict::Unknown);
+        // Non 22 ports are their own host
+        assert_eq!(known.check("example.com", 2222, &key(B)), Verdict::Unknown);
+    }
+
+    #[test]
+    fn test_ports_and_patterns() {
+        let known = known(&format!("[example.com]:2222 {}\n*.lan,!nas.lan {}\n", A, B));
+        assert_eq!(known.check("example.com", 2222, &key(A)), Verdict::Known);
+        assert_eq!(known.check("example.com", 22, &key(A)), Verdict::Unknown);
+        assert_eq!(known.check("box.lan", 22, &key(B)), Verdict::Known);
+        assert_eq!(known.check("nas.lan", 22, &key(B)), Verdict::Unknown);
+    }
+
+    #[test]
+    fn test_hashed() {
+        // ssh-keygen -H of example.com and [example.com]:2222
+        let known = known(&format!(
+            "|1|MNxHw82VGoU22j4ZGLkTmEybTWI=|Hrps/M33wzkhe6HSkiwsDdOFH3s= {}\n|1|Y1RokzWqAlOsYqB0G4Qp20GYzX0=|h+KcocsAAToli7zaGjIG+1bbZZM= {}\n",
+            A, B
+        ));
+        assert_eq!(known.check("example.com", 22, &key(A)), Verdict::Known);
+        assert_eq!(known.check("example.com", 2222, &key(B)), Verdict::Known);
+        assert!(matches!(
+            known.check("example.com", 22, &key(B)),
+            Verdict::Changed(_)
+        ));
+        assert_eq!(known.check("example.org", 22, &key(A)), Verdict::Unknown);
+    }
+
+    #[test]
+    fn test_markers() {
+        let known = known(&format!(
+            "example.com {}\n@revoked * {}\n@cert-authority *.example.com {}\n@bogus * {}\n",
+            A, B, A, A
+        ));
+        assert_eq!(
+            known.check("example.com", 22, &key(B)),
+            Verdict::Revoked("known_hosts:2".to_string())
+        );
+        // The ca line isn't a plain key for the host
+        assert_eq!(known.check("a.example.com", 22, &key(A)), Verdict::Unknown);
+        assert!(known.has_authority("a.example.com", 22));
+        assert!(!known.has_authority("example.org", 22));
+        assert_eq!(known.entries.len(), 3);
+    }
+
+    #[test]
+    fn test_policy() {
+        assert_eq!("strict".parse(), Ok(HostKeyPolicy::Strict));
+        assert_eq!("accept-new".parse(), Ok(HostKeyPolicy::AcceptNew));
+        assert_eq!("off".parse(), Ok(HostKeyPolicy::Off));
+        assert!("ask".parse::<HostKeyPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_judge() {
+        let known = known(&format!(
+            "example.com {}\n@cert-authority *.ca.example.com {}\n",
+            A, B
+        ));
+
+        assert_eq!(
+            judge(HostKeyPolicy::AcceptNew, &known, "example.com", 22, &key(A)),
+            Ok(false)
+        );
+        assert!(judge(HostKeyPolicy::AcceptNew, &known, "example.com", 22, &key(B)).is_err());
+        assert_eq!(
+            judge(
+                HostKeyPolicy::AcceptNew,
+                &known,
+                "new.example.org",
+                22,
+                &key(A)
+            ),
+            Ok(true)
+        );
+        assert!(
+            judge(
+                HostKeyPolicy::Strict,
+                &known,
+                "new.example.org",
+                22,
+                &key(A)
+            )
+            .is_err()
+        );
+
+        // Ca hosts don't get learned even under accept-new
+        let err = judge(
+            HostKeyPolicy::AcceptNew,
+            &known,
+            "a.ca.example.com",
+            22,
+            &key(A),
+        )
+        .unwrap_err();
+        assert!(err.contains("add its plain key"), "{}", err);
+    }
+
+    #[test]
+    fn test_append() {
+        let dir = TestDir::new("known-hosts");
+        let path = dir.join(".ssh/known_hosts");
+        append(&path, "Example.com", 2222, &key(&format!("{} comment", A))).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, format!("[example.com]:2222 {}\n", A));
+        let known = KnownHosts::load(&[path]);
+        assert_eq!(known.check("example.com", 2222, &key(A)), Verdict::Known);
+    }
+}
@@ -1,18 +1,26 @@
+pub mod agent;
+pub mod config;
 pub mod core;
 pub mod forwarding;
+pub mod known_hosts;
 pub mod pool;
+pub mod routes;
 pub mod state;
 
 pub use forwarding::Manager;
 pub use pool::Pool;
 
 // Export only the public side of the module with names that would make sense for client usage.
-pub use core::{Client, Session, connect, setup_port_forward};
+pub use core::{
+    Client, Identities, Identity, Jump, Session, connect, connect_route, setup_port_forward,
+};
 pub use forwarding::{Registry as FowardingRegistry, Request as ForwardingRequest};
+pub use known_hosts::HostKeyPolicy;
 pub use pool::{Ref as PoolRef, Registry as PoolRegistry, Request as PoolRequest};
+pub use routes::{Router, Routes};
 
 // Re-export state components for bevy entities/queries
 pub use state::{
-    ConnectionEntity, ConnectionRefCount, ForwardingEntity, ForwardingRefCount, HostSpec,
-    SessionHandle,
+    AuthMethod, ConnectionEntity, ConnectionError, ConnectionRefCount, ConnectionVia,
+    ForwardingEntity, ForwardingRefCount, HostSpec, SessionHandle, SshTarget,
 };
@@ -3,24 +3,37 @@ use bevy_tokio_tasks::TokioTasksRuntime;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-use super::core::{Session, connect};
+use super::config;
+use super::core::{Identities, Jump, Session, connect};
+use super::known_hosts::HostKeyPolicy;
+use super::state::{
+    ConnectionEntity, ConnectionError, ConnectionLastUsed, ConnectionRefCount, ConnectionVia,
+    HostSpec, SessionHandle,
+};
 
 pub struct Pool;
 
 impl Plugin for Pool {
     fn build(&self, app: &mut App) {
-        app.insert_resource(Registry::default()).add_systems(
-            Update,
-            (
-                establish_requested_connections,
-                check_connection_establishment,
-                cleanup_unused_connections.run_if(run_every_n_seconds(30.0)),
-            ),
-        );
+        app.insert_resource(Registry::default())
+            .init_resource::<HostKeyPolicy>()
+            .init_resource::<Identities>()
+            .add_systems(
+                Update,
+                (
+                    establish_requested_connections,
+                    check_connection_establishment,
+                    cleanup_unused_connections.run_if(run_every_n_seconds(30.0)),
+                    update_connection_entities,
+                ),
+            );
     }
 }
 
-#[derive(Resource, Default)]
+// Jump hosts are connections like any other, keyed by the spec ProxyJump named
+// them by. Whatever goes through one holds a reference to it, so a jump host
+// sticks around until everything past it is gone.
+#[derive(Resource, Default, Clone)]
 pub struct Registry {
     connections: Arc<Mutex<HashMap<String, Shared>>>,
     pending: Arc<Mutex<HashMap<String, Task>>>,
@@ -40,7 +53,7 @@ pub struct Task {
 
 #[derive(Component)]
 pub struct Request {
-    pub host_spec: String, // user@host format TODO: port and unit tests, lightyear updated to 0.17 halfway through this hack so I'm icing what I have and committing the evil
+    pub host_spec: String, // [user@]host[:port] or a ~/.ssh/config alias
 }
 
 #[derive(Component)]
@@ -94,6 +107,17 @@ impl Registry {
         }
     }
 
+    // A reference to an already connected host_spec, if there is one
+    fn acquire(&self, host_spec: &str) -> Option<Session> {
+        let mut connections = self.connections.lock().unwrap();
+
+        connections.get_mut(host_spec).map(|conn| {
+            conn.reference_count += 1;
+            conn.last_used = std::time::Instant::now();
+            conn.session.clone()
+        })
+    }
+
     pub fn release_connection(&self, host_spec: &str) {
         let mut connections = self.connections.lock().unwrap();
 
@@ -102,17 +126,88 @@ impl Registry {
         }
     }
 
-    fn add_connection(&self, host_spec: String, session: Session, initial_refs: usize) {
+    // Returns the session to use, which is whoever got there first if the same
+    // host got connected to twice, aka as a jump host and directly at once.
+    fn add_connection(&self, host_spec: String, session: Session, initial_refs: usize) -> Session {
         let mut connections = self.connections.lock().unwrap();
 
+        if let Some(conn) = connections.get_mut(&host_spec) {
+            conn.reference_count += initial_refs;
+            conn.last_used = std::time::Instant::now();
+            let existing = conn.session.clone();
+            drop(connections);
+            if let Some(jump) = &session.jump {
+                self.release_connection(&jump.host_spec);
+            }
+            return existing;
+        }
+
         connections.insert(
             host_spec,
             Shared {
-                session,
+                session: session.clone(),
                 reference_count: initial_refs,
                 last_used: std::time::Instant::now(),
             },
         );
+        session
+    }
+
+    // Every hop to host_spec but the last, pooled, then the last one which is
+    // up to the caller to add. Starts from the furthest hop we're already
+    // connected to.
+    async fn connect(
+        &self,
+        host_spec: &str,
+        identities: Identities,
+        policy: HostKeyPolicy,
+    ) -> Result<Session, String> {
+        let mut route = config::route(host_spec)?;
+        let (_, host) = route
+            .pop()
+            .ok_or_else(|| format!("no route to {}", host_spec))?;
+
+        let mut jump = None;
+        let mut start = 0;
+        for (hop, (spec, _)) in route.iter().enumerate().rev() {
+            if let Some(session) = self.acquire(spec) {
+                jump = Some(Jump {
+                    host_spec: spec.clone(),
+                    session: Arc::new(session),
+                });
+                start = hop + 1;
+                break;
+            }
+        }
+
+        for (spec, hop) in route.into_iter().skip(start) {
+            debug!("connecting to jump host {} for {}", spec, host_spec);
+            let through = jump.as_ref().map(|j| j.host_spec.clone());
+            match connect(hop, jump.take(), identities.clone(), policy).await {
+                Ok(session) => {
+                    // The next hop is its first reference
+                    let session = self.add_connection(spec.clone(), session, 1);
+                    jump = Some(Jump {
+                        host_spec: spec,
+                        session: Arc::new(session),
+                    });
+                }
+                Err(e) => {
+                    if let Some(through) = through {
+                        self.release_connection(&through);
+                    }
+                    return Err(format!("jump host {}: {}", spec, e));
+                }
+            }
+        }
+
+        let through = jump.as_ref().map(|j| j.host_spec.clone());
+        connect(host, jump, identities, policy).await.map_err(|e| {
+            if let Some(through) = through {
+                self.release_connection(&through);
+            }
+            e.to_string()
+        })
     }
 
     // This stuff might not be needed - pending creation is handled in request_connection future mitch cleanup task
@@ -125,6 +220,7 @@ impl Registry {
     fn cleanup_unused(&self, max_idle_time: std::time::Duration) {
         let mut connections = self.connections.lock().unwrap();
         let now = std::time::Instant::now();
+        let mut jumps = Vec::new();
 
         connections.retain(|host_spec, conn| {
             let should_keep =
@@ -132,10 +228,21 @@ impl Registry {
 
             if !should_keep {
                 debug!("cleaning up unused ssh connection to {}", host_spec);
+                if let Some(jump) = &conn.session.jump {
+                    jumps.push(jump.host_spec.clone());
+                }
             }
 
             should_keep
         });
+
+        // Gone jump hosts get their turn next time around once they've idled
+        for host_spec in jumps {
+            if let Some(conn) = connections.get_mut(&host_spec) {
+                conn.reference_count = conn.reference_count.saturating_sub(1);
+                conn.last_used = now;
+            }
+        }
     }
 }
 
@@ -143,6 +250,8 @@ fn establish_requested_connections(
     mut commands: Commands,
     runtime: ResMut<TokioTasksRuntime>,
     registry: ResMut<Registry>,
+    policy: Res<HostKeyPolicy>,
+    identities: Res<Identities>,
     query: Query<(Entity, &Request), (Without<Ref>, Without<Pending>)>,
 ) -> Result {
     for (entity, request) in &query {
@@ -185,24 +294,20 @@ fn establish_requested_connections(
                     host_spec, entity
                 );
 
-                // TODO: unit tests and function this hack af crap
-                let (user, hostname) = if let Some((u, h)) = host_spec.split_once('@') {
-                    (u.to_string(), h.to_string())
-                } else {
-                    let user = std::env::var("USER").unwrap_or_else(|_| "root".to_string());
-                    (user, host_spec.clone())
-                };
-
                 let result = {
                     let pending = registry.pending.lock().unwrap();
                     pending.get(&host_spec).map(|task| task.result.clone())
                 };
 
                 if let Some(result_handle) = result {
+                    let policy = *policy;
+                    let identities = identities.clone();
+                    let host_spec = host_spec.clone();
+                    let registry = registry.clone();
                     runtime.spawn_background_task(move |_ctx| async move {
-                        let res = connect(hostname, user, None)
-                            .await
-                            .map_err(|e| e.to_string());
+                        // ~/.ssh/config gets read every connect, so edits to
+                        // it don't need a daemon restart
+                        let res = registry.connect(&host_spec, identities, policy).await;
 
                         if let Ok(mut guard) = result_handle.lock() {
                             *guard = Some(res);
@@ -239,18 +344,21 @@ fn check_connection_establishment(
                         debug!("ssh connection to {} established", host_spec);
 
                         let requester_count = task.requesters.len();
-                        registry.add_connection(
-                            host_spec.clone(),
-                            session.clone(),
-                            requester_count,
-                        );
+                        let session =
+                            registry.add_connection(host_spec.clone(), session, requester_count);
 
                         for entity in task.requesters {
                             if pending_query.get(entity).is_ok() {
-                                commands.entity(entity).remove::<Pending>().insert(Ref {
-                                    host_spec: host_spec.clone(),
-                                    session: session.clone(),
-                                });
+                                commands
+                                    .entity(entity)
+                                    .remove::<(Pending, ConnectionError)>()
+                                    .insert((
+                                        Ref {
+                                            host_spec: host_spec.clone(),
+                                            session: session.clone(),
+                                        },
+                                        session.auth.clone(),
+                                    ));
                             }
                         }
                     }
@@ -259,7 +367,10 @@ fn check_connection_establishment(
 
                         for entity in task.requesters {
                             if pending_query.get(entity).is_ok() {
-                                commands.entity(entity).remove::<Pending>();
+                                commands
+                                    .entity(entity)
+                                    .remove::<Pending>()
+                                    .insert(ConnectionError(e.clone()));
                                 // TODO: Add SyncFailed component or similar here too future mitch, past jerk mitch cares not
                             }
                         }
@@ -274,6 +385,80 @@ fn check_connection_establishment(
     Ok(())
 }
 
+// Every pooled connection as a ConnectionEntity, jump hosts linked up with
+// ConnectionVia, so whats connected through what can be looked at like
+// anything else in the ecs.
+fn update_connection_entities(
+    mut commands: Commands,
+    registry: Res<Registry>,
+    query: Query<
+        (
+            Entity,
+            &HostSpec,
+            &ConnectionRefCount,
+            Option<&ConnectionVia>,
+        ),
+        With<ConnectionEntity>,
+    >,
+) -> Result {
+    let connections: Vec<(String, Shared)> = {
+        let connections = registry.connections.lock().unwrap();
+        connections
+            .iter()
+            .map(|(host_spec, conn)| (host_spec.clone(), conn.clone()))
+            .collect()
+    };
+
+    let mut entities = HashMap::new();
+    for (entity, host_spec, refs, via) in &query {
+        if connections
+            .iter()
+            .any(|(spec, _)| spec == host_spec.as_str())
+        {
+            entities.insert(host_spec.0.clone(), (entity, Some((refs.0, via.copied()))));
+        } else {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for (host_spec, conn) in &connections {
+        if !entities.contains_key(host_spec) {
+            let entity = commands
+                .spawn((
+                    ConnectionEntity,
+                    HostSpec(host_spec.clone()),
+                    SessionHandle(conn.session.clone()),
+                ))
+                .id();
+            entities.insert(host_spec.clone(), (entity, None));
+        }
+    }
+
+    for (host_spec, conn) in &connections {
+        let (entity, current) = entities[host_spec];
+        let via = conn
+            .session
+            .jump
+            .as_ref()
+            .and_then(|jump| entities.get(&jump.host_spec))
+            .map(|(jump, _)| ConnectionVia(*jump));
+
+        if current.is_none_or(|(refs, _)| refs != conn.reference_count) {
+            commands.entity(entity).insert((
+                ConnectionRefCount(conn.reference_count),
+                ConnectionLastUsed(conn.last_used),
+            ));
+        }
+        if current.is_none_or(|(_, current_via)| current_via != via) {
+            match via {
+                Some(via) => commands.entity(entity).insert(via),
+                None => commands.entity(entity).remove::<ConnectionVia>(),
+            };
+        }
+    }
+    Ok(())
+}
+
 fn cleanup_unused_connections(registry: ResMut<Registry>) -> Result {
     let max_idle = std::time::Duration::from_secs(300); // 5 minutes
     registry.cleanup_unused(max_idle);
@@ -0,0 +1,475 @@
+use bevy::prelude::*;
+use bevy_tokio_tasks::TokioTasksRuntime;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::config;
+use super::core::{Identities, connect_route};
+use super::known_hosts::HostKeyPolicy;
+use super::state::ConnectionError;
+
+// More than one way to get to a remote, aka the nas directly when I'm home
+// and through the gateway when I'm not. Each way is just a host spec, so a
+// ~/.ssh/config alias with a ProxyJump is how you say "through the gateway":
+//
+//   yeet serve --route nas=nas-lan,nas-via-gw
+//
+// and yeet cp whatever nas:/vol1 uses whichever is best right now.
+//
+// Best is whatever a heartbeat to the daemon over that route says moves a
+// chunk of data the fastest, rtt plus how long the chunk takes at the
+// throughput we saw. A lan beats going through a jump host on both. Routes get
+// probed when we start, every so often after that and whenever a sync
+// couldn't connect over the one we picked.
+//
+// This needs a yeet daemon on the other end to heartbeat, an agent only
+// remote can't be probed so it gets the first route that works.
+
+/// How often routes get probed again
+const REPROBE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Any longer and that route might as well be down
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Empty heartbeats for the rtt, best one counts
+const PROBE_PINGS: usize = 3;
+
+/// Heartbeat payload for throughput, echoed back so it goes both ways
+const PROBE_PAYLOAD: usize = 256 * 1024;
+
+/// What a route gets judged on moving, about one file chunk
+const COST_BYTES: f64 = 1024.0 * 1024.0;
+
+/// Where the daemon listens on every remote
+const DAEMON_PORT: u32 = 50051;
+
+pub struct Router;
+
+impl Plugin for Router {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Routes>().add_systems(
+            Update,
+            (
+                probe_routes.run_if(bevy::time::common_conditions::on_timer(
+                    Duration::from_secs(1),
+                )),
+                reroute_failed_connections,
+            ),
+        );
+    }
+}
+
+/// What a heartbeat over a route measured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Probe {
+    pub rtt: Duration,
+    pub throughput_bps: f64,
+}
+
+impl Probe {
+    /// About how long moving COST_BYTES takes, lower is better
+    pub fn cost(&self) -> Duration {
+        let transfer = if self.throughput_bps > 0.0 {
+            Duration::from_secs_f64(COST_BYTES / self.throughput_bps)
+        } else {
+            Duration::MAX
+        };
+        self.rtt.saturating_add(transfer)
+    }
+}
+
+/// One way to a remote and how it did last time we looked
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub spec: String,
+    /// None until its been probed
+    pub probe: Option<Result<Probe, String>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Remote {
+    pub candidates: Vec<Candidate>,
+    pub chosen: Option<String>,
+    probed_at: Option<Instant>,
+    probing: bool,
+}
+
+impl Remote {
+    fn new(specs: Vec<String>) -> Self {
+        let mut remote = Self {
+            candidates: specs
+                .into_iter()
+                .map(|spec| Candidate { spec, probe: None })
+                .collect(),
+            ..Default::default()
+        };
+        remote.pick();
+        remote
+    }
+
+    // Cheapest probe wins. Before anything's been probed go with the first
+    // one that hasn't failed in the order given, if everything failed stick
+    // with what we had instead of bouncing between dead routes.
+    fn pick(&mut self) -> bool {
+        let best = self
+            .candidates
+            .iter()
+            .filter_map(|c| match &c.probe {
+                Some(Ok(probe)) => Some((probe.cost(), c)),
+                _ => None,
+            })
+            .min_by_key(|(cost, _)| *cost)
+            .map(|(_, c)| c)
+            .or_else(|| {
+                self.candidates
+                    .iter()
+                    .find(|c| !matches!(c.probe, Some(Err(_))))
+            })
+            .map(|c| c.spec.clone())
+            .or_else(|| self.chosen.clone())
+            .or_else(|| self.candidates.first().map(|c| c.spec.clone()));
+
+        let changed = best != self.chosen;
+        self.chosen = best;
+        changed
+    }
+}
+
+/// Remotes with more than one route, by the name syncs use for them
+#[derive(Resource, Clone, Default)]
+pub struct Routes {
+    remotes: Arc<Mutex<HashMap<String, Remote>>>,
+}
+
+impl Routes {
+    pub fn new(routes: Vec<(String, Vec<String>)>) -> Self {
+        Self {
+            remotes: Arc::new(Mutex::new(
+                routes
+                    .into_iter()
+                    .map(|(name, specs)| (name, Remote::new(specs)))
+                    .collect(),
+            )),
+        }
+    }
+
+    /// name=spec[,spec...] like --route takes
+    pub fn parse(route: &str) -> Result<(String, Vec<String>), String> {
+        let (name, specs) = route
+            .split_once('=')
+            .ok_or_else(|| format!("route {} isn't name=spec[,spec...]", route))?;
+        let name = name.trim();
+        let specs: Vec<String> = specs
+            .split(',')
+            .map(|spec| spec.trim().to_string())
+            .filter(|spec| !spec.is_empty())
+            .collect();
+        if name.is_empty() || specs.is_empty() {
+            return Err(format!("route {} isn't name=spec[,spec...]", route));
+        }
+        for spec in &specs {
+            spec.parse::<super::state::SshTarget>()?;
+        }
+        Ok((name.to_string(), specs))
+    }
+
+    /// Host spec to connect to for host_spec, which is itself unless its one
+    /// of ours.
+    pub fn resolve(&self, host_spec: &str) -> String {
+        self.remotes
+            .lock()
+            .unwrap()
+            .get(host_spec)
+            .and_then(|remote| remote.chosen.clone())
+            .unwrap_or_else(|| host_spec.to_string())
+    }
+
+    /// Every remote with its routes, sorted by name
+    pub fn snapshot(&self) -> Vec<(String, Remote)> {
+        let mut remotes: Vec<(String, Remote)> = self
+            .remotes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, remote)| (name.clone(), remote.clone()))
+            .collect();
+        remotes.sort_by(|a, b| a.0.cmp(&b.0));
+        remotes
+    }
+
+    // spec didn't work out for name, returns the route to try instead if
+    // there is a different one. Either way its time for a fresh look.
+    fn failed(&self, name: &str, spec: &str, error: &str) -> Option<String> {
+        let mut remotes = self.remotes.lock().unwrap();
+        let remote = remotes.get_mut(name)?;
+        if let Some(candidate) = remote.candidates.iter_mut().find(|c| c.spec == spec) {
+            candidate.probe = Some(Err(error.to_string()));
+        }
+        remote.probed_at = None;
+        if remote.pick() {
+            remote.chosen.clone()
+        } else {
+            None
+        }
+    }
+
+    // Remotes that need probing, marked as being probed
+    fn due(&self) -> Vec<(String, Vec<String>)> {
+        let mut remotes = self.remotes.lock().unwrap();
+        remotes
+            .iter_mut()
+            .filter(|(_, remote)| {
+                !remote.probing
+                    && remote
+                        .probed_at
+                        .is_none_or(|at| at.elapsed() >= REPROBE_INTERVAL)
+            })
+            .map(|(name, remote)| {
+                remote.probing = true;
+                (
+                    name.clone(),
+                    remote.candidates.iter().map(|c| c.spec.clone()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn probed(&self, name: &str, results: Vec<(String, Result<Probe, String>)>) {
+        let mut remotes = self.remotes.lock().unwrap();
+        let Some(remote) = remotes.get_mut(name) else {
+            return;
+        };
+        for (spec, result) in results {
+            if let Some(candidate) = remote.candidates.iter_mut().find(|c| c.spec == spec) {
+                candidate.probe = Some(result);
+            }
+        }
+        remote.probing = false;
+        remote.probed_at = Some(Instant::now());
+        if remote.pick()
+            && let Some(chosen) = &remote.chosen
+        {
+            info!("route to {} is now {}", name, chosen);
+        }
+    }
+}
+
+/// The route a remote sync's connection was asked for over
+#[derive(Component, Clone, Debug)]
+pub struct Route(pub String);
+
+fn probe_routes(
+    runtime: ResMut<TokioTasksRuntime>,
+    routes: Res<Routes>,
+    policy: Option<Res<HostKeyPolicy>>,
+    identities: Option<Res<Identities>>,
+) {
+    for (name, specs) in routes.due() {
+        let routes = routes.clone();
+        let policy = policy.as_deref().copied().unwrap_or_default();
+        let identities = identities.as_deref().cloned().unwrap_or_default();
+
+        runtime.spawn_background_task(move |_ctx| async move {
+            // One at a time, probes racing each other for the same uplink
+            // would make every route look worse than it is.
+            let mut results = Vec::new();
+            for spec in specs {
+                let result = match tokio::time::timeout(
+                    PROBE_TIMEOUT,
+                    probe(&spec, identities.clone(), policy),
+                )
+                .await
+                {
+                    Ok(result) => result,
+                    Err(_) => Err(format!("no answer in {:?}", PROBE_TIMEOUT)),
+                };
+                match &result {
+                    Ok(probe) => debug!(
+                        "route {} to {}: rtt {:?} {}/s",
+                        spec,
+                        name,
+                        probe.rtt,
+                        humansize::format_size(probe.throughput_bps as u64, humansize::BINARY)
+                    ),
+                    Err(e) => debug!("route {} to {} is out: {}", spec, name, e),
+                }
+                results.push((spec, result));
+            }
+            routes.probed(&name, results);
+        });
+    }
+}
+
+// Heartbeat the daemon at the other end of spec's route
+async fn probe(spec: &str, identities: Identities, policy: HostKeyPolicy) -> Result<Probe, String> {
+    use crate::rpc::yeet::{HeartbeatRequest, yeet_client::YeetClient};
+    use hyper_util::rt::tokio::TokioIo;
+    use tower::service_fn;
+
+    let route = config::route(spec)?;
+    let session = connect_route(route, identities, policy)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Straight over a channel, no local listener to clean up after. The uri
+    // is only there to keep tonic happy.
+    let channel = tonic::transport::Endpoint::from_static("http://localhost")
+        .connect_with_connector(service_fn(move |_uri: tonic::transport::Uri| {
+            let session = session.clone();
+            async move {
+                let channel = session
+                    .handle
+                    .channel_open_direct_tcpip(
+                        crate::systems::grpc::DAEMON_HOST,
+                        DAEMON_PORT,
+                        "localhost",
+                        0,
+                    )
+                    .await
+                    .map_err(std::io::Error::other)?;
+                Ok::<_, std::io::Error>(TokioIo::new(channel.into_stream()))
+            }
+        }))
+        .await
+        .map_err(|e| format!("no yeet daemon: {}", e))?;
+    let mut client = YeetClient::new(channel);
+
+    let mut heartbeat = async |payload: Vec<u8>| -> Result<Duration, String> {
+        let start = Instant::now();
+        let reply = client
+            .heartbeat(HeartbeatRequest {
+                target: "localhost".to_string(),
+                payload,
+            })
+            .await
+            .map_err(|e| e.message().to_string())?
+            .into_inner();
+        if !reply.success {
+            return Err(reply.message);
+        }
+        Ok(start.elapsed())
+    };
+
+    let mut rtt = Duration::MAX;
+    for _ in 0..PROBE_PINGS {
+        rtt = rtt.min(heartbeat(Vec::new()).await?);
+    }
+    let elapsed = heartbeat(vec![0; PROBE_PAYLOAD]).await?;
+
+    // Both ways, minus what it would have taken empty
+    let moving = elapsed.saturating_sub(rtt).max(Duration::from_micros(1));
+    Ok(Probe {
+        rtt,
+        throughput_bps: (2 * PROBE_PAYLOAD) as f64 / moving.as_secs_f64(),
+    })
+}
+
+// A sync that couldn't connect over its route gets another go over the next
+// best one, if there is one.
+fn reroute_failed_connections(
+    mut commands: Commands,
+    routes: Res<Routes>,
+    query: Query<(Entity, &crate::RemoteHost, &Route, &ConnectionError), Added<ConnectionError>>,
+) {
+    for (entity, remote, route, error) in &query {
+        if let Some(next) = routes.failed(&remote.0, &route.0, &error.0) {
+            warn!(
+                "{} failed getting to {} ({}), trying {}",
+                route.0, remote.0, error.0, next
+            );
+            commands.entity(entity).remove::<(ConnectionError, Route)>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(rtt_ms: u64, mib_per_sec: f64) -> Option<Result<Probe, String>> {
+        Some(Ok(Probe {
+            rtt: Duration::from_millis(rtt_ms),
+            throughput_bps: mib_per_sec * 1024.0 * 1024.0,
+        }))
+    }
+
+    fn remote(probes: Vec<Option<Result<Probe, String>>>) -> Remote {
+        let mut remote = Remote::new((0..probes.len()).map(|n| format!("route{}", n)).collect());
+        for (candidate, probe) in remote.candidates.iter_mut().zip(probes) {
+            candidate.probe = probe;
+        }
+        remote.pick();
+        remote
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Routes::parse("nas=nas-lan, me@gw:2222").unwrap(),
+            (
+                "nas".to_string(),
+                vec!["nas-lan".to_string(), "me@gw:2222".to_string()]
+            )
+        );
+        assert!(Routes::parse("nas").is_err());
+        assert!(Routes::parse("=nas-lan").is_err());
+        assert!(Routes::parse("nas=").is_err());
+        assert!(Routes::parse("nas=gw:0").is_err());
+    }
+
+    #[test]
+    fn test_pick() {
+        // Nothing probed yet, first one
+        assert_eq!(remote(vec![None, None]).chosen.as_deref(), Some("route0"));
+
+        // Fast lan over a slow jump
+        let lan = remote(vec![probe(40, 5.0), probe(1, 100.0)]);
+        assert_eq!(lan.chosen.as_deref(), Some("route1"));
+
+        // Lower rtt isn't everything, 10ms more beats 100x less throughput
+        let fat = remote(vec![probe(1, 1.0), probe(11, 100.0)]);
+        assert_eq!(fat.chosen.as_deref(), Some("route1"));
+
+        // Down routes never win, all down falls back to the first
+        let down = remote(vec![Some(Err("nope".into())), None]);
+        assert_eq!(down.chosen.as_deref(), Some("route1"));
+        let all_down = remote(vec![Some(Err("nope".into())), Some(Err("nah".into()))]);
+        assert_eq!(all_down.chosen.as_deref(), Some("route0"));
+    }
+
+    #[test]
+    fn test_resolve_and_failed() {
+        let routes = Routes::new(vec![(
+            "nas".to_string(),
+            vec!["nas-lan".to_string(), "nas-gw".to_string()],
+        )]);
+        assert_eq!(routes.resolve("nas"), "nas-lan");
+        assert_eq!(routes.resolve("elsewhere"), "elsewhere");
+
+        assert_eq!(
+            routes.failed("nas", "nas-lan", "no route to host"),
+            Some("nas-gw".to_string())
+        );
+        assert_eq!(routes.resolve("nas"), "nas-gw");
+        // Nothing left to switch to
+        assert_eq!(routes.failed("nas", "nas-gw", "timed out"), None);
+        assert_eq!(routes.failed("elsewhere", "elsewhere", "eh"), None);
+
+        // Failing got it probed again
+        assert_eq!(routes.due().len(), 1);
+        assert!(routes.due().is_empty());
+        routes.probed(
+            "nas",
+            vec![(
+                "nas-gw".to_string(),
+                Ok(Probe {
+                    rtt: Duration::from_millis(5),
+                    throughput_bps: 1e6,
+                }),
+            )],
+        );
+        assert_eq!(routes.resolve("nas"), "nas-gw");
+        assert!(routes.due().is_empty());
+    }
+}
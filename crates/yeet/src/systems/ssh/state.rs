@@ -7,7 +7,7 @@ use super::core::Session;
 #[derive(Component)]
 pub struct ConnectionEntity;
 
-/// Host connection spec, aka user@host:port (note port nyi cause lazy)
+/// Host connection spec, aka [user@]host[:port] or a ~/.ssh/config alias
 #[derive(Component, Clone, PartialEq, Eq, Hash)]
 pub struct HostSpec(pub String);
 
@@ -19,6 +19,11 @@ pub struct SessionHandle(pub Session);
 #[derive(Component)]
 pub struct ConnectionRefCount(pub usize);
 
+/// Jump host connection this one is tunneled through, aka ProxyJump. Its
+/// ConnectionRefCount counts us as a user.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionVia(pub Entity);
+
 /// Last time this connection was used, also for future retry logic
 #[derive(Component)]
 pub struct ConnectionLastUsed(pub Instant);
@@ -31,6 +36,27 @@ pub struct ConnectionEstablishing;
 #[derive(Component)]
 pub struct ConnectionError(pub String);
 
+/// How an ssh connection authenticated, ends up on whatever uses it
+#[derive(Component, Clone, Debug, PartialEq, Eq)]
+pub enum AuthMethod {
+    /// ssh-agent key, by its sha256 fingerprint
+    Agent(String),
+    /// Private key file
+    Key(std::path::PathBuf),
+    /// OpenSSH user certificate next to this key file
+    Certificate(std::path::PathBuf),
+}
+
+impl std::fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthMethod::Agent(fingerprint) => write!(f, "ssh-agent key {}", fingerprint),
+            AuthMethod::Key(path) => write!(f, "key {}", path.display()),
+            AuthMethod::Certificate(path) => write!(f, "certificate for {}", path.display()),
+        }
+    }
+}
+
 /// Marker component for ssh port forwarding entities
 #[derive(Component)]
 pub struct ForwardingEntity;
@@ -76,6 +102,70 @@ impl HostSpec {
     pub fn as_str(&self) -> &str {
         &self.0
     }
+
+    pub fn target(&self) -> Result<SshTarget, String> {
+        self.0.parse()
+    }
+}
+
+/// A host spec taken apart, [user@]host[:port] where host can be a [v6]
+/// literal. An unbracketed v6 address works too, just without a port.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SshTarget {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl std::str::FromStr for SshTarget {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        // Last @ like ssh, user names with @ in them are a thing somewhere
+        let (user, rest) = match spec.rsplit_once('@') {
+            Some((user, rest)) if !user.is_empty() => (Some(user.to_string()), rest),
+            Some(_) => return Err(format!("empty user in '{}'", spec)),
+            None => (None, spec),
+        };
+
+        let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+            let (host, after) = bracketed
+                .split_once(']')
+                .ok_or_else(|| format!("no closing ] in '{}'", spec))?;
+            match after {
+                "" => (host, None),
+                _ => match after.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(format!("junk after ] in '{}'", spec)),
+                },
+            }
+        } else {
+            match rest.split_once(':') {
+                // More than one : is a bare v6 address
+                Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                _ => (rest, None),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(format!("no host in '{}'", spec));
+        }
+        let port = match port {
+            Some(port) => Some(
+                port.parse::<u16>()
+                    .ok()
+                    .filter(|p| *p != 0)
+                    .ok_or_else(|| format!("bad port '{}' in '{}'", port, spec))?,
+            ),
+            None => None,
+        };
+
+        Ok(SshTarget {
+            user,
+            host: host.to_string(),
+            port,
+        })
+    }
 }
 
 impl std::fmt::Display for HostSpec {
@@ -264,6 +354,45 @@ mod tests {
         assert_eq!(port_50051_count, 2);
     }
 
+    #[test]
+    fn test_ssh_target() {
+        let target = |spec: &str| spec.parse::<SshTarget>();
+        let ok = |user: Option<&str>, host: &str, port: Option<u16>| {
+            Ok(SshTarget {
+                user: user.map(String::from),
+                host: host.to_string(),
+                port,
+            })
+        };
+
+        assert_eq!(target("nas"), ok(None, "nas", None));
+        assert_eq!(target("me@nas"), ok(Some("me"), "nas", None));
+        assert_eq!(target("me@nas:2222"), ok(Some("me"), "nas", Some(2222)));
+        assert_eq!(target("a@b@nas"), ok(Some("a@b"), "nas", None));
+        assert_eq!(target("[::1]"), ok(None, "::1", None));
+        assert_eq!(
+            target("me@[fe80::1%eth0]:22"),
+            ok(Some("me"), "fe80::1%eth0", Some(22))
+        );
+        assert_eq!(target("fe80::1"), ok(None, "fe80::1", None));
+        assert_eq!(target("[nas]:2222"), ok(None, "nas", Some(2222)));
+        assert_eq!(target("me@[nas]:2222"), ok(Some("me"), "nas", Some(2222)));
+
+        assert!(target("").is_err());
+        assert!(target("@nas").is_err());
+        assert!(target("me@").is_err());
+        assert!(target("nas:").is_err());
+        assert!(target("nas:0").is_err());
+        assert!(target("nas:99999").is_err());
+        assert!(target("[::1").is_err());
+        assert!(target("[::1]22").is_err());
+
+        assert_eq!(
+            HostSpec("me@nas:2222".to_string()).target(),
+            ok(Some("me"), "nas", Some(2222))
+        );
+    }
+
     #[test]
     fn test_connection_lifecycle_markers() {
         let mut app = App::new();
@@ -6,8 +6,8 @@ use crate::systems::ssh::pool::{
     Pending as ConnectionPending, Ref as ConnectionRef, Request as ConnectionRequest,
 };
 use crate::{
-    Dest, IoOperation, IoProgress, RemoteHost, SimpleCopy, Source, SshForwarding, SyncComplete,
-    Uuid,
+    Dest, GlobalLimits, IoOperation, IoProgress, MemoryBudget, RateLimit, RateLimits, RemoteHost,
+    SharedWriters, SimpleCopy, Source, SshForwarding, SyncComplete, Uuid, WriterControllers,
 };
 
 pub struct Syncer;
@@ -59,12 +59,22 @@ impl Plugin for Syncer {
             )),
         );
 
+        // One limits registry, set of writer controllers and writer pool for
+        // the whole daemon, every sync shares them.
+        app.init_resource::<RateLimits>()
+            .init_resource::<WriterControllers>()
+            .init_resource::<SharedWriters>()
+            .init_resource::<MemoryBudget>()
+            .add_systems(Startup, spawn_global_limits);
+
         app.add_systems(
             Update,
             (
                 request_ssh_connections,
                 request_ssh_forwarding.after(request_ssh_connections),
                 spawn_sync_tasks.after(request_ssh_forwarding),
+                spawn_remote_dest_tasks.after(request_ssh_forwarding),
+                spawn_remote_source_tasks.after(request_ssh_forwarding),
             ),
         );
     }
@@ -110,9 +120,136 @@ fn debug_hack(
     Ok(())
 }
 
+// The global limits get their own entity so they can be replicated/shown in
+// the monitor like everything else.
+fn spawn_global_limits(mut commands: Commands, limits: Res<RateLimits>) {
+    let limit = limits.0.global();
+    commands.spawn((
+        GlobalLimits,
+        RateLimit {
+            bytes_per_sec: limit.bytes_per_sec,
+            ops_per_sec: limit.ops_per_sec,
+        },
+    ));
+}
+
+// Everything a sync needs from the daemon wide resources, the rest is per sync.
+fn daemon_subsystem(
+    limits: &RateLimits,
+    controllers: &WriterControllers,
+    writers: &SharedWriters,
+    budget: &MemoryBudget,
+    weight: Option<&crate::Weight>,
+    schedule: Option<&crate::Schedule>,
+) -> crate::io::IoSubsystem {
+    let mut subsystem = crate::io::IoSubsystem::new()
+        .with_limits(limits.0.clone())
+        .with_controllers(controllers.0.clone())
+        .with_writers(writers.0.clone())
+        .with_memory_budget(budget.0);
+    if let Some(weight) = weight {
+        subsystem = subsystem.with_weight(weight.0);
+    }
+    if let Some(schedule) = schedule {
+        subsystem = subsystem.with_schedule(schedule.0.clone());
+    }
+    subsystem
+}
+
+type Legs<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static Uuid,
+        &'static Dest,
+        Option<&'static crate::NumWriters>,
+        Option<&'static crate::Schedule>,
+        Option<&'static crate::Weight>,
+        Option<&'static crate::Trash>,
+        Option<&'static SshForwarding>,
+        Has<crate::RemoteDest>,
+    ),
+    (
+        With<crate::FanOutLeg>,
+        Without<IoOperation>,
+        Without<SyncComplete>,
+    ),
+>;
+
+// Each fan-out destination gets its own subsystem that only writes, its entity
+// tracks it like any other sync. Remote ones hand whatever the lead's scan
+// gives them to the daemon over there, so nothing starts until every one of
+// those has its ssh forward. One that can't connect holds the lead up same as
+// a remote dest sits on its ConnectionError until the Router finds a way.
+fn start_legs<F>(
+    commands: &mut Commands,
+    runtime: &TokioTasksRuntime,
+    limits: &RateLimits,
+    new_subsystem: &F,
+    fan_out: &crate::FanOut,
+    persistent: bool,
+    legs: &Legs,
+) -> Option<Vec<(u128, std::path::PathBuf, crate::io::IoSubsystem)>>
+where
+    F: Fn(Option<&crate::Weight>, Option<&crate::Schedule>) -> crate::io::IoSubsystem,
+{
+    let mine: Vec<_> = legs
+        .iter()
+        .filter(|(_, uuid, ..)| fan_out.0.contains(&uuid.0))
+        .collect();
+    if mine
+        .iter()
+        .any(|(.., forwarding, remote)| *remote && forwarding.is_none())
+    {
+        return None;
+    }
+
+    let mut fanned = Vec::new();
+    for (entity, uuid, dest, num_writers, schedule, weight, trash, forwarding, _) in mine {
+        let leg = new_subsystem(weight, schedule).with_trash(trash.map(|t| t.0.clone()));
+        commands.entity(entity).insert((
+            IoOperation {
+                uuid: uuid.0,
+                subsystem: leg.clone(),
+            },
+            IoProgress::default(),
+            crate::systems::protocol::SyncStartTime(std::time::Instant::now()),
+        ));
+
+        if let Some(forwarding) = forwarding {
+            info!(
+                "fanning out to remote dest {} over port {} (uuid: {})",
+                dest.0.display(),
+                forwarding.local_port,
+                uuid::Uuid::from_u128(uuid.0)
+            );
+            let remote = crate::rpc::transfer::RemoteDest {
+                port: forwarding.local_port,
+                dest: dest.0.clone(),
+                writers: num_writers.and_then(|nw| nw.0),
+                weight: weight.map(|w| w.0),
+                persistent,
+                limits: limits.0.clone(),
+            };
+            let (rx, report) = leg.send_to_remote(uuid.0);
+            let uuid = uuid.0;
+            runtime.spawn_background_task(move |_ctx| async move {
+                crate::rpc::transfer::send(uuid, remote, rx, report).await
+            });
+        }
+        fanned.push((uuid.0, dest.0.clone(), leg));
+    }
+    Some(fanned)
+}
+
 fn spawn_sync_tasks(
     mut commands: Commands,
     runtime: ResMut<TokioTasksRuntime>,
+    limits: Res<RateLimits>,
+    controllers: Res<WriterControllers>,
+    writers: Res<SharedWriters>,
+    budget: Res<MemoryBudget>,
     query: Query<
         (
             Entity,
@@ -121,11 +258,47 @@ fn spawn_sync_tasks(
             &Uuid,
             &SimpleCopy,
             Option<&crate::NumWriters>,
+            Option<&crate::Schedule>,
+            Option<&crate::Weight>,
+            Option<&crate::ScanOptions>,
+            Option<&crate::QuickCheck>,
+            Option<&crate::Watch>,
+            Option<&crate::TwoWay>,
+            Option<&crate::FanOut>,
+            Option<&crate::SnapshotSource>,
+            Option<&crate::Trash>,
+        ),
+        (
+            Without<IoOperation>,
+            Without<SyncComplete>,
+            Without<crate::FanOutLeg>,
+            Without<RemoteHost>,
         ),
-        (Without<IoOperation>, Without<SyncComplete>),
     >,
+    legs: Legs,
 ) -> bevy::prelude::Result {
-    for (entity, source, dest, uuid, _ignored, num_writers) in &query {
+    let new_subsystem = |weight: Option<&crate::Weight>, schedule: Option<&crate::Schedule>| {
+        daemon_subsystem(&limits, &controllers, &writers, &budget, weight, schedule)
+    };
+
+    for (
+        entity,
+        source,
+        dest,
+        uuid,
+        _ignored,
+        num_writers,
+        schedule,
+        weight,
+        scan,
+        quick_check,
+        watch,
+        two_way,
+        fan_out,
+        snapshot,
+        trash,
+    ) in &query
+    {
         let source = source.0.clone();
         let dest = dest.0.clone();
         let uuid = uuid.0;
@@ -138,8 +311,50 @@ fn spawn_sync_tasks(
             uuid::Uuid::from_u128(uuid)
         );
 
+        if let Some(schedule) = schedule {
+            debug!(
+                "schedule for {}: {}",
+                uuid::Uuid::from_u128(uuid),
+                schedule.0
+            );
+        }
+
         // Create a new I/O subsystem for this operation
-        let mut subsystem = crate::io::IoSubsystem::new();
+        let mut subsystem = new_subsystem(weight, schedule)
+            .with_quick_check(quick_check.is_some())
+            .with_watch(watch.map(|w| w.0))
+            .with_snapshot(snapshot.map(|s| s.0.clone()))
+            .with_trash(trash.map(|t| t.0.clone()));
+
+        if two_way.is_some() {
+            match crate::get_baseline_file(&source, &dest) {
+                Ok(baseline) => subsystem = subsystem.with_two_way(Some(baseline)),
+                Err(e) => {
+                    error!("no baseline location for two-way sync, not starting: {}", e);
+                    continue;
+                }
+            }
+        }
+
+        if let Some(scan) = scan {
+            subsystem = subsystem.with_scan(scan.order, scan.scanners);
+        }
+
+        if let Some(fan_out) = fan_out {
+            let Some(fanned) = start_legs(
+                &mut commands,
+                &runtime,
+                &limits,
+                &new_subsystem,
+                fan_out,
+                watch.is_some(),
+                &legs,
+            ) else {
+                continue;
+            };
+            subsystem = subsystem.with_fan_out(fanned);
+        }
+
         let subsystem_clone = subsystem.clone();
 
         // Get the number of writers (None = use CPU count)
@@ -164,29 +379,250 @@ fn spawn_sync_tasks(
     Ok(())
 }
 
-// System to request SSH connections for remote syncs
+// Remote dests scan here and send everything to the daemon on the dest host,
+// so they wait on the ssh forward to it before starting anything.
+fn spawn_remote_dest_tasks(
+    mut commands: Commands,
+    runtime: ResMut<TokioTasksRuntime>,
+    limits: Res<RateLimits>,
+    controllers: Res<WriterControllers>,
+    writers: Res<SharedWriters>,
+    budget: Res<MemoryBudget>,
+    query: Query<
+        (
+            Entity,
+            &Source,
+            &Dest,
+            &Uuid,
+            &SshForwarding,
+            Option<&crate::NumWriters>,
+            Option<&crate::Weight>,
+            Option<&crate::ScanOptions>,
+            Option<&crate::Watch>,
+            Option<&crate::SnapshotSource>,
+            Option<&crate::FanOut>,
+        ),
+        (
+            With<crate::RemoteDest>,
+            Without<IoOperation>,
+            Without<SyncComplete>,
+            Without<crate::FanOutLeg>,
+        ),
+    >,
+    legs: Legs,
+) -> bevy::prelude::Result {
+    let new_subsystem = |weight: Option<&crate::Weight>, schedule: Option<&crate::Schedule>| {
+        daemon_subsystem(&limits, &controllers, &writers, &budget, weight, schedule)
+    };
+
+    for (
+        entity,
+        source,
+        dest,
+        uuid,
+        forwarding,
+        num_writers,
+        weight,
+        scan,
+        watch,
+        snapshot,
+        fan_out,
+    ) in &query
+    {
+        let source = source.0.clone();
+        let uuid = uuid.0;
+
+        info!(
+            "spawning remote dest I/O operation {} -> {} over port {} (uuid: {})",
+            source.display(),
+            dest.display(),
+            forwarding.local_port,
+            uuid::Uuid::from_u128(uuid)
+        );
+
+        // Only scans, the writers are on the other end
+        let mut subsystem = crate::io::IoSubsystem::new()
+            .with_limits(limits.0.clone())
+            .with_memory_budget(budget.0)
+            .with_watch(watch.map(|w| w.0))
+            .with_snapshot(snapshot.map(|s| s.0.clone()));
+        if let Some(scan) = scan {
+            subsystem = subsystem.with_scan(scan.order, scan.scanners);
+        }
+
+        if let Some(fan_out) = fan_out {
+            let Some(fanned) = start_legs(
+                &mut commands,
+                &runtime,
+                &limits,
+                &new_subsystem,
+                fan_out,
+                watch.is_some(),
+                &legs,
+            ) else {
+                continue;
+            };
+            subsystem = subsystem.with_fan_out(fanned);
+        }
+
+        let writers = num_writers.and_then(|nw| nw.0);
+        let remote = crate::rpc::transfer::RemoteDest {
+            port: forwarding.local_port,
+            dest: dest.0.clone(),
+            writers,
+            weight: weight.map(|w| w.0),
+            persistent: watch.is_some(),
+            limits: limits.0.clone(),
+        };
+        let subsystem_clone = subsystem.clone();
+
+        runtime.spawn_background_task(move |_ctx| async move {
+            match subsystem.start_remote(uuid, source, writers).await {
+                Ok((rx, report)) => crate::rpc::transfer::send(uuid, remote, rx, report).await,
+                Err(e) => error!("I/O subsystem failed to start: {}", e),
+            }
+        });
+
+        commands.entity(entity).insert((
+            IoOperation {
+                uuid,
+                subsystem: subsystem_clone,
+            },
+            IoProgress::default(),
+            crate::systems::protocol::SyncStartTime(std::time::Instant::now()),
+        ));
+    }
+    Ok(())
+}
+
+// Remote sources are the other way around, the daemon on the source host scans
+// and we pull everything into our writers.
+fn spawn_remote_source_tasks(
+    mut commands: Commands,
+    runtime: ResMut<TokioTasksRuntime>,
+    limits: Res<RateLimits>,
+    controllers: Res<WriterControllers>,
+    writers: Res<SharedWriters>,
+    budget: Res<MemoryBudget>,
+    query: Query<
+        (
+            Entity,
+            &Source,
+            &Dest,
+            &Uuid,
+            &SshForwarding,
+            Option<&crate::NumWriters>,
+            Option<&crate::Schedule>,
+            Option<&crate::Weight>,
+            Option<&crate::ScanOptions>,
+            Option<&crate::QuickCheck>,
+            Option<&crate::SnapshotSource>,
+            Option<&crate::Trash>,
+        ),
+        (
+            With<RemoteHost>,
+            Without<crate::RemoteDest>,
+            Without<IoOperation>,
+            Without<SyncComplete>,
+        ),
+    >,
+) -> bevy::prelude::Result {
+    for (
+        entity,
+        source,
+        dest,
+        uuid,
+        forwarding,
+        num_writers,
+        schedule,
+        weight,
+        scan,
+        quick_check,
+        snapshot,
+        trash,
+    ) in &query
+    {
+        let dest = dest.0.clone();
+        let uuid = uuid.0;
+
+        info!(
+            "spawning remote source I/O operation {} -> {} over port {} (uuid: {})",
+            source.display(),
+            dest.display(),
+            forwarding.local_port,
+            uuid::Uuid::from_u128(uuid)
+        );
+
+        // Only writes, the scan is on the other end
+        let subsystem =
+            daemon_subsystem(&limits, &controllers, &writers, &budget, weight, schedule)
+                .with_quick_check(quick_check.is_some())
+                .with_trash(trash.map(|t| t.0.clone()));
+
+        let remote = crate::rpc::transfer::RemoteSource {
+            port: forwarding.local_port,
+            source: source.0.clone(),
+            scan_order: scan.map(|s| s.order),
+            scanners: scan.and_then(|s| s.scanners),
+            snapshot: snapshot.map(|s| s.0.clone()),
+        };
+        let subsystem_clone = subsystem.clone();
+        let writers = num_writers.and_then(|nw| nw.0);
+
+        runtime.spawn_background_task(move |_ctx| async move {
+            match crate::io::remote::Receiver::start(subsystem, uuid, dest, writers).await {
+                Ok(receiver) => {
+                    crate::rpc::transfer::pull(uuid, remote, std::sync::Arc::new(receiver)).await
+                }
+                Err(e) => error!("I/O subsystem failed to start: {}", e),
+            }
+        });
+
+        commands.entity(entity).insert((
+            IoOperation {
+                uuid,
+                subsystem: subsystem_clone,
+            },
+            IoProgress::default(),
+            crate::systems::protocol::SyncStartTime(std::time::Instant::now()),
+        ));
+    }
+    Ok(())
+}
+
+// System to request SSH connections for remote syncs, ones that failed to
+// connect keep their ConnectionError instead of hammering the host, a bad host
+// key isn't going to fix itself next tick. Remotes with more than one route go
+// over whichever is best right now, the Router clears the error if it has
+// another one to try.
 fn request_ssh_connections(
     mut commands: Commands,
+    routes: Option<Res<crate::systems::ssh::Routes>>,
     query: Query<
         (Entity, &RemoteHost, &SimpleCopy),
         (
             Without<ConnectionRequest>,
             Without<ConnectionRef>,
             Without<ConnectionPending>,
+            Without<crate::systems::ssh::ConnectionError>,
         ),
     >,
 ) -> bevy::prelude::Result {
     for (entity, remote_host, _) in &query {
-        let host_spec = remote_host.0.clone();
+        let host_spec = match &routes {
+            Some(routes) => routes.resolve(&remote_host.0),
+            None => remote_host.0.clone(),
+        };
 
         info!(
             "requesting SSH connection to {} for entity {:?}",
             host_spec, entity
         );
 
-        commands
-            .entity(entity)
-            .insert(ConnectionRequest { host_spec });
+        commands.entity(entity).insert((
+            crate::systems::ssh::routes::Route(host_spec.clone()),
+            ConnectionRequest { host_spec },
+        ));
     }
     Ok(())
 }